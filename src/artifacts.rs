@@ -0,0 +1,108 @@
+use crate::error::{self, Context, CserunError};
+use crate::history::RunRecord;
+use log::warn;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+// the names of the saved streams, stdout first
+const FILES: [&str; 2] = ["stdout.log", "stderr.log"];
+
+// the command's stdout and stderr exactly as they arrived, kept for every run under
+// ~/.cserun/runs/<run directory name> so `cserun output` can show them later
+pub struct Artifacts {
+    dir: PathBuf,
+    files: [Option<BufWriter<File>>; 2], // dropped after a write error, the run goes on
+}
+
+fn runs_dir() -> Option<PathBuf> {
+    let mut path = dirs::home_dir()?;
+    path.push(".cserun");
+    path.push("runs");
+    Some(path)
+}
+
+impl Artifacts {
+    // like the transcript, the command is already running and a failure is only a warning
+    pub fn open(run_name: &str) -> Option<Artifacts> {
+        let dir = runs_dir()?.join(run_name);
+        let open = |name: &str| -> io::Result<BufWriter<File>> {
+            fs::create_dir_all(&dir)?;
+            Ok(BufWriter::new(File::create(dir.join(name))?))
+        };
+        match (open(FILES[0]), open(FILES[1])) {
+            (Ok(stdout), Ok(stderr)) => Some(Artifacts {
+                dir,
+                files: [Some(stdout), Some(stderr)],
+            }),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Can not save the output in {}: {}", dir.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn stdout(&mut self, data: &[u8]) {
+        self.write(0, data);
+    }
+
+    pub fn stderr(&mut self, data: &[u8]) {
+        self.write(1, data);
+    }
+
+    fn write(&mut self, stream: usize, data: &[u8]) {
+        let Some(file) = &mut self.files[stream] else {
+            return;
+        };
+        if let Err(e) = file.write_all(data) {
+            warn!("Stopped saving {}: {}", FILES[stream], e);
+            self.files[stream] = None;
+        }
+    }
+
+    pub fn finish(mut self) {
+        for (stream, file) in self.files.iter_mut().enumerate() {
+            if let Some(Err(e)) = file.take().map(|mut file| file.flush()) {
+                warn!(
+                    "Stopped saving {}: {}",
+                    self.dir.join(FILES[stream]).display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+// the saved output of a run from the history, stdout to stdout and stderr to stderr
+pub fn show(record: &RunRecord) -> error::Result<()> {
+    let dir = runs_dir()
+        .map(|dir| dir.join(&record.started))
+        .filter(|dir| dir.is_dir())
+        .ok_or(format!("No output was saved for run {}", record.id))
+        .context(CserunError::Config)?;
+    for (name, mut out) in [
+        (FILES[0], Box::new(io::stdout()) as Box<dyn Write>),
+        (FILES[1], Box::new(io::stderr())),
+    ] {
+        match File::open(dir.join(name)) {
+            Ok(mut file) => {
+                io::copy(&mut file, &mut out).context(CserunError::Local)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(CserunError::Local(e.into())),
+        }
+    }
+    Ok(())
+}
+
+// the output of runs the history has forgotten goes with them
+pub fn remove(run_name: &str) {
+    let Some(dir) = runs_dir().map(|dir| dir.join(run_name)) else {
+        return;
+    };
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Could not remove {}: {}", dir.display(), e);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::error::{self, Context, CserunError};
+use crate::hash;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// what we last uploaded for one file of a workspace
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub size: u64,
+    pub mtime: u64,
+    pub hash: String,
+    // permission bits, chmod does not touch mtime so this is compared on its own
+    #[serde(default)]
+    pub mode: u32,
+}
+
+// per-workspace record of uploaded files, keyed by path relative to the local dir
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub files: BTreeMap<String, FileEntry>,
+}
+
+pub fn cache_dir() -> error::Result<PathBuf> {
+    let mut path = dirs::home_dir()
+        .ok_or("Cannot find the home directory")
+        .context(CserunError::Local)?;
+    path.push(".cserun");
+    path.push("cache");
+    fs::create_dir_all(&path).context(CserunError::Local)?;
+    Ok(path)
+}
+
+// stable id for a local directory synced to a given account, the default workspace name
+pub fn workspace_id(server_addr: &str, username: &str, local_dir: &Path) -> String {
+    let local_dir = fs::canonicalize(local_dir).unwrap_or_else(|_| local_dir.to_path_buf());
+    let key = format!("{}@{}:{}", username, server_addr, local_dir.display());
+    hash::sha256_hex(key.as_bytes())[..16].to_string()
+}
+
+// manifests are per remote workspace, the same name on another account is another workspace
+pub fn manifest_id(server_addr: &str, username: &str, workspace: &str) -> String {
+    let key = format!("{}@{}:{}", username, server_addr, workspace);
+    hash::sha256_hex(key.as_bytes())[..16].to_string()
+}
+
+impl Manifest {
+    pub fn load(id: &str) -> error::Result<Manifest> {
+        let path = cache_dir()?.join(format!("{}.toml", id));
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        // a corrupt cache only costs a full upload
+        let contents = fs::read_to_string(path).context(CserunError::Local)?;
+        Ok(toml::from_str(&contents).unwrap_or_default())
+    }
+
+    pub fn save(&self, id: &str) -> error::Result<()> {
+        let path = cache_dir()?.join(format!("{}.toml", id));
+        let contents = toml::to_string(self).context(CserunError::Local)?;
+        fs::write(path, contents).context(CserunError::Local)
+    }
+
+    pub fn remove(id: &str) -> error::Result<()> {
+        let path = cache_dir()?.join(format!("{}.toml", id));
+        if path.exists() {
+            fs::remove_file(path).context(CserunError::Local)?;
+        }
+        Ok(())
+    }
+
+    // describe the local file, only re-hashing it when size or mtime moved since last time
+    pub fn entry_for(&self, key: &str, local_path: &Path) -> std::io::Result<FileEntry> {
+        let metadata = fs::metadata(local_path)?;
+        let size = metadata.len();
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mode = file_mode(&metadata);
+        if let Some(old) = self.files.get(key) {
+            if old.size == size && old.mtime == mtime {
+                return Ok(FileEntry {
+                    mode,
+                    ..old.clone()
+                });
+            }
+        }
+        Ok(FileEntry {
+            size,
+            mtime,
+            hash: hash::sha256_file(local_path)?,
+            mode,
+        })
+    }
+}
+
+#[cfg(unix)]
+pub fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.is_dir() {
+        0o755
+    } else {
+        0o644
+    }
+}
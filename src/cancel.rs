@@ -0,0 +1,35 @@
+use crate::error::{self, CserunError};
+use crate::terminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// stops a run from another thread, e.g. the cancel button of a GUI driving the library.
+// clones share the flag, so one can be handed to the thread that cancels
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    // uploads stop before their next file, a running command gets SIGINT and SIGKILL after 3s
+    // like on ctrl-c, and the call returns CserunError::Cancelled once the run dir is removed
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+        // the output loop sleeps on the wake pipe too
+        terminal::wake();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // an error to return with `?` once cancelled, saying what was stopped
+    pub fn check(&self, what: &str) -> error::Result<()> {
+        match self.is_cancelled() {
+            true => Err(CserunError::Cancelled(what.into())),
+            false => Ok(()),
+        }
+    }
+}
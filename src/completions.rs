@@ -0,0 +1,464 @@
+use crate::history;
+use crate::parse;
+use clap::builder::ValueHint;
+use clap::{Arg, Command, ValueEnum};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+// `cserun completions <shell>`: a completion script made from the clap definition of the cli,
+// so it never falls behind the options. the names from the config and the history change
+// between runs, the script asks `cserun __complete <kind>` for them as it completes
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+// what `cserun __complete` lists, one per line
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Profiles,
+    Hosts,
+    Templates,
+    Runs,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Profiles => "profiles",
+            Kind::Hosts => "hosts",
+            Kind::Templates => "templates",
+            Kind::Runs => "runs",
+        }
+    }
+}
+
+// the names, nothing at all rather than an error, the shell has nowhere to show one
+pub fn candidates(kind: Kind) -> Vec<String> {
+    match kind {
+        Kind::Profiles => parse::config_names("profiles"),
+        Kind::Hosts => parse::config_names("hosts"),
+        Kind::Templates => parse::config_names("templates"),
+        // the latest first, as they are looked for
+        Kind::Runs => std::iter::once("last".to_string())
+            .chain(
+                history::load_runs()
+                    .unwrap_or_default()
+                    .iter()
+                    .rev()
+                    .map(|run| run.id.to_string()),
+            )
+            .collect(),
+    }
+}
+
+// what an option's value, or a positional argument, is completed with
+#[derive(Clone, PartialEq)]
+enum Values {
+    Dynamic(Kind),
+    Choices(Vec<String>),
+    Files,
+    Nothing,
+}
+
+// a subcommand, "" for the top level and e.g. "auth store" below it
+struct Node {
+    path: String,
+    about: String,
+    options: Vec<(String, String)>, // with their help
+    subcommands: Vec<String>,
+    positional: Values,
+}
+
+fn values(arg: &Arg) -> Values {
+    match arg.get_id().as_str() {
+        "profile" => return Values::Dynamic(Kind::Profiles),
+        "host" => return Values::Dynamic(Kind::Hosts),
+        "command" => return Values::Dynamic(Kind::Templates),
+        "run" if arg.is_positional() => return Values::Dynamic(Kind::Runs),
+        _ => {}
+    }
+    let choices: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect();
+    if !choices.is_empty() {
+        return Values::Choices(choices);
+    }
+    match arg.get_value_hint() {
+        ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath | ValueHint::Unknown => {
+            Values::Files
+        }
+        _ => Values::Nothing,
+    }
+}
+
+fn help(arg: &Arg) -> String {
+    arg.get_help()
+        .map(|help| help.to_string())
+        .unwrap_or_default()
+}
+
+// every visible subcommand with its options, and the values of every option that takes one. an
+// option name means the same in every subcommand, so its values are looked up by name alone
+fn walk(cmd: &Command, path: &str, nodes: &mut Vec<Node>, valued: &mut BTreeMap<String, Values>) {
+    let mut options = Vec::new();
+    for arg in cmd.get_arguments().filter(|arg| !arg.is_hide_set()) {
+        if arg.is_positional() {
+            continue;
+        }
+        let mut names: Vec<String> = arg
+            .get_short()
+            .map(|s| format!("-{}", s))
+            .into_iter()
+            .collect();
+        if let Some(long) = arg.get_long() {
+            names.push(format!("--{}", long));
+        }
+        for alias in arg.get_visible_aliases().unwrap_or_default() {
+            names.push(format!("--{}", alias));
+        }
+        for name in names {
+            if arg.get_action().takes_values() {
+                valued.entry(name.clone()).or_insert_with(|| values(arg));
+            }
+            options.push((name, help(arg)));
+        }
+    }
+    let positional = cmd
+        .get_positionals()
+        .find(|arg| !arg.is_hide_set())
+        .map_or(Values::Nothing, values);
+    // clap's own `help` repeats the whole tree below it
+    let subcommands: Vec<&Command> = cmd
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set() && cmd.get_name() != "help")
+        .collect();
+    nodes.push(Node {
+        path: path.to_string(),
+        about: cmd
+            .get_about()
+            .map(|about| about.to_string())
+            .unwrap_or_default(),
+        options,
+        subcommands: subcommands
+            .iter()
+            .map(|sub| sub.get_name().to_string())
+            .collect(),
+        positional,
+    });
+    for sub in subcommands {
+        let path = match path {
+            "" => sub.get_name().to_string(),
+            path => format!("{} {}", path, sub.get_name()),
+        };
+        walk(sub, &path, nodes, valued);
+    }
+}
+
+pub fn script(cmd: &mut Command, shell: Shell) -> String {
+    // the global options only reach the subcommands once clap has built them
+    cmd.build();
+    let mut nodes = Vec::new();
+    let mut valued = BTreeMap::new();
+    walk(cmd, "", &mut nodes, &mut valued);
+    let bin = cmd.get_name().to_string();
+    match shell {
+        Shell::Bash => bash(&bin, &nodes, &valued),
+        Shell::Zsh => zsh(&bin, &nodes, &valued),
+        Shell::Fish => fish(&bin, &nodes, &valued),
+    }
+}
+
+fn words(items: &[String]) -> String {
+    items.join(" ")
+}
+
+// the part bash and zsh share: which subcommand the line is in, how many positional arguments
+// came before the cursor and whether the previous word wants a value
+fn find_path(nodes: &[Node], valued: &BTreeMap<String, Values>, first: &str) -> String {
+    let mut script = String::new();
+    let takes_value: Vec<String> = valued.keys().cloned().collect();
+    let _ = writeln!(script, "    local cmdpath=\"\" word i n=0 skip=0");
+    let _ = writeln!(script, "    for ((i = {}; i < CURRENT; i++)); do", first);
+    let _ = writeln!(script, "        word=\"${{words[i]}}\"");
+    let _ = writeln!(
+        script,
+        "        if [[ $skip == 1 ]]; then skip=0; continue; fi"
+    );
+    let _ = writeln!(script, "        case \"$word\" in");
+    let _ = writeln!(script, "            {}) skip=1 ;;", takes_value.join("|"));
+    let _ = writeln!(script, "            -*) ;;");
+    let _ = writeln!(script, "            *)");
+    let _ = writeln!(script, "                case \"$n/$cmdpath/$word\" in");
+    for node in nodes {
+        for sub in &node.subcommands {
+            let path = match node.path.as_str() {
+                "" => sub.clone(),
+                path => format!("{} {}", path, sub),
+            };
+            let _ = writeln!(
+                script,
+                "                    \"0/{}/{}\") cmdpath=\"{}\" ;;",
+                node.path, sub, path
+            );
+        }
+    }
+    let _ = writeln!(script, "                    *) n=$((n + 1)) ;;");
+    let _ = writeln!(script, "                esac ;;");
+    let _ = writeln!(script, "        esac");
+    let _ = writeln!(script, "    done");
+    script
+}
+
+fn bash(bin: &str, nodes: &[Node], valued: &BTreeMap<String, Values>) -> String {
+    let function = format!("_{}", bin.replace('-', "_"));
+    // `=` sets the candidates, `+=` adds to the subcommands
+    let reply = |values: &Values, op: &str| match values {
+        Values::Dynamic(kind) => format!(
+            "COMPREPLY{}($(compgen -W \"$({} __complete {} 2>/dev/null)\" -- \"$cur\"))",
+            op,
+            bin,
+            kind.name()
+        ),
+        Values::Choices(choices) => format!(
+            "COMPREPLY{}($(compgen -W \"{}\" -- \"$cur\"))",
+            op,
+            words(choices)
+        ),
+        Values::Files => format!("COMPREPLY{}($(compgen -f -- \"$cur\"))", op),
+        Values::Nothing => format!("COMPREPLY{}()", op),
+    };
+    let mut script = String::new();
+    let _ = writeln!(script, "{}() {{", function);
+    let _ = writeln!(
+        script,
+        "    local words=(\"${{COMP_WORDS[@]}}\") CURRENT=$COMP_CWORD"
+    );
+    let _ = writeln!(
+        script,
+        "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\""
+    );
+    script.push_str(&find_path(nodes, valued, "1"));
+    let _ = writeln!(script, "    case \"$prev\" in");
+    for (name, values) in valued {
+        let _ = writeln!(
+            script,
+            "        {}) {}; return ;;",
+            name,
+            reply(values, "=")
+        );
+    }
+    let _ = writeln!(script, "    esac");
+    let _ = writeln!(script, "    case \"$cmdpath/$cur\" in");
+    for node in nodes {
+        let options: Vec<String> = node.options.iter().map(|(name, _)| name.clone()).collect();
+        let _ = writeln!(
+            script,
+            "        \"{}/\"-*) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")) ;;",
+            node.path,
+            words(&options)
+        );
+    }
+    for node in nodes {
+        let mut replies = Vec::new();
+        if !node.subcommands.is_empty() {
+            replies.push(format!(
+                "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+                words(&node.subcommands)
+            ));
+        }
+        // a template or run id is only the first argument, the rest are files
+        match &node.positional {
+            Values::Dynamic(_) | Values::Choices(_) => replies.push(format!(
+                "[[ $n == 0 ]] && {}",
+                reply(&node.positional, "+=")
+            )),
+            Values::Files => replies.push(reply(&node.positional, "+=")),
+            Values::Nothing => {}
+        }
+        if replies.is_empty() {
+            continue;
+        }
+        let _ = writeln!(
+            script,
+            "        \"{}/\"*) {} ;;",
+            node.path,
+            replies.join("; ")
+        );
+    }
+    let _ = writeln!(script, "    esac");
+    let _ = writeln!(script, "}}");
+    let _ = writeln!(
+        script,
+        "complete -o bashdefault -o default -F {} {}",
+        function, bin
+    );
+    script
+}
+
+fn zsh(bin: &str, nodes: &[Node], valued: &BTreeMap<String, Values>) -> String {
+    let function = format!("_{}", bin.replace('-', "_"));
+    let add = |values: &Values| match values {
+        Values::Dynamic(kind) => format!(
+            "compadd -- ${{(f)\"$({} __complete {} 2>/dev/null)\"}}",
+            bin,
+            kind.name()
+        ),
+        Values::Choices(choices) => format!("compadd -- {}", words(choices)),
+        Values::Files => "_files".to_string(),
+        Values::Nothing => "_message value".to_string(),
+    };
+    let mut script = String::new();
+    let _ = writeln!(script, "#compdef {}", bin);
+    let _ = writeln!(script);
+    let _ = writeln!(script, "{}() {{", function);
+    let _ = writeln!(
+        script,
+        "    local cur=\"${{words[CURRENT]}}\" prev=\"${{words[CURRENT-1]}}\""
+    );
+    script.push_str(&find_path(nodes, valued, "2"));
+    let _ = writeln!(script, "    case \"$prev\" in");
+    for (name, values) in valued {
+        let _ = writeln!(script, "        {}) {}; return ;;", name, add(values));
+    }
+    let _ = writeln!(script, "    esac");
+    let _ = writeln!(script, "    case \"$cmdpath/$cur\" in");
+    for node in nodes {
+        let _ = writeln!(script, "        \"{}/\"-*)", node.path);
+        let _ = writeln!(script, "            local -a options=(");
+        for (name, help) in &node.options {
+            let _ = writeln!(
+                script,
+                "                {}",
+                zsh_quote(&format!("{}:{}", name.replace(':', "\\:"), help))
+            );
+        }
+        let _ = writeln!(script, "            )");
+        let _ = writeln!(script, "            _describe option options ;;");
+    }
+    for node in nodes {
+        let mut adds = Vec::new();
+        if !node.subcommands.is_empty() {
+            adds.push(format!("compadd -- {}", words(&node.subcommands)));
+        }
+        match &node.positional {
+            Values::Dynamic(_) | Values::Choices(_) => {
+                adds.push(format!("[[ $n == 0 ]] && {}", add(&node.positional)))
+            }
+            Values::Files => adds.push("_files".to_string()),
+            Values::Nothing => {}
+        }
+        if adds.is_empty() {
+            continue;
+        }
+        let _ = writeln!(
+            script,
+            "        \"{}/\"*) {} ;;",
+            node.path,
+            adds.join("; ")
+        );
+    }
+    let _ = writeln!(script, "    esac");
+    let _ = writeln!(script, "}}");
+    let _ = writeln!(script);
+    // autoloaded from $fpath the file is the function's body, sourced it registers it
+    let _ = writeln!(
+        script,
+        "if [[ \"${{funcstack[1]}}\" == \"{}\" ]]; then",
+        function
+    );
+    let _ = writeln!(script, "    {} \"$@\"", function);
+    let _ = writeln!(script, "else");
+    let _ = writeln!(script, "    compdef {} {}", function, bin);
+    let _ = writeln!(script, "fi");
+    script
+}
+
+fn zsh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn fish(bin: &str, nodes: &[Node], valued: &BTreeMap<String, Values>) -> String {
+    let mut script = String::new();
+    // fish only knows which subcommands were seen, not where in the tree they are
+    let condition = |node: &Node| match node.path.rsplit_once(' ') {
+        None if node.path.is_empty() => "__fish_use_subcommand".to_string(),
+        None => format!("__fish_seen_subcommand_from {}", node.path),
+        Some((_, last)) => format!("__fish_seen_subcommand_from {}", last),
+    };
+    for node in nodes {
+        let condition = condition(node);
+        for sub in &node.subcommands {
+            let path = match node.path.as_str() {
+                "" => sub.clone(),
+                path => format!("{} {}", path, sub),
+            };
+            let about = nodes
+                .iter()
+                .find(|other| other.path == path)
+                .map_or("", |other| other.about.as_str());
+            let condition = match node.path.as_str() {
+                "" => condition.clone(),
+                _ => format!(
+                    "{}; and not __fish_seen_subcommand_from {}",
+                    condition,
+                    words(&node.subcommands)
+                ),
+            };
+            let _ = writeln!(
+                script,
+                "complete -c {} -n {} -f -a {} -d {}",
+                bin,
+                fish_quote(&condition),
+                sub,
+                fish_quote(about)
+            );
+        }
+        for (name, help) in &node.options {
+            let flag = match name.strip_prefix("--") {
+                Some(long) => format!("-l {}", long),
+                None => format!("-s {}", name.trim_start_matches('-')),
+            };
+            let value = match valued.get(name) {
+                Some(Values::Dynamic(kind)) => {
+                    format!(
+                        " -x -a {}",
+                        fish_quote(&format!("({} __complete {})", bin, kind.name()))
+                    )
+                }
+                Some(Values::Choices(choices)) => format!(" -x -a {}", fish_quote(&words(choices))),
+                Some(Values::Files) => " -r -F".to_string(),
+                Some(Values::Nothing) => " -x".to_string(),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                script,
+                "complete -c {} -n {} {}{} -d {}",
+                bin,
+                fish_quote(&condition),
+                flag,
+                value,
+                fish_quote(help)
+            );
+        }
+        if let Values::Dynamic(kind) = &node.positional {
+            let _ = writeln!(
+                script,
+                "complete -c {} -n {} -f -a {}",
+                bin,
+                fish_quote(&condition),
+                fish_quote(&format!("({} __complete {})", bin, kind.name()))
+            );
+        }
+    }
+    script
+}
+
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
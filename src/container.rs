@@ -0,0 +1,126 @@
+use crate::error::{self, Context, CserunError};
+use crate::executor::Executor;
+use crate::local::{self, Local};
+use crate::ssh::{Config, Finished};
+use crate::terminal;
+use log::{info, warn};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+// where the copied files appear inside the container
+const MOUNT: &str = "/cserun";
+
+// the docker and podman backends: the files are copied like for the local backend, and that
+// directory is mounted into a fresh container of the image the command runs in
+pub struct Container<'a> {
+    conf: &'a Config,
+    engine: &'static str, // "docker" or "podman"
+    image: String,
+    name: String, // of the container, to stop it on --timeout
+    local: Local<'a>,
+}
+
+impl<'a> Container<'a> {
+    pub fn new(conf: &'a Config, engine: &'static str) -> error::Result<Container<'a>> {
+        let image = conf.image.clone().ok_or_else(|| {
+            CserunError::Config(
+                format!(
+                    "backend = \"{}\" needs an image, e.g. image = \"gcc:13\"",
+                    engine
+                )
+                .into(),
+            )
+        })?;
+        let local = Local::new(conf)?;
+        let name = format!("cserun-{}", local.name());
+        Ok(Container {
+            conf,
+            engine,
+            image,
+            name,
+            local,
+        })
+    }
+
+    fn command(&self, command: &str) -> Command {
+        let workdir = match &self.conf.workdir {
+            Some(workdir) => Path::new(MOUNT).join(workdir),
+            None => Path::new(MOUNT).to_path_buf(),
+        };
+        let mut process = Command::new(self.engine);
+        process
+            .args(["run", "--rm", "--name", &self.name, "-i"])
+            // z relabels the directory for SELinux, elsewhere it does nothing
+            .arg("-v")
+            .arg(format!("{}:{}:z", self.local.container.display(), MOUNT))
+            .arg("-w")
+            .arg(workdir);
+        if self.conf.pty {
+            process.arg("-t");
+        }
+        for (key, value) in &self.conf.envs {
+            process.arg("-e").arg(format!("{}={}", key, value));
+        }
+        // docker runs as root, which would leave files we can not remove or fetch properly.
+        // rootless podman already maps root to us
+        #[cfg(unix)]
+        if self.engine == "docker" {
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            process.arg("--user").arg(format!("{}:{}", uid, gid));
+        }
+        process.arg(&self.image);
+        process
+            .args(
+                self.conf
+                    .shell
+                    .as_deref()
+                    .unwrap_or("sh -c")
+                    .split_whitespace(),
+            )
+            .arg(command);
+        process
+    }
+}
+
+impl Executor for Container<'_> {
+    fn describe(&self) -> String {
+        self.local.describe()
+    }
+
+    fn sync(&mut self) -> error::Result<(usize, u64)> {
+        self.local.sync()
+    }
+
+    fn run(&mut self, command: &str) -> error::Result<Finished> {
+        info!(
+            "Running `{}` in {} with {}",
+            command, self.image, self.engine
+        );
+        terminal::catch_interrupt();
+        let child = self
+            .command(command)
+            .spawn()
+            .map_err(|e| format!("Failed to start {}: {}", self.engine, e))
+            .context(CserunError::Exec)?;
+        // killing the client would leave the container running
+        let (engine, name) = (self.engine, self.name.clone());
+        local::wait(self.conf, child, move |_: &mut Child| {
+            let stopped = Command::new(engine)
+                .args(["kill", &name])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            if !stopped.is_ok_and(|status| status.success()) {
+                warn!("Could not stop the container {}", name);
+            }
+        })
+    }
+
+    fn fetch(&mut self, patterns: &[String]) -> error::Result<usize> {
+        self.local.fetch(patterns)
+    }
+
+    fn cleanup(&mut self, keep: bool) -> error::Result<()> {
+        self.local.cleanup(keep)
+    }
+}
@@ -0,0 +1,603 @@
+use crate::error::{self, Context, CserunError};
+use crate::ssh::Config;
+use crate::{hash, terminal};
+use log::{debug, info, warn};
+use ssh2::Session;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// the authenticated session of this process and the account it is for, handed to the daemon
+static HELD: Mutex<Option<(String, Session)>> = Mutex::new(None);
+// set in the daemon, which neither forwards requests nor starts another daemon
+static SERVING: AtomicBool = AtomicBool::new(false);
+// in the process forked off by Daemon::start, the daemon to become after the run and the pipe
+// the exit status goes back through
+static PENDING: Mutex<Option<(Daemon, File)>> = Mutex::new(None);
+// in the process waiting for the run, the forked process doing it
+static RUN_PID: AtomicI32 = AtomicI32::new(0);
+
+// what the client sends: a run with stdout and stderr attached, then its stdin, ctrl-c and EOF
+const RUN: u8 = b'r';
+const STOP: u8 = b's';
+const STDIN: u8 = b'd';
+const STDIN_EOF: u8 = b'e';
+const INTERRUPT: u8 = b'i';
+// what the daemon answers before the exit status
+const ACCEPTED: u8 = b'a';
+const STALE: u8 = b'x';
+const BUSY: u8 = b'b';
+
+fn account(conf: &Config) -> String {
+    format!("{}@{}", conf.username, conf.server_addr)
+}
+
+// one socket per account, in a directory only the user can open
+fn socket_path(conf: &Config) -> Option<PathBuf> {
+    let mut path = dirs::home_dir()?;
+    path.push(".cserun");
+    path.push("control");
+    path.push(format!(
+        "{}.sock",
+        &hash::sha256_hex(account(conf).as_bytes())[..16]
+    ));
+    Some(path)
+}
+
+// remember the session of this run, so a daemon can keep it open afterwards
+pub fn hold(conf: &Config, sess: &Session) {
+    if conf.control_persist.is_some() && !SERVING.load(Ordering::SeqCst) {
+        *HELD.lock().unwrap() = Some((account(conf), sess.clone()));
+    }
+}
+
+// the daemon's session when it is for the same account
+pub fn held(conf: &Config) -> Option<Session> {
+    if !SERVING.load(Ordering::SeqCst) {
+        return None;
+    }
+    let held = HELD.lock().unwrap();
+    let (held_account, sess) = held.as_ref()?;
+    (*held_account == account(conf)).then(|| sess.clone())
+}
+
+// run this invocation of cserun in the daemon of the account, None when there is no daemon
+// or its connection has gone away, the caller then runs it on its own
+pub fn forward(conf: &Config) -> error::Result<Option<i32>> {
+    if SERVING.load(Ordering::SeqCst) {
+        return Ok(None);
+    }
+    let Some(path) = socket_path(conf) else {
+        return Ok(None);
+    };
+    let Ok(stream) = UnixStream::connect(&path) else {
+        return Ok(None);
+    };
+    let mut payload = std::env::current_dir()
+        .context(CserunError::Local)?
+        .into_os_string()
+        .into_vec();
+    for arg in std::env::args_os() {
+        payload.push(0);
+        payload.extend(arg.into_vec());
+    }
+    send_with_fds(&stream, RUN, &[libc::STDOUT_FILENO, libc::STDERR_FILENO])
+        .and_then(|_| write_frame(&stream, &payload))
+        .context(CserunError::Local)?;
+    let mut answer = [0];
+    match (&stream).read_exact(&mut answer).map(|_| answer[0]) {
+        Ok(ACCEPTED) => {}
+        Ok(BUSY) => {
+            debug!("The control daemon is running another command, connecting again");
+            return Ok(None);
+        }
+        _ => {
+            debug!("The control daemon's connection is gone, connecting again");
+            return Ok(None);
+        }
+    }
+    info!("Running through the control daemon for {}", account(conf));
+
+    // stdin goes through the socket, so nothing is left reading the terminal after the run.
+    // the thread may wait for input until cserun exits
+    let writer = Arc::new(Mutex::new(stream.try_clone().context(CserunError::Local)?));
+    let stdin_writer = writer.clone();
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buffer = [0; 4096];
+        loop {
+            let size = stdin.read(&mut buffer).unwrap_or(0);
+            let writer = stdin_writer.lock().unwrap();
+            if size == 0 {
+                let _ = (&*writer).write_all(&[STDIN_EOF]);
+                break;
+            }
+            let sent = (&*writer)
+                .write_all(&[STDIN])
+                .and_then(|_| write_frame(&writer, &buffer[..size]));
+            if sent.is_err() {
+                break;
+            }
+        }
+    });
+
+    terminal::catch_interrupt();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .context(CserunError::Local)?;
+    let mut status = [0; 4];
+    let mut received = 0;
+    let mut interrupt_sent = false;
+    while received < status.len() {
+        if terminal::interrupted() && !interrupt_sent {
+            let _ = (&*writer.lock().unwrap()).write_all(&[INTERRUPT]);
+            interrupt_sent = true;
+        }
+        match (&stream).read(&mut status[received..]) {
+            Ok(0) => {
+                return Err(CserunError::Exec(
+                    "The control daemon exited during the run".into(),
+                ))
+            }
+            Ok(size) => received += size,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(CserunError::Exec(e.into())),
+        }
+    }
+    Ok(Some(i32::from_be_bytes(status)))
+}
+
+// ask the daemon of the account to exit, false if there is none
+pub fn stop(conf: &Config) -> error::Result<bool> {
+    let Some(path) = socket_path(conf) else {
+        return Ok(false);
+    };
+    let Ok(stream) = UnixStream::connect(&path) else {
+        return Ok(false);
+    };
+    send_with_fds(&stream, STOP, &[]).context(CserunError::Local)?;
+    let mut answer = [0];
+    (&stream)
+        .read_exact(&mut answer)
+        .context(CserunError::Local)?;
+    Ok(true)
+}
+
+// a background process keeping the session of this run open for the next runs
+pub struct Daemon {
+    socket: PathBuf,
+    persist: Duration,
+}
+
+impl Daemon {
+    // only with control_persist, and never from inside the daemon
+    pub fn for_config(conf: &Config) -> Option<Daemon> {
+        if SERVING.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(Daemon {
+            socket: socket_path(conf)?,
+            persist: conf.control_persist?,
+        })
+    }
+
+    // fork before the run, while this process has no other threads yet. the child does the run
+    // and stays on as the daemon afterwards, see `serve_after`. this process only waits for the
+    // exit status of the run and exits with it, so the shell sees the run end as usual
+    pub fn start(self) {
+        // another run started one in the meantime
+        if UnixStream::connect(&self.socket).is_ok() {
+            return;
+        }
+        let mut pipe = [0; 2];
+        if unsafe { libc::pipe(pipe.as_mut_ptr()) } != 0 {
+            warn!(
+                "Could not start the control daemon: {}",
+                io::Error::last_os_error()
+            );
+            return;
+        }
+        // commands the run starts must not keep the pipe open
+        let (reader, writer) = unsafe { (File::from_raw_fd(pipe[0]), File::from_raw_fd(pipe[1])) };
+        for fd in pipe {
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        match unsafe { libc::fork() } {
+            -1 => warn!(
+                "Could not start the control daemon: {}",
+                io::Error::last_os_error()
+            ),
+            0 => {
+                drop(reader);
+                *PENDING.lock().unwrap() = Some((self, writer));
+            }
+            pid => {
+                drop(writer);
+                wait_for_run(pid, reader);
+            }
+        }
+    }
+
+    // None if a daemon is already listening
+    fn bind(&self) -> io::Result<Option<UnixListener>> {
+        if UnixStream::connect(&self.socket).is_ok() {
+            return Ok(None);
+        }
+        if let Some(dir) = self.socket.parent() {
+            fs::create_dir_all(dir)?;
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+        }
+        // left behind by a daemon that did not exit cleanly
+        let _ = fs::remove_file(&self.socket);
+        UnixListener::bind(&self.socket).map(Some)
+    }
+
+    // every client gets a thread, so a stop or a second run is answered right away. the runs
+    // share the process's stdio and directory though, one more run while another is going
+    // is told to connect on its own
+    fn serve(&self, listener: UnixListener, sess: &Session, serve: fn(Vec<OsString>) -> i32) {
+        let inode = fs::metadata(&self.socket).map(|m| m.ino()).ok();
+        if listener.set_nonblocking(true).is_err() {
+            return;
+        }
+        let activity = Arc::new(Mutex::new(Activity {
+            running: false,
+            closing: false,
+            stop: false,
+            idle_since: Instant::now(),
+        }));
+        loop {
+            {
+                let mut activity = activity.lock().unwrap();
+                // keepalives are only sent when due, a failure means the server is gone
+                if !activity.running
+                    && (activity.closing
+                        || activity.stop
+                        || activity.idle_since.elapsed() >= self.persist
+                        || sess.keepalive_send().is_err())
+                {
+                    activity.closing = true;
+                    break;
+                }
+            }
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let activity = activity.clone();
+                    let sess = sess.clone();
+                    std::thread::spawn(move || {
+                        let _ = stream
+                            .set_nonblocking(false)
+                            .and_then(|_| handle(stream, &sess, serve, &activity));
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    wait_readable(listener.as_raw_fd(), Duration::from_secs(1));
+                }
+                Err(_) => break,
+            }
+        }
+        // a new daemon may have taken the path over already
+        if fs::metadata(&self.socket).map(|m| m.ino()).ok() == inode {
+            let _ = fs::remove_file(&self.socket);
+        }
+    }
+}
+
+// what the daemon is up to, shared by the accepting loop and the clients' threads
+struct Activity {
+    running: bool,
+    closing: bool, // the loop is about to exit and takes no more runs
+    stop: bool,    // `cserun daemon stop`, exit once the run is over
+    idle_since: Instant,
+}
+
+// in the process forked off before the run: hand the exit status to the waiting parent and keep
+// the run's session open for the next runs, which does not return. does nothing when no daemon
+// was forked off or the run did not connect
+pub fn serve_after(exit_code: i32, serve: fn(Vec<OsString>) -> i32) {
+    let Some((daemon, status_writer)) = PENDING.lock().unwrap().take() else {
+        return;
+    };
+    // dropping status_writer on the way out lets the parent wait for this process instead
+    let Some(sess) = HELD.lock().unwrap().as_ref().map(|(_, sess)| sess.clone()) else {
+        return;
+    };
+    let listener = match daemon.bind() {
+        Ok(Some(listener)) => listener,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Could not start the control daemon: {}", e);
+            return;
+        }
+    };
+    info!(
+        "Keeping the connection open for {}s, `cserun daemon stop` closes it",
+        daemon.persist.as_secs()
+    );
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    SERVING.store(true, Ordering::SeqCst);
+    // out of the terminal's process group before the shell takes the terminal back
+    unsafe {
+        libc::setsid();
+    }
+    let _ = null_stdio();
+    let _ = (&status_writer).write_all(&exit_code.to_be_bytes());
+    drop(status_writer);
+    daemon.serve(listener, &sess, serve);
+    std::process::exit(0);
+}
+
+// the process the shell started: exit with the status of the run, which the forked process
+// sends before it goes on as the daemon, or with its own exit status when it does not
+fn wait_for_run(pid: libc::pid_t, status_reader: File) -> ! {
+    RUN_PID.store(pid, Ordering::SeqCst);
+    unsafe {
+        // ctrl-c reaches the run through the terminal already
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+        libc::signal(
+            libc::SIGTERM,
+            pass_signal as *const () as libc::sighandler_t,
+        );
+        libc::signal(libc::SIGHUP, pass_signal as *const () as libc::sighandler_t);
+    }
+    let mut status = [0; 4];
+    if (&status_reader).read_exact(&mut status).is_ok() {
+        std::process::exit(i32::from_be_bytes(status));
+    }
+    let mut wait_status = 0;
+    while unsafe { libc::waitpid(pid, &mut wait_status, 0) } == -1 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            std::process::exit(1);
+        }
+    }
+    if libc::WIFSIGNALED(wait_status) {
+        std::process::exit(128 + libc::WTERMSIG(wait_status));
+    }
+    std::process::exit(libc::WEXITSTATUS(wait_status));
+}
+
+// a kill of the process the shell knows is meant for the run
+extern "C" fn pass_signal(signal: libc::c_int) {
+    unsafe {
+        libc::kill(RUN_PID.load(Ordering::SeqCst), signal);
+    }
+}
+
+// one client on its own thread
+fn handle(
+    stream: UnixStream,
+    sess: &Session,
+    serve: fn(Vec<OsString>) -> i32,
+    activity: &Mutex<Activity>,
+) -> io::Result<()> {
+    let (kind, fds) = recv_with_fds(&stream)?;
+    let files: Vec<File> = fds
+        .into_iter()
+        .map(|fd| unsafe { File::from_raw_fd(fd) })
+        .collect();
+    match kind {
+        STOP => {
+            activity.lock().unwrap().stop = true;
+            return (&stream).write_all(&[ACCEPTED]);
+        }
+        RUN if files.len() == 2 => {}
+        _ => return Ok(()),
+    }
+    let payload = read_frame(&stream)?;
+    {
+        let mut activity = activity.lock().unwrap();
+        if activity.running || activity.closing {
+            return (&stream).write_all(&[BUSY]);
+        }
+        if sess.keepalive_send().is_err() {
+            activity.closing = true;
+            return (&stream).write_all(&[STALE]);
+        }
+        activity.running = true;
+    }
+    let result = run_client(stream, payload, files, sess, serve);
+    let mut activity = activity.lock().unwrap();
+    activity.running = false;
+    activity.idle_since = Instant::now();
+    result
+}
+
+// the run of one client, in its directory, with its output and a pipe carrying its stdin
+fn run_client(
+    stream: UnixStream,
+    payload: Vec<u8>,
+    files: Vec<File>,
+    sess: &Session,
+    serve: fn(Vec<OsString>) -> i32,
+) -> io::Result<()> {
+    (&stream).write_all(&[ACCEPTED])?;
+    let mut parts = payload
+        .split(|&b| b == 0)
+        .map(|part| OsString::from_vec(part.to_vec()));
+    let cwd = parts.next().unwrap_or_default();
+    let args: Vec<OsString> = parts.collect();
+
+    let mut pipe = [0; 2];
+    if unsafe { libc::pipe(pipe.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stdin_writer = unsafe { File::from_raw_fd(pipe[1]) };
+    unsafe {
+        libc::dup2(pipe[0], libc::STDIN_FILENO);
+        libc::close(pipe[0]);
+        libc::dup2(files[0].as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(files[1].as_raw_fd(), libc::STDERR_FILENO);
+    }
+    drop(files);
+    terminal::set_interrupted(false);
+    sess.set_blocking(true);
+
+    let reader = stream.try_clone()?;
+    let input = std::thread::spawn(move || pump_client(reader, stdin_writer));
+    let status = match std::env::set_current_dir(Path::new(&cwd)) {
+        // a panic, e.g. printing to a closed pipe, ends the run but not the daemon
+        Ok(()) => std::panic::catch_unwind(|| serve(args)).unwrap_or(101),
+        Err(e) => {
+            eprintln!("Error: {:?}", format!("{}: {}", cwd.to_string_lossy(), e));
+            1
+        }
+    };
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    null_stdio()?;
+
+    (&stream).write_all(&status.to_be_bytes())?;
+    // ends pump_client, which closes the pipe for whatever still reads stdin
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    let _ = input.join();
+    Ok(())
+}
+
+// pass the client's stdin into the pipe and ctrl-c on to the run
+fn pump_client(mut reader: UnixStream, stdin_writer: File) {
+    let mut stdin_writer = Some(stdin_writer);
+    let mut kind = [0];
+    while reader.read_exact(&mut kind).is_ok() {
+        match kind[0] {
+            STDIN => match read_frame(&reader) {
+                Ok(data) => {
+                    if let Some(writer) = &mut stdin_writer {
+                        // the run may not read stdin at all
+                        if writer.write_all(&data).is_err() {
+                            stdin_writer = None;
+                        }
+                    }
+                }
+                Err(_) => break,
+            },
+            STDIN_EOF => stdin_writer = None,
+            INTERRUPT => terminal::set_interrupted(true),
+            _ => break,
+        }
+    }
+}
+
+fn write_frame(mut stream: &UnixStream, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(data)
+}
+
+fn read_frame(mut stream: &UnixStream) -> io::Result<Vec<u8>> {
+    let mut size = [0; 4];
+    stream.read_exact(&mut size)?;
+    let mut data = vec![0; u32::from_be_bytes(size) as usize];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// stdin, stdout and stderr on /dev/null between runs
+fn null_stdio() -> io::Result<()> {
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        unsafe {
+            libc::dup2(null.as_raw_fd(), fd);
+        }
+    }
+    Ok(())
+}
+
+fn wait_readable(fd: RawFd, timeout: Duration) {
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe {
+        libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int);
+    }
+}
+
+// one byte of data with file descriptors attached, SCM_RIGHTS needs some data to go with
+fn send_with_fds(stream: &UnixStream, byte: u8, fds: &[RawFd]) -> io::Result<()> {
+    let mut data = [byte];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let fds_size = std::mem::size_of_val(fds);
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_size as u32) } as usize];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_size as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+    match unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+// the byte and descriptors sent with send_with_fds
+fn recv_with_fds(stream: &UnixStream) -> io::Result<(u8, Vec<RawFd>)> {
+    const MAX_FDS: usize = 4;
+    let mut data = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let fds_size = MAX_FDS * std::mem::size_of::<RawFd>();
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_size as u32) } as usize];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    match unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+        _ => {}
+    }
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let size = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for index in 0..size / std::mem::size_of::<RawFd>() {
+                    fds.push(std::ptr::read_unaligned(data.add(index)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((data[0], fds))
+}
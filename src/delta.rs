@@ -0,0 +1,314 @@
+use crate::cache;
+use crate::error::{self, Context, CserunError};
+use crate::hash::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+// smaller files are cheaper to send whole than to patch with a round trip
+pub const MIN_SIZE: u64 = 1 << 20;
+
+// how much of the new file diff keeps in memory besides the window, before dropping what it
+// has passed
+const DIFF_BUFFER: usize = 1 << 20;
+
+// the blocks of a file as it was uploaded, so the next upload can tell which ones are still there.
+// kept locally by the file's sha256, the server needs nothing but dd
+pub struct Signature {
+    block_size: usize,
+    blocks: Vec<(u32, [u8; 16])>, // rolling checksum and truncated sha256 of each full block
+}
+
+// a step in rebuilding the new file on the server
+#[derive(Debug, PartialEq)]
+pub enum Op {
+    Copy { from: u64, to: u64, len: u64 }, // bytes of the old remote file, moved to `to`
+    Literal { to: u64, len: u64 },         // bytes of the new local file, sent over the wire
+}
+
+// rsync's weak checksum, cheap to roll one byte along
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Rolling {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((block.len() - i) as u32 * byte as u32);
+        }
+        Rolling {
+            a,
+            b,
+            len: block.len() as u32,
+        }
+    }
+
+    fn roll(&mut self, out: u8, new: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; 16] {
+    let mut hasher = Sha256::new();
+    hasher.update(block);
+    let mut strong = [0; 16];
+    strong.copy_from_slice(&hasher.finish()[..16]);
+    strong
+}
+
+impl Signature {
+    // the blocks of a file of `len` bytes, read a block at a time. about the square root of the
+    // size, like rsync, so both the signature and the literal data around a change stay small
+    pub fn new(mut reader: impl Read, len: u64) -> io::Result<Signature> {
+        let block_size = ((len as f64).sqrt() as usize).clamp(2048, 65536);
+        let mut block = vec![0; block_size];
+        let mut blocks = Vec::new();
+        for _ in 0..len / block_size as u64 {
+            reader.read_exact(&mut block)?;
+            blocks.push((Rolling::new(&block).digest(), strong(&block)));
+        }
+        Ok(Signature { block_size, blocks })
+    }
+
+    // copies start and end on these, so the server can move whole blocks with dd
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn path(hash: &str) -> error::Result<PathBuf> {
+        let dir = cache::cache_dir()?.join("signatures");
+        fs::create_dir_all(&dir).context(CserunError::Local)?;
+        Ok(dir.join(hash))
+    }
+
+    // the signature of an uploaded version, None if it was never kept or is unreadable
+    pub fn load(hash: &str) -> Option<Signature> {
+        let bytes = fs::read(Signature::path(hash).ok()?).ok()?;
+        let block_size = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+        if block_size == 0 || (bytes.len() - 4) % 20 != 0 {
+            return None;
+        }
+        let blocks = bytes[4..]
+            .chunks_exact(20)
+            .map(|entry| {
+                let weak = u32::from_le_bytes(entry[..4].try_into().unwrap());
+                let strong: [u8; 16] = entry[4..].try_into().unwrap();
+                (weak, strong)
+            })
+            .collect();
+        Some(Signature { block_size, blocks })
+    }
+
+    pub fn save(&self, hash: &str) -> error::Result<()> {
+        let mut bytes = Vec::with_capacity(4 + self.blocks.len() * 20);
+        bytes.extend_from_slice(&(self.block_size as u32).to_le_bytes());
+        for (weak, strong) in &self.blocks {
+            bytes.extend_from_slice(&weak.to_le_bytes());
+            bytes.extend_from_slice(strong);
+        }
+        fs::write(Signature::path(hash)?, bytes).context(CserunError::Local)
+    }
+
+    pub fn remove(hash: &str) {
+        if let Ok(path) = Signature::path(hash) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    // how to build the new file from the old one: blocks found anywhere in it are copied,
+    // everything in between is sent. adjacent copies are merged into one. the file is read
+    // once, only a window of it is in memory at a time
+    pub fn diff(&self, reader: impl Read) -> io::Result<Vec<Op>> {
+        let size = self.block_size;
+        let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, (weak, _)) in self.blocks.iter().enumerate() {
+            by_weak.entry(*weak).or_default().push(index);
+        }
+
+        let mut window = Window::new(reader);
+        let mut ops = Vec::new();
+        let mut literal_start = 0;
+        let mut offset = 0;
+        let mut rolling = match window.fill(size)? {
+            true => Some(Rolling::new(window.get(0, size))),
+            false => None,
+        };
+        while let Some(current) = rolling {
+            let found = by_weak.get(&current.digest()).and_then(|candidates| {
+                let strong = strong(window.get(offset, size));
+                candidates
+                    .iter()
+                    .find(|&&index| self.blocks[index].1 == strong)
+            });
+            if let Some(&index) = found {
+                if literal_start < offset {
+                    ops.push(Op::Literal {
+                        to: literal_start as u64,
+                        len: (offset - literal_start) as u64,
+                    });
+                }
+                push_copy(&mut ops, (index * size) as u64, offset as u64, size as u64);
+                offset += size;
+                literal_start = offset;
+                window.forget(offset);
+                rolling = match window.fill(offset + size)? {
+                    true => Some(Rolling::new(window.get(offset, size))),
+                    false => None,
+                };
+                continue;
+            }
+            if !window.fill(offset + size + 1)? {
+                break;
+            }
+            let mut next = current;
+            next.roll(window.get(offset, 1)[0], window.get(offset + size, 1)[0]);
+            rolling = Some(next);
+            offset += 1;
+            window.forget(offset);
+        }
+        let len = window.len()?;
+        if literal_start < len {
+            ops.push(Op::Literal {
+                to: literal_start as u64,
+                len: (len - literal_start) as u64,
+            });
+        }
+        Ok(ops)
+    }
+}
+
+// the part of the new file diff is looking at, by offset in the whole file
+struct Window<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    start: usize, // offset of the first byte in buffer
+    eof: bool,
+}
+
+impl<R: Read> Window<R> {
+    fn new(reader: R) -> Window<R> {
+        Window {
+            reader,
+            buffer: Vec::new(),
+            start: 0,
+            eof: false,
+        }
+    }
+
+    // read until the file is there up to `end`, false if it is shorter
+    fn fill(&mut self, end: usize) -> io::Result<bool> {
+        let mut chunk = [0; 65536];
+        while self.start + self.buffer.len() < end && !self.eof {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.start + self.buffer.len() >= end)
+    }
+
+    fn get(&self, offset: usize, len: usize) -> &[u8] {
+        &self.buffer[offset - self.start..offset - self.start + len]
+    }
+
+    // nothing before `offset` is looked at again
+    fn forget(&mut self, offset: usize) {
+        if offset - self.start >= DIFF_BUFFER {
+            self.buffer.drain(..offset - self.start);
+            self.start = offset;
+        }
+    }
+
+    // the size of the whole file, reading what is left of it
+    fn len(&mut self) -> io::Result<usize> {
+        self.fill(usize::MAX)?;
+        Ok(self.start + self.buffer.len())
+    }
+}
+
+fn push_copy(ops: &mut Vec<Op>, from: u64, to: u64, len: u64) {
+    if let Some(Op::Copy {
+        from: last_from,
+        to: last_to,
+        len: last_len,
+    }) = ops.last_mut()
+    {
+        if *last_from + *last_len == from && *last_to + *last_len == to {
+            *last_len += len;
+            return;
+        }
+    }
+    ops.push(Op::Copy { from, to, len });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // put the new file together from the old one and the literals, as the server does
+    fn apply(old: &[u8], new: &[u8], ops: &[Op]) -> Vec<u8> {
+        let mut built = Vec::new();
+        for op in ops {
+            match *op {
+                Op::Copy { from, to, len } => {
+                    assert_eq!(to as usize, built.len());
+                    built.extend_from_slice(&old[from as usize..(from + len) as usize]);
+                }
+                Op::Literal { to, len } => {
+                    assert_eq!(to as usize, built.len());
+                    built.extend_from_slice(&new[to as usize..(to + len) as usize]);
+                }
+            }
+        }
+        built
+    }
+
+    #[test]
+    fn diff_rebuilds_the_new_file() {
+        // larger than DIFF_BUFFER, so the window moves on
+        let mut state = 1u32;
+        let old: Vec<u8> = (0..3 << 20)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 24) as u8
+            })
+            .collect();
+        let mut new = old.clone();
+        new[100_000..100_010].copy_from_slice(b"0123456789");
+        new.splice(2_000_000..2_000_000, b"inserted".iter().copied());
+        new.truncate(new.len() - 5000);
+
+        let signature = Signature::new(&old[..], old.len() as u64).unwrap();
+        let ops = signature.diff(&new[..]).unwrap();
+        assert_eq!(apply(&old, &new, &ops), new);
+        let block_size = signature.block_size() as u64;
+        let literal: u64 = ops
+            .iter()
+            .map(|op| match *op {
+                Op::Copy { from, len, .. } => {
+                    assert_eq!((from % block_size, len % block_size), (0, 0));
+                    0
+                }
+                Op::Literal { len, .. } => len,
+            })
+            .sum();
+        assert!(literal < 4 * block_size + 5000);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::error::{self, Context, CserunError};
+use crate::history::{self, RunRecord};
+use crate::ssh::{self, Config};
+use console::style;
+use indicatif::HumanDuration;
+use log::warn;
+use ssh2::Session;
+use std::io::{self, Write};
+use std::time::Duration;
+
+// how a run started with --detach is doing on the server
+pub enum State {
+    Running(Duration),
+    Finished { exit_status: i32, seconds: f64 },
+    Lost, // its process is gone without an exit status, e.g. after a reboot
+    Missing,
+}
+
+// the tmux session a detached run is started in
+pub fn session_name(run_dir_name: &str) -> String {
+    format!("cserun-{}", run_dir_name)
+}
+
+// the files the detached command leaves next to its run directory, for the shell
+fn dir(record: &RunRecord) -> String {
+    ssh::shell_path(&record.remote_dir)
+}
+
+// the script that runs in the tmux session or under nohup: the command with its output
+// copied into `output`, and its exit status into `exit_status` once it is done
+pub fn script(run_dir: &str, command_line: &str) -> String {
+    let dir = ssh::shell_path(run_dir);
+    format!(
+        "cd\n\
+         date +%s > {dir}/started\n\
+         {{ sh -c {command}; echo $? > {dir}/exit_status.part; }} 2>&1 | tee -a {dir}/output\n\
+         mv {dir}/exit_status.part {dir}/exit_status\n",
+        dir = dir,
+        command = ssh::shell_quote(command_line)
+    )
+}
+
+// start the script in a new tmux session, or under nohup where there is no tmux.
+// prints which one was used
+pub fn launch_command(run_dir: &str, session: &str) -> String {
+    let dir = ssh::shell_path(run_dir);
+    let run = format!("sh {}/detached.sh", dir);
+    format!(
+        ": > {dir}/output && if command -v tmux >/dev/null 2>&1; then \
+         tmux new-session -d -s {session} {tmux_run} && echo tmux; \
+         else nohup {run} >/dev/null 2>&1 </dev/null & echo nohup; fi",
+        dir = dir,
+        session = ssh::shell_quote(session),
+        tmux_run = ssh::shell_quote(&run),
+        run = run
+    )
+}
+
+fn find(conf: &Config, run: &str) -> error::Result<RunRecord> {
+    let record = history::find_run(&conf.server_addr, &conf.username, run)?;
+    if !record.detached {
+        return Err(CserunError::Config(
+            format!(
+                "Run {} was not started with --detach, see `cserun list`",
+                record.id
+            )
+            .into(),
+        ));
+    }
+    Ok(record)
+}
+
+fn state(sess: &ssh2::Session, record: &RunRecord) -> error::Result<State> {
+    let script = format!(
+        "cd {} 2>/dev/null || {{ echo missing; exit 0; }}\n",
+        dir(record)
+    ) + r#"now=$(date +%s)
+started=$(cat started 2>/dev/null || echo "$now")
+if [ -f exit_status ]; then
+    ended=$(stat -c %Y exit_status 2>/dev/null || stat -f %m exit_status)
+    echo "finished $(cat exit_status) $(( ended - started ))"
+elif [ -f pid ] && kill -0 "$(cat pid)" 2>/dev/null; then
+    echo "running $(( now - started ))"
+else
+    echo lost
+fi
+"#;
+    let output = ssh::run_remote_script(sess, &script).context(CserunError::Exec)?;
+    let output = String::from_utf8_lossy(&output);
+    let mut fields = output.split_whitespace();
+    let number = |field: Option<&str>| field.and_then(|field| field.parse::<i64>().ok());
+    Ok(match fields.next() {
+        Some("missing") => State::Missing,
+        Some("running") => State::Running(Duration::from_secs(
+            number(fields.next()).unwrap_or(0).max(0) as u64,
+        )),
+        Some("finished") => State::Finished {
+            exit_status: number(fields.next()).unwrap_or(-1) as i32,
+            seconds: number(fields.next()).unwrap_or(0).max(0) as f64,
+        },
+        Some("lost") => State::Lost,
+        _ => {
+            return Err(CserunError::Exec(
+                format!("Unexpected status output {:?}", output.trim()).into(),
+            ))
+        }
+    })
+}
+
+// what the history shows once the run is over
+fn record_finished(record: &RunRecord, exit_status: i32, seconds: f64) {
+    if let Err(e) = history::finish_detached(record.id, exit_status, seconds) {
+        warn!(
+            "Could not update run {} in the history: {}",
+            record.id,
+            e.report()
+        );
+    }
+}
+
+// `cserun status`: one line on how a detached run is doing
+pub fn status(conf: &Config, run: &str) -> error::Result<i32> {
+    let record = find(conf, run)?;
+    let conn = ssh::connect(conf)?;
+    match state(&conn.sess, &record)? {
+        State::Running(elapsed) => println!(
+            "Run {} is running for {}, `cserun attach {}` follows its output",
+            record.id,
+            HumanDuration(elapsed),
+            record.id
+        ),
+        State::Finished {
+            exit_status,
+            seconds,
+        } => {
+            let status = match exit_status {
+                0 => style(exit_status).green(),
+                _ => style(exit_status).red(),
+            };
+            println!(
+                "Run {} finished with exit status {} after {}",
+                record.id,
+                status,
+                HumanDuration(Duration::from_secs_f64(seconds))
+            );
+            record_finished(&record, exit_status, seconds);
+        }
+        State::Lost => println!(
+            "Run {} is no longer running and left no exit status, was the server restarted?",
+            record.id
+        ),
+        State::Missing => println!(
+            "Run {}'s directory {} is no longer on the server",
+            record.id,
+            ssh::display_path(&record.remote_dir)
+        ),
+    }
+    Ok(0)
+}
+
+// `cserun attach`: the output of a detached run so far, then as it comes until the command
+// is done. exits with its status. ctrl-c only stops following, the command keeps running.
+// a dropped connection is made again up to `retries` times, going on after the output shown
+pub fn attach(conf: &Config, run: &str) -> error::Result<i32> {
+    let record = find(conf, run)?;
+    let mut shown: u64 = 0;
+    let (conn, exit_status) = ssh::with_retries(conf, |_| {
+        let conn = ssh::connect(conf)?;
+        if let State::Missing = state(&conn.sess, &record)? {
+            return Err(CserunError::Exec(
+                format!(
+                    "{} is no longer on the server, the output of run {} is gone",
+                    ssh::display_path(&record.remote_dir),
+                    record.id
+                )
+                .into(),
+            ));
+        }
+        let exit_status = follow(&conn.sess, &record, &mut shown)?;
+        Ok((conn, exit_status))
+    })?;
+
+    if let State::Finished {
+        exit_status,
+        seconds,
+    } = state(&conn.sess, &record)?
+    {
+        record_finished(&record, exit_status, seconds);
+    }
+    Ok(exit_status)
+}
+
+// stream the output of a detached run from byte `shown` on until the command is done, counting
+// what was written, and return its exit status
+fn follow(sess: &Session, record: &RunRecord, shown: &mut u64) -> error::Result<i32> {
+    // tail -f has no end of its own, so it is stopped a moment after the exit status appears
+    let script = format!(
+        "cd {} || exit 255\ntail -c +{} -f output &\n",
+        dir(record),
+        *shown + 1
+    ) + r#"tail_pid=$!
+while [ ! -f exit_status ]; do
+    if ! kill -0 "$(cat pid 2>/dev/null)" 2>/dev/null; then
+        sleep 1
+        [ -f exit_status ] && break
+        kill "$tail_pid"
+        echo "cserun: the command is no longer running and left no exit status" >&2
+        exit 255
+    fi
+    sleep 1
+done
+sleep 1
+kill "$tail_pid"
+exit "$(cat exit_status)"
+"#;
+    let mut channel = sess.channel_session().context(CserunError::Exec)?;
+    (|| -> Result<(), error::Source> {
+        channel.exec("sh -s")?;
+        channel.write_all(script.as_bytes())?;
+        channel.send_eof()?;
+        Ok(())
+    })()
+    .context(CserunError::Exec)?;
+    let mut stdout = Counted {
+        inner: io::stdout(),
+        count: shown,
+    };
+    ssh::copy_output(sess, &mut channel, &mut stdout, &mut io::stderr())
+        .context(CserunError::Exec)?;
+    channel.wait_close().context(CserunError::Exec)?;
+    channel.exit_status().context(CserunError::Exec)
+}
+
+// a writer that adds up the bytes written through it
+struct Counted<'a, W> {
+    inner: W,
+    count: &'a mut u64,
+}
+
+impl<W: Write> Write for Counted<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(data)?;
+        *self.count += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
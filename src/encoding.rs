@@ -0,0 +1,259 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+// how the remote command's output is shown, its bytes go through untouched unless asked otherwise
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Raw, // utf-8 text, binary data, anything the local terminal or pipe can take as is
+    Latin1, // ISO-8859-1
+    Windows1252,
+    Multibyte(&'static str), // GBK, Big5, Shift_JIS..., by its iconv name
+}
+
+// the encodings where a character can take more than one byte, converted by the system's iconv
+const MULTIBYTE: [(&[&str], &str); 7] = [
+    (&["gbk", "cp936"], "GBK"),
+    (&["gb18030"], "GB18030"),
+    (&["gb2312", "euc-cn"], "GB2312"),
+    (&["big5"], "BIG5"),
+    (&["shift-jis", "sjis"], "SHIFT_JIS"),
+    (&["euc-jp"], "EUC-JP"),
+    (&["euc-kr"], "EUC-KR"),
+];
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Encoding, String> {
+        let name = s.to_lowercase().replace('_', "-");
+        match name.as_str() {
+            "raw" | "utf-8" | "utf8" => return Ok(Encoding::Raw),
+            "latin1" | "latin-1" | "iso-8859-1" => return Ok(Encoding::Latin1),
+            "windows-1252" | "cp1252" => return Ok(Encoding::Windows1252),
+            _ => {}
+        }
+        let Some((_, iconv_name)) = MULTIBYTE
+            .iter()
+            .find(|(names, _)| names.contains(&name.as_str()))
+        else {
+            return Err(format!(
+                "{:?} is not a known encoding, use utf-8, latin1, windows-1252, gbk, gb18030, \
+                 gb2312, big5, shift_jis, euc-jp or euc-kr",
+                s
+            ));
+        };
+        check_iconv(iconv_name)?;
+        Ok(Encoding::Multibyte(iconv_name))
+    }
+}
+
+// what 0x80-0x9f stand for in windows-1252, the unassigned ones stay C1 controls like in latin1
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+impl Encoding {
+    // a whole output as utf-8. every byte is a character on its own in the single byte
+    // encodings, so chunks of those can be converted as they arrive too
+    pub fn decode(self, data: &[u8]) -> Cow<'_, [u8]> {
+        let text: String = match self {
+            Encoding::Raw => return Cow::Borrowed(data),
+            Encoding::Multibyte(_) => {
+                let mut decoder = self.decoder();
+                let mut text = decoder.decode(data).into_owned();
+                text.extend(decoder.finish());
+                return Cow::Owned(text);
+            }
+            Encoding::Latin1 => data.iter().map(|&byte| char::from(byte)).collect(),
+            Encoding::Windows1252 => data
+                .iter()
+                .map(|&byte| match byte {
+                    0x80..=0x9f => WINDOWS_1252[byte as usize - 0x80],
+                    _ => char::from(byte),
+                })
+                .collect(),
+        };
+        Cow::Owned(text.into_bytes())
+    }
+
+    // for output that arrives in chunks, one for each stream: a multibyte character can be cut
+    // in two by a read
+    pub fn decoder(self) -> Decoder {
+        Decoder {
+            encoding: self,
+            #[cfg(unix)]
+            iconv: match self {
+                Encoding::Multibyte(name) => Iconv::open(name),
+                _ => None,
+            },
+        }
+    }
+}
+
+pub struct Decoder {
+    encoding: Encoding,
+    #[cfg(unix)]
+    iconv: Option<Iconv>,
+}
+
+impl Decoder {
+    // the chunk as utf-8, a character it ends in the middle of waits for the next chunk
+    pub fn decode<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        #[cfg(unix)]
+        if let Some(iconv) = &mut self.iconv {
+            return Cow::Owned(iconv.convert(data));
+        }
+        match self.encoding {
+            Encoding::Multibyte(_) => Cow::Borrowed(data),
+            encoding => encoding.decode(data),
+        }
+    }
+
+    // what is left of a character cut off by the end of the output
+    fn finish(&mut self) -> Vec<u8> {
+        #[cfg(unix)]
+        if let Some(iconv) = &mut self.iconv {
+            if !iconv.pending.is_empty() {
+                iconv.pending.clear();
+                return REPLACEMENT.as_bytes().to_vec();
+            }
+        }
+        Vec::new()
+    }
+}
+
+const REPLACEMENT: &str = "\u{fffd}";
+
+#[cfg(unix)]
+fn check_iconv(name: &str) -> Result<(), String> {
+    match Iconv::open(name) {
+        Some(_) => Ok(()),
+        None => Err(format!("The system's iconv can not convert from {}", name)),
+    }
+}
+
+// the multibyte encodings are refused up front rather than shown as raw bytes
+#[cfg(not(unix))]
+fn check_iconv(name: &str) -> Result<(), String> {
+    Err(format!(
+        "{} is converted with the system's iconv, which is not available on this platform, \
+         only utf-8, latin1 and windows-1252 are",
+        name
+    ))
+}
+
+#[cfg(unix)]
+struct Iconv {
+    handle: libc::iconv_t,
+    pending: Vec<u8>, // the start of a character the last chunk ended in
+}
+
+#[cfg(unix)]
+impl Iconv {
+    fn open(name: &str) -> Option<Iconv> {
+        let from = std::ffi::CString::new(name).ok()?;
+        let handle = unsafe { libc::iconv_open(c"UTF-8".as_ptr(), from.as_ptr()) };
+        // (iconv_t) -1 when the conversion is not supported
+        if handle as isize == -1 {
+            return None;
+        }
+        Some(Iconv {
+            handle,
+            pending: Vec::new(),
+        })
+    }
+
+    fn convert(&mut self, data: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(data);
+        let mut output = Vec::with_capacity(self.pending.len() * 2);
+        let mut chunk = [0u8; 4096];
+        let mut done = 0;
+        while done < self.pending.len() {
+            let mut input = self.pending[done..].as_ptr() as *mut libc::c_char;
+            let mut input_left = self.pending.len() - done;
+            let mut out = chunk.as_mut_ptr().cast::<libc::c_char>();
+            let mut out_left = chunk.len();
+            let result = unsafe {
+                libc::iconv(
+                    self.handle,
+                    &mut input,
+                    &mut input_left,
+                    &mut out,
+                    &mut out_left,
+                )
+            };
+            let error = std::io::Error::last_os_error().raw_os_error();
+            output.extend_from_slice(&chunk[..chunk.len() - out_left]);
+            done = self.pending.len() - input_left;
+            if result != usize::MAX {
+                continue;
+            }
+            match error {
+                // the chunk buffer is full
+                Some(libc::E2BIG) => {}
+                // a character the data ends in the middle of
+                Some(libc::EINVAL) => break,
+                // not a character of the encoding, shown like invalid utf-8 would be
+                _ => {
+                    output.extend_from_slice(REPLACEMENT.as_bytes());
+                    done += 1;
+                }
+            }
+        }
+        self.pending.drain(..done);
+        output
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Iconv {
+    fn drop(&mut self) {
+        unsafe { libc::iconv_close(self.handle) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_as_written_in_the_option() {
+        assert_eq!("UTF-8".parse(), Ok(Encoding::Raw));
+        assert_eq!("latin1".parse(), Ok(Encoding::Latin1));
+        assert_eq!("ISO_8859_1".parse(), Ok(Encoding::Latin1));
+        assert_eq!("cp1252".parse(), Ok(Encoding::Windows1252));
+        assert!("ebcdic".parse::<Encoding>().is_err());
+    }
+
+    #[test]
+    fn single_byte_encodings() {
+        assert_eq!(Encoding::Raw.decode(b"\xff\x00"), &b"\xff\x00"[..]);
+        assert_eq!(
+            Encoding::Latin1.decode(b"caf\xe9 \x80"),
+            "café \u{80}".as_bytes()
+        );
+        assert_eq!(
+            Encoding::Windows1252.decode(b"\x93quoted\x94 \x80 \x81"),
+            "“quoted” € \u{81}".as_bytes()
+        );
+    }
+
+    // the system's iconv, so only where there is one that knows GBK
+    #[cfg(unix)]
+    #[test]
+    fn multibyte_characters_split_between_chunks() {
+        let Ok(gbk) = "gbk".parse::<Encoding>() else {
+            return;
+        };
+        assert_eq!(gbk.decode(b"\xc4\xe3\xba\xc3"), "你好".as_bytes());
+        let mut decoder = gbk.decoder();
+        let mut text = decoder.decode(b"ok \xc4\xe3\xba").into_owned();
+        assert_eq!(text, "ok 你".as_bytes());
+        text.extend_from_slice(&decoder.decode(b"\xc3!"));
+        assert_eq!(text, "ok 你好!".as_bytes());
+        // a character cut off by the end of the output, and a byte that starts none
+        assert_eq!(gbk.decode(b"\xff a \xc4"), "\u{fffd} a \u{fffd}".as_bytes());
+    }
+}
@@ -0,0 +1,132 @@
+use std::error::Error;
+use std::fmt;
+
+// the underlying error, kept around as the source of a CserunError
+pub type Source = Box<dyn Error + Send + Sync>;
+
+pub type Result<T> = std::result::Result<T, CserunError>;
+
+// what went wrong, by the stage of a run it went wrong in
+#[derive(Debug)]
+pub enum CserunError {
+    Config(Source),    // invalid settings or arguments, e.g. a bad glob or server address
+    Local(Source),     // reading local files or the cache, prompting on the terminal
+    Connect(Source),   // reaching the server, directly or through the jump host
+    Handshake(Source), // the ssh handshake and the host key check
+    Auth(Source),
+    Sftp(Source), // remote file system operations, e.g. creating directories
+    Transfer(Source),
+    Exec(Source), // starting or talking to the remote command
+    Cleanup(Source),
+    Cancelled(Source), // stopped with a CancelToken, the source says during what
+}
+
+impl CserunError {
+    // the lower level error this one wraps
+    pub fn source_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        match self {
+            CserunError::Config(e)
+            | CserunError::Local(e)
+            | CserunError::Connect(e)
+            | CserunError::Handshake(e)
+            | CserunError::Auth(e)
+            | CserunError::Sftp(e)
+            | CserunError::Transfer(e)
+            | CserunError::Exec(e)
+            | CserunError::Cleanup(e)
+            | CserunError::Cancelled(e) => e.as_ref(),
+        }
+    }
+
+    // the stage and the messages of the errors below it, what the user gets to see
+    pub fn report(&self) -> Report<'_> {
+        Report(self)
+    }
+
+    // a short stable name of the variant, e.g. for `--output json`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CserunError::Config(_) => "config",
+            CserunError::Local(_) => "local",
+            CserunError::Connect(_) => "connect",
+            CserunError::Handshake(_) => "handshake",
+            CserunError::Auth(_) => "auth",
+            CserunError::Sftp(_) => "sftp",
+            CserunError::Transfer(_) => "transfer",
+            CserunError::Exec(_) => "exec",
+            CserunError::Cleanup(_) => "cleanup",
+            CserunError::Cancelled(_) => "cancelled",
+        }
+    }
+}
+
+impl fmt::Display for CserunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            CserunError::Config(_) => "Invalid configuration",
+            CserunError::Local(_) => "Local error",
+            CserunError::Connect(_) => "Failed to connect",
+            CserunError::Handshake(_) => "SSH handshake failed",
+            CserunError::Auth(_) => "Authentication failed",
+            CserunError::Sftp(_) => "SFTP error",
+            CserunError::Transfer(_) => "File transfer failed",
+            CserunError::Exec(_) => "Remote command failed",
+            CserunError::Cleanup(_) => "Cleanup failed",
+            CserunError::Cancelled(_) => "Cancelled",
+        };
+        f.write_str(stage)
+    }
+}
+
+// Display is only the stage, the wrapped error is the source, so a chain printed by a reporter
+// like anyhow's {:#} has every message once. report() prints the whole chain
+impl Error for CserunError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source_error())
+    }
+}
+
+// an error and every source below it, e.g. "Authentication failed: Tried password (...)"
+pub struct Report<'a>(pub &'a (dyn Error + 'static));
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(error) = source {
+            write!(f, ": {}", error)?;
+            source = error.source();
+        }
+        Ok(())
+    }
+}
+
+// tag a lower level error with the stage it happened in, e.g. `.context(CserunError::Auth)?`
+pub trait Context<T> {
+    fn context(self, kind: fn(Source) -> CserunError) -> Result<T>;
+}
+
+impl<T, E: Into<Source>> Context<T> for std::result::Result<T, E> {
+    fn context(self, kind: fn(Source) -> CserunError) -> Result<T> {
+        self.map_err(|e| kind(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_message_once() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        let e = CserunError::Connect(Box::new(io));
+        assert_eq!(e.to_string(), "Failed to connect");
+        assert_eq!(e.source().unwrap().to_string(), "refused");
+        assert_eq!(e.report().to_string(), "Failed to connect: refused");
+        let cleanup = CserunError::Cleanup(Box::new(e));
+        assert_eq!(
+            cleanup.report().to_string(),
+            "Cleanup failed: Failed to connect: refused"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+// `--output json` writes one JSON object per line to stdout, including the remote command's
+// output, so a wrapping script never has to tell our messages apart from the command's.
+// --events-fd and --events-socket send the same events to a channel of their own instead, e.g.
+// for an editor extension, while the output stays as it is
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static STDOUT: AtomicBool = AtomicBool::new(false);
+static CHANNEL: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+pub enum Value<'a> {
+    Str(&'a str),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(value: &'a str) -> Self {
+        Value::Str(value)
+    }
+}
+
+impl From<i32> for Value<'_> {
+    fn from(value: i32) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<usize> for Value<'_> {
+    fn from(value: usize) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<u64> for Value<'_> {
+    fn from(value: u64) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<f64> for Value<'_> {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<bool> for Value<'_> {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+pub fn enable() {
+    let _ = STARTED.set(Instant::now());
+    STDOUT.store(true, Ordering::Relaxed);
+}
+
+// whether stdout is all events, with --output json
+pub fn enabled() -> bool {
+    STDOUT.load(Ordering::Relaxed)
+}
+
+// --events-fd: a file descriptor inherited from whoever started cserun, e.g. a pipe
+#[cfg(unix)]
+pub fn open_fd(fd: i32) -> io::Result<()> {
+    use std::os::fd::FromRawFd;
+    if fd <= 2 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(io::Error::other(format!(
+            "{} is not an open file descriptor other than stdin, stdout and stderr",
+            fd
+        )));
+    }
+    open_channel(Box::new(unsafe { std::fs::File::from_raw_fd(fd) }));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn open_fd(_fd: i32) -> io::Result<()> {
+    Err(io::Error::other("--events-fd is only supported on unix"))
+}
+
+// --events-socket: a unix socket something is listening on
+#[cfg(unix)]
+pub fn connect_socket(path: &Path) -> io::Result<()> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    open_channel(Box::new(stream));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn connect_socket(_path: &Path) -> io::Result<()> {
+    Err(io::Error::other(
+        "--events-socket is only supported on unix",
+    ))
+}
+
+fn open_channel(channel: Box<dyn Write + Send>) {
+    let _ = STARTED.set(Instant::now());
+    let _ = CHANNEL.set(Mutex::new(channel));
+}
+
+// does nothing unless json output or an events channel is on, every event carries the seconds
+// since cserun started
+pub fn emit(event: &str, fields: &[(&str, Value)]) {
+    let Some(started) = STARTED.get() else {
+        return;
+    };
+    let mut line = format!("{{\"event\":{}", quote(event));
+    let _ = write!(line, ",\"elapsed\":{:.3}", started.elapsed().as_secs_f64());
+    for (key, value) in fields {
+        let _ = write!(line, ",{}:", quote(key));
+        match value {
+            Value::Str(s) => line.push_str(&quote(s)),
+            Value::Int(n) => {
+                let _ = write!(line, "{}", n);
+            }
+            // JSON has no NaN or infinity
+            Value::Float(f) if f.is_finite() => {
+                let _ = write!(line, "{:.3}", f);
+            }
+            Value::Float(_) => line.push_str("null"),
+            Value::Bool(b) => {
+                let _ = write!(line, "{}", b);
+            }
+        }
+    }
+    line.push('}');
+    if enabled() {
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+    // a reader that went away does not stop the run
+    if let Some(channel) = CHANNEL.get() {
+        let mut channel = channel.lock().unwrap();
+        let _ = writeln!(channel, "{}", line);
+        let _ = channel.flush();
+    }
+}
+
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// remote output as `stdout`/`stderr` events, holding back a utf-8 sequence split across reads
+pub struct OutputStream {
+    event: &'static str,
+    pending: Vec<u8>,
+}
+
+impl OutputStream {
+    pub fn new(event: &'static str) -> OutputStream {
+        OutputStream {
+            event,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        if STARTED.get().is_none() {
+            return;
+        }
+        self.pending.extend_from_slice(data);
+        let complete = match std::str::from_utf8(&self.pending) {
+            Ok(_) => self.pending.len(),
+            // an incomplete sequence at the end, wait for the rest of it
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => self.pending.len(),
+        };
+        if complete == 0 {
+            return;
+        }
+        let rest = self.pending.split_off(complete);
+        let chunk = std::mem::replace(&mut self.pending, rest);
+        emit(
+            self.event,
+            &[("data", String::from_utf8_lossy(&chunk).as_ref().into())],
+        );
+    }
+
+    pub fn finish(&mut self) {
+        if !self.pending.is_empty() {
+            let chunk = std::mem::take(&mut self.pending);
+            emit(
+                self.event,
+                &[("data", String::from_utf8_lossy(&chunk).as_ref().into())],
+            );
+        }
+    }
+}
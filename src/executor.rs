@@ -0,0 +1,111 @@
+use crate::container::Container;
+use crate::error::{self, CserunError};
+use crate::events;
+use crate::hooks;
+use crate::local::Local;
+use crate::runner;
+use crate::ssh::{self, Config, Finished};
+use log::{error, info};
+use std::time::Instant;
+
+// where commands run: the ssh server, a temporary directory on this machine to try the
+// whole pipeline offline, or a container of the course's image
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+    Ssh,
+    Local,
+    Docker,
+    Podman,
+}
+
+// the steps of a run, whatever it runs on. `cserun run` only goes through it for the local and
+// container backends, a run on the server keeps to ssh::exec, which retries, resumes uploads and
+// has the server-only options. runner::Session implements it for library users
+pub trait Executor {
+    // where the run happens, for messages
+    fn describe(&self) -> String;
+
+    // copy the local directory to where the command runs, returns the files and bytes sent
+    fn sync(&mut self) -> error::Result<(usize, u64)>;
+
+    // run a command in the copy, its output goes straight to ours
+    fn run(&mut self, command: &str) -> error::Result<Finished>;
+
+    // copy files matching the globs back into the local directory
+    fn fetch(&mut self, patterns: &[String]) -> error::Result<usize>;
+
+    // remove the run directory, or leave it there with --keep
+    fn cleanup(&mut self, keep: bool) -> error::Result<()>;
+}
+
+// connect to the configured backend and set up a run directory there
+pub fn connect(conf: &Config) -> error::Result<Box<dyn Executor + '_>> {
+    Ok(match conf.backend {
+        Backend::Ssh => Box::new(runner::Session::connect(conf)?),
+        Backend::Local => Box::new(Local::new(conf)?),
+        Backend::Docker => Box::new(Container::new(conf, "docker")?),
+        Backend::Podman => Box::new(Container::new(conf, "podman")?),
+    })
+}
+
+// a run through the Executor steps, for the backends other than ssh
+pub fn exec(conf: &Config) -> error::Result<i32> {
+    let unsupported = [
+        (conf.detach, "--detach"),
+        (!conf.matrix.is_empty(), "--matrix and --each"),
+        (!conf.forwards.is_empty(), "--forward"),
+        (conf.script.is_some(), "--script"),
+        (conf.slurm.enabled, "--slurm"),
+        (conf.target_host.is_some(), "--target-host"),
+        (conf.x11, "--x11"),
+        (conf.limits.any(), "--nice, --mem-limit and --cpu-time"),
+        (conf.rerun_dir.is_some(), "rerun"),
+        (events::enabled(), "--output json"),
+    ];
+    if let Some((_, what)) = unsupported.iter().find(|(used, _)| *used) {
+        return Err(CserunError::Config(
+            format!("{} needs the ssh backend", what).into(),
+        ));
+    }
+    ssh::check_workdir(conf)?;
+    let started = Instant::now();
+    if let (Some(hook), false) = (&conf.hooks.pre_sync, conf.no_sync) {
+        hooks::run_local("pre_sync", hook, &conf.local_dir, &[])?;
+    }
+
+    let mut executor = connect(conf)?;
+    if !conf.no_sync {
+        let (files, bytes) = executor.sync()?;
+        info!(
+            "Copied {} file(s), {} bytes to {}",
+            files,
+            bytes,
+            executor.describe()
+        );
+    }
+    let finished = executor.run(&conf.command)?;
+
+    // an interrupted command has nothing worth fetching
+    if !conf.fetch.is_empty() && !finished.interrupted && !finished.timed_out {
+        let count = executor.fetch(&conf.fetch)?;
+        info!("Fetched {} file(s)", count);
+    }
+    executor.cleanup(conf.keep)?;
+
+    if finished.timed_out {
+        error!(
+            "Timed out after {}s, the command was stopped",
+            conf.timeout.unwrap_or_default().as_secs()
+        );
+    }
+    if let Some(hook) = &conf.hooks.post_run {
+        if !finished.interrupted && !finished.timed_out {
+            let status = ("CSERUN_EXIT_STATUS", finished.exit_status.to_string());
+            hooks::run_local("post_run", hook, &conf.local_dir, &[status])?;
+        }
+    }
+    if !finished.interrupted {
+        ssh::notify_finished(conf, &finished, started.elapsed());
+    }
+    Ok(finished.code())
+}
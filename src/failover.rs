@@ -0,0 +1,229 @@
+use crate::error::{self, Context, CserunError};
+use crate::ssh::{self, AddrOrder, Auth, Config};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// how long a probed load is trusted, so a burst of runs does not probe every node every time
+const LOAD_MAX_AGE: u64 = 5 * 60;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LastHosts {
+    // the host that last accepted a connection, per account, so the next run tries it first
+    #[serde(default)]
+    hosts: BTreeMap<String, String>,
+    // the last probed load per address
+    #[serde(default)]
+    loads: BTreeMap<String, Load>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Load {
+    per_cpu: f64,
+    probed: u64, // seconds since the epoch
+}
+
+fn last_hosts_path() -> error::Result<PathBuf> {
+    let mut path = dirs::home_dir()
+        .ok_or("Cannot find the home directory")
+        .context(CserunError::Local)?;
+    path.push(".cserun");
+    fs::create_dir_all(&path).context(CserunError::Local)?;
+    path.push("hosts.toml");
+    Ok(path)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+fn load_last_hosts() -> LastHosts {
+    // losing it only costs a slower connect
+    last_hosts_path()
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn account(conf: &Config) -> String {
+    format!("{}@{}", conf.username, conf.server_addr)
+}
+
+// every address to try in order: the one that worked last time, then the rest as listed or
+// shuffled. by load the least loaded goes first instead
+pub fn candidates(conf: &Config) -> Vec<String> {
+    let mut addrs: Vec<String> = [conf.server_addr.clone()]
+        .into_iter()
+        .chain(conf.fallback_addrs.iter().cloned())
+        .collect();
+    if addrs.len() == 1 {
+        return addrs;
+    }
+    match conf.addr_order {
+        AddrOrder::Listed => {}
+        AddrOrder::Random => shuffle(&mut addrs),
+        // each probe logs in, with 2FA that would mean a code per node
+        AddrOrder::LeastLoaded
+            if conf
+                .auth
+                .iter()
+                .any(|auth| matches!(auth, Auth::KeyboardInteractive)) =>
+        {
+            warn!("Not probing the load of the servers with keyboard-interactive authentication");
+        }
+        AddrOrder::LeastLoaded => {
+            order_by_load(conf, &mut addrs);
+            return addrs;
+        }
+    }
+    if let Some(last) = load_last_hosts().hosts.get(&account(conf)) {
+        if let Some(index) = addrs.iter().position(|addr| addr == last) {
+            let last = addrs.remove(index);
+            addrs.insert(0, last);
+        }
+    }
+    addrs
+}
+
+// the least loaded first, nodes that could not be probed last in their listed order
+fn order_by_load(conf: &Config, addrs: &mut [String]) {
+    let mut state = load_last_hosts();
+    let fresh = |addr: &String| {
+        state
+            .loads
+            .get(addr)
+            .filter(|load| now().saturating_sub(load.probed) < LOAD_MAX_AGE)
+            .map(|load| load.per_cpu)
+    };
+    let cached: Vec<Option<f64>> = addrs.iter().map(fresh).collect();
+    // the stale ones are probed side by side, a saved password is only asked for once
+    let probed: Vec<Option<f64>> = thread::scope(|scope| {
+        let probes: Vec<_> = addrs
+            .iter()
+            .zip(&cached)
+            .map(|(addr, cached)| {
+                scope.spawn(move || match cached {
+                    Some(load) => Some(*load),
+                    None => match ssh::probe_load(conf, addr) {
+                        Ok(load) => {
+                            info!("{} has a load of {:.2} per cpu", addr, load);
+                            Some(load)
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Could not probe the load of {}: {}",
+                                addr,
+                                error::Report(e.source_error())
+                            );
+                            None
+                        }
+                    },
+                })
+            })
+            .collect();
+        probes
+            .into_iter()
+            .map(|probe| probe.join().unwrap_or(None))
+            .collect()
+    });
+
+    // new probes are kept for the next runs, failures are tried again
+    let mut new_loads = false;
+    for ((addr, cached), probed) in addrs.iter().zip(&cached).zip(&probed) {
+        if let (None, Some(per_cpu)) = (cached, probed) {
+            let load = Load {
+                per_cpu: *per_cpu,
+                probed: now(),
+            };
+            state.loads.insert(addr.clone(), load);
+            new_loads = true;
+        }
+    }
+    if new_loads {
+        save_last_hosts(&state);
+    }
+
+    let mut ranked: Vec<(String, Option<f64>)> = addrs.iter().cloned().zip(probed).collect();
+    ranked.sort_by(|(_, a), (_, b)| {
+        a.unwrap_or(f64::INFINITY)
+            .total_cmp(&b.unwrap_or(f64::INFINITY))
+    });
+    for (slot, (addr, _)) in addrs.iter_mut().zip(ranked) {
+        *slot = addr;
+    }
+}
+
+// good enough to spread runs over the login nodes, no need for a random number crate
+fn shuffle(addrs: &mut [String]) {
+    let mut state = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |now| now.as_nanos() as u64)
+        ^ u64::from(std::process::id())
+        | 1;
+    for i in (1..addrs.len()).rev() {
+        // xorshift
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        addrs.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+// connect to the first candidate that answers, and the address it was
+pub fn connect(
+    conf: &Config,
+    open: impl Fn(&str) -> error::Result<TcpStream>,
+) -> error::Result<(TcpStream, String)> {
+    let addrs = candidates(conf);
+    if addrs.len() == 1 {
+        let addr = addrs.into_iter().next().unwrap_or_default();
+        return Ok((open(&addr)?, addr));
+    }
+    let mut failures = Vec::new();
+    for addr in &addrs {
+        match open(addr) {
+            Ok(stream) => {
+                remember(conf, addr);
+                return Ok((stream, addr.clone()));
+            }
+            Err(e) => {
+                warn!(
+                    "Could not connect to {}: {}",
+                    addr,
+                    error::Report(e.source_error())
+                );
+                failures.push(format!("{}: {}", addr, error::Report(e.source_error())));
+            }
+        }
+    }
+    Err(CserunError::Connect(
+        format!("No server could be reached ({})", failures.join("; ")).into(),
+    ))
+}
+
+fn remember(conf: &Config, addr: &str) {
+    let mut last = load_last_hosts();
+    if last.hosts.get(&account(conf)).map(String::as_str) == Some(addr) {
+        return;
+    }
+    last.hosts.insert(account(conf), addr.to_string());
+    save_last_hosts(&last);
+}
+
+fn save_last_hosts(last: &LastHosts) {
+    let saved = last_hosts_path().and_then(|path| {
+        let contents = toml::to_string(last).context(CserunError::Local)?;
+        fs::write(path, contents).context(CserunError::Local)
+    });
+    if let Err(e) = saved {
+        warn!("Could not save ~/.cserun/hosts.toml: {}", e.report());
+    }
+}
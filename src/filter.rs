@@ -0,0 +1,156 @@
+use regex_automata::meta::Regex;
+use std::collections::VecDeque;
+
+// --grep and --highlight: what of the command's output is shown, and what stands out in it.
+// the log file and the transcript still get every line
+#[derive(Clone, Debug, Default)]
+pub struct OutputFilter {
+    pub grep: Vec<Regex>,      // only lines matching one of these are shown
+    pub highlight: Vec<Regex>, // colored where they match, like the matches of grep
+    pub context: usize,        // lines shown before and after each grep match
+}
+
+impl OutputFilter {
+    pub fn is_empty(&self) -> bool {
+        self.grep.is_empty() && self.highlight.is_empty()
+    }
+}
+
+pub fn parse_pattern(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| {
+        // the syntax error says what is wrong, the build error only that something is
+        let reason = match e.syntax_error() {
+            Some(syntax) => syntax.to_string(),
+            None => e.to_string(),
+        };
+        format!("{:?} is not a valid pattern: {}", pattern, reason)
+    })
+}
+
+// what grep shows of one stream: the lines before the next match, and how many after the
+// last one are still due
+#[derive(Default)]
+struct Context {
+    before: VecDeque<Vec<u8>>,
+    after: usize,
+    shown: bool,   // anything shown yet
+    skipped: bool, // lines left out since then, marked with -- like grep does
+}
+
+// the command's output filtered line by line, stdout and stderr each on their own
+pub struct LineFilter {
+    filter: OutputFilter,
+    partial: [Vec<u8>; 2], // unfinished last line of stdout and stderr
+    context: [Context; 2],
+    color: [bool; 2],
+}
+
+impl LineFilter {
+    pub fn new(filter: &OutputFilter) -> LineFilter {
+        LineFilter {
+            filter: filter.clone(),
+            partial: [Vec::new(), Vec::new()],
+            context: Default::default(),
+            color: [console::colors_enabled(), console::colors_enabled_stderr()],
+        }
+    }
+
+    // the lines of stdout that are shown, the rest of an unfinished line waits for its newline
+    pub fn stdout(&mut self, data: &[u8]) -> Vec<u8> {
+        self.push(0, data)
+    }
+
+    pub fn stderr(&mut self, data: &[u8]) -> Vec<u8> {
+        self.push(1, data)
+    }
+
+    fn push(&mut self, stream: usize, data: &[u8]) -> Vec<u8> {
+        self.partial[stream].extend_from_slice(data);
+        let Some(end) = self.partial[stream].iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let lines: Vec<u8> = self.partial[stream].drain(..=end).collect();
+        let mut shown = Vec::new();
+        for line in lines.split_inclusive(|&b| b == b'\n') {
+            self.line(stream, line, &mut shown);
+        }
+        shown
+    }
+
+    // the unfinished last lines once the command is done, with a newline if they are shown
+    pub fn finish(&mut self) -> [Vec<u8>; 2] {
+        [0, 1].map(|stream| {
+            let mut shown = Vec::new();
+            if !self.partial[stream].is_empty() {
+                let mut line = std::mem::take(&mut self.partial[stream]);
+                line.push(b'\n');
+                self.line(stream, &line, &mut shown);
+            }
+            shown
+        })
+    }
+
+    fn line(&mut self, stream: usize, line: &[u8], shown: &mut Vec<u8>) {
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        if self.filter.grep.is_empty() {
+            self.paint(stream, text, shown);
+            return;
+        }
+        let context_lines = self.filter.context;
+        if self.filter.grep.iter().any(|regex| regex.is_match(text)) {
+            let context = &mut self.context[stream];
+            if context.skipped && context.shown && context_lines > 0 {
+                shown.extend_from_slice(b"--\n");
+            }
+            let before: Vec<Vec<u8>> = context.before.drain(..).collect();
+            context.after = context_lines;
+            context.shown = true;
+            context.skipped = false;
+            for earlier in &before {
+                self.paint(stream, earlier, shown);
+            }
+            self.paint(stream, text, shown);
+        } else if self.context[stream].after > 0 {
+            self.context[stream].after -= 1;
+            self.paint(stream, text, shown);
+        } else {
+            let context = &mut self.context[stream];
+            context.before.push_back(text.to_vec());
+            if context.before.len() > context_lines {
+                context.before.pop_front();
+                context.skipped = true;
+            }
+        }
+    }
+
+    // the line with what the patterns match in bold red, as grep --color does
+    fn paint(&self, stream: usize, text: &[u8], shown: &mut Vec<u8>) {
+        let mut matches: Vec<(usize, usize)> = Vec::new();
+        if self.color[stream] {
+            for regex in self.filter.grep.iter().chain(&self.filter.highlight) {
+                matches.extend(
+                    regex
+                        .find_iter(text)
+                        .filter(|m| !m.is_empty())
+                        .map(|m| (m.start(), m.end())),
+                );
+            }
+            matches.sort_unstable();
+        }
+        let mut at = 0;
+        for (start, end) in matches {
+            // overlapping matches are colored as one
+            if end <= at {
+                continue;
+            }
+            let start = start.max(at);
+            shown.extend_from_slice(&text[at..start]);
+            shown.extend_from_slice(b"\x1b[1;31m");
+            shown.extend_from_slice(&text[start..end]);
+            shown.extend_from_slice(b"\x1b[0m");
+            at = end;
+        }
+        shown.extend_from_slice(&text[at..]);
+        shown.push(b'\n');
+    }
+}
@@ -0,0 +1,811 @@
+use crate::error::{self, Context, CserunError};
+use crate::hash;
+use log::{info, warn};
+use ssh2::Session;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+#[cfg(unix)]
+use std::sync::Mutex;
+
+// a local port forwarded to host:port as seen from the server, like ssh -L
+#[derive(Clone, Debug)]
+pub struct Forward {
+    pub bind_address: String,
+    pub local_port: u16,
+    pub host: String,
+    pub port: u16,
+}
+
+// [bind_address:]port:host:hostport, or a single port forwarded to the same port on the server
+impl FromStr for Forward {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Forward, String> {
+        let invalid = || format!("{:?} is not a forward like 8000 or 8000:localhost:8000", s);
+        let port = |p: &str| p.parse::<u16>().map_err(|_| invalid());
+        let parts: Vec<&str> = s.split(':').collect();
+        let (bind_address, local_port, host, remote_port) = match parts[..] {
+            [local_port] => ("localhost", local_port, "localhost", local_port),
+            [local_port, host, remote_port] => ("localhost", local_port, host, remote_port),
+            [bind_address, local_port, host, remote_port] => {
+                (bind_address, local_port, host, remote_port)
+            }
+            _ => return Err(invalid()),
+        };
+        if host.is_empty() || bind_address.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Forward {
+            bind_address: bind_address.to_string(),
+            local_port: port(local_port)?,
+            host: host.to_string(),
+            port: port(remote_port)?,
+        })
+    }
+}
+
+// the forwards of one run, driven from the output loop so they share its session
+#[derive(Default)]
+pub struct Tunnels {
+    listeners: Vec<(TcpListener, Forward)>,
+    pending: VecDeque<(TcpStream, usize)>, // accepted, waiting for their channel
+    open: Vec<Tunnel>,
+    x11: Option<X11>,
+    agent: Option<Agent>,
+}
+
+// --x11: programs on the server show their windows on the local X server, like ssh -X. the ssh2
+// crate has no x11 channels, so the server forwards a display port back instead, like ssh -R,
+// with a fake cookie in its xauth that is swapped for the real one on the way in
+pub struct X11 {
+    listener: ssh2::Listener,
+    display: u16, // localhost:N on the server
+    fake_cookie: [u8; 16],
+    real_cookie: Option<Vec<u8>>,
+    local: LocalDisplay,
+}
+
+// --forward-agent: ssh and git in the command use the keys of the local agent, like ssh -A.
+// libssh2 hands the channels the server opens for the agent to a callback, which queues them
+// with their session until the output loop of that session picks them up
+pub struct Agent {
+    #[cfg(unix)]
+    socket: PathBuf,
+    #[cfg(unix)]
+    sess: Session,
+}
+
+// session and channel pointers, as the callback got them.
+// SAFETY: a queued channel belongs to libssh2's session until take_channels wraps it in an
+// AgentChannel, which frees it. entries only exist while the Agent of their session lives: it
+// holds a clone of the Session, so the session can not be freed with channels still queued, and
+// dropping it unregisters the callback and frees whatever is left of its entries. the pointers
+// are never dereferenced while they sit here, the session ones only tell the sessions apart
+#[cfg(unix)]
+static AGENT_CHANNELS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+#[cfg(unix)]
+const LIBSSH2_CALLBACK_AUTHAGENT: libc::c_int = 7;
+
+// libssh2 1.11 has it, libssh2-sys does not declare it
+#[cfg(unix)]
+extern "C" {
+    fn libssh2_session_callback_set2(
+        session: *mut libssh2_sys::LIBSSH2_SESSION,
+        cbtype: libc::c_int,
+        callback: *const libc::c_void,
+    ) -> *const libc::c_void;
+}
+
+// runs inside a libssh2 call on the session, which holds the session's lock, when the server
+// opens a channel for the agent
+#[cfg(unix)]
+extern "C" fn on_agent_channel(
+    session: *mut libssh2_sys::LIBSSH2_SESSION,
+    channel: *mut libssh2_sys::LIBSSH2_CHANNEL,
+    _abstract: *mut *mut libc::c_void,
+) {
+    // a panic must not unwind into libssh2
+    if let Ok(mut channels) = AGENT_CHANNELS.lock() {
+        channels.push((session as usize, channel as usize));
+    }
+}
+
+// a channel the server opened for the agent, the ssh2 crate has no type for these. it owns the
+// channel and frees it when dropped, the clone of the session keeps that alive until then
+#[cfg(unix)]
+struct AgentChannel {
+    raw: *mut libssh2_sys::LIBSSH2_CHANNEL,
+    sess: Session,
+}
+
+// where the local X server listens, from $DISPLAY
+enum LocalDisplay {
+    Tcp(String, u16),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+// a local end of a tunnel
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+// the server end of a tunnel
+enum Remote {
+    Channel(ssh2::Channel),
+    #[cfg(unix)]
+    Agent(AgentChannel),
+}
+
+struct Tunnel {
+    socket: Socket,
+    channel: Remote,
+    cookie: Option<([u8; 16], Option<Vec<u8>>)>, // x11 only, until the setup has been through
+    to_remote: Vec<u8>,
+    to_local: Vec<u8>,
+    local_eof: bool,
+    eof_sent: bool,
+    remote_eof: bool,
+}
+
+impl Tunnels {
+    // listen on the local ports, before the output loop starts
+    pub fn bind(forwards: &[Forward]) -> io::Result<Tunnels> {
+        let mut tunnels = Tunnels::default();
+        for forward in forwards {
+            let listener = TcpListener::bind((forward.bind_address.as_str(), forward.local_port))
+                .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Can not listen on {}:{}: {}",
+                        forward.bind_address, forward.local_port, e
+                    ),
+                )
+            })?;
+            listener.set_nonblocking(true)?;
+            info!(
+                "Forwarding {}:{} to {}:{} on the server",
+                forward.bind_address, forward.local_port, forward.host, forward.port
+            );
+            tunnels.listeners.push((listener, forward.clone()));
+        }
+        Ok(tunnels)
+    }
+
+    // accept connections and move data both ways, the session must be non-blocking.
+    // a broken tunnel is dropped with a warning, the command keeps running
+    pub fn poll(&mut self, sess: &Session) -> bool {
+        let mut active = false;
+        for (index, (listener, _)) in self.listeners.iter().enumerate() {
+            while let Ok((socket, _)) = listener.accept() {
+                if socket.set_nonblocking(true).is_ok() {
+                    self.pending.push_back((socket, index));
+                    active = true;
+                }
+            }
+        }
+        if let Some(x11) = &mut self.x11 {
+            while let Ok(channel) = x11.listener.accept() {
+                match x11.local.connect() {
+                    Ok(socket) => {
+                        let cookie = (x11.fake_cookie, x11.real_cookie.clone());
+                        let mut tunnel = Tunnel::new(socket, Remote::Channel(channel));
+                        tunnel.cookie = Some(cookie);
+                        self.open.push(tunnel);
+                        active = true;
+                    }
+                    Err(e) => warn!("Could not connect to the local X server: {}", e),
+                }
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(agent) = &self.agent {
+            for channel in agent.take_channels() {
+                let socket = UnixStream::connect(&agent.socket)
+                    .and_then(|socket| socket.set_nonblocking(true).map(|_| socket));
+                match socket {
+                    Ok(socket) => {
+                        self.open
+                            .push(Tunnel::new(Socket::Unix(socket), Remote::Agent(channel)));
+                        active = true;
+                    }
+                    Err(e) => warn!("Could not connect to the local ssh agent: {}", e),
+                }
+            }
+        }
+
+        // one channel opens at a time, libssh2 resumes the same open on the next call
+        if let Some((_, index)) = self.pending.front() {
+            let forward = &self.listeners[*index].1;
+            match sess.channel_direct_tcpip(&forward.host, forward.port, None) {
+                Ok(channel) => {
+                    let (socket, _) = self.pending.pop_front().unwrap();
+                    self.open
+                        .push(Tunnel::new(Socket::Tcp(socket), Remote::Channel(channel)));
+                    active = true;
+                }
+                Err(e) => match io::Error::from(e) {
+                    e if e.kind() == io::ErrorKind::WouldBlock => {}
+                    e => {
+                        warn!(
+                            "Could not forward to {}:{}: {}",
+                            forward.host, forward.port, e
+                        );
+                        self.pending.pop_front();
+                    }
+                },
+            }
+        }
+
+        self.open.retain_mut(|tunnel| match tunnel.pump() {
+            Ok(pumped) => {
+                active |= pumped;
+                !tunnel.closed()
+            }
+            Err(e) => {
+                warn!("Forwarded connection closed: {}", e);
+                false
+            }
+        });
+        active
+    }
+
+    // the x11 connections to take from the server, once the command that uses them started
+    pub fn add_x11(&mut self, x11: Option<X11>) {
+        self.x11 = x11;
+    }
+
+    // the agent connections to take from the server, like the x11 ones
+    pub fn add_agent(&mut self, agent: Option<Agent>) {
+        self.agent = agent;
+    }
+
+    // the local sockets to wait on besides the ssh session
+    #[cfg(unix)]
+    pub fn raw_fds(&self) -> Vec<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+        let listeners = self.listeners.iter().map(|(l, _)| l.as_raw_fd());
+        let sockets = self.open.iter().map(|t| match &t.socket {
+            Socket::Tcp(socket) => socket.as_raw_fd(),
+            Socket::Unix(socket) => socket.as_raw_fd(),
+        });
+        listeners.chain(sockets).collect()
+    }
+}
+
+impl X11 {
+    // listen for x11 connections on the server, the session must still be blocking
+    pub fn listen(sess: &Session) -> error::Result<X11> {
+        let display = std::env::var("DISPLAY")
+            .ok()
+            .filter(|display| !display.is_empty())
+            .ok_or("--x11 needs a local X server, DISPLAY is not set")
+            .context(CserunError::Config)?;
+        let local = LocalDisplay::parse(&display)
+            .ok_or(format!("--x11 can not use DISPLAY={}", display))
+            .context(CserunError::Config)?;
+        // like sshd, from display 10 on to stay clear of real X servers on the machine
+        let (listener, number) = (10..100)
+            .find_map(|number| {
+                sess.channel_forward_listen(6000 + number, Some("localhost"), None)
+                    .ok()
+                    .map(|(listener, _)| (listener, number))
+            })
+            .ok_or("Could not listen for X11 connections on the server, is forwarding allowed?")
+            .context(CserunError::Exec)?;
+        let mut fake_cookie = [0u8; 16];
+        random_bytes(&mut fake_cookie).context(CserunError::Local)?;
+        let real_cookie = local_cookie(&display);
+        if real_cookie.is_none() {
+            warn!(
+                "No xauth cookie found for DISPLAY={}, X11 connections go without one",
+                display
+            );
+        }
+        info!("Forwarding X11 to DISPLAY={}", display);
+        Ok(X11 {
+            listener,
+            display: number,
+            fake_cookie,
+            real_cookie,
+            local,
+        })
+    }
+
+    // the command with its DISPLAY and the fake cookie in the server's xauth, which is removed
+    // again once the command is done so the cookies of old runs do not pile up there
+    pub fn wrap(&self, command: &str) -> String {
+        format!(
+            "export DISPLAY=localhost:{display}.0; \
+             xauth add unix:{display}.0 MIT-MAGIC-COOKIE-1 {cookie} >/dev/null 2>&1; \
+             {{ {command}\n}}; status=$?; \
+             xauth remove unix:{display}.0 >/dev/null 2>&1; exit $status",
+            display = self.display,
+            cookie = hash::to_hex(&self.fake_cookie),
+            command = command
+        )
+    }
+}
+
+impl Agent {
+    // ask for the agent on the command's channel before it starts, the session must still be
+    // blocking
+    #[cfg(unix)]
+    pub fn request(sess: &Session, channel: &mut ssh2::Channel) -> error::Result<Agent> {
+        let socket = std::env::var_os("SSH_AUTH_SOCK")
+            .filter(|socket| !socket.is_empty())
+            .map(PathBuf::from)
+            .ok_or("--forward-agent needs a running ssh agent, SSH_AUTH_SOCK is not set")
+            .context(CserunError::Config)?;
+        // SAFETY: set under the session's lock, and the callback keeps no pointer to anything
+        // but the queue, which outlives every session. Drop for Agent unregisters it again
+        unsafe {
+            libssh2_session_callback_set2(
+                &mut *sess.raw(),
+                LIBSSH2_CALLBACK_AUTHAGENT,
+                on_agent_channel as *const libc::c_void,
+            );
+        }
+        channel
+            .request_auth_agent_forwarding()
+            .map_err(|e| format!("The server refused to forward the ssh agent: {}", e))
+            .context(CserunError::Exec)?;
+        info!("Forwarding the ssh agent at {}", socket.display());
+        Ok(Agent {
+            socket,
+            sess: sess.clone(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn request(_sess: &Session, _channel: &mut ssh2::Channel) -> error::Result<Agent> {
+        Err(CserunError::Config(
+            "--forward-agent needs an agent on a unix socket".into(),
+        ))
+    }
+
+    // the channels the server opened for this session so far
+    #[cfg(unix)]
+    fn take_channels(&self) -> Vec<AgentChannel> {
+        let session = &mut *self.sess.raw() as *mut libssh2_sys::LIBSSH2_SESSION as usize;
+        let mut queue = AGENT_CHANNELS.lock().unwrap();
+        let (mine, others) = queue.drain(..).partition(|(owner, _)| *owner == session);
+        *queue = others;
+        mine.into_iter()
+            .map(|(_, raw)| AgentChannel {
+                raw: raw as *mut libssh2_sys::LIBSSH2_CHANNEL,
+                sess: self.sess.clone(),
+            })
+            .collect()
+    }
+}
+
+// channels the server opens from now on are refused by libssh2, and the ones opened too late
+// for the command are freed, before the session goes and another one can turn up at its address
+#[cfg(unix)]
+impl Drop for Agent {
+    fn drop(&mut self) {
+        // SAFETY: under the session's lock, so the callback is not running for it meanwhile
+        unsafe {
+            libssh2_session_callback_set2(
+                &mut *self.sess.raw(),
+                LIBSSH2_CALLBACK_AUTHAGENT,
+                std::ptr::null(),
+            );
+        }
+        drop(self.take_channels());
+    }
+}
+
+#[cfg(unix)]
+impl AgentChannel {
+    // a libssh2 call under the session's lock, which the ssh2 crate takes for its calls too
+    fn call(
+        &self,
+        call: impl FnOnce(*mut libssh2_sys::LIBSSH2_CHANNEL) -> isize,
+    ) -> io::Result<usize> {
+        let rc = {
+            let _session = self.sess.raw();
+            call(self.raw)
+        };
+        match rc {
+            rc if rc >= 0 => Ok(rc as usize),
+            rc => Err(ssh2::Error::from_session_error(&self.sess, rc as libc::c_int).into()),
+        }
+    }
+
+    fn eof(&self) -> bool {
+        let _session = self.sess.raw();
+        unsafe { libssh2_sys::libssh2_channel_eof(self.raw) == 1 }
+    }
+}
+
+#[cfg(unix)]
+impl Read for AgentChannel {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.call(|raw| unsafe {
+            libssh2_sys::libssh2_channel_read_ex(raw, 0, buffer.as_mut_ptr().cast(), buffer.len())
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Write for AgentChannel {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.call(|raw| unsafe {
+            libssh2_sys::libssh2_channel_write_ex(raw, 0, data.as_ptr().cast(), data.len())
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for AgentChannel {
+    fn drop(&mut self) {
+        let _session = self.sess.raw();
+        // SAFETY: the channel is this one's alone, freed once and under the session's lock
+        unsafe {
+            libssh2_sys::libssh2_channel_free(self.raw);
+        }
+    }
+}
+
+impl Read for Remote {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Remote::Channel(channel) => channel.read(buffer),
+            #[cfg(unix)]
+            Remote::Agent(channel) => channel.read(buffer),
+        }
+    }
+}
+
+impl Write for Remote {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Remote::Channel(channel) => channel.write(data),
+            #[cfg(unix)]
+            Remote::Agent(channel) => channel.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Remote {
+    fn eof(&self) -> bool {
+        match self {
+            Remote::Channel(channel) => channel.eof(),
+            #[cfg(unix)]
+            Remote::Agent(channel) => channel.eof(),
+        }
+    }
+
+    fn send_eof(&mut self) -> io::Result<()> {
+        match self {
+            Remote::Channel(channel) => channel.send_eof().map_err(io::Error::from),
+            #[cfg(unix)]
+            Remote::Agent(channel) => channel
+                .call(|raw| unsafe { libssh2_sys::libssh2_channel_send_eof(raw) as isize })
+                .map(|_| ()),
+        }
+    }
+}
+
+impl LocalDisplay {
+    // [host]:display[.screen], a unix socket for no host, or a socket path on macOS
+    fn parse(display: &str) -> Option<LocalDisplay> {
+        let (host, rest) = display.rsplit_once(':')?;
+        #[cfg(unix)]
+        if host.starts_with('/') {
+            return Some(LocalDisplay::Unix(PathBuf::from(display)));
+        }
+        let number: u16 = rest.split('.').next()?.parse().ok()?;
+        match host {
+            #[cfg(unix)]
+            "" | "unix" => Some(LocalDisplay::Unix(PathBuf::from(format!(
+                "/tmp/.X11-unix/X{}",
+                number
+            )))),
+            #[cfg(not(unix))]
+            "" => Some(LocalDisplay::Tcp("localhost".to_string(), 6000 + number)),
+            host => Some(LocalDisplay::Tcp(host.to_string(), 6000 + number)),
+        }
+    }
+
+    fn connect(&self) -> io::Result<Socket> {
+        match self {
+            LocalDisplay::Tcp(host, port) => {
+                let socket = TcpStream::connect((host.as_str(), *port))?;
+                socket.set_nonblocking(true)?;
+                Ok(Socket::Tcp(socket))
+            }
+            #[cfg(unix)]
+            LocalDisplay::Unix(path) => {
+                let socket = UnixStream::connect(path)?;
+                socket.set_nonblocking(true)?;
+                Ok(Socket::Unix(socket))
+            }
+        }
+    }
+}
+
+// the MIT-MAGIC-COOKIE-1 of the local display, from `xauth list`
+fn local_cookie(display: &str) -> Option<Vec<u8>> {
+    let output = Command::new("xauth")
+        .args(["list", display])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let hex = output.lines().find_map(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        (fields.next()? == "MIT-MAGIC-COOKIE-1").then(|| fields.next())?
+    })?;
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+// fill the buffer from the random number generator of the OS
+fn random_bytes(buffer: &mut [u8]) -> io::Result<()> {
+    // getrandom may return less than asked for, getentropy takes at most 256 bytes a call
+    for chunk in buffer.chunks_mut(256) {
+        let mut filled = 0;
+        while filled < chunk.len() {
+            let rest = &mut chunk[filled..];
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            let size = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+            #[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+            let size = match unsafe { libc::getentropy(rest.as_mut_ptr().cast(), rest.len()) } {
+                0 => rest.len() as isize,
+                _ => -1,
+            };
+            #[cfg(windows)]
+            let size = {
+                const BCRYPT_USE_SYSTEM_PREFERRED_RNG: u32 = 2;
+                #[link(name = "bcrypt")]
+                extern "system" {
+                    fn BCryptGenRandom(
+                        algorithm: *mut std::ffi::c_void,
+                        buffer: *mut u8,
+                        size: u32,
+                        flags: u32,
+                    ) -> i32;
+                }
+                let status = unsafe {
+                    BCryptGenRandom(
+                        std::ptr::null_mut(),
+                        rest.as_mut_ptr(),
+                        rest.len() as u32,
+                        BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+                    )
+                };
+                if status < 0 {
+                    return Err(io::Error::other(format!(
+                        "BCryptGenRandom failed with {:#x}",
+                        status
+                    )));
+                }
+                rest.len() as isize
+            };
+            if size < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            filled += size as usize;
+        }
+    }
+    Ok(())
+}
+
+// swap the fake cookie for the real one in the X11 connection setup, the first thing a client
+// sends. false while the setup is still incomplete, an error if the cookie is not ours, which
+// is someone else on the server who found the port
+fn swap_cookie(setup: &mut [u8], fake: &[u8], real: Option<&[u8]>) -> io::Result<bool> {
+    if setup.len() < 12 {
+        return Ok(false);
+    }
+    let read = |at: usize| match setup[0] {
+        b'B' => u16::from_be_bytes([setup[at], setup[at + 1]]),
+        _ => u16::from_le_bytes([setup[at], setup[at + 1]]),
+    } as usize;
+    let (name_len, data_len) = (read(6), read(8));
+    let data_start = 12 + name_len.div_ceil(4) * 4;
+    if setup.len() < data_start + data_len.div_ceil(4) * 4 {
+        return Ok(false);
+    }
+    let data = &mut setup[data_start..data_start + data_len];
+    if data != fake {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "refused an X11 connection with the wrong cookie",
+        ));
+    }
+    if let Some(real) = real.filter(|real| real.len() == fake.len()) {
+        data.copy_from_slice(real);
+    }
+    Ok(true)
+}
+
+impl Read for Socket {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(socket) => socket.read(buffer),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.read(buffer),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(socket) => socket.write(data),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Socket {
+    fn shutdown_write(&self) {
+        let _ = match self {
+            Socket::Tcp(socket) => socket.shutdown(Shutdown::Write),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.shutdown(Shutdown::Write),
+        };
+    }
+}
+
+impl Tunnel {
+    fn new(socket: Socket, channel: Remote) -> Tunnel {
+        Tunnel {
+            socket,
+            channel,
+            cookie: None,
+            to_remote: Vec::new(),
+            to_local: Vec::new(),
+            local_eof: false,
+            eof_sent: false,
+            remote_eof: false,
+        }
+    }
+
+    fn closed(&self) -> bool {
+        self.eof_sent && self.remote_eof
+    }
+
+    // move what is ready without blocking, true if anything moved
+    fn pump(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 16384];
+        let mut active = false;
+
+        if self.to_remote.is_empty() && !self.local_eof {
+            match self.socket.read(&mut buffer) {
+                Ok(0) => self.local_eof = true,
+                Ok(size) => self.to_remote.extend_from_slice(&buffer[..size]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !self.to_remote.is_empty() {
+            match self.channel.write(&self.to_remote) {
+                Ok(size) => {
+                    self.to_remote.drain(..size);
+                    active = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.local_eof && self.to_remote.is_empty() && !self.eof_sent {
+            match self.channel.send_eof() {
+                Ok(()) => self.eof_sent = true,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.to_local.is_empty() && !self.remote_eof {
+            match self.channel.read(&mut buffer) {
+                Ok(size) => self.to_local.extend_from_slice(&buffer[..size]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some((fake, real)) = &self.cookie {
+            // nothing goes to the X server before the cookie is checked
+            if !swap_cookie(&mut self.to_local, fake, real.as_deref())? {
+                if self.channel.eof() {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                return Ok(active);
+            }
+            self.cookie = None;
+        }
+        if !self.to_local.is_empty() {
+            match self.socket.write(&self.to_local) {
+                Ok(size) => {
+                    self.to_local.drain(..size);
+                    active = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // pass the server side closing on once everything it sent is written
+        if self.to_local.is_empty() && !self.remote_eof && self.channel.eof() {
+            self.remote_eof = true;
+            self.socket.shutdown_write();
+        }
+        Ok(active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(forward: &str) -> (String, u16, String, u16) {
+        let forward: Forward = forward.parse().unwrap();
+        (
+            forward.bind_address,
+            forward.local_port,
+            forward.host,
+            forward.port,
+        )
+    }
+
+    #[test]
+    fn forward_like_ssh_l() {
+        let local = |port, host: &str, hostport| {
+            ("localhost".to_string(), port, host.to_string(), hostport)
+        };
+        assert_eq!(parts("8000"), local(8000, "localhost", 8000));
+        assert_eq!(
+            parts("8080:db.internal:5432"),
+            local(8080, "db.internal", 5432)
+        );
+        assert_eq!(
+            parts("0.0.0.0:8000:localhost:80"),
+            ("0.0.0.0".to_string(), 8000, "localhost".to_string(), 80)
+        );
+        for forward in [
+            "",
+            "http",
+            "8000:localhost",
+            "70000",
+            "8000::80",
+            ":8000:localhost:80",
+            "a:b:c:d:e",
+        ] {
+            assert!(forward.parse::<Forward>().is_err(), "{:?}", forward);
+        }
+    }
+}
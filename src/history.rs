@@ -0,0 +1,177 @@
+use crate::artifacts;
+use crate::error::{self, Context, CserunError};
+use crate::provenance::Provenance;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+
+// a remote run directory that was left in place with --keep
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeptRun {
+    pub server_addr: String,
+    pub username: String,
+    pub remote_dir: String,
+    pub created: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeptRuns {
+    #[serde(default)]
+    runs: Vec<KeptRun>,
+}
+
+fn kept_runs_path() -> error::Result<PathBuf> {
+    let mut path = dirs::home_dir()
+        .ok_or("Cannot find the home directory")
+        .context(CserunError::Local)?;
+    path.push(".cserun");
+    fs::create_dir_all(&path).context(CserunError::Local)?;
+    path.push("kept.toml");
+    Ok(path)
+}
+
+pub fn load_kept() -> error::Result<Vec<KeptRun>> {
+    let path = kept_runs_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(path).context(CserunError::Local)?;
+    let kept: KeptRuns = toml::from_str(&contents).context(CserunError::Local)?;
+    Ok(kept.runs)
+}
+
+fn save_kept(runs: Vec<KeptRun>) -> error::Result<()> {
+    let contents = toml::to_string(&KeptRuns { runs }).context(CserunError::Local)?;
+    fs::write(kept_runs_path()?, contents).context(CserunError::Local)
+}
+
+// kept.toml and history.toml are read, changed and written back by runs that may finish at the
+// same time, e.g. with several --host, so that happens under a lock on history.lock. it goes
+// away with the file when dropped
+fn lock() -> error::Result<File> {
+    let path = kept_runs_path()?.with_file_name("history.lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .context(CserunError::Local)?;
+    file.lock().context(CserunError::Local)?;
+    Ok(file)
+}
+
+// change the kept runs under the lock
+pub fn update_kept(change: impl FnOnce(&mut Vec<KeptRun>)) -> error::Result<()> {
+    let _lock = lock()?;
+    let mut runs = load_kept()?;
+    change(&mut runs);
+    save_kept(runs)
+}
+
+pub fn record_kept(run: KeptRun) -> error::Result<()> {
+    update_kept(|runs| runs.push(run))
+}
+
+// how many runs history.toml remembers, the oldest are dropped first
+const HISTORY_LIMIT: usize = 200;
+
+// a finished run, for `cserun list`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub id: u64,
+    pub started: String, // the name of the run directory, its local start time
+    pub server_addr: String,
+    pub username: String,
+    pub local_dir: PathBuf,
+    pub command: String,
+    pub script: Option<PathBuf>,
+    pub workdir: Option<PathBuf>,
+    pub remote_dir: String,
+    pub exit_status: i32,
+    pub seconds: f64,
+    pub files_uploaded: usize,
+    pub bytes_uploaded: u64,
+    #[serde(default)]
+    pub detached: bool, // started with --detach, its exit status is known once it was seen done
+    #[serde(default)]
+    pub running: bool,
+    #[serde(default)]
+    pub provenance: Option<Provenance>, // also in the run directory's manifest.json
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RunHistory {
+    #[serde(default)]
+    runs: Vec<RunRecord>,
+}
+
+fn history_path() -> error::Result<PathBuf> {
+    Ok(kept_runs_path()?.with_file_name("history.toml"))
+}
+
+// past runs, oldest first
+pub fn load_runs() -> error::Result<Vec<RunRecord>> {
+    let path = history_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(path).context(CserunError::Local)?;
+    let history: RunHistory = toml::from_str(&contents).context(CserunError::Local)?;
+    Ok(history.runs)
+}
+
+// a run of the account by its id, or its latest one with "last"
+pub fn find_run(server_addr: &str, username: &str, run: &str) -> error::Result<RunRecord> {
+    let mut runs = load_runs()?
+        .into_iter()
+        .filter(|r| r.server_addr == server_addr && r.username == username);
+    let found = match run {
+        "last" => runs.next_back(),
+        id => {
+            let id: u64 = id
+                .parse()
+                .map_err(|_| format!("{:?} is not a run id or \"last\"", id))
+                .context(CserunError::Config)?;
+            runs.find(|r| r.id == id)
+        }
+    };
+    found
+        .ok_or(format!("No run {} in the history, see `cserun list`", run))
+        .context(CserunError::Config)
+}
+
+fn save_runs(runs: Vec<RunRecord>) -> error::Result<()> {
+    let contents = toml::to_string(&RunHistory { runs }).context(CserunError::Local)?;
+    fs::write(history_path()?, contents).context(CserunError::Local)
+}
+
+// add a run with the next id, which is returned
+pub fn record_run(mut run: RunRecord) -> error::Result<u64> {
+    let _lock = lock()?;
+    let mut runs = load_runs()?;
+    let id = runs.last().map_or(1, |last| last.id + 1);
+    run.id = id;
+    runs.push(run);
+    let dropped = runs.len().saturating_sub(HISTORY_LIMIT);
+    for forgotten in runs.drain(..dropped) {
+        artifacts::remove(&forgotten.started);
+    }
+    save_runs(runs)?;
+    Ok(id)
+}
+
+// a detached run that was seen done on the server
+pub fn finish_detached(id: u64, exit_status: i32, seconds: f64) -> error::Result<()> {
+    let _lock = lock()?;
+    let mut runs = load_runs()?;
+    let Some(run) = runs.iter_mut().find(|run| run.id == id) else {
+        return Ok(());
+    };
+    if !run.running {
+        return Ok(());
+    }
+    run.running = false;
+    run.exit_status = exit_status;
+    run.seconds = seconds;
+    save_runs(runs)
+}
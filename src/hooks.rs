@@ -0,0 +1,102 @@
+use crate::error::{self, Context, CserunError};
+use crate::events;
+use crate::ssh::{self, RunDir};
+use log::info;
+use ssh2::Session;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// commands from the [hooks] section, run around every run
+#[derive(Clone, Debug, Default)]
+pub struct Hooks {
+    pub pre_sync: Option<String>, // local, before the files are uploaded, e.g. `make generate`
+    pub pre_run: Option<String>,  // remote, in the same shell right before the command
+    pub post_run: Option<String>, // local, once the command and the downloads are done
+}
+
+// run a local hook through the shell in the local directory, a failure stops the run
+pub fn run_local(
+    name: &str,
+    hook: &str,
+    local_dir: &Path,
+    envs: &[(&str, String)],
+) -> error::Result<()> {
+    info!("Running the {} hook: {}", name, hook);
+    let mut command = match cfg!(windows) {
+        true => {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(hook);
+            command
+        }
+        false => {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(hook);
+            command
+        }
+    };
+    // stdout carries nothing but events with --output json
+    if events::enabled() {
+        command.stdout(Stdio::from(io::stderr()));
+    }
+    let status = command
+        .current_dir(local_dir)
+        .envs(envs.iter().map(|(key, value)| (key, value)))
+        .status()
+        .map_err(|e| format!("Failed to run the {} hook: {}", name, e))
+        .context(CserunError::Local)?;
+    if !status.success() {
+        return Err(CserunError::Local(
+            format!("The {} hook `{}` failed with {}", name, hook, status).into(),
+        ));
+    }
+    Ok(())
+}
+
+// the command with pre_run in front of it, in the same shell so `module load` and the like
+// still apply to the command. the marker only goes away once pre_run has succeeded. set -e stops
+// at any failing statement of `a; b`, which an `|| exit` after it would not, and the newline keeps
+// a trailing comment from swallowing the rest
+pub fn with_pre_run(hooks: &Hooks, command: String, run_dir: &RunDir) -> String {
+    let Some(pre_run) = &hooks.pre_run else {
+        return command;
+    };
+    let marker = ssh::shell_path(marker_path(run_dir));
+    format!(
+        "touch {marker}; set -e; {{ {pre_run}\n}}; set +e; rm -f {marker}; {command}",
+        marker = marker,
+        pre_run = pre_run,
+        command = command,
+    )
+}
+
+fn marker_path(run_dir: &RunDir) -> String {
+    format!("{}/pre_run", run_dir.path)
+}
+
+// a failed pre_run is an error of the run, not the exit status of the command. it exits with the
+// status of its failing statement, told apart from the command by the marker it leaves behind
+pub fn check_pre_run(
+    sess: &Session,
+    hooks: &Hooks,
+    run_dir: &RunDir,
+    exit_status: i32,
+) -> error::Result<()> {
+    let Some(pre_run) = &hooks.pre_run else {
+        return Ok(());
+    };
+    if exit_status == 0 {
+        return Ok(());
+    }
+    let test = format!("test -f {}", ssh::shell_quote(&marker_path(run_dir)));
+    match ssh::run_remote(sess, &test) {
+        Ok(_) => Err(CserunError::Exec(
+            format!(
+                "The pre_run hook `{}` failed, the command was not run",
+                pre_run
+            )
+            .into(),
+        )),
+        Err(_) => Ok(()),
+    }
+}
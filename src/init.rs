@@ -0,0 +1,211 @@
+use crate::error::{self, Context, CserunError};
+use crate::parse;
+use crate::ssh;
+use console::style;
+use ssh2::Session;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+// the keys ssh-keygen creates by default, the newest kind first
+const KEY_NAMES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+enum AuthChoice {
+    Agent,
+    Key(PathBuf),
+    Password,
+    KeyboardInteractive,
+}
+
+// the config just written, as the runs will read it
+fn written_config() -> error::Result<ssh::Config> {
+    parse::get_ssh_config(None, None).map_err(|e| CserunError::Config(e.into()))
+}
+
+// ask for the settings a first run needs, write the config file and try them out
+pub fn wizard(force: bool) -> error::Result<i32> {
+    let config_path = parse::get_config_path()
+        .map_err(|e| e.to_string())
+        .context(CserunError::Local)?;
+    if config_path.exists()
+        && !force
+        && !confirm(
+            &format!("{} already exists, overwrite it?", config_path.display()),
+            false,
+        )?
+    {
+        println!("Left the config file as it was");
+        return Ok(1);
+    }
+
+    let addr = ask("Server address", Some("cse.unsw.edu.au"))?;
+    let port = loop {
+        match ask("Port", Some("22"))?.parse::<u16>() {
+            Ok(port) => break port,
+            Err(_) => eprintln!("That is not a port number"),
+        }
+    };
+    let user = ask("Username (e.g. your zID)", None)?;
+    let auth = ask_auth()?;
+
+    fs::write(&config_path, config_contents(&addr, port, &user, &auth))
+        .context(CserunError::Local)?;
+    println!("Wrote {}", config_path.display());
+
+    // there is no keyring backend for Windows to offer
+    if matches!(auth, AuthChoice::Password)
+        && !cfg!(windows)
+        && confirm(
+            "Store the password in the system keyring instead of typing it every run?",
+            true,
+        )?
+    {
+        ssh::store_secret(&written_config()?)?;
+    }
+    if !confirm("Test the connection now?", true)? {
+        return Ok(0);
+    }
+    let conf = written_config()?;
+    match ssh::connect(&conf) {
+        Ok(conn) => {
+            println!(
+                "{} Connected to {} as {}",
+                style("✔").green(),
+                conn.addr,
+                conf.username
+            );
+            Ok(0)
+        }
+        Err(e) => {
+            eprintln!("{} {}", style("✘").red(), e.report());
+            eprintln!(
+                "The config is saved anyway, edit {} or run `cserun init` again",
+                config_path.display()
+            );
+            Ok(1)
+        }
+    }
+}
+
+// offer what was found on this machine, the agent if it holds keys, then the key files
+fn ask_auth() -> error::Result<AuthChoice> {
+    let agent_keys = agent_identities();
+    let keys: Vec<PathBuf> = dirs::home_dir()
+        .map(|home| {
+            KEY_NAMES
+                .iter()
+                .map(|name| home.join(".ssh").join(name))
+                .filter(|path| path.is_file())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut choices = Vec::new();
+    if agent_keys > 0 {
+        choices.push((
+            AuthChoice::Agent,
+            format!("the running ssh-agent ({} key(s))", agent_keys),
+        ));
+    }
+    for key in keys {
+        let label = format!("the key {}", key.display());
+        choices.push((AuthChoice::Key(key), label));
+    }
+    choices.push((AuthChoice::Password, "a password".to_string()));
+    choices.push((
+        AuthChoice::KeyboardInteractive,
+        "keyboard-interactive, for 2FA codes".to_string(),
+    ));
+
+    eprintln!("How do you log in?");
+    for (number, (_, label)) in choices.iter().enumerate() {
+        eprintln!("  {}) {}", number + 1, label);
+    }
+    loop {
+        let answer = ask("Choice", Some("1"))?;
+        match answer.parse::<usize>() {
+            Ok(number) if (1..=choices.len()).contains(&number) => {
+                return Ok(choices.swap_remove(number - 1).0);
+            }
+            _ => eprintln!("Pick a number from 1 to {}", choices.len()),
+        }
+    }
+}
+
+// how many keys the agent holds, 0 without a running agent
+fn agent_identities() -> usize {
+    (|| -> Result<usize, ssh2::Error> {
+        let mut agent = Session::new()?.agent()?;
+        agent.connect()?;
+        agent.list_identities()?;
+        Ok(agent.identities()?.len())
+    })()
+    .unwrap_or(0)
+}
+
+fn config_contents(addr: &str, port: u16, user: &str, auth: &AuthChoice) -> String {
+    let quote = |s: &str| toml::Value::String(s.to_string()).to_string();
+    let auth = match auth {
+        AuthChoice::Agent => "type = \"agent\"\n".to_string(),
+        AuthChoice::Key(path) => format!(
+            "type = \"key\"\nprivate_key_path = {}\n",
+            quote(&path.to_string_lossy())
+        ),
+        AuthChoice::Password => {
+            "type = \"password\"\n# password = \"...\" # asked for when missing, or kept with `cserun auth store`\n"
+                .to_string()
+        }
+        AuthChoice::KeyboardInteractive => "type = \"keyboard-interactive\"\n".to_string(),
+    };
+    format!(
+        "# written by `cserun init`, see example.toml in the repository for every option\n\
+         [server]\n\
+         addr = {}\n\
+         port = {}\n\
+         user = {}\n\
+         # shell = \"bash\" # run commands in a login shell so .bash_profile applies\n\
+         \n\
+         [auth]\n\
+         {}",
+        quote(addr),
+        port,
+        quote(user),
+        auth
+    )
+}
+
+pub fn ask(question: &str, default: Option<&str>) -> error::Result<String> {
+    loop {
+        match default {
+            Some(default) if !default.is_empty() => eprint!("{} [{}]: ", question, default),
+            _ => eprint!("{}: ", question),
+        }
+        io::stderr().flush().context(CserunError::Local)?;
+        let mut answer = String::new();
+        if io::stdin()
+            .read_line(&mut answer)
+            .context(CserunError::Local)?
+            == 0
+        {
+            return Err(CserunError::Local("No answer, stdin was closed".into()));
+        }
+        match (answer.trim(), default) {
+            ("", Some(default)) => return Ok(default.to_string()),
+            ("", None) => continue,
+            (answer, _) => return Ok(answer.to_string()),
+        }
+    }
+}
+
+pub fn confirm(question: &str, default: bool) -> error::Result<bool> {
+    let hint = match default {
+        true => "Y/n",
+        false => "y/N",
+    };
+    let answer = ask(&format!("{} [{}]", question, hint), Some(""))?;
+    Ok(match answer.to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    })
+}
@@ -0,0 +1,134 @@
+use crate::error::{self, Context, CserunError};
+use log::debug;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+// the system keyring through its command line tools: `security` for the macOS Keychain, and
+// `secret-tool` for the Secret Service on Linux and the BSDs. there is no backend for the
+// Windows Credential Manager, lookups there find nothing and storing fails
+
+// everything is stored under this service, the account says which secret it is
+const SERVICE: &str = "cserun";
+
+// the account name of a login password
+pub fn password_account(username: &str, host: &str) -> String {
+    format!("password {}@{}", username, host)
+}
+
+// the account name of a private key's passphrase
+pub fn passphrase_account(private_key: &std::path::Path) -> String {
+    format!("passphrase {}", private_key.display())
+}
+
+// look a secret up, a missing keyring tool or entry is just no secret
+pub fn get(account: &str) -> Option<String> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args(["find-generic-password", "-s", SERVICE, "-a", account, "-w"])
+            .stderr(Stdio::null())
+            .output()
+    } else if cfg!(target_os = "windows") {
+        return None;
+    } else {
+        Command::new("secret-tool")
+            .args(["lookup", "service", SERVICE, "account", account])
+            .stderr(Stdio::null())
+            .output()
+    };
+    match output {
+        Ok(output) if output.status.success() && !output.stdout.is_empty() => {
+            let secret = String::from_utf8_lossy(&output.stdout);
+            Some(secret.trim_end_matches(['\r', '\n']).to_string())
+        }
+        Ok(_) => None,
+        Err(e) => {
+            debug!("Keyring lookup failed: {}", e);
+            None
+        }
+    }
+}
+
+// store a secret, replacing an existing one. `security` asks for the secret itself,
+// so it never shows up in the process list
+pub fn store(account: &str, secret: impl FnOnce() -> io::Result<String>) -> error::Result<()> {
+    let status = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args([
+                "add-generic-password",
+                "-U",
+                "-s",
+                SERVICE,
+                "-a",
+                account,
+                "-w",
+            ])
+            .status()
+            .context(CserunError::Local)?
+    } else if cfg!(target_os = "windows") {
+        return Err(unsupported());
+    } else {
+        let secret = secret().context(CserunError::Local)?;
+        let mut child = Command::new("secret-tool")
+            .args(["store", "--label", &format!("{} ({})", account, SERVICE)])
+            .args(["service", SERVICE, "account", account])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(tool_error)?;
+        // secret-tool reads the secret from stdin, so it never shows up in the process list
+        let written = match child.stdin.take() {
+            Some(mut stdin) => stdin.write_all(secret.as_bytes()),
+            None => Err(io::Error::other(
+                "no stdin to pass the secret to secret-tool",
+            )),
+        };
+        let status = child.wait().context(CserunError::Local)?;
+        written.context(CserunError::Local)?;
+        status
+    };
+    match status.success() {
+        true => Ok(()),
+        false => Err(CserunError::Local(
+            "The keyring refused to store the secret".into(),
+        )),
+    }
+}
+
+// remove a stored secret, false if there was none
+pub fn forget(account: &str) -> error::Result<bool> {
+    let status = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args(["delete-generic-password", "-s", SERVICE, "-a", account])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .context(CserunError::Local)?
+    } else if cfg!(target_os = "windows") {
+        return Err(unsupported());
+    } else {
+        // secret-tool succeeds either way, so look first
+        if get(account).is_none() {
+            return Ok(false);
+        }
+        Command::new("secret-tool")
+            .args(["clear", "service", SERVICE, "account", account])
+            .status()
+            .map_err(tool_error)?
+    };
+    Ok(status.success())
+}
+
+fn tool_error(e: io::Error) -> CserunError {
+    match e.kind() {
+        io::ErrorKind::NotFound => CserunError::Local(
+            "secret-tool not found, install libsecret-tools to use the keyring".into(),
+        ),
+        _ => CserunError::Local(e.into()),
+    }
+}
+
+fn unsupported() -> CserunError {
+    CserunError::Local(
+        "The keyring is only supported on macOS, Linux and the BSDs, use the SSH agent on Windows"
+            .into(),
+    )
+}
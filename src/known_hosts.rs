@@ -0,0 +1,255 @@
+use crate::error::{self, Context, CserunError};
+use crate::hash;
+use console::style;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use ssh2::{CheckResult, KnownHostFileKind, KnownHostKeyFormat, Session};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+fn known_hosts_path() -> error::Result<PathBuf> {
+    let mut path = dirs::home_dir()
+        .ok_or("Cannot find the home directory")
+        .context(CserunError::Local)?;
+    path.push(".ssh");
+    path.push("known_hosts");
+    Ok(path)
+}
+
+// OpenSSH only writes the port into the entry when it is not the default one
+fn host_entry_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+// a host key cserun trusted once, kept apart from known_hosts so editing or losing that file
+// does not quietly let a different key in
+#[derive(Debug, Serialize, Deserialize)]
+struct Pin {
+    fingerprint: String,
+    added: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Pins {
+    #[serde(default)]
+    hosts: BTreeMap<String, Pin>, // by known_hosts entry name
+}
+
+fn pins_path() -> error::Result<PathBuf> {
+    let mut path = dirs::home_dir()
+        .ok_or("Cannot find the home directory")
+        .context(CserunError::Local)?;
+    path.push(".cserun");
+    // not hosts.toml, failover keeps the last working address of each server in that one
+    path.push("host_keys.toml");
+    Ok(path)
+}
+
+fn load_pins(path: &PathBuf) -> error::Result<Pins> {
+    if !path.exists() {
+        return Ok(Pins::default());
+    }
+    let contents = fs::read_to_string(path).context(CserunError::Local)?;
+    toml::from_str(&contents)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+        .context(CserunError::Local)
+}
+
+fn save_pins(path: &PathBuf, pins: &Pins) -> error::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context(CserunError::Local)?;
+    }
+    let contents = toml::to_string(pins).context(CserunError::Local)?;
+    fs::write(path, contents).context(CserunError::Local)
+}
+
+// verify the server's host key against the key pinned in ~/.cserun/host_keys.toml, or against
+// ~/.ssh/known_hosts for a host without one, must be called after the handshake. the key is
+// pinned the first time it is trusted for good, from known_hosts or by answering the prompt
+pub fn check(sess: &Session, host: &str, port: u16, strict: bool) -> error::Result<()> {
+    let (key, _) = sess
+        .host_key()
+        .ok_or("Server did not provide a host key")
+        .context(CserunError::Handshake)?;
+    let fingerprint = hash::ssh_fingerprint(key);
+    let name = host_entry_name(host, port);
+    let pins_path = pins_path()?;
+    let mut pins = load_pins(&pins_path)?;
+    match pins.hosts.get(&name) {
+        Some(pin) if pin.fingerprint == fingerprint => return Ok(()),
+        Some(pin) => {
+            eprintln!(
+                "{}",
+                style("@@@ WARNING: THE HOST KEY OF THIS SERVER HAS CHANGED! @@@")
+                    .red()
+                    .bold()
+            );
+            eprintln!(
+                "Someone could be eavesdropping on you right now, or the server's key was replaced.\n\
+                 Pinned on {}: {}\nOffered now: {}\n\
+                 If the change is expected, remove {:?} from {} and connect again.",
+                pin.added,
+                pin.fingerprint,
+                fingerprint,
+                name,
+                pins_path.display()
+            );
+            return Err(CserunError::Handshake(
+                format!("Host key for {} does not match the pinned key", name).into(),
+            ));
+        }
+        None => {}
+    }
+    if check_known_hosts(sess, host, port, strict)? {
+        pins.hosts.insert(
+            name.clone(),
+            Pin {
+                fingerprint,
+                added: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            },
+        );
+        save_pins(&pins_path, &pins)?;
+        info!("Pinned the host key of {} in {}", name, pins_path.display());
+    }
+    Ok(())
+}
+
+// the known_hosts part of check, true if the key is trusted for good rather than just this once
+fn check_known_hosts(sess: &Session, host: &str, port: u16, strict: bool) -> error::Result<bool> {
+    let (key, key_type) = sess
+        .host_key()
+        .ok_or("Server did not provide a host key")
+        .context(CserunError::Handshake)?;
+    let path = known_hosts_path()?;
+
+    let mut known_hosts = sess.known_hosts().context(CserunError::Handshake)?;
+    if path.exists() {
+        known_hosts
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .context(CserunError::Local)?;
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(true),
+        CheckResult::Mismatch => Err(CserunError::Handshake(
+            format!(
+                "Host key for {} has changed and does not match {}, someone could be eavesdropping on you",
+                host,
+                path.display()
+            )
+            .into(),
+        )),
+        CheckResult::Failure => Err(CserunError::Handshake(
+            "Failed to check the host key against known_hosts".into(),
+        )),
+        CheckResult::NotFound => {
+            if strict {
+                return Err(CserunError::Handshake(
+                    format!(
+                        "No host key is known for {} and strict host key checking is enabled",
+                        host
+                    )
+                    .into(),
+                ));
+            }
+            // asked on the terminal itself, stdin may be a pipe or the input of the command
+            let Ok(terminal) = open_terminal() else {
+                return Err(CserunError::Handshake(
+                    format!(
+                        "No host key is known for {} (key fingerprint {}) and there is no terminal to ask on, add it to {} first, e.g. with `ssh-keyscan -p {} {} >> {}`, or set strict_host_key_checking = true to refuse unknown hosts without asking",
+                        host,
+                        hash::ssh_fingerprint(key),
+                        path.display(),
+                        port,
+                        host,
+                        path.display()
+                    )
+                    .into(),
+                ));
+            };
+            eprintln!(
+                "The authenticity of host '{}' can't be established.\nKey fingerprint is {}.",
+                host_entry_name(host, port),
+                hash::ssh_fingerprint(key)
+            );
+            match prompt_trust(terminal).context(CserunError::Local)? {
+                Trust::Accept => Ok(false),
+                Trust::Reject => Err(CserunError::Handshake(
+                    "Host key verification rejected by user".into(),
+                )),
+                Trust::Remember => {
+                    remember(sess, &path, &host_entry_name(host, port), key, key_type.into())
+                        .context(CserunError::Local)?;
+                    Ok(true)
+                }
+            }
+        }
+    }
+}
+
+enum Trust {
+    Accept,
+    Reject,
+    Remember,
+}
+
+// the controlling terminal, like ssh asks on, an error without one, e.g. under cron or in CI
+fn open_terminal() -> io::Result<File> {
+    #[cfg(windows)]
+    return File::open("CONIN$");
+    #[cfg(not(windows))]
+    File::open("/dev/tty")
+}
+
+fn prompt_trust(terminal: File) -> io::Result<Trust> {
+    let mut terminal = BufReader::new(terminal);
+    loop {
+        eprint!("Trust this host? [a]ccept once / [r]eject / re[m]ember: ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        if terminal.read_line(&mut answer)? == 0 {
+            return Ok(Trust::Reject);
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "a" | "accept" => return Ok(Trust::Accept),
+            "r" | "reject" => return Ok(Trust::Reject),
+            "m" | "remember" => return Ok(Trust::Remember),
+            _ => continue,
+        }
+    }
+}
+
+// append a single entry instead of rewriting the whole file, so lines libssh2 can't parse are kept
+fn remember(
+    sess: &Session,
+    path: &PathBuf,
+    name: &str,
+    key: &[u8],
+    format: KnownHostKeyFormat,
+) -> Result<(), error::Source> {
+    let mut entry = sess.known_hosts()?;
+    entry.add(name, key, "added by cserun", format)?;
+    let host = entry
+        .hosts()?
+        .into_iter()
+        .next()
+        .ok_or("Failed to build the known_hosts entry")?;
+    let line = entry.write_string(&host, KnownHostFileKind::OpenSSH)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    if !line.ends_with('\n') {
+        file.write_all(b"\n")?;
+    }
+    warn!("Permanently added '{}' to {}", name, path.display());
+    Ok(())
+}
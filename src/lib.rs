@@ -0,0 +1,50 @@
+pub mod artifacts;
+pub mod cache;
+pub mod cancel;
+pub mod completions;
+pub mod container;
+#[cfg(unix)]
+pub mod control;
+pub mod delta;
+pub mod detach;
+pub mod encoding;
+pub mod error;
+pub mod events;
+pub mod executor;
+pub mod failover;
+pub mod filter;
+pub mod forward;
+pub mod hash;
+pub mod history;
+pub mod hooks;
+pub mod init;
+pub mod keyring;
+pub mod known_hosts;
+pub mod limits;
+pub mod local;
+pub mod logger;
+pub mod matrix;
+pub mod notify;
+pub mod parse;
+pub mod partial;
+pub mod prefix;
+pub mod preflight;
+pub mod provenance;
+pub mod proxy;
+pub mod runner;
+pub mod slurm;
+pub mod snapshot;
+pub mod ssh;
+pub mod ssh_config;
+pub mod sync;
+pub mod tar;
+#[cfg(feature = "async")]
+pub mod task;
+pub mod terminal;
+pub mod throttle;
+pub mod transcript;
+pub mod watcher;
+
+pub use cancel::CancelToken;
+pub use error::CserunError;
+pub use runner::{Runner, RunnerBuilder, Session};
@@ -0,0 +1,81 @@
+use crate::ssh::shell_quote;
+
+// --nice, --mem-limit and --cpu-time: teaching servers suspend accounts that hog the machine,
+// so the remote command can be started with less priority and a cap on memory and cpu time
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub nice: Option<i32>,
+    pub mem_limit: Option<u64>, // bytes
+    pub cpu_time: Option<u64>,  // seconds
+    // how each limit is applied on the server, `{}` is the nice level, the memory in KiB and the
+    // cpu seconds. one starting with `ulimit` runs in the shell before the command, anything
+    // else is put in front of the command, like nice or timeout
+    pub nice_command: String,
+    pub mem_limit_command: String,
+    pub cpu_time_command: String,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            nice: None,
+            mem_limit: None,
+            cpu_time: None,
+            nice_command: "nice -n {}".to_string(),
+            mem_limit_command: "ulimit -v {}".to_string(),
+            cpu_time_command: "ulimit -t {}".to_string(),
+        }
+    }
+}
+
+impl Limits {
+    pub fn any(&self) -> bool {
+        self.nice.is_some() || self.mem_limit.is_some() || self.cpu_time.is_some()
+    }
+
+    // the statements to run before the command and what to put in front of it, in the order
+    // nice, memory, cpu time
+    fn split(&self) -> (String, Vec<String>) {
+        let mut statements = String::new();
+        let mut prefixes = Vec::new();
+        let limits = [
+            (self.nice.map(|nice| nice.to_string()), &self.nice_command),
+            (
+                self.mem_limit.map(|bytes| bytes.div_ceil(1024).to_string()),
+                &self.mem_limit_command,
+            ),
+            (
+                self.cpu_time.map(|seconds| seconds.to_string()),
+                &self.cpu_time_command,
+            ),
+        ];
+        for (value, template) in limits {
+            let Some(value) = value else { continue };
+            let applied = template.replace("{}", &value);
+            match template.trim_start().starts_with("ulimit") {
+                // a limit that can not be set is not worth running without it
+                true => statements.push_str(&format!("{} || exit 1; ", applied)),
+                false => prefixes.push(applied),
+            }
+        }
+        (statements, prefixes)
+    }
+}
+
+// the command run by `shell`, or as is, with the limits applied. the ulimits are set in the
+// shell that wrote the pid file, the exec keeps the pid
+pub fn wrap(limits: &Limits, shell: Option<&str>, command: &str) -> String {
+    let (statements, prefixes) = limits.split();
+    match (shell, prefixes.is_empty()) {
+        (None, true) => format!("{}{}", statements, command),
+        (Some(shell), true) => format!("{}exec {} {}", statements, shell, shell_quote(command)),
+        // a prefix runs a program, so the command needs a shell of its own to be one
+        (shell, false) => format!(
+            "{}exec {} {} {}",
+            statements,
+            prefixes.join(" "),
+            shell.unwrap_or("\"${SHELL:-sh}\" -c"),
+            shell_quote(command)
+        ),
+    }
+}
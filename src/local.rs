@@ -0,0 +1,213 @@
+use crate::error::{self, Context, CserunError};
+use crate::executor::Executor;
+use crate::ssh::{Config, Finished, RunDir};
+use crate::sync;
+use crate::terminal;
+use globset::GlobSet;
+use log::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how long a command may keep running after ctrl-c before it is killed, as on the server
+const INTERRUPT_GRACE: Duration = Duration::from_secs(3);
+
+// the local backend: the files are copied into a run directory under the system's temporary
+// directory and the command runs there, e.g. to try a config or the pipeline without a server
+pub struct Local<'a> {
+    conf: &'a Config,
+    dir: PathBuf,           // the run directory
+    pub container: PathBuf, // where the files are copied to, like the container dir on the server
+}
+
+impl<'a> Local<'a> {
+    pub fn new(conf: &'a Config) -> error::Result<Local<'a>> {
+        let dir = std::env::temp_dir()
+            .join("cserun")
+            .join(RunDir::new(conf).name);
+        let container = dir.join("container");
+        fs::create_dir_all(&container)
+            .map_err(|e| format!("{}: {}", container.display(), e))
+            .context(CserunError::Local)?;
+        Ok(Local {
+            conf,
+            dir,
+            container,
+        })
+    }
+
+    // of the run directory, e.g. to name things after the run
+    pub fn name(&self) -> &str {
+        self.dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+    }
+
+    fn workdir(&self) -> PathBuf {
+        match &self.conf.workdir {
+            Some(workdir) => self.container.join(workdir),
+            None => self.container.clone(),
+        }
+    }
+
+    // the command through the configured shell, or sh
+    fn command(&self, command: &str) -> Command {
+        let mut words = self
+            .conf
+            .shell
+            .as_deref()
+            .unwrap_or("sh -c")
+            .split_whitespace();
+        let mut process = Command::new(words.next().unwrap_or("sh"));
+        process.args(words).arg(command);
+        process
+            .current_dir(self.workdir())
+            .envs(self.conf.envs.iter().map(|(key, value)| (key, value)));
+        process
+    }
+}
+
+impl Executor for Local<'_> {
+    fn describe(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    fn sync(&mut self) -> error::Result<(usize, u64)> {
+        let local_dir = self.conf.upload_dir();
+        let files = sync::list_files(local_dir, &self.conf.walk)?;
+        let mut bytes = 0;
+        for (path, size) in &files {
+            let target = self
+                .container
+                .join(path.strip_prefix(local_dir).unwrap_or(path));
+            (|| {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(path, &target)
+            })()
+            .map_err(|e| format!("{}: {}", path.display(), e))
+            .context(CserunError::Transfer)?;
+            bytes += size;
+        }
+        sync::report_skipped(self.conf.walk.max_file_size);
+        Ok((files.len(), bytes))
+    }
+
+    fn run(&mut self, command: &str) -> error::Result<Finished> {
+        info!("Running `{}` in {}", command, self.workdir().display());
+        terminal::catch_interrupt();
+        let child = self
+            .command(command)
+            .spawn()
+            .map_err(|e| format!("Failed to start the command: {}", e))
+            .context(CserunError::Exec)?;
+        wait(self.conf, child, kill)
+    }
+
+    fn fetch(&mut self, patterns: &[String]) -> error::Result<usize> {
+        let patterns = sync::build_globset(patterns)?;
+        let mut matched = Vec::new();
+        find_matching(&self.container, &self.container, &patterns, &mut matched)
+            .context(CserunError::Transfer)?;
+        for path in &matched {
+            let relative = path.strip_prefix(&self.container).unwrap_or(path);
+            let target = self.conf.local_dir.join(relative);
+            (|| {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(path, &target)
+            })()
+            .map_err(|e| format!("{}: {}", target.display(), e))
+            .context(CserunError::Transfer)?;
+        }
+        Ok(matched.len())
+    }
+
+    fn cleanup(&mut self, keep: bool) -> error::Result<()> {
+        if keep {
+            info!("Kept the run directory {}", self.dir.display());
+            return Ok(());
+        }
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("Could not remove {}: {}", self.dir.display(), e);
+        }
+        Ok(())
+    }
+}
+
+// wait for a started command, stopping it with `stop` on --timeout or a while after ctrl-c
+pub fn wait(
+    conf: &Config,
+    mut child: Child,
+    mut stop: impl FnMut(&mut Child),
+) -> error::Result<Finished> {
+    let started = Instant::now();
+    let mut interrupted_at = None;
+    let mut timed_out = false;
+    let mut stopped = false;
+    let status = loop {
+        if let Some(status) = child.try_wait().context(CserunError::Exec)? {
+            break status;
+        }
+        // ctrl-c reached the command too, it gets a moment to exit on its own
+        if terminal::interrupted() && interrupted_at.is_none() {
+            interrupted_at = Some(Instant::now());
+        }
+        let grace_over = interrupted_at.is_some_and(|at| at.elapsed() >= INTERRUPT_GRACE);
+        let time_up = conf
+            .timeout
+            .is_some_and(|timeout| started.elapsed() >= timeout);
+        if (grace_over || time_up) && !stopped {
+            stopped = true;
+            timed_out = time_up;
+            stop(&mut child);
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    Ok(Finished {
+        exit_status: exit_code(status),
+        interrupted: interrupted_at.is_some(),
+        timed_out,
+    })
+}
+
+fn kill(child: &mut Child) {
+    if let Err(e) = child.kill() {
+        warn!("Could not stop the command: {}", e);
+    }
+}
+
+// the status a shell would report, 128 + the signal for a command killed by one
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(-1)
+}
+
+// every file under dir whose path relative to base matches one of the globs
+fn find_matching(
+    base: &Path,
+    dir: &Path,
+    patterns: &GlobSet,
+    matched: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_matching(base, &path, patterns, matched)?;
+        } else if patterns.is_match(path.strip_prefix(base).unwrap_or(&path)) {
+            matched.push(path);
+        }
+    }
+    Ok(())
+}
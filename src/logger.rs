@@ -0,0 +1,40 @@
+use console::style;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+// writes our own status messages to stderr, so stdout only carries the remote command's output
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // dependencies log through the same facade, only show ours
+        metadata.level() <= log::max_level() && metadata.target().starts_with("cserun")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("{} {}", style("error:").red().bold(), record.args()),
+            Level::Warn => eprintln!("{} {}", style("warning:").yellow().bold(), record.args()),
+            Level::Info => eprintln!("{}", record.args()),
+            Level::Debug | Level::Trace => eprintln!("{}", style(record.args()).dim()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// -q only shows errors, the default adds warnings, then -v for progress steps and -vv for details
+pub fn init(quiet: bool, verbose: u8) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    // only fails when a logger is already set
+    let _ = log::set_logger(&Logger);
+    log::set_max_level(level);
+}
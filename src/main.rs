@@ -1,63 +1,1443 @@
-mod parse;
-mod ssh;
-
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use console::style;
+#[cfg(unix)]
+use cserun::control;
+use cserun::error::{self, CserunError};
+use cserun::filter;
+use cserun::prefix::Prefix;
+use cserun::snapshot::Snapshot;
+use cserun::{
+    artifacts, completions, detach, encoding, events, executor, forward, history, init, logger,
+    matrix, parse, ssh, sync,
+};
+use log::{info, warn};
+use regex_automata::meta::Regex;
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// A simple tool to run commands on CSE server
 #[derive(Parser, Debug)]
-#[clap(version, about, long_about = None)]
-struct Args {
+#[clap(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    subcommand: Option<Commands>,
+
+    // `cserun <command>` is kept as a shorthand for `cserun run <command>`
+    #[clap(flatten)]
+    run: Option<RunArgs>,
+
+    /// Use a named profile from the config files
+    #[clap(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+
+    /// Use a server from the [hosts] section of the config instead of [server], repeat it to run on several at once
+    #[clap(long, value_name = "NAME", global = true, value_delimiter = ',')]
+    host: Vec<String>,
+
+    /// Connect to this port on every server address, overriding the config
+    #[clap(short = 'p', long, value_name = "PORT", global = true, value_parser = clap::value_parser!(u16).range(1..))]
+    port: Option<u16>,
+
+    /// Only print errors besides the remote command's output, and no progress bars
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Show connection and sync steps, repeat (-vv) to list every file
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print human readable output, or JSON lines events for scripts
+    #[clap(long, value_enum, value_name = "FORMAT", global = true, default_value_t = Output::Human)]
+    output: Output,
+
+    /// Also write the JSON lines events to this inherited file descriptor, e.g. for an editor extension (unix only)
+    #[clap(long, value_name = "N", global = true)]
+    events_fd: Option<i32>,
+
+    /// Also write the JSON lines events to this unix socket (unix only)
+    #[clap(long, value_name = "PATH", global = true, conflicts_with = "events_fd")]
+    events_socket: Option<PathBuf>,
+
+    /// Compress SSH traffic, helps with text heavy trees over slow links
+    #[clap(short = 'C', long, global = true)]
+    compress: bool,
+
+    /// Only connect over IPv4
+    #[clap(short = '4', long, global = true, conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only connect over IPv6
+    #[clap(short = '6', long, global = true)]
+    ipv6: bool,
+
+    /// Upload and fetch into this local directory instead of the current one
+    #[clap(long, value_name = "PATH", global = true)]
+    dir: Option<PathBuf>,
+
+    /// Prompt for the password or key passphrase instead of using the one in the config
+    #[clap(long, global = true)]
+    ask_pass: bool,
+
+    /// Show the path of config file
+    #[clap(long, conflicts_with = "RunArgs")]
+    config: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Output {
+    Human,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Color {
+    Never,
+    Auto,
+    Always,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Backend {
+    Ssh,
+    Local,
+    Docker,
+    Podman,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Sync the local directory and run a command on the cse server
+    Run(RunArgs),
+
+    /// Upload the local directory into its persistent workspace without running anything
+    Sync {
+        /// Number of parallel SFTP connections used to upload files
+        #[clap(long, value_name = "N")]
+        jobs: Option<usize>,
+
+        /// Cap uploads at this many bytes per second, e.g. 500k or 2M
+        #[clap(long, value_name = "RATE", value_parser = parse::parse_size)]
+        limit_rate: Option<u64>,
+
+        /// Only upload files matching the glob, can be repeated
+        #[clap(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// Do not upload files matching the glob, can be repeated
+        #[clap(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Do not upload files larger than this, e.g. 50M
+        #[clap(long, value_name = "SIZE", value_parser = parse::parse_size)]
+        max_file_size: Option<u64>,
+
+        /// Do not upload files that look binary, like compiled programs or core dumps
+        #[clap(long)]
+        skip_binaries: bool,
+
+        /// Also upload hidden files and directories, like .env or .clang-format
+        #[clap(long)]
+        hidden: bool,
+
+        /// Only upload the files git tracks, as a fresh clone would have them
+        #[clap(long)]
+        git_only: bool,
+
+        /// With --git-only, also upload untracked files that are not ignored
+        #[clap(long, requires = "git_only")]
+        git_untracked: bool,
+
+        /// Upload even if the server seems to be short of disk space or quota
+        #[clap(long)]
+        force: bool,
+
+        /// Compare the sha256 of every file on the server with the local one after uploading
+        #[clap(long)]
+        verify: bool,
+
+        /// Remove files from the workspace that were deleted or renamed locally since the last sync
+        #[clap(long)]
+        delete: bool,
+
+        /// Do not draw progress bars, e.g. for logs, -vv lists every file instead
+        #[clap(long)]
+        no_progress: bool,
+
+        /// Keep the connection and upload local changes as they happen, until ctrl-c
+        #[clap(long)]
+        watch: bool,
+
+        /// How long the files must stay unchanged before acting, and how often they are checked without inotify, e.g. 1s
+        #[clap(long, value_name = "DURATION", default_value = "1s", value_parser = parse::parse_duration, requires = "watch")]
+        interval: Duration,
+
+        /// With --watch, also download the files that change in the workspace on the server
+        #[clap(long, requires = "watch")]
+        sync_back: bool,
+    },
+
+    /// Download files matching the globs from the workspace
+    Fetch {
+        /// Globs of the files to download, relative to the workspace
+        #[clap(value_name = "GLOB", required = true)]
+        patterns: Vec<String>,
+
+        /// Do not draw progress bars, e.g. for logs, -vv lists every file instead
+        #[clap(long)]
+        no_progress: bool,
+    },
+
+    /// Remove run directories from the server, by default the ones kept with --keep
+    Clean {
+        /// Remove every run directory older than this, e.g. 7d or 12h, instead of the kept ones
+        #[clap(long, value_name = "DURATION", value_parser = parse::parse_duration)]
+        older_than: Option<Duration>,
+
+        /// Remove every run directory, not only the kept ones
+        #[clap(long, conflicts_with = "older_than")]
+        all: bool,
+
+        /// Also remove the workspace of the local directory
+        #[clap(long)]
+        workspace: bool,
+
+        /// Only list what would be removed
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// List the past runs, or the run directories and workspaces on the cse server
+    List {
+        /// List what is left on the server instead
+        #[clap(long)]
+        remote: bool,
+
+        /// How many of the latest runs to show
+        #[clap(short = 'n', long, value_name = "N", default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Run a command from the history again, in its run directory if that was kept
+    Rerun {
+        /// The id shown by `cserun list`, or "last"
+        #[clap(value_name = "ID")]
+        run: String,
+
+        /// Upload the local directory again into a new run directory
+        #[clap(long)]
+        resync: bool,
+
+        /// Do not draw progress bars, e.g. for logs, -vv lists every file instead
+        #[clap(long)]
+        no_progress: bool,
+    },
+
+    /// Follow the output of a run started with --detach until it finishes
+    Attach {
+        /// The id printed by --detach and shown by `cserun list`, or "last"
+        #[clap(value_name = "ID", default_value = "last")]
+        run: String,
+    },
+
+    /// Print the saved stdout and stderr of an earlier run
+    Output {
+        /// The id shown by `cserun list`, or "last"
+        #[clap(value_name = "ID", default_value = "last")]
+        run: String,
+    },
+
+    /// Show whether a run started with --detach is still going, or how it ended
+    Status {
+        /// The id printed by --detach and shown by `cserun list`, or "last"
+        #[clap(value_name = "ID", default_value = "last")]
+        run: String,
+    },
+
+    /// Sync the workspace and open an interactive shell in it (unix only)
+    Shell {
+        /// Open the shell without uploading the local directory first
+        #[clap(long)]
+        no_sync: bool,
+
+        /// Do not draw progress bars, e.g. for logs, -vv lists every file instead
+        #[clap(long, conflicts_with = "no_sync")]
+        no_progress: bool,
+    },
+
+    /// Keep the password or key passphrase in the system keyring, on macOS, Linux and the BSDs (not Windows)
+    Auth {
+        #[clap(subcommand)]
+        action: AuthAction,
+    },
+
+    /// Manage the background process keeping the connection open, see control_persist (unix only)
+    Daemon {
+        #[clap(subcommand)]
+        action: DaemonAction,
+    },
+
+    /// Set up the config file by answering a few questions
+    Init {
+        /// Overwrite an existing config file without asking
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Run a command in the workspace, and again whenever local files change
+    Watch {
+        /// How long the files must stay unchanged before acting, and how often they are checked without inotify, e.g. 1s
+        #[clap(long, value_name = "DURATION", default_value = "1s", value_parser = parse::parse_duration)]
+        interval: Duration,
+
+        #[clap(flatten)]
+        run: RunArgs,
+    },
+
+    /// Print a completion script for the shell, with the profiles, hosts, templates and runs completed as well
+    Completions {
+        #[clap(value_enum)]
+        shell: completions::Shell,
+    },
+
+    /// The profiles, hosts, templates or run ids for the completion scripts, one per line
+    #[clap(name = "__complete", hide = true)]
+    Complete {
+        #[clap(value_enum)]
+        kind: completions::Kind,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuthAction {
+    /// Ask for the secret of the configured authentication and store it
+    Store,
+
+    /// Remove the stored secret again
+    Forget,
+}
+
+#[derive(Subcommand, Debug)]
+enum DaemonAction {
+    /// Close the kept connection and stop the daemon
+    Stop,
+}
+
+#[derive(Args, Clone, Debug)]
+struct RunArgs {
     /// Do not sync files before running the command
-    #[clap(long, requires = "command")]
+    #[clap(long)]
     no_sync: bool,
 
-    /// Set environment variables, in the format of KEY:VALUE
-    #[clap(long, value_name = "KEY:VALUE", value_parser = parse_env, requires = "command")]
-    env: Vec<String>,
+    /// Show the files that would be uploaded and the command that would run, without connecting
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Request a pseudo-terminal, for interactive programs like vim or ncurses menus (unix only)
+    #[clap(long)]
+    pty: bool,
+
+    /// Start the command in a tmux session (or under nohup) on the server and return right away
+    #[clap(
+        long,
+        conflicts_with_all = ["pty", "matrix", "each", "fetch", "sync_back", "forward", "timeout", "prefix", "grep", "highlight", "log_file"]
+    )]
+    detach: bool,
+
+    /// Print the output in whole lines tagged [stdout] and [stderr], or [LABEL] with --prefix=LABEL
+    #[clap(
+        long,
+        value_name = "LABEL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ""
+    )]
+    prefix: Option<String>,
+
+    /// With --prefix, start every line with the time it arrived
+    #[clap(long, requires = "prefix")]
+    prefix_timestamps: bool,
+
+    /// Only show output lines matching the regular expression, can be repeated
+    #[clap(long, value_name = "PATTERN", value_parser = filter::parse_pattern)]
+    grep: Vec<Regex>,
+
+    /// Color what the regular expression matches in the output, can be repeated
+    #[clap(long, value_name = "PATTERN", value_parser = filter::parse_pattern)]
+    highlight: Vec<Regex>,
+
+    /// With --grep, also show this many lines before and after each matching line
+    #[clap(long, value_name = "N")]
+    context: Option<usize>,
+
+    /// Tell remote programs to print colors, auto only does when stdout is a terminal
+    #[clap(long, value_name = "WHEN")]
+    color: Option<Color>,
+
+    /// Transcode the command's output from this encoding for display: latin1, windows-1252, gbk,
+    /// gb18030, gb2312, big5, shift_jis, euc-jp or euc-kr. The multibyte ones need the system's
+    /// iconv, so they are refused on Windows
+    #[clap(long, visible_alias = "remote-encoding", value_name = "ENCODING")]
+    encoding: Option<encoding::Encoding>,
+
+    /// Do not load the login profile when a shell is set in the config
+    #[clap(long)]
+    no_login: bool,
+
+    /// Run the command in this subdirectory of the uploaded tree, e.g. ass2/part1
+    #[clap(long, value_name = "PATH")]
+    workdir: Option<PathBuf>,
+
+    /// Always exit with 0 instead of the remote command's exit status
+    #[clap(long)]
+    ignore_exit_status: bool,
+
+    /// Exit with 0 if the remote command's exit status is one of these, e.g. 1, 0,2 or 1-3, and
+    /// with 1 otherwise
+    #[clap(long, value_name = "STATUSES", value_parser = parse_expect_exit, conflicts_with = "ignore_exit_status")]
+    expect_exit: Option<ExpectExit>,
+
+    /// Download remote files matching the glob back after the command finishes
+    #[clap(long, value_name = "GLOB")]
+    fetch: Vec<String>,
+
+    /// Also write the command's output with timestamps into this file
+    #[clap(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Download files the command created or changed without asking first
+    #[clap(long)]
+    sync_back: bool,
+
+    /// Reuse a persistent remote workspace for this project, only uploading new or changed files
+    #[clap(long, alias = "incremental")]
+    workspace: bool,
+
+    /// Remove files from the workspace that were deleted or renamed locally since the last sync
+    #[clap(long, conflicts_with = "no_sync")]
+    delete: bool,
+
+    /// Number of parallel SFTP connections used to upload files
+    #[clap(long, value_name = "N", conflicts_with = "no_sync")]
+    jobs: Option<usize>,
+
+    /// Cap uploads at this many bytes per second, e.g. 500k or 2M
+    #[clap(long, value_name = "RATE", value_parser = parse::parse_size, conflicts_with = "no_sync")]
+    limit_rate: Option<u64>,
+
+    /// Only upload files matching the glob, can be repeated
+    #[clap(long, value_name = "GLOB", conflicts_with = "no_sync")]
+    include: Vec<String>,
+
+    /// Do not upload files matching the glob, can be repeated
+    #[clap(long, value_name = "GLOB", conflicts_with = "no_sync")]
+    exclude: Vec<String>,
+
+    /// Only upload the files git tracks, as a fresh clone would have them
+    #[clap(long, conflicts_with = "no_sync")]
+    git_only: bool,
+
+    /// With --git-only, also upload untracked files that are not ignored
+    #[clap(long, requires = "git_only")]
+    git_untracked: bool,
+
+    /// Upload the files of this git commit, branch or tag instead of the working directory
+    #[clap(long, value_name = "REV", conflicts_with_all = ["no_sync", "git_only"])]
+    rev: Option<String>,
+
+    /// Only upload this file or directory instead of the whole directory, can be repeated
+    #[clap(long, value_name = "PATH", conflicts_with = "no_sync")]
+    path: Vec<PathBuf>,
+
+    /// Do not upload files larger than this, e.g. 50M
+    #[clap(long, value_name = "SIZE", value_parser = parse::parse_size, conflicts_with = "no_sync")]
+    max_file_size: Option<u64>,
+
+    /// Do not upload files that look binary, like compiled programs or core dumps
+    #[clap(long, conflicts_with = "no_sync")]
+    skip_binaries: bool,
+
+    /// Also upload hidden files and directories, like .env or .clang-format
+    #[clap(long, conflicts_with = "no_sync")]
+    hidden: bool,
+
+    /// Do not draw progress bars, e.g. for logs, -vv lists every file instead
+    #[clap(long)]
+    no_progress: bool,
 
-    /// The command to run on the cse server
+    /// Upload even if the server seems to be short of disk space or quota
+    #[clap(long)]
+    force: bool,
+
+    /// Compare the sha256 of every file on the server with the local one before running
+    #[clap(long, conflicts_with = "no_sync")]
+    verify: bool,
+
+    /// Show a desktop notification with the command, its exit status and how long it took
+    #[clap(long)]
+    notify: bool,
+
+    /// Ring the terminal bell when the command is done
+    #[clap(long)]
+    bell: bool,
+
+    /// Keep the remote run directory instead of deleting it afterwards
+    #[clap(long)]
+    keep: bool,
+
+    /// Where to run the command: on the server, in a temporary directory on this machine, or in a container
+    #[clap(long, value_name = "BACKEND")]
+    backend: Option<Backend>,
+
+    /// The container image for the docker and podman backends, e.g. gcc:13
+    #[clap(long, value_name = "IMAGE")]
+    image: Option<String>,
+
+    /// Run the command on this machine, reached with ssh from the server, which shares the home directory
+    #[clap(long, value_name = "HOST")]
+    target_host: Option<String>,
+
+    /// Submit the command as a SLURM batch job with sbatch and follow its output
+    #[clap(long)]
+    slurm: bool,
+
+    /// The SLURM partition to submit to, implies --slurm
+    #[clap(long, value_name = "PARTITION")]
+    partition: Option<String>,
+
+    /// The SLURM time limit, as sbatch --time takes it, e.g. 10:00, implies --slurm
+    #[clap(long, value_name = "TIME")]
+    slurm_time: Option<String>,
+
+    /// The memory the SLURM job asks for, e.g. 4G, implies --slurm
+    #[clap(long, value_name = "SIZE")]
+    mem: Option<String>,
+
+    /// The CPUs the SLURM job asks for, implies --slurm
+    #[clap(long, value_name = "N")]
+    cpus: Option<u32>,
+
+    /// Stop the remote command if it runs longer than this, e.g. 90s, 5m or 1h
+    #[clap(long, value_name = "DURATION", value_parser = parse::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Run the remote command with a lower priority, as nice -n takes it, e.g. 10
+    #[clap(long, value_name = "N", allow_negative_numbers = true)]
+    nice: Option<i32>,
+
+    /// Cap the memory of the remote command, e.g. 2G
+    #[clap(long, value_name = "SIZE", value_parser = parse::parse_size)]
+    mem_limit: Option<u64>,
+
+    /// Cap the cpu time of the remote command, e.g. 300 or 5m
+    #[clap(long, value_name = "DURATION", value_parser = parse::parse_duration)]
+    cpu_time: Option<Duration>,
+
+    /// How many times to reconnect after a network error before the command has started
+    #[clap(long, value_name = "N")]
+    retries: Option<u32>,
+
+    /// Print how long connecting, authenticating, syncing and the command took
+    #[clap(long)]
+    timings: bool,
+
+    /// Set environment variables for the remote command, in the format of KEY=VALUE
+    #[clap(long, value_name = "KEY=VALUE", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+
+    /// Forward a local port to the server while the command runs, as [BIND:]PORT:HOST:HOSTPORT or PORT
+    #[clap(long, value_name = "FORWARD")]
+    forward: Vec<forward::Forward>,
+
+    /// Show the windows of X11 programs on the local X server, like ssh -X
+    #[clap(long)]
+    x11: bool,
+
+    /// Let ssh and git in the command use the keys of the local ssh agent, like ssh -A (unix only)
+    #[clap(short = 'A', long)]
+    forward_agent: bool,
+
+    /// Run several commands one after another in the same directory, stopping at the first failure
+    #[clap(short = 'c', long = "command", value_name = "COMMAND")]
+    commands: Vec<String>,
+
+    /// Upload a local script and run it instead of a command
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["command", "commands"])]
+    script: Option<PathBuf>,
+
+    /// Run every command even if one fails, exiting with the status of the last failure
+    #[clap(long)]
+    keep_going: bool,
+
+    /// Run the commands in the file, one per line, side by side and print a summary table
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["command", "commands", "script", "each"])]
+    matrix: Option<PathBuf>,
+
+    /// Run the command once per value side by side, the value quoted in place of {} or at the end
+    #[clap(
+        long,
+        value_name = "ARG",
+        value_delimiter = ',',
+        requires = "command",
+        conflicts_with_all = ["commands", "script"]
+    )]
+    each: Vec<String>,
+
+    /// How many --matrix or --each commands run at once, 4 by default
+    #[clap(long, value_name = "N")]
+    parallel: Option<usize>,
+
+    /// The command to run on the cse server, or the name of a template from the config
     command: Option<String>,
 
-    /// Show the path of config file
-    #[clap(long, conflicts_with_all = &["no_sync", "env", "command"])]
-    config: bool,
+    /// Values for the template's placeholders, in order
+    #[clap(value_name = "ARGS")]
+    template_args: Vec<String>,
+
+    /// The command as separate words after --, each one quoted for the remote shell
+    #[clap(last = true, value_name = "ARGV", conflicts_with_all = ["command", "script"])]
+    argv: Vec<String>,
 }
 
-fn parse_env(s: &str) -> Result<String, String> {
-    if s.contains(':') && s.split(':').count() == 2 && !s.starts_with(':') && !s.ends_with(':') {
-        Ok(s.to_string())
-    } else {
-        Err("Environment variable must be in KEY:VALUE format".to_string())
+// KEY=VALUE, or the older KEY:VALUE form
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    let split = match s.find('=') {
+        Some(index) => Some(index),
+        None => s.find(':'),
+    };
+    match split {
+        Some(index) if parse::is_env_name(&s[..index]) => {
+            Ok((s[..index].to_string(), s[index + 1..].to_string()))
+        }
+        _ => Err("Environment variable must be in KEY=VALUE format".to_string()),
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    if args.config {
+// the exit statuses --expect-exit accepts, as written and as inclusive ranges
+#[derive(Clone, Debug)]
+struct ExpectExit(String, Vec<(i32, i32)>);
+
+impl ExpectExit {
+    fn matches(&self, status: i32) -> bool {
+        self.1
+            .iter()
+            .any(|&(low, high)| (low..=high).contains(&status))
+    }
+}
+
+fn parse_expect_exit(s: &str) -> Result<ExpectExit, String> {
+    let invalid = || format!("{:?} is not a list of exit statuses like 1, 0,2 or 1-3", s);
+    let mut ranges = Vec::new();
+    for part in s.split(',').map(str::trim) {
+        let (low, high) = part.split_once('-').unwrap_or((part, part));
+        let low: i32 = low.trim().parse().map_err(|_| invalid())?;
+        let high: i32 = high.trim().parse().map_err(|_| invalid())?;
+        if low > high {
+            return Err(invalid());
+        }
+        ranges.push((low, high));
+    }
+    Ok(ExpectExit(s.to_string(), ranges))
+}
+
+// `watch` is the interval to check for changes at, the command then runs again until ctrl-c
+fn run(mut conf: ssh::Config, args: RunArgs, watch: Option<Duration>) -> error::Result<i32> {
+    // a template name expands to its command, filled with the arguments after it
+    let command = match args.command {
+        Some(name) if conf.templates.contains_key(&name) => Some(parse::expand_template(
+            &name,
+            &conf.templates[&name],
+            &args.template_args,
+        )?),
+        command if !args.template_args.is_empty() => {
+            return Err(CserunError::Config(
+                format!(
+                    "There is no template named {:?}, quote a command with arguments",
+                    command.unwrap_or_default()
+                )
+                .into(),
+            ))
+        }
+        // after -- every word is passed on as it is, quotes and spaces included
+        None if !args.argv.is_empty() => Some(ssh::join_args(&args.argv)),
+        command => command,
+    };
+    conf.matrix = match (&args.matrix, args.each.is_empty()) {
+        (Some(path), _) => matrix::read_file(path)?,
+        (None, false) => matrix::expand(command.as_deref().unwrap_or_default(), &args.each),
+        (None, true) => Vec::new(),
+    };
+    if let Some(parallel) = args.parallel {
+        conf.parallel = parallel;
+    }
+    // the matrix commands one after another is what the history shows and rerun runs
+    let commands: Vec<String> = match conf.matrix.is_empty() {
+        true => command.into_iter().chain(args.commands).collect(),
+        false => conf.matrix.clone(),
+    };
+    conf.command = ssh::chain_commands(&commands, args.keep_going || !conf.matrix.is_empty());
+    conf.script = args.script;
+    conf.workdir = args.workdir;
+    if args.no_login {
+        conf.shell = conf.shell.as_deref().map(ssh::without_login);
+    }
+    // variables from the command line override the ones from the config
+    for (key, value) in args.env {
+        conf.envs.retain(|(existing, _)| *existing != key);
+        conf.envs.push((key, value));
+    }
+    conf.no_sync = args.no_sync;
+    conf.pty |= args.pty;
+    conf.detach = args.detach;
+    // a detached command has no terminal, whatever the config asks for
+    conf.pty &= !conf.detach;
+    if let Some(color) = args.color {
+        conf.color = match color {
+            Color::Never => ssh::Color::Never,
+            Color::Auto => ssh::Color::Auto,
+            Color::Always => ssh::Color::Always,
+        };
+    }
+    if let Some(encoding) = args.encoding {
+        conf.encoding = encoding;
+    }
+    conf.progress = progress(args.no_progress);
+    conf.fetch.extend(args.fetch);
+    if args.log_file.is_some() {
+        conf.log_file = args.log_file;
+    }
+    if args.sync_back {
+        conf.sync_back = ssh::SyncBack::Always;
+    }
+    conf.forwards = args.forward;
+    conf.forward_agent = args.forward_agent;
+    conf.workspace |= args.workspace;
+    conf.delete |= args.delete;
+    conf.keep |= args.keep;
+    conf.notify |= args.notify;
+    conf.bell |= args.bell;
+    conf.force = args.force;
+    conf.verify = args.verify;
+    conf.timings = args.timings;
+    if let Some(jobs) = args.jobs {
+        conf.jobs = jobs;
+    }
+    if args.limit_rate.is_some() {
+        conf.limit_rate = args.limit_rate;
+    }
+    conf.walk.include = args.include;
+    conf.walk.exclude = args.exclude;
+    conf.walk.paths = args.path;
+    if args.max_file_size.is_some() {
+        conf.walk.max_file_size = args.max_file_size;
+    }
+    conf.walk.skip_binaries |= args.skip_binaries;
+    if args.hidden {
+        conf.walk.hidden = Some(true);
+    }
+    git_only(&mut conf, args.git_only, args.git_untracked);
+    if let Some(rev) = &args.rev {
+        if watch.is_some() {
+            return Err(CserunError::Config(
+                "--rev can not be used with watch".into(),
+            ));
+        }
+        conf.snapshot = Some(Snapshot::export(&conf.local_dir, rev)?);
+        // everything in the commit was meant to be there, .cserunignore and the config still apply
+        conf.walk.ignore_files.gitignore = false;
+        conf.walk.ignore_files.git_global = false;
+        conf.walk.ignore_files.git_exclude = false;
+    }
+    if let Some(retries) = args.retries {
+        conf.retries = retries;
+    }
+    if args.timeout.is_some() {
+        conf.timeout = args.timeout;
+    }
+    if conf.pty && events::enabled() {
+        return Err(CserunError::Config(
+            "--pty can not be combined with --output json".into(),
+        ));
+    }
+    if conf.pty && !conf.matrix.is_empty() {
+        return Err(CserunError::Config(
+            "--pty can not be used with --matrix or --each".into(),
+        ));
+    }
+    let slurm = &mut conf.slurm;
+    slurm.enabled |= args.slurm
+        || args.partition.is_some()
+        || args.slurm_time.is_some()
+        || args.mem.is_some()
+        || args.cpus.is_some();
+    slurm.partition = args.partition.or(slurm.partition.take());
+    slurm.time = args.slurm_time.or(slurm.time.take());
+    slurm.mem = args.mem.or(slurm.mem.take());
+    slurm.cpus = args.cpus.or(slurm.cpus);
+    if conf.slurm.enabled && !conf.matrix.is_empty() {
+        return Err(CserunError::Config(
+            "--slurm can not be used with --matrix or --each".into(),
+        ));
+    }
+    if args.target_host.is_some() {
+        conf.target_host = args.target_host;
+    }
+    if conf.target_host.is_some() && (conf.detach || conf.slurm.enabled) {
+        return Err(CserunError::Config(
+            "--target-host can not be used with --detach or --slurm".into(),
+        ));
+    }
+    conf.x11 = args.x11;
+    let x11_conflict =
+        conf.detach || conf.slurm.enabled || conf.target_host.is_some() || !conf.matrix.is_empty();
+    if conf.x11 && x11_conflict {
+        return Err(CserunError::Config(
+            "--x11 can not be used with --detach, --slurm, --target-host, --matrix or --each"
+                .into(),
+        ));
+    }
+    if conf.forward_agent && (conf.detach || conf.slurm.enabled || !conf.matrix.is_empty()) {
+        // the agent is only there while cserun waits for the command
+        return Err(CserunError::Config(
+            "--forward-agent can not be used with --detach, --slurm, --matrix or --each".into(),
+        ));
+    }
+    let limits = &mut conf.limits;
+    limits.nice = args.nice.or(limits.nice);
+    limits.mem_limit = args.mem_limit.or(limits.mem_limit);
+    limits.cpu_time = args.cpu_time.map(|time| time.as_secs()).or(limits.cpu_time);
+    if conf.slurm.enabled && conf.limits.any() {
+        // the job runs on a compute node with what it asked slurm for, not on the login node
+        if args.nice.is_some() || args.mem_limit.is_some() || args.cpu_time.is_some() {
+            return Err(CserunError::Config(
+                "--nice, --mem-limit and --cpu-time can not be used with --slurm, use --mem and --cpus"
+                    .into(),
+            ));
+        }
+        conf.limits = Default::default();
+    }
+    if !args.grep.is_empty() || !args.highlight.is_empty() {
+        // what a pty shows is drawn on the screen, not written in lines
+        if conf.pty {
+            return Err(CserunError::Config(
+                "--grep and --highlight can not be combined with --pty".into(),
+            ));
+        }
+        conf.filter.grep.extend(args.grep);
+        conf.filter.highlight.extend(args.highlight);
+    }
+    if let Some(context) = args.context {
+        conf.filter.context = context;
+    }
+    if let Some(label) = args.prefix {
+        // a pty merges the streams and the program draws on the screen itself
+        if conf.pty {
+            return Err(CserunError::Config(
+                "--prefix can not be combined with --pty".into(),
+            ));
+        }
+        conf.prefix = Some(Prefix {
+            label: Some(label).filter(|label| !label.is_empty()),
+            timestamps: args.prefix_timestamps,
+        });
+    }
+    if let Some(backend) = args.backend {
+        conf.backend = match backend {
+            Backend::Ssh => executor::Backend::Ssh,
+            Backend::Local => executor::Backend::Local,
+            Backend::Docker => executor::Backend::Docker,
+            Backend::Podman => executor::Backend::Podman,
+        };
+    }
+    if let Some(image) = args.image {
+        conf.image = Some(image);
+    }
+    if args.dry_run {
+        ssh::dry_run(conf)?;
+        return Ok(0);
+    }
+    let exit_code = match watch {
+        Some(interval) => {
+            if conf.no_sync {
+                return Err(CserunError::Config(
+                    "--no-sync can not be used with watch".into(),
+                ));
+            }
+            if conf.detach {
+                return Err(CserunError::Config(
+                    "--detach can not be used with watch".into(),
+                ));
+            }
+            if conf.backend != executor::Backend::Ssh {
+                return Err(CserunError::Config("watch needs the ssh backend".into()));
+            }
+            // only the workspace remembers what was uploaded last time
+            conf.workspace = true;
+            // downloaded files would count as local changes and start the next run
+            conf.sync_back = ssh::SyncBack::Never;
+            ssh::watch(&conf, interval)?
+        }
+        None => match conf.backend {
+            executor::Backend::Ssh => ssh::exec(&conf)?,
+            _ => executor::exec(&conf)?,
+        },
+    };
+    match (&args.expect_exit, args.ignore_exit_status) {
+        (Some(expected), _) if expected.matches(exit_code) => Ok(0),
+        (Some(expected), _) => {
+            log::error!(
+                "Expected exit status {}, the command exited with {}",
+                expected.0,
+                exit_code
+            );
+            Ok(1)
+        }
+        (None, true) => Ok(0),
+        (None, false) => Ok(exit_code),
+    }
+}
+
+// several --host: the same run on every server at once, each line of output tagged with its
+// host, then the exit status of each. the run fails if it failed on any of them
+fn run_hosts(hosts: Vec<(String, ssh::Config)>, args: RunArgs) -> error::Result<i32> {
+    let unsupported = [
+        (args.pty, "--pty"),
+        (args.detach, "--detach"),
+        (!args.forward.is_empty(), "--forward"),
+        (args.x11, "--x11"),
+        (args.forward_agent, "--forward-agent"),
+    ];
+    if let Some((_, what)) = unsupported.iter().find(|(used, _)| *used) {
+        return Err(CserunError::Config(
+            format!("{} can not be used with more than one --host", what).into(),
+        ));
+    }
+    let run_on = |host: &str, mut conf: ssh::Config| {
+        let mut args = args.clone();
+        args.prefix = Some(match args.prefix.as_deref() {
+            Some(label) if !label.is_empty() => format!("{} {}", host, label),
+            _ => host.to_string(),
+        });
+        // progress bars of several uploads would draw over each other
+        args.no_progress = true;
+        conf.pty = false;
+        // the runs would each get a random share of it
+        conf.stdin = false;
+        run(conf, args, None)
+    };
+    // what would happen on each, one after another so the listings stay apart
+    if args.dry_run {
+        for (host, conf) in hosts {
+            println!("{}", style(format!("[{}]", host)).bold());
+            run_on(&host, conf)?;
+        }
+        return Ok(0);
+    }
+    let results: Vec<(String, error::Result<i32>)> = std::thread::scope(|scope| {
+        let runs: Vec<_> = hosts
+            .into_iter()
+            .map(|(host, conf)| {
+                let run_on = &run_on;
+                let run = scope.spawn({
+                    let host = host.clone();
+                    move || run_on(&host, conf)
+                });
+                (host, run)
+            })
+            .collect();
+        // a run that panicked is a failed row, the others still get theirs
+        runs.into_iter()
+            .map(|(host, run)| {
+                let result = run
+                    .join()
+                    .unwrap_or_else(|_| Err(CserunError::Local("The run panicked".into())));
+                (host, result)
+            })
+            .collect()
+    });
+
+    let width = results
+        .iter()
+        .map(|(host, _)| host.len())
+        .max()
+        .unwrap_or(0);
+    let mut table = format!("{:width$}  Exit status\n", "Host", width = width.max(4));
+    let mut exit_code = 0;
+    for (host, result) in &results {
+        let status = match result {
+            Ok(0) => style("0".to_string()).green(),
+            Ok(code) => style(code.to_string()).red(),
+            Err(e) => style(format!("failed: {}", e.report())).red(),
+        };
+        let code = match result {
+            Ok(code) => *code,
+            Err(_) => 1,
+        };
+        events::emit(
+            "host_finished",
+            &[("host", host.as_str().into()), ("exit_status", code.into())],
+        );
+        // the first host that failed decides, as a single run would
+        if exit_code == 0 {
+            exit_code = code;
+        }
+        table.push_str(&format!(
+            "{:width$}  {}\n",
+            host,
+            status,
+            width = width.max(4)
+        ));
+    }
+    if !events::enabled() {
+        eprint!("{}", table);
+    }
+    Ok(exit_code)
+}
+
+// the command of an earlier run, from the directory it was started in
+fn rerun(mut conf: ssh::Config, run: &str, resync: bool, no_progress: bool) -> error::Result<i32> {
+    let record = history::find_run(&conf.server_addr, &conf.username, run)?;
+    match &record.script {
+        Some(script) => info!("Running {} again: {}", record.id, script.display()),
+        None => info!("Running {} again: {}", record.id, record.command),
+    }
+    conf.command = record.command;
+    conf.script = record.script;
+    conf.workdir = record.workdir;
+    conf.local_dir = record.local_dir;
+    if !resync {
+        conf.rerun_dir = Some(record.remote_dir);
+        conf.no_sync = true;
+    }
+    conf.progress = progress(no_progress);
+    ssh::exec(&conf)
+}
+
+// progress bars only make sense on a terminal, and -q or json output hides them too
+fn progress(no_progress: bool) -> bool {
+    !no_progress
+        && !events::enabled()
+        && log::max_level() > log::LevelFilter::Error
+        && console::Term::stderr().is_term()
+}
+
+// checked before anything runs, and before a run is handed to the control daemon
+fn check_command(args: &RunArgs) {
+    if args.command.is_none()
+        && args.commands.is_empty()
+        && args.script.is_none()
+        && args.matrix.is_none()
+        && args.argv.is_empty()
+    {
+        use clap::CommandFactory;
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "a <COMMAND>, -- <ARGV>..., -c <COMMAND>, --script <FILE> or --matrix <FILE> is required",
+            )
+            .exit();
+    }
+}
+
+// --git-only, with a warning that changed tracked files still go up as they are on disk
+fn git_only(conf: &mut ssh::Config, git_only: bool, untracked: bool) {
+    if !git_only {
+        return;
+    }
+    conf.walk.git_only = Some(match untracked {
+        true => ssh::GitFiles::Untracked,
+        false => ssh::GitFiles::Tracked,
+    });
+    let changed = sync::uncommitted_changes(&conf.local_dir);
+    if !changed.is_empty() {
+        warn!(
+            "{} tracked file(s) have uncommitted changes and are uploaded as they are: {}",
+            changed.len(),
+            changed.join(", ")
+        );
+    }
+}
+
+// whether the subcommand uploads from or downloads into the local directory. a rerun goes
+// back to the directory of the earlier run
+fn uses_local_dir(subcommand: &Commands) -> bool {
+    match subcommand {
+        Commands::Run(_) | Commands::Watch { .. } | Commands::Shell { .. } => true,
+        Commands::Sync { .. } | Commands::Fetch { .. } => true,
+        Commands::Clean { workspace, .. } => *workspace,
+        Commands::List { .. } | Commands::Rerun { .. } => false,
+        Commands::Attach { .. } | Commands::Status { .. } | Commands::Output { .. } => false,
+        Commands::Auth { .. } | Commands::Daemon { .. } | Commands::Init { .. } => false,
+        Commands::Completions { .. } | Commands::Complete { .. } => false,
+    }
+}
+
+// whether the control daemon can run this, it has no terminal of its own for a pty or prompts
+#[cfg(unix)]
+fn forwardable(subcommand: &Commands) -> bool {
+    match subcommand {
+        Commands::Run(args) | Commands::Watch { run: args, .. } => !args.pty && !args.dry_run,
+        Commands::Sync { .. } | Commands::Fetch { .. } | Commands::Clean { .. } => true,
+        Commands::List { .. } | Commands::Rerun { .. } => true,
+        Commands::Attach { .. } | Commands::Status { .. } | Commands::Output { .. } => true,
+        Commands::Shell { .. } | Commands::Auth { .. } | Commands::Daemon { .. } => false,
+        Commands::Init { .. } => false,
+        Commands::Completions { .. } | Commands::Complete { .. } => false,
+    }
+}
+
+// one invocation in the control daemon, with the arguments of the cserun it stands in for
+#[cfg(unix)]
+fn serve(args: Vec<std::ffi::OsString>) -> i32 {
+    let cli = match Cli::try_parse_from(args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            return e.exit_code();
+        }
+    };
+    match dispatch(cli) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("Error: {:?}", error::Report(e.as_ref()).to_string());
+            1
+        }
+    }
+}
+
+#[cfg(unix)]
+fn stop_daemon(conf: &ssh::Config) -> error::Result<()> {
+    match control::stop(conf)? {
+        true => println!("Stopped the control daemon for {}", conf.server_addr),
+        false => println!("No control daemon is running for {}", conf.server_addr),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn stop_daemon(_conf: &ssh::Config) -> error::Result<()> {
+    Err(CserunError::Config(
+        "The control daemon is only supported on unix".into(),
+    ))
+}
+
+fn main() {
+    let exit_code = match dispatch(Cli::parse()) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("Error: {:?}", error::Report(e.as_ref()).to_string());
+            1
+        }
+    };
+    // with control_persist the process that did the run stays on as the daemon
+    #[cfg(unix)]
+    control::serve_after(exit_code, serve);
+    std::process::exit(exit_code);
+}
+
+fn dispatch(cli: Cli) -> Result<i32, Box<dyn std::error::Error>> {
+    logger::init(cli.quiet, cli.verbose);
+    if cli.output == Output::Json {
+        events::enable();
+    }
+    if let Some(fd) = cli.events_fd {
+        events::open_fd(fd).map_err(|e| format!("Error: --events-fd {}: {}", fd, e))?;
+    }
+    if let Some(path) = &cli.events_socket {
+        events::connect_socket(path)
+            .map_err(|e| format!("Error: --events-socket {}: {}", path.display(), e))?;
+    }
+    if cli.config {
         let config_path = parse::get_config_path()?;
         println!("Config file path: {}", config_path.display());
-        return Ok(());
+        if let Some(project_path) = parse::find_project_config() {
+            println!("Project config file path: {}", project_path.display());
+        }
+        return Ok(0);
     }
 
-    let command_to_exec = args.command.unwrap();
+    let subcommand = match (cli.subcommand, cli.run) {
+        (Some(subcommand), _) => subcommand,
+        (None, Some(run)) => Commands::Run(run),
+        (None, None) => {
+            use clap::CommandFactory;
+            Cli::command().print_help()?;
+            return Ok(0);
+        }
+    };
 
-    let mut conf = parse::get_ssh_config();
-    conf.command.push_str(command_to_exec.as_str());
-    conf.envs = args.env;
-    conf.no_sync = args.no_sync;
-    match ssh::exec(conf) {
-        Ok(exit_code) => {
-            std::process::exit(exit_code);
+    // there is no config to read yet
+    if let Commands::Init { force } = subcommand {
+        return init::wizard(force).map_err(|e| format!("Error: {}", e.report()).into());
+    }
+    // nor does completing need one that is complete
+    match subcommand {
+        Commands::Completions { shell } => {
+            use clap::CommandFactory;
+            print!("{}", completions::script(&mut Cli::command(), shell));
+            return Ok(0);
         }
+        Commands::Complete { kind } => {
+            for name in completions::candidates(kind) {
+                println!("{}", name);
+            }
+            return Ok(0);
+        }
+        _ => {}
+    }
+
+    if cli.host.len() > 1 && !matches!(subcommand, Commands::Run(_)) {
+        return Err("Error: Only `run` can use more than one --host".into());
+    }
+    let configure = |host: Option<&str>| {
+        let mut conf = parse::get_ssh_config(cli.profile.as_deref(), host)?;
+        conf.compress |= cli.compress;
+        if let Some(port) = cli.port {
+            conf.server_addr = ssh::replace_port(&conf.server_addr, port);
+            for addr in &mut conf.fallback_addrs {
+                *addr = ssh::replace_port(addr, port);
+            }
+        }
+        if cli.ipv4 {
+            conf.address_family = ssh::AddressFamily::Ipv4;
+        } else if cli.ipv6 {
+            conf.address_family = ssh::AddressFamily::Ipv6;
+        }
+        if cli.ask_pass {
+            for auth in &mut conf.auth {
+                match auth {
+                    ssh::Auth::Password(password) => *password = None,
+                    ssh::Auth::AuthKey(key) => key.passphrase = None,
+                    ssh::Auth::Agent(_) | ssh::Auth::KeyboardInteractive => {}
+                }
+            }
+        }
+        if let Some(dir) = &cli.dir {
+            conf.local_dir = dir.clone();
+        }
+        Ok::<_, String>(conf)
+    };
+    // an invalid config prints its message and fails the run, without ending a control daemon
+    let conf = match configure(cli.host.first().map(String::as_str)) {
+        Ok(conf) => conf,
         Err(e) => {
+            eprintln!("{}", e);
+            return Ok(1);
+        }
+    };
+    if uses_local_dir(&subcommand) && !conf.local_dir.is_dir() {
+        return Err(format!(
+            "Error: Local directory {} does not exist or is not a directory",
+            conf.local_dir.display()
+        )
+        .into());
+    }
+    if let Commands::Run(args) | Commands::Watch { run: args, .. } = &subcommand {
+        check_command(args);
+    }
+    if let (Commands::Run(args), true) = (&subcommand, cli.host.len() > 1) {
+        let hosts = cli
+            .host
+            .iter()
+            .map(|host| Ok((host.clone(), configure(Some(host))?)))
+            .collect::<Result<_, String>>();
+        let hosts = match hosts {
+            Ok(hosts) => hosts,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Ok(1);
+            }
+        };
+        return run_hosts(hosts, args.clone()).map_err(|e| format!("Error: {}", e.report()).into());
+    }
+    let mut conf = conf;
+    #[cfg(unix)]
+    if conf.control_persist.is_some()
+        && cli.output == Output::Human
+        && !cli.ask_pass
+        && !conf.pty
+        && forwardable(&subcommand)
+    {
+        if let Some(exit_code) = control::forward(&conf)? {
+            return Ok(exit_code);
+        }
+    }
+    // forked off before the run, it serves once the run is over with the connection it opened
+    #[cfg(unix)]
+    if let Some(daemon) = control::Daemon::for_config(&conf) {
+        daemon.start();
+    }
+    #[cfg(not(unix))]
+    if conf.control_persist.is_some() {
+        warn!("control_persist is only supported on unix, every run connects on its own");
+    }
+
+    let result = match subcommand {
+        Commands::Run(args) => run(conf, args, None),
+        Commands::Sync {
+            jobs,
+            limit_rate,
+            include,
+            exclude,
+            max_file_size,
+            skip_binaries,
+            hidden,
+            git_only: only_git,
+            git_untracked,
+            force,
+            verify,
+            delete,
+            no_progress,
+            watch,
+            interval,
+            sync_back,
+        } => {
+            // syncing on its own only makes sense into the persistent workspace
+            conf.workspace = true;
+            conf.progress = progress(no_progress);
+            if let Some(jobs) = jobs {
+                conf.jobs = jobs;
+            }
+            if limit_rate.is_some() {
+                conf.limit_rate = limit_rate;
+            }
+            conf.walk.include = include;
+            conf.walk.exclude = exclude;
+            if max_file_size.is_some() {
+                conf.walk.max_file_size = max_file_size;
+            }
+            conf.walk.skip_binaries |= skip_binaries;
+            if hidden {
+                conf.walk.hidden = Some(true);
+            }
+            git_only(&mut conf, only_git, git_untracked);
+            conf.force = force;
+            conf.verify = verify;
+            // a mirror also loses the files removed locally
+            conf.delete |= delete || watch;
+            match watch {
+                true => ssh::watch_workspace(conf, interval, sync_back),
+                false => ssh::sync_workspace(conf),
+            }
+            .map(|_| 0)
+        }
+        Commands::Fetch {
+            patterns,
+            no_progress,
+        } => {
+            conf.fetch = patterns;
+            conf.progress = progress(no_progress);
+            ssh::fetch_workspace(conf).map(|_| 0)
+        }
+        Commands::Clean {
+            older_than,
+            all,
+            workspace,
+            dry_run,
+        } => ssh::clean(
+            conf,
+            ssh::CleanOptions {
+                older_than,
+                all,
+                workspace,
+                dry_run,
+            },
+        )
+        .map(|_| 0),
+        Commands::List { remote: true, .. } => ssh::list(conf).map(|_| 0),
+        Commands::List { limit, .. } => ssh::list_history(&conf, limit).map(|_| 0),
+        Commands::Rerun {
+            run,
+            resync,
+            no_progress,
+        } => rerun(conf, &run, resync, no_progress),
+        Commands::Attach { run } => detach::attach(&conf, &run),
+        Commands::Status { run } => detach::status(&conf, &run),
+        Commands::Output { run } => {
+            let record = history::find_run(&conf.server_addr, &conf.username, &run)?;
+            artifacts::show(&record).map(|_| 0)
+        }
+        Commands::Shell {
+            no_sync,
+            no_progress,
+        } => {
+            conf.workspace = true;
+            conf.progress = progress(no_progress);
+            ssh::shell(conf, !no_sync)
+        }
+        Commands::Auth { action } => match action {
+            AuthAction::Store => ssh::store_secret(&conf),
+            AuthAction::Forget => ssh::forget_secret(&conf),
+        }
+        .map(|_| 0),
+        Commands::Daemon {
+            action: DaemonAction::Stop,
+        } => stop_daemon(&conf).map(|_| 0),
+        Commands::Watch {
+            interval,
+            run: args,
+        } => run(conf, args, Some(interval)),
+        Commands::Init { .. } | Commands::Completions { .. } | Commands::Complete { .. } => {
+            unreachable!("handled before the config is read")
+        }
+    };
+    match result {
+        Ok(exit_code) => Ok(exit_code),
+        Err(e) => {
+            events::emit(
+                "error",
+                &[
+                    ("kind", e.kind().into()),
+                    ("message", e.report().to_string().as_str().into()),
+                ],
+            );
+            // only settings can cause these, otherwise the config is not worth a look
+            if !matches!(
+                e,
+                CserunError::Config(_)
+                    | CserunError::Connect(_)
+                    | CserunError::Handshake(_)
+                    | CserunError::Auth(_)
+            ) {
+                return Err(format!("Error: {}", e.report()).into());
+            }
             // ask user to check the config file
             let config_path = parse::get_config_path()?;
             let new_e = format!(
                 "Error: {}, please check the config file at {}",
-                e,
+                e.report(),
                 config_path.display()
             );
             Err(new_e.into())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_as_key_value() {
+        let env = |key: &str, value: &str| Ok((key.to_string(), value.to_string()));
+        assert_eq!(parse_env("CC=gcc"), env("CC", "gcc"));
+        assert_eq!(parse_env("FLAGS=-O2 -DX=1"), env("FLAGS", "-O2 -DX=1"));
+        assert_eq!(parse_env("EMPTY="), env("EMPTY", ""));
+        // the older form, unless there is an = to split at
+        assert_eq!(parse_env("PATH:/usr/bin"), env("PATH", "/usr/bin"));
+        assert_eq!(parse_env("URL=http://x"), env("URL", "http://x"));
+        for s in ["CC", "=gcc", "1CC=gcc", "MY-VAR=1", "A B=1"] {
+            assert!(parse_env(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn expect_exit_lists_and_ranges() {
+        let expected = parse_expect_exit("0, 2-4").unwrap();
+        assert!(expected.matches(0) && expected.matches(3) && expected.matches(4));
+        assert!(!expected.matches(1) && !expected.matches(5));
+        assert!(parse_expect_exit("1").unwrap().matches(1));
+        for s in ["", "one", "3-1", "1,,2", "1-", "-1", "0x1"] {
+            assert!(parse_expect_exit(s).is_err(), "{:?}", s);
+        }
+    }
+}
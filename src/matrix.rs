@@ -0,0 +1,111 @@
+use crate::error::{self, Context, CserunError};
+use crate::ssh;
+use std::fs;
+use std::path::Path;
+
+// how many matrix commands run at once when --parallel is not given
+pub const DEFAULT_PARALLEL: usize = 4;
+
+// how one command of the matrix went, in the order they were given
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    pub command: String,
+    pub exit_status: Option<i32>, // None if it never started, e.g. after ctrl-c
+    pub seconds: f64,
+    pub timed_out: bool,
+}
+
+// --matrix: one command per line, blank lines and # comments skipped
+pub fn read_file(path: &Path) -> error::Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+        .context(CserunError::Local)?;
+    let commands: Vec<String> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+    if commands.is_empty() {
+        return Err(CserunError::Config(
+            format!("{} has no commands in it", path.display()).into(),
+        ));
+    }
+    Ok(commands)
+}
+
+// --each: the command once per argument, quoted in place of every {} or added at the end
+pub fn expand(command: &str, args: &[String]) -> Vec<String> {
+    args.iter()
+        .map(|arg| {
+            let arg = ssh::shell_quote(arg);
+            match command.contains("{}") {
+                true => command.replace("{}", &arg),
+                false => format!("{} {}", command, arg),
+            }
+        })
+        .collect()
+}
+
+// the lines of the table printed once every command is done
+pub fn summary(outcomes: &[Outcome]) -> Vec<String> {
+    let status = |outcome: &Outcome| match (outcome.exit_status, outcome.timed_out) {
+        (_, true) => "timeout".to_string(),
+        (Some(status), false) => status.to_string(),
+        (None, false) => "-".to_string(),
+    };
+    let width = outcomes
+        .iter()
+        .map(|outcome| status(outcome).len())
+        .max()
+        .unwrap_or(0)
+        .max("STATUS".len());
+    let mut lines = vec![format!(
+        "{:>3}  {:<width$}  {:>8}  COMMAND",
+        "#",
+        "STATUS",
+        "TIME",
+        width = width
+    )];
+    for (index, outcome) in outcomes.iter().enumerate() {
+        lines.push(format!(
+            "{:>3}  {:<width$}  {:>7.1}s  {}",
+            index + 1,
+            status(outcome),
+            outcome.seconds,
+            outcome.command,
+            width = width
+        ));
+    }
+    let passed = outcomes
+        .iter()
+        .filter(|outcome| outcome.exit_status == Some(0) && !outcome.timed_out)
+        .count();
+    let not_run = outcomes
+        .iter()
+        .filter(|outcome| outcome.exit_status.is_none())
+        .count();
+    let mut total = format!(
+        "{} command(s), {} passed, {} failed",
+        outcomes.len(),
+        passed,
+        outcomes.len() - passed - not_run
+    );
+    if not_run > 0 {
+        total.push_str(&format!(", {} not run", not_run));
+    }
+    lines.push(total);
+    lines
+}
+
+// what cserun exits with: the status of the first command that failed, in the given order
+pub fn exit_status(outcomes: &[Outcome]) -> i32 {
+    outcomes
+        .iter()
+        .find_map(|outcome| match (outcome.exit_status, outcome.timed_out) {
+            (_, true) => Some(124),
+            (Some(0), false) | (None, false) => None,
+            (Some(status), false) => Some(status),
+        })
+        .unwrap_or(0)
+}
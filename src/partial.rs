@@ -0,0 +1,72 @@
+use crate::error::{self, Context, CserunError};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// smaller files are quicker to send again than to keep track of
+pub const MIN_SIZE: u64 = 1 << 20;
+
+// upload workers record their files side by side
+static LOCK: Mutex<()> = Mutex::new(());
+
+// uploads that were cut off, so the next attempt can carry on where the remote .part file stops.
+// keyed by account and remote path, the part is only trusted if the local file has the same
+// sha256 as when it was started
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Partials {
+    #[serde(default)]
+    parts: BTreeMap<String, String>,
+}
+
+fn partials_path() -> error::Result<PathBuf> {
+    let mut path = dirs::home_dir()
+        .ok_or("Cannot find the home directory")
+        .context(CserunError::Local)?;
+    path.push(".cserun");
+    fs::create_dir_all(&path).context(CserunError::Local)?;
+    path.push("partial.toml");
+    Ok(path)
+}
+
+fn load() -> Partials {
+    // losing it only means starting the file over
+    partials_path()
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn save(partials: &Partials) {
+    let saved = partials_path().and_then(|path| {
+        let contents = toml::to_string(partials).context(CserunError::Local)?;
+        fs::write(path, contents).context(CserunError::Local)
+    });
+    if let Err(e) = saved {
+        warn!("Could not save ~/.cserun/partial.toml: {}", e.report());
+    }
+}
+
+// the hash of the contents the part at `key` was started with
+pub fn started(key: &str) -> Option<String> {
+    let _lock = LOCK.lock().unwrap();
+    load().parts.remove(key)
+}
+
+pub fn start(key: &str, hash: &str) {
+    let _lock = LOCK.lock().unwrap();
+    let mut partials = load();
+    partials.parts.insert(key.to_string(), hash.to_string());
+    save(&partials);
+}
+
+pub fn finish(key: &str) {
+    let _lock = LOCK.lock().unwrap();
+    let mut partials = load();
+    if partials.parts.remove(key).is_some() {
+        save(&partials);
+    }
+}
@@ -0,0 +1,73 @@
+// --prefix: how each line of the command's output is tagged
+#[derive(Clone, Debug, Default)]
+pub struct Prefix {
+    pub label: Option<String>, // in place of the stream names, e.g. to tell runs apart
+    pub timestamps: bool,
+}
+
+// the command's output cut into whole lines, so chunks of stdout and stderr never end up in
+// the middle of each other's lines
+pub struct Prefixer {
+    prefix: Prefix,
+    partial: [Vec<u8>; 2], // unfinished last line of stdout and stderr
+}
+
+impl Prefixer {
+    pub fn new(prefix: &Prefix) -> Prefixer {
+        Prefixer {
+            prefix: prefix.clone(),
+            partial: [Vec::new(), Vec::new()],
+        }
+    }
+
+    // the complete lines of stdout so far, tagged. the rest waits for its newline
+    pub fn stdout(&mut self, data: &[u8]) -> Vec<u8> {
+        self.push(0, data)
+    }
+
+    pub fn stderr(&mut self, data: &[u8]) -> Vec<u8> {
+        self.push(1, data)
+    }
+
+    fn push(&mut self, stream: usize, data: &[u8]) -> Vec<u8> {
+        self.partial[stream].extend_from_slice(data);
+        let Some(end) = self.partial[stream].iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let lines: Vec<u8> = self.partial[stream].drain(..=end).collect();
+        let mut tagged = Vec::new();
+        for line in lines.split_inclusive(|&b| b == b'\n') {
+            tagged.extend_from_slice(self.tag(stream).as_bytes());
+            tagged.extend_from_slice(line);
+        }
+        tagged
+    }
+
+    // the unfinished last lines of stdout and stderr once the command is done, with a newline
+    pub fn finish(&mut self) -> [Vec<u8>; 2] {
+        [0, 1].map(|stream| match self.partial[stream].is_empty() {
+            true => Vec::new(),
+            false => {
+                let mut line = self.tag(stream).into_bytes();
+                line.append(&mut self.partial[stream]);
+                line.push(b'\n');
+                line
+            }
+        })
+    }
+
+    fn tag(&self, stream: usize) -> String {
+        let name = match &self.prefix.label {
+            Some(label) => label.as_str(),
+            None => ["stdout", "stderr"][stream],
+        };
+        match self.prefix.timestamps {
+            true => format!(
+                "{} [{}] ",
+                chrono::Local::now().format("%H:%M:%S%.3f"),
+                name
+            ),
+            false => format!("[{}] ", name),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::error::{self, Context, CserunError};
+use crate::ssh::{self, Config};
+use log::{debug, info};
+use ssh2::Session;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+// a program the command needs on the server, e.g. "gcc" or "python3>=3.10"
+#[derive(Clone, Debug)]
+pub struct Requirement {
+    pub program: String,
+    pub version: Option<(String, Vec<u64>)>, // the comparison and the version it is against
+}
+
+impl FromStr for Requirement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Requirement, String> {
+        let invalid = || format!("{:?} is not a requirement like gcc or python3>=3.10", s);
+        let split = s.find(['<', '>', '=']).unwrap_or(s.len());
+        let (program, constraint) = s.split_at(split);
+        let program = program.trim();
+        // it ends up in a shell script
+        if program.is_empty()
+            || !program
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._+-".contains(c))
+        {
+            return Err(invalid());
+        }
+        let version = match constraint {
+            "" => None,
+            constraint => {
+                let split = constraint
+                    .find(|c: char| !"<>=".contains(c))
+                    .unwrap_or(constraint.len());
+                let (operator, version) = constraint.split_at(split);
+                if !matches!(operator, ">=" | ">" | "=" | "==" | "<=" | "<") {
+                    return Err(invalid());
+                }
+                Some((
+                    operator.to_string(),
+                    parse_version(version).ok_or_else(invalid)?,
+                ))
+            }
+        };
+        Ok(Requirement {
+            program: program.to_string(),
+            version,
+        })
+    }
+}
+
+// "3.10" or "12.2.0" as numbers, so 3.10 comes after 3.9
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version
+        .trim()
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+// the first dotted number in `--version` output, e.g. 3.10.12 in "Python 3.10.12"
+fn find_version(output: &str) -> Option<Vec<u64>> {
+    output
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .map(|word| word.trim_matches('.'))
+        .filter(|word| word.contains('.'))
+        .find_map(parse_version)
+}
+
+// missing parts count as 0, so 3.10 is the same as 3.10.0
+fn compare_versions(a: &[u64], b: &[u64]) -> Ordering {
+    let parts = a.len().max(b.len());
+    let part = |v: &[u64], i: usize| v.get(i).copied().unwrap_or(0);
+    (0..parts)
+        .map(|i| part(a, i).cmp(&part(b, i)))
+        .find(|order| order.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+const TAG: &str = "cserun-requires: ";
+
+// look for every required program in one round trip, before anything is uploaded.
+// the probe runs in the configured shell, so programs from `module load` in the profile count
+pub fn check(sess: &Session, conf: &Config) -> error::Result<()> {
+    if conf.requires.is_empty() {
+        return Ok(());
+    }
+    // one line per requirement: "missing", or "found" and the first line of `--version`.
+    // tagged, a login profile may print its own lines
+    let mut script = String::new();
+    for requirement in &conf.requires {
+        let version = match requirement.version {
+            Some(_) => format!(
+                "$({} --version 2>&1 </dev/null | head -n 1)",
+                requirement.program
+            ),
+            None => String::new(),
+        };
+        script.push_str(&format!(
+            "if command -v {program} >/dev/null 2>&1; then echo \"{tag}found {version}\"; else echo {tag}missing; fi; ",
+            tag = TAG,
+            program = requirement.program,
+            version = version,
+        ));
+    }
+    // the login shell only provides the PATH, the script itself is sh
+    let probe = match &conf.shell {
+        Some(shell) => format!(
+            "{} {}",
+            shell,
+            ssh::shell_quote(&format!("sh -c {}", ssh::shell_quote(&script)))
+        ),
+        None => script,
+    };
+    let output = ssh::run_remote(sess, &probe).context(CserunError::Exec)?;
+    let output = String::from_utf8_lossy(&output);
+
+    let mut problems = Vec::new();
+    let mut lines = output.lines().filter_map(|line| line.strip_prefix(TAG));
+    for requirement in &conf.requires {
+        let line = lines.next().unwrap_or("missing");
+        let Some(found) = line.strip_prefix("found") else {
+            problems.push(format!("{} is not installed", requirement.program));
+            continue;
+        };
+        debug!("Found {} on the server:{}", requirement.program, found);
+        let Some((operator, wanted)) = &requirement.version else {
+            continue;
+        };
+        let Some(installed) = find_version(found) else {
+            problems.push(format!(
+                "the version of {} is unknown, `{} --version` printed {:?}",
+                requirement.program,
+                requirement.program,
+                found.trim()
+            ));
+            continue;
+        };
+        let order = compare_versions(&installed, wanted);
+        let satisfied = match operator.as_str() {
+            ">=" => order.is_ge(),
+            ">" => order.is_gt(),
+            "<=" => order.is_le(),
+            "<" => order.is_lt(),
+            _ => order.is_eq(),
+        };
+        if !satisfied {
+            problems.push(format!(
+                "{} {} does not match {}{}",
+                requirement.program,
+                join_version(&installed),
+                operator,
+                join_version(wanted)
+            ));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(CserunError::Exec(
+            format!(
+                "The server is missing requirements: {}",
+                problems.join("; ")
+            )
+            .into(),
+        ));
+    }
+    info!("Found the required programs on the server");
+    Ok(())
+}
+
+fn join_version(version: &[u64]) -> String {
+    let parts: Vec<String> = version.iter().map(|part| part.to_string()).collect();
+    parts.join(".")
+}
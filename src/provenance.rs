@@ -0,0 +1,131 @@
+use crate::events::quote;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// where a run came from, written to manifest.json in the run directory and kept in the history,
+// for when an autotest result is disputed or something that worked yesterday does not
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Provenance {
+    pub cserun_version: String,
+    pub local_host: String,
+    pub local_user: String,
+    pub git_commit: Option<String>, // None outside of a git repository
+    pub git_dirty: bool,            // uncommitted changes to tracked files
+    pub started: String,
+    pub uploaded: String, // when the files were on the server
+    pub files: usize,
+    pub bytes: u64,
+    pub command: String, // as the remote shell was given it
+}
+
+impl Provenance {
+    // what is known before anything is uploaded. with --rev the commit is what was exported,
+    // which has no uncommitted changes
+    pub fn collect(local_dir: &Path, rev: Option<&str>, command: &str) -> Provenance {
+        let (git_commit, git_dirty) = match git(local_dir, &["rev-parse", "HEAD"]) {
+            _ if rev.is_some() => (rev.map(String::from), false),
+            Some(commit) => {
+                let status = git(
+                    local_dir,
+                    &["status", "--porcelain", "--untracked-files=no", "."],
+                );
+                (
+                    Some(commit),
+                    status.is_some_and(|status| !status.is_empty()),
+                )
+            }
+            None => (None, false),
+        };
+        Provenance {
+            cserun_version: env!("CARGO_PKG_VERSION").to_string(),
+            local_host: hostname(),
+            local_user: username(),
+            git_commit,
+            git_dirty,
+            started: now(),
+            command: command.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // once the upload is done
+    pub fn uploaded(&mut self, (files, bytes): (usize, u64)) {
+        self.uploaded = now();
+        self.files = files;
+        self.bytes = bytes;
+    }
+
+    pub fn to_json(&self, server_addr: &str, username: &str, remote_dir: &str) -> String {
+        let git_commit = match &self.git_commit {
+            Some(commit) => quote(commit),
+            None => "null".to_string(),
+        };
+        let mut json = String::from("{\n");
+        for (key, value) in [
+            ("cserun_version", quote(&self.cserun_version)),
+            ("local_host", quote(&self.local_host)),
+            ("local_user", quote(&self.local_user)),
+            ("git_commit", git_commit),
+            ("git_dirty", self.git_dirty.to_string()),
+            ("server", quote(server_addr)),
+            ("username", quote(username)),
+            ("remote_dir", quote(remote_dir)),
+            ("started", quote(&self.started)),
+            ("uploaded", quote(&self.uploaded)),
+            ("files", self.files.to_string()),
+            ("bytes", self.bytes.to_string()),
+            ("command", quote(&self.command)),
+        ] {
+            let _ = writeln!(json, "  {}: {},", quote(key), value);
+        }
+        // no trailing comma after the last field
+        json.truncate(json.len() - 2);
+        json.push_str("\n}\n");
+        json
+    }
+}
+
+fn now() -> String {
+    chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+}
+
+// the trimmed stdout of a git command, None if it failed or there is no git
+pub fn git(local_dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(local_dir)
+        .args(args)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    if result != 0 {
+        return String::new();
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+// who is running cserun on this machine
+pub fn username() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
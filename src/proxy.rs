@@ -0,0 +1,228 @@
+use crate::hash;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::time::Duration;
+
+// a proxy the ssh connection is tunneled through, from `proxy = "socks5://host:port"`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub addr: String, // host:port of the proxy itself
+    pub credentials: Option<(String, String)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyKind {
+    Socks5, // the proxy resolves the server's name, so it works without local DNS
+    Http,   // CONNECT
+}
+
+impl Proxy {
+    // scheme://[user:password@]host[:port], the port defaults to 1080 for socks5 and 8080 for http
+    pub fn parse(url: &str) -> Result<Proxy, String> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| format!("{:?} is not a URL like socks5://127.0.0.1:1080", url))?;
+        let (kind, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" | "socks5h" => (ProxyKind::Socks5, 1080),
+            "http" => (ProxyKind::Http, 8080),
+            _ => {
+                return Err(format!(
+                    "unsupported proxy scheme {:?}, use socks5:// or http://",
+                    scheme
+                ))
+            }
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, host_port) = match rest.rsplit_once('@') {
+            Some((userinfo, host_port)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (Some((user.to_string(), password.to_string())), host_port)
+            }
+            None => (None, rest),
+        };
+        // a bracketed IPv6 address has colons of its own
+        let addr = match host_port.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                port.parse::<u16>()
+                    .map_err(|_| format!("invalid proxy port {:?}", port))?;
+                format!("{}:{}", host, port)
+            }
+            _ => format!("{}:{}", host_port, default_port),
+        };
+        if addr.starts_with(':') {
+            return Err(format!("{:?} has no proxy host", url));
+        }
+        Ok(Proxy {
+            kind,
+            addr,
+            credentials,
+        })
+    }
+
+    // ask the proxy on `stream` for a tunnel to host:port. once this returns, the stream
+    // carries the ssh connection as if it went to the server directly
+    pub fn open(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        // a proxy that accepts the connection and then hangs is a handshake that never ends
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let opened = match self.kind {
+            ProxyKind::Socks5 => self.open_socks5(stream, host, port),
+            ProxyKind::Http => self.open_http(stream, host, port),
+        };
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        opened
+    }
+
+    // RFC 1928, with RFC 1929 username/password auth when the URL has credentials
+    fn open_socks5(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let methods: &[u8] = match self.credentials {
+            Some(_) => &[0, 2],
+            None => &[0],
+        };
+        let mut greeting = vec![5, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting)?;
+        let mut chosen = [0; 2];
+        stream.read_exact(&mut chosen)?;
+        if chosen[0] != 5 {
+            return Err(io::Error::other("the proxy does not speak SOCKS5"));
+        }
+        match (chosen[1], &self.credentials) {
+            (0, _) => {}
+            (2, Some((user, password))) => {
+                if user.len() > 255 || password.len() > 255 {
+                    return Err(io::Error::other(
+                        "the proxy username or password is too long",
+                    ));
+                }
+                let mut request = vec![1, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request)?;
+                let mut status = [0; 2];
+                stream.read_exact(&mut status)?;
+                if status[1] != 0 {
+                    return Err(io::Error::other(
+                        "the proxy rejected the username or password",
+                    ));
+                }
+            }
+            _ => {
+                return Err(io::Error::other(match self.credentials {
+                    Some(_) => "the proxy accepts none of the offered authentication methods",
+                    None => "the proxy requires a username and password in the URL",
+                }))
+            }
+        }
+
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) if host.len() <= 255 => {
+                request.extend_from_slice(&[3, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            }
+            Err(_) => {
+                return Err(io::Error::other(format!(
+                    "{} is too long a host name",
+                    host
+                )))
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            let reason = match reply[1] {
+                2 => "the connection is not allowed by the proxy's rules",
+                3 => "the network is unreachable",
+                4 => "the host is unreachable",
+                5 => "the connection was refused",
+                6 => "the connection timed out",
+                7 | 8 => "the request is not supported by the proxy",
+                _ => "the proxy failed",
+            };
+            return Err(io::Error::new(
+                match reply[1] {
+                    5 => io::ErrorKind::ConnectionRefused,
+                    6 => io::ErrorKind::TimedOut,
+                    _ => io::ErrorKind::Other,
+                },
+                format!(
+                    "SOCKS5 proxy could not connect to {}:{}: {}",
+                    host, port, reason
+                ),
+            ));
+        }
+        // the address the proxy bound, not needed but it has to be read past
+        let bound_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => return Err(io::Error::other("unexpected SOCKS5 reply")),
+        };
+        let mut bound = vec![0; bound_len + 2];
+        stream.read_exact(&mut bound)
+    }
+
+    fn open_http(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let target = match host.contains(':') && !host.starts_with('[') {
+            true => format!("[{}]:{}", host, port),
+            false => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+        if let Some((user, password)) = &self.credentials {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                hash::base64(format!("{}:{}", user, password).as_bytes())
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // byte by byte, whatever follows the headers already belongs to ssh
+        let mut response = Vec::new();
+        let mut byte = [0; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > 16384 {
+                return Err(io::Error::other(
+                    "the proxy's response headers are too long",
+                ));
+            }
+            stream.read_exact(&mut byte)?;
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "HTTP proxy refused CONNECT to {}: {}",
+                target, status_line
+            ))),
+        }
+    }
+}
@@ -0,0 +1,548 @@
+use crate::cancel::CancelToken;
+use crate::encoding::Encoding;
+use crate::error::{self, CserunError};
+use crate::executor::Executor;
+use crate::forward::Forward;
+use crate::matrix::Outcome;
+use crate::prefix::Prefix;
+use crate::proxy::Proxy;
+use crate::ssh::{
+    self, AddressFamily, Auth, Color, Config, Connection, Finished, GitFiles, RunDir, Symlinks,
+    SyncBack, Transfer, TransferBackend,
+};
+use crate::sync;
+use log::{info, warn};
+use std::cell::{Cell, Ref, RefCell};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// the same settings as the config file and flags, for driving cserun from other programs
+pub struct Runner {
+    conf: Config,
+}
+
+pub struct RunnerBuilder {
+    server: Option<String>,
+    user: Option<String>,
+    conf: Config,
+}
+
+// a connection with its own run directory, the steps of `cserun run` one at a time.
+// output of `run` goes to this process's stdout and stderr, like it does for the binary.
+// every call goes over the same connection, which is logged in again if it dropped
+pub struct Session<'a> {
+    conf: &'a Config,
+    conn: RefCell<Connection>,
+    last_used: Cell<Instant>,
+    run_dir: RunDir,
+}
+
+// how long a connection may sit unused before it is checked ahead of the next call, a nat or
+// firewall may have dropped it in the meantime
+const IDLE_CHECK: Duration = Duration::from_secs(10);
+
+impl Runner {
+    pub fn builder() -> RunnerBuilder {
+        RunnerBuilder {
+            server: None,
+            user: None,
+            conf: Config::default(),
+        }
+    }
+
+    // use settings loaded elsewhere, e.g. with parse::get_ssh_config
+    pub fn with_config(conf: Config) -> Runner {
+        Runner { conf }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.conf
+    }
+
+    // a handle for another thread to stop the calls of this runner's sessions with
+    pub fn cancel_token(&self) -> CancelToken {
+        self.conf.cancel.clone()
+    }
+
+    // connect and authenticate, retrying network errors like the binary does
+    pub fn connect(&self) -> error::Result<Session<'_>> {
+        Session::connect(&self.conf)
+    }
+}
+
+impl RunnerBuilder {
+    // "host" or "host:port"
+    pub fn server(mut self, server: &str) -> Self {
+        self.server = Some(server.to_string());
+        self
+    }
+
+    pub fn user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    // can be given several times, the methods are tried in order. the agent if none is given
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.conf.auth.push(auth);
+        self
+    }
+
+    // [user@]host[:port]
+    pub fn jump_host(mut self, jump_host: &str) -> Self {
+        self.conf.jump_host = Some(jump_host.to_string());
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.conf.proxy = Some(proxy);
+        self
+    }
+
+    pub fn strict_host_key_checking(mut self, strict: bool) -> Self {
+        self.conf.strict_host_key_checking = strict;
+        self
+    }
+
+    // the directory that is uploaded and fetched into, the current one by default
+    pub fn local_dir(mut self, local_dir: impl Into<PathBuf>) -> Self {
+        self.conf.local_dir = local_dir.into();
+        self
+    }
+
+    // start commands in this subdirectory of the local directory instead of its root
+    pub fn workdir(mut self, workdir: impl Into<PathBuf>) -> Self {
+        self.conf.workdir = Some(workdir.into());
+        self
+    }
+
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.conf.envs.retain(|(existing, _)| existing != key);
+        self.conf.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    // gitignore style pattern, on top of .gitignore and .cserunignore
+    pub fn ignore(mut self, pattern: &str) -> Self {
+        self.conf.walk.ignore.push(pattern.to_string());
+        self
+    }
+
+    pub fn include(mut self, glob: &str) -> Self {
+        self.conf.walk.include.push(glob.to_string());
+        self
+    }
+
+    pub fn exclude(mut self, glob: &str) -> Self {
+        self.conf.walk.exclude.push(glob.to_string());
+        self
+    }
+
+    // only upload files git knows of, like --git-only
+    pub fn git_only(mut self, files: GitFiles) -> Self {
+        self.conf.walk.git_only = Some(files);
+        self
+    }
+
+    // only upload these files and directories, relative to the local directory
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.conf.walk.paths.push(path.into());
+        self
+    }
+
+    // false uploads build output like target/ and node_modules/ too
+    pub fn default_excludes(mut self, enabled: bool) -> Self {
+        self.conf.walk.default_excludes = enabled;
+        self
+    }
+
+    // leave out files larger than this many bytes
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.conf.walk.max_file_size = Some(bytes);
+        self
+    }
+
+    // leave out files that look binary, compiled programs, core dumps and the like
+    pub fn skip_binaries(mut self, skip: bool) -> Self {
+        self.conf.walk.skip_binaries = skip;
+        self
+    }
+
+    // upload dotfiles too, or leave them out without a warning
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.conf.walk.hidden = Some(hidden);
+        self
+    }
+
+    pub fn symlinks(mut self, symlinks: Symlinks) -> Self {
+        self.conf.walk.symlinks = symlinks;
+        self
+    }
+
+    // mode of the directories created on the server, e.g. 0o700, uploaded files get no more
+    pub fn remote_dir_mode(mut self, mode: u32) -> Self {
+        self.conf.remote_dir_mode = Some(mode);
+        self
+    }
+
+    // sync into the persistent workspace, named after the local directory unless a name is given
+    pub fn workspace(mut self, name: Option<&str>) -> Self {
+        self.conf.workspace = true;
+        self.conf.workspace_name = name.map(String::from);
+        self
+    }
+
+    pub fn transfer(mut self, transfer: Transfer) -> Self {
+        self.conf.transfer = transfer;
+        self
+    }
+
+    pub fn transfer_backend(mut self, backend: TransferBackend) -> Self {
+        self.conf.transfer_backend = backend;
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.conf.jobs = jobs;
+        self
+    }
+
+    // run commands through this shell, e.g. "bash -l -c" to load the login profile first
+    pub fn shell(mut self, shell: &str) -> Self {
+        self.conf.shell = Some(shell.to_string());
+        self
+    }
+
+    pub fn pty(mut self, pty: bool) -> Self {
+        self.conf.pty = pty;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.conf.timeout = Some(timeout);
+        self
+    }
+
+    // per server address
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.conf.connect_timeout = timeout;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.conf.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn address_family(mut self, family: AddressFamily) -> Self {
+        self.conf.address_family = family;
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.conf.retries = retries;
+        self
+    }
+
+    // seconds, 0 disables keepalives
+    pub fn keepalive_interval(mut self, interval: u32) -> Self {
+        self.conf.keepalive_interval = interval;
+        self
+    }
+
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.conf.compress = compress;
+        self
+    }
+
+    // larger uploads ask for confirmation on the terminal first
+    pub fn max_upload_size(mut self, bytes: u64) -> Self {
+        self.conf.max_upload_size = Some(bytes);
+        self
+    }
+
+    // transcode the command's output for display, raw bytes by default
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.conf.encoding = encoding;
+        self
+    }
+
+    // tell remote programs to print colors even though they see no terminal
+    pub fn color(mut self, color: Color) -> Self {
+        self.conf.color = color;
+        self
+    }
+
+    // cap uploads at this many bytes per second
+    pub fn limit_rate(mut self, bytes_per_second: u64) -> Self {
+        self.conf.limit_rate = Some(bytes_per_second);
+        self
+    }
+
+    // copy files uploaded by earlier runs from the server cache instead of sending them again
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.conf.cache = cache;
+        self
+    }
+
+    // cached files not copied into a run for this long are removed from the server cache
+    pub fn cache_max_age(mut self, age: Duration) -> Self {
+        self.conf.cache_max_age = age;
+        self
+    }
+
+    // send only the changed blocks of large workspace files, on by default
+    pub fn delta(mut self, delta: bool) -> Self {
+        self.conf.delta = delta;
+        self
+    }
+
+    // remove files from the workspace that no longer exist locally
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.conf.delete = delete;
+        self
+    }
+
+    // skip the check for free space on the server before uploading
+    pub fn force(mut self, force: bool) -> Self {
+        self.conf.force = force;
+        self
+    }
+
+    // check the sha256 of every uploaded file on the server before running the command
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.conf.verify = verify;
+        self
+    }
+
+    // tunnel a local port to the server while commands run
+    pub fn forward(mut self, forward: Forward) -> Self {
+        self.conf.forwards.push(forward);
+        self
+    }
+
+    // used by ssh::exec, a Session downloads with fetch instead
+    pub fn sync_back(mut self, sync_back: SyncBack) -> Self {
+        self.conf.sync_back = sync_back;
+        self
+    }
+
+    // also write the output of each run, with timestamps, into this file
+    pub fn log_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.conf.log_file = Some(path.into());
+        self
+    }
+
+    // how many commands Session::run_matrix runs at once
+    pub fn parallel(mut self, parallel: usize) -> Self {
+        self.conf.parallel = parallel;
+        self
+    }
+
+    // print the output in whole lines, each tagged with its stream or the label
+    pub fn prefix(mut self, prefix: Prefix) -> Self {
+        self.conf.prefix = Some(prefix);
+        self
+    }
+
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.conf.progress = progress;
+        self
+    }
+
+    // share a token with other runners, e.g. to stop them all at once. each runner has its own
+    // otherwise, see Runner::cancel_token
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.conf.cancel = token;
+        self
+    }
+
+    pub fn build(mut self) -> error::Result<Runner> {
+        let server = self
+            .server
+            .ok_or_else(|| CserunError::Config("No server given".into()))?;
+        self.conf.username = self
+            .user
+            .ok_or_else(|| CserunError::Config("No username given".into()))?;
+        if self.conf.auth.is_empty() {
+            self.conf.auth.push(Auth::Agent(None));
+        }
+        self.conf.server_addr = ssh::normalize_addr(&server, 22)
+            .map_err(|e| CserunError::Config(format!("Invalid server address: {}", e).into()))?;
+        ssh::check_workdir(&self.conf)?;
+        Ok(Runner { conf: self.conf })
+    }
+}
+
+impl<'a> Session<'a> {
+    // connect with settings from anywhere, e.g. parse::get_ssh_config
+    pub fn connect(conf: &'a Config) -> error::Result<Session<'a>> {
+        conf.cancel.check("before connecting")?;
+        let (conn, run_dir) = ssh::with_retries(conf, |_| {
+            let conn = ssh::connect(conf)?;
+            let run_dir = ssh::claim_run_dir(&conn, conf)?;
+            Ok((conn, run_dir))
+        })?;
+        Ok(Session {
+            conf,
+            conn: RefCell::new(conn),
+            last_used: Cell::new(Instant::now()),
+            run_dir,
+        })
+    }
+
+    // where the local directory is uploaded to, inside the run directory or the workspace
+    pub fn container_path(&self) -> &Path {
+        &self.run_dir.container_path
+    }
+
+    // whether the server still answers on the connection
+    pub fn is_alive(&self) -> bool {
+        ssh::run_remote(&self.conn.borrow().sess, "true").is_ok()
+    }
+
+    // connect and log in again, the run directory stays the same
+    pub fn reconnect(&self) -> error::Result<()> {
+        let conn = ssh::with_retries(self.conf, |_| ssh::connect(self.conf))?;
+        info!("Reconnected to {}", conn.addr);
+        *self.conn.borrow_mut() = conn;
+        self.last_used.set(Instant::now());
+        Ok(())
+    }
+
+    // the connection for the next call, checked first if it was not used for a while
+    fn connection(&self) -> error::Result<Ref<'_, Connection>> {
+        if self.last_used.get().elapsed() > IDLE_CHECK && !self.is_alive() {
+            self.reconnect()?;
+        }
+        self.last_used.set(Instant::now());
+        Ok(self.conn.borrow())
+    }
+
+    // a transfer, again on a new connection after a network error. a command is not run again,
+    // it may have done part of its work already
+    fn transfer<T>(
+        &self,
+        mut transfer: impl FnMut(&Connection, bool) -> error::Result<T>,
+    ) -> error::Result<T> {
+        ssh::with_retries(self.conf, |resume| {
+            if resume {
+                self.reconnect()?;
+            }
+            let conn = self.connection()?;
+            transfer(&conn, resume)
+        })
+    }
+
+    // upload the local directory, only new or changed files when using a workspace
+    pub fn sync(&self) -> error::Result<()> {
+        self.sync_files().map(|_| ())
+    }
+
+    fn sync_files(&self) -> error::Result<((usize, u64), usize)> {
+        let synced = self.transfer(|conn, resume| {
+            ssh::sync_files(
+                conn,
+                self.conf,
+                &self.run_dir.container_path,
+                &self.run_dir.manifest_id,
+                resume,
+            )
+        });
+        self.cancelled(synced, "after uploading")
+    }
+
+    // run a command in the container dir, the exit status is in the returned Finished
+    pub fn run(&self, command: &str) -> error::Result<Finished> {
+        let finished = (|| {
+            let conn = self.connection()?;
+            let remote_dir_path = Path::new(&self.run_dir.path);
+            conn.mkdir(remote_dir_path)?;
+            conn.mkdir(&self.run_dir.container_path)?;
+            conn.write_file(&remote_dir_path.join("command.txt"), command.as_bytes())?;
+            let channel = ssh::start_command(&conn, self.conf, command, &self.run_dir)?;
+            ssh::wait_command(&conn.sess, channel, self.conf, &self.run_dir, command)
+        })();
+        self.last_used.set(Instant::now());
+        self.cancelled(finished, "while the command was running")
+    }
+
+    // run the commands side by side in the container dir, see Runner::parallel
+    pub fn run_matrix(&self, commands: &[String]) -> error::Result<(Finished, Vec<Outcome>)> {
+        let finished = (|| {
+            let conn = self.connection()?;
+            let remote_dir_path = Path::new(&self.run_dir.path);
+            conn.mkdir(remote_dir_path)?;
+            conn.mkdir(&self.run_dir.container_path)?;
+            ssh::run_matrix(&conn, self.conf, &self.run_dir, commands)
+        })();
+        self.last_used.set(Instant::now());
+        self.cancelled(finished, "while the commands were running")
+    }
+
+    // download files matching the globs from the container dir into the local directory
+    pub fn fetch(&self, patterns: &[String]) -> error::Result<usize> {
+        let patterns = sync::build_globset(patterns)?;
+        let fetched = self.transfer(|conn, _| {
+            conn.download_dir(
+                &self.run_dir.container_path,
+                &self.conf.local_dir,
+                &patterns,
+                self.conf.progress,
+            )
+        });
+        self.cancelled(fetched, "after downloading")
+    }
+
+    // once the token is cancelled a call fails with CserunError::Cancelled, `during` when it
+    // finished anyway, and the run directory is removed unless kept. other errors stay as they are
+    fn cancelled<T>(&self, result: error::Result<T>, during: &str) -> error::Result<T> {
+        if !self.conf.cancel.is_cancelled() {
+            return result;
+        }
+        let result = result.and_then(|_| Err(CserunError::Cancelled(during.into())));
+        if let (Err(CserunError::Cancelled(_)), false) = (&result, self.conf.keep) {
+            let removed = self
+                .connection()
+                .and_then(|conn| ssh::remove_remote_dir(&conn.sess, &self.run_dir.path));
+            if let Err(e) = removed {
+                warn!("Could not clean up after cancelling: {}", e.report());
+            }
+        }
+        result
+    }
+
+    // remove the run directory, a workspace is left in place
+    pub fn cleanup(self) -> error::Result<()> {
+        ssh::remove_remote_dir(&self.connection()?.sess, &self.run_dir.path)
+    }
+}
+
+impl Executor for Session<'_> {
+    fn describe(&self) -> String {
+        format!(
+            "{}:{}",
+            self.conf.server_addr,
+            ssh::display_path(&self.run_dir.path)
+        )
+    }
+
+    fn sync(&mut self) -> error::Result<(usize, u64)> {
+        self.sync_files().map(|(uploaded, _)| uploaded)
+    }
+
+    fn run(&mut self, command: &str) -> error::Result<Finished> {
+        Session::run(self, command)
+    }
+
+    fn fetch(&mut self, patterns: &[String]) -> error::Result<usize> {
+        Session::fetch(self, patterns)
+    }
+
+    fn cleanup(&mut self, keep: bool) -> error::Result<()> {
+        match keep {
+            true => Ok(()),
+            false => ssh::remove_remote_dir(&self.connection()?.sess, &self.run_dir.path),
+        }
+    }
+}
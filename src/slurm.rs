@@ -0,0 +1,110 @@
+use crate::error;
+use crate::ssh::{self, Config, Connection, RunDir};
+use std::fmt::Write as _;
+use std::path::Path;
+
+// --slurm: on clusters where the login node is not for computing, the command is submitted as a
+// batch job from the run directory, and its output file is followed until the job is done
+#[derive(Clone, Debug, Default)]
+pub struct Slurm {
+    pub enabled: bool,
+    pub partition: Option<String>,
+    pub time: Option<String>, // as sbatch takes it, e.g. "10:00" or "1-0"
+    pub mem: Option<String>,  // e.g. "4G"
+    pub cpus: Option<u32>,
+    pub options: Vec<String>, // anything else for sbatch, e.g. "--gres=gpu:1"
+}
+
+fn job_path(run_dir: &RunDir) -> String {
+    format!("{}/job.sh", run_dir.path)
+}
+
+fn output_path(run_dir: &RunDir) -> String {
+    format!("{}/slurm.out", run_dir.path)
+}
+
+fn exit_status_path(run_dir: &RunDir) -> String {
+    format!("{}/slurm_exit_status", run_dir.path)
+}
+
+// what to start in the working directory for the command. with --slurm the batch script is
+// uploaded first, and the command that submits and follows it is what runs
+pub fn prepare(
+    conn: &Connection,
+    conf: &Config,
+    command: &str,
+    run_dir: &RunDir,
+) -> error::Result<String> {
+    if !conf.slurm.enabled {
+        return Ok(command.to_string());
+    }
+    let script = batch_script(conf, command, run_dir);
+    conn.write_file(Path::new(&job_path(run_dir)), script.as_bytes())?;
+    Ok(follow_command(run_dir))
+}
+
+// the batch script, uploaded as job.sh next to the container dir. it starts where it was
+// submitted from, the working directory, with the environment of the submitting shell
+pub fn batch_script(conf: &Config, command: &str, run_dir: &RunDir) -> String {
+    let slurm = &conf.slurm;
+    let mut script = String::from("#!/bin/sh\n");
+    let options = [
+        slurm
+            .partition
+            .as_ref()
+            .map(|p| format!("--partition={}", p)),
+        slurm.time.as_ref().map(|time| format!("--time={}", time)),
+        slurm.mem.as_ref().map(|mem| format!("--mem={}", mem)),
+        slurm.cpus.map(|cpus| format!("--cpus-per-task={}", cpus)),
+    ];
+    for option in options.into_iter().flatten().chain(slurm.options.clone()) {
+        let _ = writeln!(script, "#SBATCH {}", option);
+    }
+    // the account's shell when none is configured, as over ssh
+    let shell = conf.shell.as_deref().unwrap_or("\"${SHELL:-sh}\" -c");
+    let _ = writeln!(script, "{} {}", shell, ssh::shell_quote(command));
+    let _ = writeln!(
+        script,
+        "echo $? > {}",
+        ssh::shell_path(exit_status_path(run_dir))
+    );
+    script
+}
+
+// what runs over ssh in place of the command: submit the job, print its output as it grows and
+// exit with its exit status. ctrl-c or --timeout cancel the job
+pub fn follow_command(run_dir: &RunDir) -> String {
+    let script = format!(
+        "job=$(sbatch --parsable --job-name={name} --output={output} {job}) || exit $?\n\
+         job=${{job%%;*}}\n\
+         echo \"Submitted batch job $job\" >&2\n\
+         trap 'scancel \"$job\" 2>/dev/null; exit 130' INT TERM HUP\n",
+        name = ssh::shell_quote(&format!("cserun-{}", run_dir.name)),
+        output = ssh::shell_path(output_path(run_dir)),
+        job = ssh::shell_path(job_path(run_dir)),
+    ) + &format!(
+        "out={output}\n\
+         shown=0\n\
+         show() {{\n\
+         \x20   [ -f \"$out\" ] || return 0\n\
+         \x20   size=$(($(wc -c < \"$out\")))\n\
+         \x20   if [ \"$size\" -gt \"$shown\" ]; then\n\
+         \x20       tail -c +$((shown + 1)) \"$out\" | head -c $((size - shown))\n\
+         \x20       shown=$size\n\
+         \x20   fi\n\
+         }}\n\
+         while [ -n \"$(squeue -h -j \"$job\" -o %T 2>/dev/null)\" ]; do show; sleep 2; done\n\
+         # the last lines may take a moment to show up on a shared filesystem\n\
+         sleep 1\n\
+         show\n\
+         status={status}\n\
+         if [ -f \"$status\" ]; then exit \"$(cat \"$status\")\"; fi\n\
+         state=$(sacct -n -X -P -j \"$job\" -o State 2>/dev/null | head -n 1)\n\
+         echo \"Job $job ended without an exit status${{state:+, it is $state}}\" >&2\n\
+         exit 1\n",
+        output = ssh::shell_path(output_path(run_dir)),
+        status = ssh::shell_path(exit_status_path(run_dir)),
+    );
+    // sh whatever the login shell is
+    format!("sh -c {}", ssh::shell_quote(&script))
+}
@@ -0,0 +1,121 @@
+use crate::error::{self, Context, CserunError};
+use crate::provenance;
+use log::{debug, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// --rev: the files of a git revision, exported to a temporary directory and uploaded in place
+// of the working directory. the directory is removed again when it is dropped
+pub struct Snapshot {
+    pub rev: String,    // as given, e.g. HEAD or a branch
+    pub commit: String, // what it resolved to
+    pub dir: PathBuf,
+}
+
+impl Snapshot {
+    // `git archive` of the revision, limited to the part of the repository local_dir is in
+    pub fn export(local_dir: &Path, rev: &str) -> error::Result<Snapshot> {
+        let commit = provenance::git(
+            local_dir,
+            &[
+                "rev-parse",
+                "--verify",
+                "--quiet",
+                &format!("{}^{{commit}}", rev),
+            ],
+        )
+        .filter(|commit| !commit.is_empty())
+        .ok_or_else(|| {
+            CserunError::Config(
+                format!(
+                    "--rev {}: no such commit in a git repository at {}",
+                    rev,
+                    local_dir.display()
+                )
+                .into(),
+            )
+        })?;
+        // e.g. "lab1/" when local_dir is a subdirectory of the repository. git archive is run
+        // from the top, in a subdirectory it would only take what is below it
+        let prefix =
+            provenance::git(local_dir, &["rev-parse", "--show-prefix"]).unwrap_or_default();
+        let top = provenance::git(local_dir, &["rev-parse", "--show-toplevel"])
+            .map(PathBuf::from)
+            .unwrap_or_else(|| local_dir.to_path_buf());
+        let dir = std::env::temp_dir().join(format!(
+            "cserun-rev-{}-{}",
+            std::process::id(),
+            &commit[..12.min(commit.len())]
+        ));
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("{}: {}", dir.display(), e))
+            .context(CserunError::Local)?;
+        // from here on dropping it cleans up
+        let snapshot = Snapshot {
+            rev: rev.to_string(),
+            commit,
+            dir,
+        };
+
+        let mut archive = Command::new("git")
+            .arg("-C")
+            .arg(&top)
+            .args(["archive", "--format=tar"])
+            .arg(format!("{}:{}", snapshot.commit, prefix))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run git: {}", e))
+            .context(CserunError::Local)?;
+        let archive_stdout = archive
+            .stdout
+            .take()
+            .ok_or("Failed to open git's stdout")
+            .context(CserunError::Local)?;
+        let extract = Command::new("tar")
+            .args(["-xf", "-", "-C"])
+            .arg(&snapshot.dir)
+            .stdin(archive_stdout)
+            .stderr(Stdio::piped())
+            .output()
+            .map_err(|e| format!("Failed to run tar: {}", e))
+            .context(CserunError::Local)?;
+        let archived = archive.wait_with_output().context(CserunError::Local)?;
+        if !archived.status.success() {
+            return Err(CserunError::Local(
+                format!(
+                    "git archive of {} failed: {}",
+                    rev,
+                    String::from_utf8_lossy(&archived.stderr).trim()
+                )
+                .into(),
+            ));
+        }
+        if !extract.status.success() {
+            return Err(CserunError::Local(
+                format!(
+                    "Failed to extract the files of {}: {}",
+                    rev,
+                    String::from_utf8_lossy(&extract.stderr).trim()
+                )
+                .into(),
+            ));
+        }
+        debug!(
+            "Exported {} ({}) to {}",
+            rev,
+            snapshot.commit,
+            snapshot.dir.display()
+        );
+        Ok(snapshot)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("Could not remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
@@ -1,13 +1,50 @@
+use crate::artifacts::Artifacts;
+use crate::cache::{self, Manifest};
+use crate::cancel::CancelToken;
+#[cfg(unix)]
+use crate::control;
+use crate::detach;
+use crate::encoding::Encoding;
+use crate::error::{self, Context, CserunError};
+use crate::events;
+use crate::executor::Backend;
+use crate::failover;
+use crate::filter::{LineFilter, OutputFilter};
+use crate::forward::{Agent, Forward, Tunnels, X11};
+use crate::hash;
+use crate::history::{self, KeptRun, RunRecord};
+use crate::hooks::{self, Hooks};
+use crate::keyring;
+use crate::known_hosts;
+use crate::limits::{self, Limits};
+use crate::matrix::{self, Outcome};
+use crate::notify;
+use crate::prefix::{Prefix, Prefixer};
+use crate::preflight::{self, Requirement};
+use crate::provenance::Provenance;
+use crate::proxy::Proxy;
+use crate::slurm::{self, Slurm};
+use crate::snapshot::Snapshot;
+use crate::sync::{self, sftp_mkdir_recursive};
+use crate::terminal;
+use crate::throttle::RateLimit;
+use crate::transcript::Transcript;
+use crate::watcher::Watcher;
 use console::{style, Emoji};
-use ignore::WalkBuilder;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, HumanDuration};
+use log::{debug, error, info, warn};
 use ssh2::Session;
-use std::fs;
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
 use std::io::Write;
 use std::io::{self, Read};
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
 static TRUCK: Emoji<'_, '_> = Emoji("🚚  ", "");
 static CLIP: Emoji<'_, '_> = Emoji("🔗  ", "");
@@ -15,9 +52,9 @@ static SPARKLE: Emoji<'_, '_> = Emoji("✨  ", "");
 static NETWORK: Emoji<'_, '_> = Emoji("🌐  ", "");
 static LOCK: Emoji<'_, '_> = Emoji("🔒  ", "");
 static PROHIBITED: Emoji<'_, '_> = Emoji("🚫  ", "");
-static FOLDER: Emoji<'_, '_> = Emoji("📁 ", "");
-static FILE: Emoji<'_, '_> = Emoji("📄 ", "");
 static SPACESHIP: Emoji<'_, '_> = Emoji("🚀  ", "");
+static INBOX: Emoji<'_, '_> = Emoji("📥  ", "");
+static FOLDER: Emoji<'_, '_> = Emoji("📁  ", "");
 
 pub struct AuthKey {
     pub pubkey: Option<PathBuf>,
@@ -26,267 +63,4096 @@ pub struct AuthKey {
 }
 
 pub enum Auth {
-    Password(String),
+    Password(Option<String>), // asked for on the terminal when not given
     AuthKey(AuthKey),
-    Agent,
+    Agent(Option<String>), // only use the agent identity with this comment or fingerprint
+    KeyboardInteractive,
 }
 
+// passwords and passphrases typed in at a prompt, so retries, upload workers
+// and the jump host don't ask again
+static PROMPTED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+// libssh2 error codes for a private key that can not be decrypted and a rejected login
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+const LIBSSH2_ERROR_FILE: i32 = -16;
+const LIBSSH2_ERROR_AUTHENTICATION_FAILED: i32 = -18;
+const PROMPT_ATTEMPTS: usize = 3;
+
+// ask on the terminal with hidden input unless the keyring has it,
+// `again` skips what was typed in or stored before
+fn prompt_secret(key: &str, prompt: &str, again: bool) -> io::Result<String> {
+    let mut prompted = PROMPTED.lock().unwrap();
+    if !again {
+        if let Some((_, secret)) = prompted.iter().find(|(k, _)| k == key) {
+            return Ok(secret.clone());
+        }
+        if let Some(secret) = keyring::get(key) {
+            prompted.push((key.to_string(), secret.clone()));
+            return Ok(secret);
+        }
+    }
+    let secret = rpassword::prompt_password(prompt)?;
+    prompted.retain(|(k, _)| k != key);
+    prompted.push((key.to_string(), secret.clone()));
+    Ok(secret)
+}
+
+fn error_code(e: &ssh2::Error) -> Option<i32> {
+    match e.code() {
+        ssh2::ErrorCode::Session(code) => Some(code),
+        ssh2::ErrorCode::SFTP(_) => None,
+    }
+}
+
+// answers keyboard-interactive challenges (e.g. Duo/OTP) on the local terminal
+struct TerminalPrompt;
+
+impl ssh2::KeyboardInteractivePrompt for TerminalPrompt {
+    fn prompt<'a>(
+        &mut self,
+        _username: &str,
+        instructions: &str,
+        prompts: &[ssh2::Prompt<'a>],
+    ) -> Vec<String> {
+        if !instructions.is_empty() {
+            eprintln!("{}", instructions);
+        }
+        prompts
+            .iter()
+            .map(|prompt| {
+                if prompt.echo {
+                    eprint!("{}", prompt.text);
+                    let _ = io::stderr().flush();
+                    let mut answer = String::new();
+                    let _ = io::stdin().read_line(&mut answer);
+                    answer.trim_end_matches(['\r', '\n']).to_string()
+                } else {
+                    // hide the input for password-type prompts
+                    rpassword::prompt_password(prompt.text.as_ref()).unwrap_or_default()
+                }
+            })
+            .collect()
+    }
+}
+
+// how long to wait for a server address to accept the connection, shorter when there are
+// other addresses to try
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_FALLBACK_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+// directories created on the server without remote_dir_mode
+pub const DIR_MODE: u32 = 0o755;
+// head start of each resolved address of a host before the next one is tried alongside it,
+// as in RFC 8305 (happy eyeballs)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
 pub struct Config {
-    pub server_addr: String,
+    pub server_addr: String, // names the server in caches and history, whichever host is used
+    pub fallback_addrs: Vec<String>, // other login nodes tried when server_addr can not be reached
+    pub addr_order: AddrOrder,
+    pub connect_timeout: Duration,   // per address
+    pub handshake_timeout: Duration, // for the ssh handshake and logging in, once connected
+    pub address_family: AddressFamily,
     pub username: String,
-    pub auth: Auth,
+    pub auth: Vec<Auth>, // tried in order until one is accepted
     pub command: String,
-    pub envs: Vec<String>,
+    pub matrix: Vec<String>, // commands run side by side instead of the command, with --matrix or --each
+    pub parallel: usize,     // how many of the matrix commands run at once
+    pub shell: Option<String>, // e.g. "bash -l -c", the command is passed to it as one argument
+    pub script: Option<PathBuf>, // a local script that is uploaded and run instead of the command
+    pub local_dir: PathBuf,  // the directory that is uploaded and fetched into
+    pub snapshot: Option<Snapshot>, // --rev, a git revision uploaded instead of local_dir
+    pub workdir: Option<PathBuf>, // where the command starts, relative to the uploaded tree
+    pub envs: Vec<(String, String)>,
+    pub templates: BTreeMap<String, String>, // named commands with {placeholders}, from [templates]
     pub no_sync: bool,
+    pub rerun_dir: Option<String>, // an earlier run's directory to run in again, as it was left
+    pub remote_dir: String, // where run directories and workspaces go, relative to the remote home or absolute, may hold $VARIABLES
+    pub remote_dir_expanded: OnceLock<String>, // remote_dir once the server expanded its variables
+    pub remote_dir_mode: Option<u32>, // mode of the directories created, uploaded files get no more than it allows
+    pub strict_host_key_checking: bool,
+    pub jump_host: Option<String>,
+    pub proxy: Option<Proxy>, // socks5 or http proxy the connection to the server or jump host goes through
+    pub pty: bool,
+    pub stdin: bool, // forward local stdin to the command, otherwise its stdin is closed right away
+    pub detach: bool, // start the command in the background on the server and return
+    pub prefix: Option<Prefix>, // tag every line of output with its stream or a label
+    pub filter: OutputFilter, // --grep and --highlight
+    pub encoding: Encoding, // what the command's output is transcoded from for display
+    pub color: Color,
+    pub fetch: Vec<String>,
+    pub log_file: Option<PathBuf>, // a copy of the command's output, with timestamps
+    pub log_runs: bool,            // one log per run in ~/.cserun/logs when there is no log_file
+    pub save_output: bool,         // keep stdout and stderr of every run in ~/.cserun/runs
+    pub sync_back: SyncBack,
+    pub workspace: bool,
+    pub workspace_name: Option<String>,
+    pub transfer: Transfer,
+    pub jobs: usize,
+    pub progress: bool,
+    pub keep: bool,
+    pub notify: bool, // a desktop notification when the command is done
+    pub bell: bool,
+    pub timeout: Option<Duration>,
+    pub keepalive_interval: u32,
+    pub control_persist: Option<Duration>, // keep the connection in a daemon for the next runs
+    pub retries: u32,
+    pub cancel: CancelToken, // stops the run when cancelled from another thread
+    pub transfer_backend: TransferBackend,
+    pub compress: bool,
+    pub walk: WalkOptions,
+    pub max_upload_size: Option<u64>,
+    pub limit_rate: Option<u64>, // bytes per second for all uploads together
+    pub cache: bool, // copy files already uploaded by an earlier run from the cache in remote_dir on the server
+    pub cache_max_age: Duration, // cached files unused for longer are removed, in whole days
+    pub delta: bool, // send only the changed blocks of large files in a workspace
+    pub delete: bool, // remove files from the workspace that were deleted locally
+    pub force: bool, // upload even when the server looks short of space
+    pub verify: bool, // compare checksums of the uploaded tree before running anything
+    pub timings: bool, // print how long each phase of the run took
+    pub forwards: Vec<Forward>, // local ports tunnelled to the server while the command runs
+    pub x11: bool,   // programs on the server show their windows locally
+    pub forward_agent: bool, // ssh and git in the command use the keys of the local agent
+    pub requires: Vec<Requirement>, // programs checked for on the server before uploading
+    pub hooks: Hooks,
+    pub target_host: Option<String>, // a machine reached with ssh from the server, e.g. a lab box
+    pub limits: Limits,              // nice, ulimit or timeout around the remote command
+    pub slurm: Slurm, // submit the command as a batch job instead of running it on the server
+    pub backend: Backend, // where commands run, the server unless trying things locally
+    pub image: Option<String>, // the container image of the docker and podman backends
+}
+
+impl Config {
+    // where run directories and workspaces go, with its variables expanded once connected
+    pub fn remote_dir(&self) -> &str {
+        match self.remote_dir_expanded.get() {
+            Some(dir) => dir,
+            None => &self.remote_dir,
+        }
+    }
+
+    // where the uploaded files are taken from
+    pub fn upload_dir(&self) -> &Path {
+        match &self.snapshot {
+            Some(snapshot) => &snapshot.dir,
+            None => &self.local_dir,
+        }
+    }
+}
+
+// the settings of a run before a config file or the builder changed anything
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            server_addr: String::new(),
+            fallback_addrs: Vec::new(),
+            addr_order: AddrOrder::Listed,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            address_family: AddressFamily::Any,
+            username: String::new(),
+            auth: Vec::new(),
+            command: String::new(),
+            matrix: Vec::new(),
+            parallel: matrix::DEFAULT_PARALLEL,
+            shell: None,
+            script: None,
+            local_dir: PathBuf::from("./"),
+            snapshot: None,
+            workdir: None,
+            envs: Vec::new(),
+            templates: BTreeMap::new(),
+            no_sync: false,
+            rerun_dir: None,
+            remote_dir: ".cserun".to_string(),
+            remote_dir_mode: None,
+            remote_dir_expanded: OnceLock::new(),
+            encoding: Encoding::Raw,
+            color: Color::Never,
+            requires: Vec::new(),
+            hooks: Hooks::default(),
+            target_host: None,
+            limits: Limits::default(),
+            slurm: Slurm::default(),
+            backend: Backend::Ssh,
+            image: None,
+            timings: false,
+            strict_host_key_checking: false,
+            jump_host: None,
+            proxy: None,
+            detach: false,
+            pty: false,
+            stdin: true,
+            prefix: None,
+            filter: OutputFilter::default(),
+            fetch: Vec::new(),
+            log_file: None,
+            log_runs: false,
+            save_output: false,
+            sync_back: SyncBack::Never,
+            workspace: false,
+            workspace_name: None,
+            transfer: Transfer::Sftp,
+            jobs: 1,
+            progress: false,
+            keep: false,
+            notify: false,
+            bell: false,
+            timeout: None,
+            keepalive_interval: 30,
+            control_persist: None,
+            retries: 3,
+            cancel: CancelToken::default(),
+            transfer_backend: TransferBackend::Auto,
+            compress: false,
+            walk: WalkOptions::default(),
+            max_upload_size: None,
+            limit_rate: None,
+            cache: false,
+            cache_max_age: sync::CACHE_MAX_AGE,
+            delta: true,
+            delete: false,
+            force: false,
+            verify: false,
+            forwards: Vec::new(),
+            x11: false,
+            forward_agent: false,
+        }
+    }
+}
+
+impl Default for WalkOptions {
+    fn default() -> WalkOptions {
+        WalkOptions {
+            symlinks: Symlinks::Follow,
+            ignore: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            paths: Vec::new(),
+            ignore_files: IgnoreFiles::default(),
+            default_excludes: true,
+            git_only: None,
+            max_file_size: None,
+            skip_binaries: false,
+            hidden: None,
+        }
+    }
+}
+
+// what to do with files the command changed in the container dir
+#[derive(Clone, Copy, PartialEq)]
+pub enum SyncBack {
+    Never,
+    Ask, // only on a terminal, otherwise they are just listed
+    Always,
+}
+
+// the order the server addresses are tried in
+#[derive(Clone, Copy, PartialEq)]
+pub enum AddrOrder {
+    Listed,      // the last one that worked first, then as listed
+    Random,      // the last one that worked first, then shuffled to spread the load
+    LeastLoaded, // by the load average per cpu, probed over short sessions of their own
+}
+
+// which of a host's resolved addresses are connected to, like ssh's -4 and -6
+#[derive(Clone, Copy, PartialEq)]
+pub enum AddressFamily {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => addr.is_ipv4(),
+            AddressFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+// whether remote programs are told to print colors, they see no terminal without --pty
+#[derive(Clone, Copy, PartialEq)]
+pub enum Color {
+    Never,
+    Auto, // when the local stdout is a terminal
+    Always,
+}
+
+pub enum Transfer {
+    Sftp,
+    Tar(TarCompression),
+}
+
+pub enum TarCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+// which local files take part in an upload
+#[derive(Clone)]
+pub struct WalkOptions {
+    pub symlinks: Symlinks,
+    pub ignore: Vec<String>,  // gitignore style patterns from the config
+    pub include: Vec<String>, // globs from --include, only matching files are uploaded
+    pub exclude: Vec<String>, // globs from --exclude
+    pub paths: Vec<PathBuf>,  // --path, only these are walked, relative to the local dir
+    pub ignore_files: IgnoreFiles,
+    pub default_excludes: bool, // leave out sync::DEFAULT_EXCLUDES, build output and the like
+    pub git_only: Option<GitFiles>, // only what git knows of, on top of the other rules
+    pub max_file_size: Option<u64>, // files larger than this are left out
+    pub skip_binaries: bool,    // files that look binary are left out
+    pub hidden: Option<bool>,   // upload dotfiles, unset leaves them out and says which
+}
+
+// the files --git-only takes from git
+#[derive(Clone, Copy, PartialEq)]
+pub enum GitFiles {
+    Tracked,   // `git ls-files`, staged new files included
+    Untracked, // also untracked files that are not ignored
+}
+
+// which ignore files are read, all of them by default
+#[derive(Clone, Copy)]
+pub struct IgnoreFiles {
+    pub gitignore: bool,    // .gitignore
+    pub ignore: bool,       // .ignore
+    pub cserunignore: bool, // .cserunignore
+    pub git_global: bool,   // git's core.excludesFile, ~/.config/git/ignore unless set
+    pub git_exclude: bool,  // .git/info/exclude
+    pub outside_git: bool, // also read .gitignore and the git excludes when the directory is not a git repository
+}
+
+impl Default for IgnoreFiles {
+    fn default() -> IgnoreFiles {
+        IgnoreFiles {
+            gitignore: true,
+            ignore: true,
+            cserunignore: true,
+            git_global: true,
+            git_exclude: true,
+            outside_git: false,
+        }
+    }
+}
+
+// what to do with symlinks found while uploading
+#[derive(Clone, Copy)]
+pub enum Symlinks {
+    Follow,   // upload the contents of the target
+    Preserve, // recreate the link on the remote side
+    Skip,
+}
+
+pub enum TransferBackend {
+    Auto,
+    Sftp,
+    Scp,
+}
+
+// an authenticated session with its sftp subsystem, shared by every subcommand.
+// sftp is None on servers where the subsystem is disabled, files then go over scp and exec
+pub struct Connection {
+    pub sess: Session,
+    pub addr: String, // the address connected to, one of the candidates
+    pub sftp: Option<ssh2::Sftp>,
+    pub timings: Timings,
+    pub dir_mode: u32, // for the directories it creates, see remote_dir_mode
+}
+
+// how long each phase of a run took, to tell a slow network from a slow command
+#[derive(Clone, Copy, Default)]
+pub struct Timings {
+    pub connect: Duration, // the tcp connection and the ssh handshake, zero for a reused connection
+    pub auth: Duration,
+    pub sync: Duration,
+    pub exec: Duration,
+}
+
+impl Connection {
+    pub fn mkdir(&self, path: &Path) -> error::Result<()> {
+        match &self.sftp {
+            Some(sftp) => sftp_mkdir_recursive(sftp, path, self.dir_mode),
+            None => {
+                // -m only applies to the last directory, the parents are shared ones
+                run_remote(
+                    &self.sess,
+                    &format!(
+                        "mkdir -p -m {:o} -- {}",
+                        self.dir_mode,
+                        shell_quote(&remote_str(path))
+                    ),
+                )
+                .context(CserunError::Sftp)?;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write_file(&self, path: &Path, contents: &[u8]) -> error::Result<()> {
+        match &self.sftp {
+            Some(sftp) => (|| -> Result<(), error::Source> {
+                sftp.create(path)?.write_all(contents)?;
+                Ok(())
+            })()
+            .context(CserunError::Sftp),
+            None => (|| -> Result<(), error::Source> {
+                let mut channel = self
+                    .sess
+                    .scp_send(path, 0o644, contents.len() as u64, None)?;
+                channel.write_all(contents)?;
+                channel.send_eof()?;
+                channel.wait_eof()?;
+                channel.wait_close()?;
+                Ok(())
+            })()
+            .context(CserunError::Transfer),
+        }
+    }
+
+    pub fn download_dir(
+        &self,
+        remote_base_path: &Path,
+        local_base_path: &Path,
+        patterns: &globset::GlobSet,
+        progress: bool,
+    ) -> error::Result<usize> {
+        match &self.sftp {
+            Some(sftp) => {
+                sync::download_dir(sftp, remote_base_path, local_base_path, patterns, progress)
+            }
+            None => sync::download_dir_scp(
+                &self.sess,
+                remote_base_path,
+                local_base_path,
+                patterns,
+                progress,
+            ),
+        }
+    }
+
+    // move a remote file or directory, to a name that does not exist yet
+    pub fn rename(&self, from: &Path, to: &Path) -> error::Result<()> {
+        match &self.sftp {
+            Some(sftp) => sftp.rename(from, to, None).context(CserunError::Sftp),
+            None => {
+                run_remote(
+                    &self.sess,
+                    &format!(
+                        "mv -- {} {}",
+                        shell_quote(&remote_str(from)),
+                        shell_quote(&remote_str(to))
+                    ),
+                )
+                .context(CserunError::Sftp)?;
+                Ok(())
+            }
+        }
+    }
+
+    // create a single directory, false if something is there already
+    pub fn create_dir(&self, path: &Path) -> error::Result<bool> {
+        match &self.sftp {
+            Some(sftp) => match sftp.mkdir(path, self.dir_mode as i32) {
+                Ok(()) => Ok(true),
+                Err(_) if sftp.stat(path).is_ok() => Ok(false),
+                Err(e) => Err(e).context(CserunError::Sftp),
+            },
+            None => {
+                let output = run_remote(
+                    &self.sess,
+                    &format!(
+                        "if mkdir -m {mode:o} -- {path}; then echo created; elif [ -e {path} ]; then echo exists; else exit 1; fi",
+                        mode = self.dir_mode,
+                        path = shell_quote(&remote_str(path))
+                    ),
+                )
+                .context(CserunError::Sftp)?;
+                Ok(output.starts_with(b"created"))
+            }
+        }
+    }
+
+    pub fn exists(&self, path: &Path) -> bool {
+        match &self.sftp {
+            Some(sftp) => sftp.stat(path).is_ok(),
+            None => run_remote(
+                &self.sess,
+                &format!("test -e {}", shell_quote(&remote_str(path))),
+            )
+            .is_ok(),
+        }
+    }
+
+    // subdirectories of a remote dir, empty if it does not exist
+    pub fn list_dirs(&self, path: &Path) -> error::Result<Vec<PathBuf>> {
+        let dirs = match &self.sftp {
+            Some(sftp) => sftp
+                .readdir(path)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(_, stat)| stat.is_dir())
+                .map(|(path, _)| path)
+                .collect(),
+            None => {
+                let output = run_remote(
+                    &self.sess,
+                    &format!(
+                        "find {} -mindepth 1 -maxdepth 1 -type d 2>/dev/null; true",
+                        shell_quote(&remote_str(path))
+                    ),
+                )
+                .context(CserunError::Sftp)?;
+                String::from_utf8_lossy(&output)
+                    .lines()
+                    .map(PathBuf::from)
+                    .collect()
+            }
+        };
+        Ok(dirs)
+    }
+}
+
+pub fn connect(conf: &Config) -> error::Result<Connection> {
+    // in the control daemon, the connection of the first run is used again
+    #[cfg(unix)]
+    if let Some(sess) = control::held(conf) {
+        info!(
+            "{} {} Reusing the connection to {}",
+            style("[1/5]").bold().dim(),
+            NETWORK,
+            style(&conf.server_addr).italic().cyan()
+        );
+        expand_remote_dir(&sess, conf)?;
+        let sftp = open_sftp_subsystem(&sess, conf)?;
+        return Ok(Connection {
+            sess,
+            addr: conf.server_addr.clone(),
+            sftp,
+            timings: Timings::default(),
+            dir_mode: conf.remote_dir_mode.unwrap_or(DIR_MODE),
+        });
+    }
+
+    let started = Instant::now();
+    let (tcp, addr) = failover::connect(conf, |addr| open_tcp(conf, addr))?;
+    match &conf.jump_host {
+        Some(jump) => info!(
+            "{} {} Connecting to {} via {}",
+            style("[1/5]").bold().dim(),
+            NETWORK,
+            style(&addr).italic().cyan(),
+            style(jump).italic().cyan()
+        ),
+        None => info!(
+            "{} {} Connecting to {}",
+            style("[1/5]").bold().dim(),
+            NETWORK,
+            style(&addr).italic().cyan()
+        ),
+    }
+    events::emit("connected", &[("host", addr.as_str().into())]);
+
+    let sess = handshake(tcp, &addr, conf)?;
+    info!(
+        "{} {} Handshake successful",
+        style("[2/5]").bold().dim(),
+        CLIP
+    );
+    let connected = Instant::now();
+
+    // a saved password belongs to the server, not to whichever of its nodes answered
+    let (account_host, _) = split_addr(&conf.server_addr)?;
+    authenticate_timed(&sess, &conf.username, account_host, conf)?;
+    info!(
+        "{} {} Authenticated as {}",
+        style("[3/5]").bold().dim(),
+        LOCK,
+        style(&conf.username).italic().green()
+    );
+    check_platform(&sess)?;
+    #[cfg(unix)]
+    control::hold(conf, &sess);
+
+    expand_remote_dir(&sess, conf)?;
+    let sftp = open_sftp_subsystem(&sess, conf)?;
+    Ok(Connection {
+        sess,
+        addr,
+        sftp,
+        timings: Timings {
+            connect: connected - started,
+            auth: connected.elapsed(),
+            ..Timings::default()
+        },
+        dir_mode: conf.remote_dir_mode.unwrap_or(DIR_MODE),
+    })
+}
+
+// every remote command line is written for a POSIX shell. OpenSSH for Windows runs them with
+// cmd.exe or PowerShell unless its DefaultShell is set to e.g. Git Bash, so a server that says
+// it is Windows in its banner is asked whether it has a POSIX shell before anything is sent
+fn check_platform(sess: &Session) -> error::Result<()> {
+    let banner = sess.banner().unwrap_or_default();
+    if !banner.contains("Windows") {
+        return Ok(());
+    }
+    match run_remote(sess, "uname -s") {
+        Ok(system) => {
+            // e.g. MINGW64_NT or CYGWIN_NT, which run commands like a Linux server would
+            info!(
+                "The server runs Windows with a POSIX shell ({}), file modes may not apply",
+                String::from_utf8_lossy(&system).trim()
+            );
+            Ok(())
+        }
+        Err(_) => Err(CserunError::Exec(
+            format!(
+                "Unsupported remote platform: the server ({}) does not run commands in a POSIX \
+                 shell. Set the DefaultShell of its OpenSSH to bash, e.g. of Git Bash or WSL",
+                banner
+            )
+            .into(),
+        )),
+    }
+}
+
+// a remote_dir with $VARIABLES in it, e.g. "/scratch/$USER/cserun", is expanded by the server's
+// sh once per run. an unset variable is an error rather than a directory at the top of /
+fn expand_remote_dir(sess: &Session, conf: &Config) -> error::Result<()> {
+    if !conf.remote_dir.contains('$') || conf.remote_dir_expanded.get().is_some() {
+        return Ok(());
+    }
+    // parse made sure there is no $( in it, so only the variables are expanded
+    let value = conf
+        .remote_dir
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('`', "\\`");
+    let script = format!("set -u; printf '%s' \"{}\"", value);
+    let output = run_remote(sess, &format!("sh -c {}", shell_quote(&script)))
+        .map_err(|e| {
+            format!(
+                "Could not expand remote_dir {:?} on the server: {}",
+                conf.remote_dir, e
+            )
+        })
+        .context(CserunError::Config)?;
+    let dir = String::from_utf8_lossy(&output)
+        .trim_end_matches('/')
+        .to_string();
+    if dir.is_empty() || dir.split('/').any(|part| part == "..") {
+        return Err(CserunError::Config(
+            format!(
+                "remote_dir {:?} expands to {:?} on the server",
+                conf.remote_dir, dir
+            )
+            .into(),
+        ));
+    }
+    debug!("remote_dir {:?} is {}", conf.remote_dir, dir);
+    let _ = conf.remote_dir_expanded.set(dir);
+    Ok(())
+}
+
+// sftp as the transfer backend asks for, None to use scp
+fn open_sftp_subsystem(sess: &Session, conf: &Config) -> error::Result<Option<ssh2::Sftp>> {
+    Ok(match conf.transfer_backend {
+        TransferBackend::Sftp => Some(sess.sftp().context(CserunError::Sftp)?),
+        TransferBackend::Scp => None,
+        TransferBackend::Auto => match sess.sftp() {
+            Ok(sftp) => Some(sftp),
+            Err(e) => {
+                warn!("SFTP is not available ({}), falling back to scp", e);
+                None
+            }
+        },
+    })
+}
+
+// the persistent workspace of the local directory, and the id of its upload manifest
+fn workspace(conf: &Config) -> (PathBuf, String) {
+    let workspace_name = match &conf.workspace_name {
+        Some(name) => name.clone(),
+        None => cache::workspace_id(&conf.server_addr, &conf.username, &conf.local_dir),
+    };
+    let manifest_id = cache::manifest_id(&conf.server_addr, &conf.username, &workspace_name);
+    (
+        PathBuf::from(format!(
+            "{}/workspaces/{}",
+            conf.remote_dir(),
+            workspace_name
+        )),
+        manifest_id,
+    )
+}
+
+// upload the local directory into the container dir, honouring the workspace and transfer settings.
+// returns the number of files and bytes uploaded, and how many files were skipped as unchanged or
+// left out by --max-file-size and --skip-binaries
+pub fn sync_files(
+    conn: &Connection,
+    conf: &Config,
+    container_path: &Path,
+    manifest_id: &str,
+    resume: bool,
+) -> error::Result<((usize, u64), usize)> {
+    conf.cancel.check("before uploading")?;
+    let sess = &conn.sess;
+    let local_path = conf.upload_dir();
+    let started = Instant::now();
+    let limit = conf.limit_rate.map(RateLimit::new);
+    let ((uploaded, bytes), unchanged) = match (&conf.transfer, &conn.sftp) {
+        (Transfer::Tar(compression), _) if !conf.workspace => {
+            let (count, bytes) = sync::scan(local_path, &conf.walk)?;
+            confirm_upload(sess, conf, count, bytes, resume)?;
+            let sent = sync::upload_tar(
+                sess,
+                local_path,
+                container_path,
+                compression,
+                &conf.walk,
+                conf.progress,
+                limit.as_ref(),
+            )?;
+            (sent, 0)
+        }
+        // without sftp there is no way to compare with the remote side, send everything as tar
+        (transfer, None) => {
+            if conf.workspace && conf.delete {
+                warn!("Deleting removed files from the workspace needs SFTP, they are left there");
+            }
+            let compression = match transfer {
+                Transfer::Tar(compression) => compression,
+                Transfer::Sftp => &TarCompression::None,
+            };
+            let (count, bytes) = sync::scan(local_path, &conf.walk)?;
+            confirm_upload(sess, conf, count, bytes, resume)?;
+            let sent = sync::upload_tar(
+                sess,
+                local_path,
+                container_path,
+                compression,
+                &conf.walk,
+                conf.progress,
+                limit.as_ref(),
+            )?;
+            (sent, 0)
+        }
+        (_, Some(sftp)) => {
+            let mut manifest = match conf.workspace {
+                true => Some(Manifest::load(manifest_id)?),
+                false => None,
+            };
+            sftp_mkdir_recursive(sftp, container_path, conn.dir_mode)?;
+            let pb = sync::sync_spinner(conf.progress)?;
+            let (files, unchanged) = sync::plan_upload(
+                sftp,
+                local_path,
+                container_path,
+                manifest.as_mut(),
+                resume,
+                conf,
+                &pb,
+            )?;
+            if let (true, Some(manifest)) = (conf.delete, manifest.as_mut()) {
+                let deleted = sync::delete_removed(sftp, local_path, container_path, manifest)?;
+                if deleted > 0 {
+                    info!(
+                        "Deleted {} file(s) from the workspace that are gone locally",
+                        deleted
+                    );
+                }
+            }
+            // every extra worker needs its own session, checked against the same host key
+            let host_key = sess.host_key().map(|(key, _)| key.to_vec());
+            let connect = || {
+                open_sftp(conf, &conn.addr, host_key.as_deref()).map_err(|e| e.report().to_string())
+            };
+            // extra sessions would ask for the 2FA code again
+            let jobs = match conf
+                .auth
+                .iter()
+                .any(|auth| matches!(auth, Auth::KeyboardInteractive))
+            {
+                true => 1,
+                false => conf.jobs,
+            };
+            pb.finish_and_clear();
+            let (files, to_cache, reused) = match conf.cache {
+                true => sync::reuse_cached(sess, sftp, files, manifest.as_mut(), conf)?,
+                false => (files, Vec::new(), 0),
+            };
+            if reused > 0 {
+                info!("Copied {} file(s) from the server cache", reused);
+            }
+            let (files, patched, patch_bytes) = match conf.delta {
+                true => sync::upload_deltas(sess, sftp, files, manifest.as_mut(), limit.as_ref())?,
+                false => (files, 0, 0),
+            };
+            if patched > 0 {
+                info!(
+                    "Sent {} changed file(s) as deltas, {}",
+                    patched,
+                    HumanBytes(patch_bytes)
+                );
+            }
+            let to_sign = match conf.delta {
+                true => sync::signature_entries(&files),
+                false => Vec::new(),
+            };
+            let bytes = files.iter().map(|file| file.size).sum();
+            confirm_upload(sess, conf, files.len(), bytes, resume)?;
+            let result = sync::upload_files(
+                sftp,
+                files,
+                manifest.as_mut(),
+                jobs,
+                &connect,
+                conf,
+                limit.as_ref(),
+            );
+            if result.is_ok() {
+                sync::store_cached(sess, &to_cache, conf);
+                sync::store_signatures(&to_sign);
+            }
+            if conf.cache {
+                sync::prune_cache(sess, conf);
+            }
+            // keep track of whatever made it across, even if the upload failed midway
+            if let Some(manifest) = &manifest {
+                manifest.save(manifest_id)?;
+            }
+            let (count, bytes) = result?;
+            ((count + patched, bytes + patch_bytes), unchanged + reused)
+        }
+    };
+    // the effective rate, which is what ssh compression improves on text heavy trees
+    let seconds = started.elapsed().as_secs_f64().max(0.001);
+    events::emit(
+        "upload_finished",
+        &[
+            ("files", uploaded.into()),
+            ("unchanged", unchanged.into()),
+            ("bytes", bytes.into()),
+            ("seconds", seconds.into()),
+        ],
+    );
+    info!(
+        "{} {} Synced local files to remote ({} uploaded, {} unchanged, {} at {}/s)",
+        style("[4/5]").bold().dim(),
+        TRUCK,
+        uploaded,
+        unchanged,
+        HumanBytes(bytes),
+        HumanBytes((bytes as f64 / seconds) as u64)
+    );
+    let left_out = sync::report_skipped(conf.walk.max_file_size);
+    if let Some(mode) = conf.remote_dir_mode {
+        restrict_modes(sess, container_path, mode)?;
+    }
+    if conf.verify {
+        let checked = sync::verify_upload(sess, local_path, container_path, &conf.walk)?;
+        info!("Verified {} file(s) against their local sha256", checked);
+    }
+    Ok(((uploaded, bytes), unchanged + left_out))
+}
+
+// remote_dir_mode for what the upload left with other modes: a tar extracts with the server's
+// umask, the cache and deltas copy files, and a workspace has directories from before
+fn restrict_modes(sess: &Session, container_path: &Path, mode: u32) -> error::Result<()> {
+    let path = shell_quote(&remote_str(container_path));
+    let mut command = format!(
+        "chmod {mode:o} {path} && find {path} -type d -exec chmod {mode:o} {{}} +",
+        mode = mode,
+        path = path
+    );
+    // the group and others bits the directories do not have, the owner's are all there
+    let removed = !mode & 0o077;
+    let classes = [("g", removed >> 3), ("o", removed & 0o7)];
+    let symbolic: Vec<String> = classes
+        .iter()
+        .filter(|(_, bits)| *bits != 0)
+        .map(|(class, bits)| {
+            let perms: String = [(0o4, 'r'), (0o2, 'w'), (0o1, 'x')]
+                .iter()
+                .filter(|(bit, _)| bits & bit != 0)
+                .map(|(_, perm)| perm)
+                .collect();
+            format!("{}-{}", class, perms)
+        })
+        .collect();
+    if !symbolic.is_empty() {
+        command.push_str(&format!(
+            " && find {} ! -type d ! -type l -exec chmod {} {{}} +",
+            path,
+            symbolic.join(",")
+        ));
+    }
+    run_remote(sess, &command).context(CserunError::Transfer)?;
+    Ok(())
+}
+
+// say how much is about to be sent, and ask first when it is more than max_upload_size
+fn confirm_upload(
+    sess: &Session,
+    conf: &Config,
+    count: usize,
+    bytes: u64,
+    resume: bool,
+) -> error::Result<()> {
+    info!(
+        "{} {} Uploading {} file(s), {}",
+        style("[4/5]").bold().dim(),
+        TRUCK,
+        count,
+        HumanBytes(bytes)
+    );
+    events::emit(
+        "upload_started",
+        &[("files", count.into()), ("bytes", bytes.into())],
+    );
+    check_remote_space(sess, conf, bytes)?;
+    let limit = match conf.max_upload_size {
+        Some(limit) if bytes > limit && !resume => limit,
+        _ => return Ok(()),
+    };
+    eprintln!(
+        "{}",
+        style(format!(
+            "This is more than max_upload_size ({}), check your ignore rules.",
+            HumanBytes(limit)
+        ))
+        .yellow()
+    );
+    eprint!("Upload anyway? [y/N]: ");
+    io::stderr().flush().context(CserunError::Local)?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context(CserunError::Local)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(CserunError::Transfer(
+            "Upload aborted, it is larger than max_upload_size".into(),
+        )),
+    }
+}
+
+// refuse an upload the server has no room for, a full quota otherwise fails halfway
+// through with a confusing sftp error
+fn check_remote_space(sess: &Session, conf: &Config, bytes: u64) -> error::Result<()> {
+    if conf.force || bytes == 0 {
+        return Ok(());
+    }
+    let Some(available) = sync::remote_space(sess) else {
+        return Ok(());
+    };
+    debug!("{} free on the server", HumanBytes(available));
+    match bytes > available {
+        true => Err(CserunError::Transfer(
+            format!(
+                "Insufficient remote space (need {}, have {}), free some space or use --force",
+                HumanBytes(bytes),
+                HumanBytes(available)
+            )
+            .into(),
+        )),
+        false => Ok(()),
+    }
+}
+
+// join the commands given with -c into one, run in order in the same directory.
+// braces instead of subshells, so a `cd` or a variable carries over to the next command
+pub fn chain_commands(commands: &[String], keep_going: bool) -> String {
+    if let [command] = commands {
+        return command.clone();
+    }
+    let groups = commands.iter().map(|command| format!("{{ {}\n}}", command));
+    match keep_going {
+        false => groups.collect::<Vec<_>>().join(" && "),
+        true => format!(
+            "{{ status=0; {}; exit $status; }}",
+            groups
+                .map(|group| format!("{} || status=$?", group))
+                .collect::<Vec<_>>()
+                .join("; ")
+        ),
+    }
+}
+
+// what runs in the container dir, the command or the uploaded script after the pre_run hook
+fn remote_command(conf: &Config, run_dir: &RunDir) -> String {
+    let command = match conf.script {
+        Some(_) => {
+            let path = shell_path(run_dir.script_path());
+            format!("chmod +x {} && {}", path, path)
+        }
+        None => conf.command.clone(),
+    };
+    hooks::with_pre_run(&conf.hooks, command, run_dir)
+}
+
+// the full remote command line, `exports` are the variables the server refused to setenv
+fn command_line(
+    conf: &Config,
+    command: &str,
+    pid_file: &str,
+    workdir: &Path,
+    exports: &[(String, String)],
+) -> String {
+    let mut pre_exec_command = String::new();
+    for (key, value) in exports {
+        pre_exec_command.push_str(&format!("export {}={} && ", key, shell_quote(value)));
+    }
+    // before exec, cd to the remote dir. a failed setup exits with its own status,
+    // and the user command is a separate list so its `||` and `;` apply to it alone
+    pre_exec_command.push_str(&format!(
+        "cd {} || exit {}; ",
+        shell_quote(&remote_str(workdir)),
+        SETUP_FAILED
+    ));
+    let command = format!("{}{}", pre_exec_command, command);
+    // the pid is written before the exec, so it stays the process group the signals go to
+    let pid = format!(
+        "echo $$ > {} || exit {}; ",
+        shell_quote(pid_file),
+        SETUP_FAILED
+    );
+    let line = format!(
+        "{}{}",
+        pid,
+        limits::wrap(&conf.limits, conf.shell.as_deref(), &command)
+    );
+    // with --target-host the pid is the one on the target, where the signals go too
+    on_target(conf, &line)
+}
+
+// a command for the server run on the --target-host instead, with ssh from the server. the
+// target shares the home directory, so the paths in the run directory are the same there
+fn on_target(conf: &Config, command: &str) -> String {
+    match &conf.target_host {
+        Some(host) => format!(
+            "exec ssh -o BatchMode=yes {}{} {}",
+            if conf.pty { "-tt " } else { "" },
+            shell_quote(host),
+            shell_quote(command)
+        ),
+        None => command.to_string(),
+    }
+}
+
+// the exit status of the wrapper when the run dir can not be entered, checked against the
+// server before it is reported, since the user command may exit with it too
+const SETUP_FAILED: i32 = 254;
+
+// a setup failure is an error of cserun, not the exit status of the command
+fn check_setup(sess: &Session, run_dir: &RunDir, exit_status: i32) -> error::Result<()> {
+    if exit_status != SETUP_FAILED {
+        return Ok(());
+    }
+    let test = format!(
+        "test -d {} && test -f {}",
+        shell_quote(&remote_str(&run_dir.workdir)),
+        shell_quote(&run_dir.pid_file())
+    );
+    match run_remote(sess, &test) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(CserunError::Exec(
+            format!(
+                "The command did not start, {} is missing on the server",
+                display_path(&run_dir.workdir)
+            )
+            .into(),
+        )),
+    }
+}
+
+// drop the login flags from a shell like "bash -l -c", for --no-login
+pub fn without_login(shell: &str) -> String {
+    shell
+        .split_whitespace()
+        .filter_map(|word| match word {
+            "--login" => None,
+            _ if word.starts_with('-') && !word.starts_with("--") => {
+                let flags = word.replace('l', "");
+                (flags != "-").then_some(flags)
+            }
+            _ => Some(word.to_string()),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// show what exec would upload and run, without connecting to the server
+pub fn dry_run(conf: Config) -> error::Result<()> {
+    check_workdir(&conf)?;
+    let local_dir = conf.upload_dir();
+    let run_dir = RunDir::new(&conf);
+
+    println!("{}", style("Files to upload").bold());
+    if let Some(snapshot) = &conf.snapshot {
+        println!("  from {} ({})", snapshot.rev, snapshot.commit);
+    }
+    if conf.no_sync {
+        println!("  (none, --no-sync)");
+    } else {
+        let files = sync::list_files(local_dir, &conf.walk)?;
+        for (path, size) in &files {
+            let path = path.strip_prefix(local_dir).unwrap_or(path);
+            println!("  {} ({})", path.display(), HumanBytes(*size));
+        }
+        let total: u64 = files.iter().map(|(_, size)| size).sum();
+        println!("  {} file(s), {}", files.len(), HumanBytes(total));
+        sync::report_skipped(conf.walk.max_file_size);
+        if conf.workspace {
+            println!("  only new or changed files are sent to the workspace");
+        }
+        if conf.workspace && conf.delete {
+            let (_, manifest_id) = workspace(&conf);
+            for key in sync::removed_files(local_dir, &Manifest::load(&manifest_id)?) {
+                println!("  delete {}", key);
+            }
+        }
+    }
+
+    println!("{}", style("Remote").bold());
+    println!("  server:    {}@{}", conf.username, conf.server_addr);
+    if !conf.fallback_addrs.is_empty() {
+        println!("  fallback:  {}", conf.fallback_addrs.join(", "));
+    }
+    println!("  run dir:   {}", display_path(&run_dir.path));
+    println!("  directory: {}", display_path(&run_dir.workdir));
+    if let Some(script) = &conf.script {
+        println!("  script:    {}", script.display());
+    }
+    if conf.detach {
+        println!(
+            "  detach:    tmux session {}, or nohup without tmux",
+            detach::session_name(&run_dir.name)
+        );
+    }
+    if conf.slurm.enabled {
+        // the command below is what the job runs
+        println!("  slurm:     submitted with sbatch as");
+        let script = slurm::batch_script(&conf, &remote_command(&conf, &run_dir), &run_dir);
+        for line in script.lines() {
+            println!("             {}", line);
+        }
+    }
+    if conf.matrix.is_empty() {
+        // the server may accept setenv instead, but the exports show every variable
+        let command = command_line(
+            &conf,
+            &remote_command(&conf, &run_dir),
+            &run_dir.pid_file(),
+            &run_dir.workdir,
+            &remote_envs(&conf),
+        );
+        println!("  command:   {}", command);
+    } else {
+        println!(
+            "  matrix:    {} command(s), {} at a time",
+            conf.matrix.len(),
+            conf.parallel.max(1)
+        );
+        for command in &conf.matrix {
+            println!("             {}", command);
+        }
+    }
+    if !conf.fetch.is_empty() {
+        println!("  fetch:     {}", conf.fetch.join(", "));
+    }
+    for forward in &conf.forwards {
+        println!(
+            "  forward:   {}:{} -> {}:{}",
+            forward.bind_address, forward.local_port, forward.host, forward.port
+        );
+    }
+    Ok(())
+}
+
+// --workdir has to stay inside the uploaded tree, and exist in it when it is uploaded
+pub fn check_workdir(conf: &Config) -> error::Result<()> {
+    let Some(workdir) = &conf.workdir else {
+        return Ok(());
+    };
+    if !sync::is_inside(workdir) {
+        return Err(CserunError::Config(
+            format!(
+                "Working directory {} is outside the uploaded directory",
+                workdir.display()
+            )
+            .into(),
+        ));
+    }
+    if !conf.no_sync && !conf.upload_dir().join(workdir).is_dir() {
+        let uploaded = match &conf.snapshot {
+            Some(snapshot) => format!("{} at {}", conf.local_dir.display(), snapshot.rev),
+            None => conf.local_dir.display().to_string(),
+        };
+        return Err(CserunError::Config(
+            format!(
+                "Working directory {} is not a directory in {}",
+                workdir.display(),
+                uploaded
+            )
+            .into(),
+        ));
+    }
+    Ok(())
+}
+
+// where a run lives on the remote side, paths are relative to the remote home
+pub struct RunDir {
+    pub name: String,
+    pub path: String,
+    pub container_path: PathBuf, // where the local directory is uploaded to
+    pub workdir: PathBuf,        // the command's working directory, inside container_path
+    pub manifest_id: String,
+    pub reused: bool, // an earlier run's directory, it stays for that run's owner to clean
+}
+
+impl RunDir {
+    pub fn new(conf: &Config) -> RunDir {
+        // named after the local time it starts at, with the pid and a random part so runs started
+        // in the same millisecond, e.g. by make -j, get their own.
+        // e.g. ~/.cserun/temp/2024-02-14-01-10-40-224-4242-9f3a/
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        let name = format!(
+            "{}-{}-{:04x}",
+            chrono::Local::now().format("%Y-%m-%d-%H-%M-%S-%3f"),
+            std::process::id(),
+            hasher.finish() as u16
+        );
+        // ssh2's sftp use ~/ as root, no need to add ~/
+        let path = match &conf.rerun_dir {
+            Some(dir) => dir.clone(),
+            None => format!("{}/temp/{}", conf.remote_dir(), name),
+        };
+        // workspace runs reuse one remote dir per project
+        let (workspace_path, manifest_id) = workspace(conf);
+        let container_path = if conf.workspace {
+            workspace_path
+        } else {
+            Path::new(&path).join("container")
+        };
+        let workdir = match &conf.workdir {
+            Some(workdir) => container_path.join(workdir),
+            None => container_path.clone(),
+        };
+        RunDir {
+            name,
+            path,
+            container_path,
+            workdir,
+            manifest_id,
+            reused: conf.rerun_dir.is_some(),
+        }
+    }
+
+    // remember the remote shell's pid, its process group is what ctrl-c has to stop
+    pub fn pid_file(&self) -> String {
+        format!("{}/pid", self.path)
+    }
+
+    // where the files are uploaded to before they become the container dir, so a failed
+    // upload never leaves a container the command could run in
+    pub fn staging_path(&self) -> PathBuf {
+        Path::new(&self.path).join("container.partial")
+    }
+
+    // where --script is uploaded to, next to the container dir rather than in it
+    pub fn script_path(&self) -> String {
+        format!("{}/script", self.path)
+    }
+}
+
+// a run directory of our own. another run that took the name first only gets this one a new name
+pub fn claim_run_dir(conn: &Connection, conf: &Config) -> error::Result<RunDir> {
+    let mut attempts = 0;
+    let run_dir = loop {
+        let run_dir = RunDir::new(conf);
+        if run_dir.reused {
+            return Ok(run_dir);
+        }
+        let path = Path::new(&run_dir.path);
+        if let Some(parent) = path.parent() {
+            conn.mkdir(parent)?;
+        }
+        if conn.create_dir(path)? {
+            break run_dir;
+        }
+        attempts += 1;
+        if attempts == 5 {
+            return Err(CserunError::Exec(
+                format!(
+                    "Could not find a free name for the run directory, {} is taken",
+                    display_path(path)
+                )
+                .into(),
+            ));
+        }
+        debug!("{} is taken, trying another name", display_path(path));
+    };
+    info!(
+        "{} Run {} in {}",
+        FOLDER,
+        style(&run_dir.name).bold(),
+        style(display_path(&run_dir.path)).italic()
+    );
+    events::emit(
+        "run_dir",
+        &[
+            ("id", run_dir.name.as_str().into()),
+            ("path", run_dir.path.as_str().into()),
+        ],
+    );
+    Ok(run_dir)
+}
+
+// how a remote command ended
+pub struct Finished {
+    pub exit_status: i32,
+    pub interrupted: bool,
+    pub timed_out: bool,
+}
+
+impl Finished {
+    // what cserun itself exits with
+    pub fn code(&self) -> i32 {
+        if self.timed_out {
+            // the same status as timeout(1)
+            124
+        } else if self.interrupted {
+            // same as a shell would report for a command killed by SIGINT
+            130
+        } else {
+            self.exit_status
+        }
+    }
+}
+
+pub fn exec(conf: &Config) -> error::Result<i32> {
+    check_workdir(conf)?;
+    let started = Instant::now();
+    // read before connecting, so a typo in the path fails right away
+    let script = match &conf.script {
+        Some(path) => Some(unix_line_endings(
+            std::fs::read(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))
+                .context(CserunError::Local)?,
+        )),
+        None => None,
+    };
+    if let (Some(hook), false) = (&conf.hooks.pre_sync, conf.no_sync) {
+        hooks::run_local("pre_sync", hook, &conf.local_dir, &[])?;
+    }
+
+    // where the run directory goes may depend on the server's environment, see remote_dir
+    let (conn, run_dir) = with_retries(conf, |_| {
+        let conn = connect(conf)?;
+        let run_dir = claim_run_dir(&conn, conf)?;
+        Ok((conn, run_dir))
+    })?;
+    let mut connected = Some(conn);
+    let remote_dir_path = Path::new(&run_dir.path);
+    let command = remote_command(conf, &run_dir);
+    let rev = conf
+        .snapshot
+        .as_ref()
+        .map(|snapshot| snapshot.commit.as_str());
+    let mut provenance = Provenance::collect(&conf.local_dir, rev, &command);
+    // set while files are on their way up, to know what a failure leaves behind
+    let mut uploading = false;
+    // everything up to starting the command can simply be done again on a new connection
+    let result = with_retries(conf, |resume| {
+        let mut conn = match connected.take() {
+            Some(conn) => conn,
+            None => connect(conf)?,
+        };
+
+        // a rerun without syncing needs what the earlier run left behind
+        if run_dir.reused && !conn.exists(&run_dir.container_path) {
+            return Err(CserunError::Exec(
+                format!(
+                    "{} is no longer on the server, use --resync to upload the files again",
+                    display_path(&run_dir.container_path)
+                )
+                .into(),
+            ));
+        }
+        preflight::check(&conn.sess, conf)?;
+
+        // create the remote dir
+        conn.mkdir(remote_dir_path)?;
+
+        // log the command to command.txt
+        conn.write_file(&remote_dir_path.join("command.txt"), command.as_bytes())?;
+        if let Some(script) = &script {
+            conn.write_file(Path::new(&run_dir.script_path()), script)?;
+        }
+
+        let sync_started = Instant::now();
+        let (uploaded, skipped) = if !conf.no_sync {
+            // the workspace is kept between runs and synced in place, a run dir is uploaded
+            // under another name and only renamed once everything is there
+            let staging = match conf.workspace {
+                true => None,
+                false => Some(run_dir.staging_path()),
+            };
+            uploading = true;
+            let synced = sync_files(
+                &conn,
+                conf,
+                staging.as_ref().unwrap_or(&run_dir.container_path),
+                &run_dir.manifest_id,
+                resume,
+            )
+            .inspect_err(|e| {
+                // a retry resumes from what is there, anything else means the run is over
+                if !conf.workspace && !is_transient(e) {
+                    discard_run_dir(&conn.sess, &run_dir);
+                    uploading = false;
+                }
+            })?;
+            if let Some(staging) = &staging {
+                conn.rename(staging, &run_dir.container_path)?;
+            }
+            uploading = false;
+            synced
+        } else {
+            // only create the container dir
+            conn.mkdir(&run_dir.container_path)?;
+            info!(
+                "{} {} Skipped syncing local files",
+                style("[4/5]").bold().dim(),
+                PROHIBITED
+            );
+            ((0, 0), 0)
+        };
+        conn.timings.sync = sync_started.elapsed();
+        provenance.uploaded(uploaded);
+        // an earlier run's directory keeps the manifest of that run
+        if !run_dir.reused {
+            let manifest = provenance.to_json(&conf.server_addr, &conf.username, &run_dir.path);
+            conn.write_file(&remote_dir_path.join("manifest.json"), manifest.as_bytes())?;
+        }
+
+        if conf.detach {
+            let launcher = start_detached(&conn, conf, &command, &run_dir)?;
+            return Ok((conn, None, (uploaded, skipped), Some(launcher)));
+        }
+        // matrix commands are started one by one as others finish
+        let channel = match conf.matrix.is_empty() {
+            true => Some(start_command(&conn, conf, &command, &run_dir)?),
+            false => None,
+        };
+        Ok((conn, channel, (uploaded, skipped), None))
+    });
+    if result.is_err() && uploading && !conf.workspace {
+        // the connection is gone, nothing to delete it with
+        warn!(
+            "The upload did not finish, its partial files were left in {}",
+            display_path(&run_dir.path)
+        );
+    }
+    let (conn, channel, (uploaded, skipped), launcher) = result?;
+    if let Some(launcher) = launcher {
+        return detached(conf, &run_dir, &launcher, &provenance, started.elapsed());
+    }
+    let sess = &conn.sess;
+
+    let exec_started = Instant::now();
+    let finished = match channel {
+        Some(channel) => wait_command(sess, channel, conf, &run_dir, &command)?,
+        None => run_matrix(&conn, conf, &run_dir, &conf.matrix)?.0,
+    };
+    let timings = Timings {
+        exec: exec_started.elapsed(),
+        ..conn.timings
+    };
+
+    // an interrupted command has nothing worth fetching
+    if !conf.fetch.is_empty() && !finished.interrupted && !finished.timed_out {
+        let patterns = sync::build_globset(&conf.fetch)?;
+        let count = conn.download_dir(
+            &run_dir.container_path,
+            &conf.local_dir,
+            &patterns,
+            conf.progress,
+        )?;
+        info!("{} Fetched {} file(s) from remote", INBOX, count);
+        events::emit("fetch_finished", &[("files", count.into())]);
+    }
+    if conf.sync_back != SyncBack::Never && !finished.interrupted && !finished.timed_out {
+        sync_back(&conn, conf, &run_dir)?;
+    }
+
+    if run_dir.reused {
+        info!(
+            "{} Left {} as it was",
+            FOLDER,
+            style(display_path(&run_dir.path)).italic()
+        );
+    } else if conf.keep {
+        history::record_kept(KeptRun {
+            server_addr: conf.server_addr.clone(),
+            username: conf.username.clone(),
+            remote_dir: run_dir.path.clone(),
+            created: run_dir.name.clone(),
+        })?;
+        info!(
+            "{} Kept remote run directory {}",
+            FOLDER,
+            style(display_path(&run_dir.path)).italic()
+        );
+    } else {
+        remove_remote_dir(sess, &run_dir.path)?;
+    }
+    // the run dir is taken care of, whoever cancelled the run is told so instead of a summary
+    conf.cancel.check("while the command was running")?;
+
+    if finished.timed_out {
+        error!(
+            "Timed out after {}s, the remote command was stopped",
+            conf.timeout.unwrap_or_default().as_secs()
+        );
+    }
+    report_timings(conf, &timings, uploaded, started.elapsed());
+    let kept = run_dir.reused || conf.keep;
+    report_summary(
+        &conf.server_addr,
+        &finished,
+        &timings,
+        (uploaded, skipped),
+        &run_dir.path,
+        kept,
+    );
+    // the run itself went fine, a history that can not be written is only worth a warning
+    let recorded = history::record_run(RunRecord {
+        exit_status: finished.code(),
+        seconds: started.elapsed().as_secs_f64(),
+        ..run_record(conf, &run_dir, &provenance)
+    });
+    if let Err(e) = recorded {
+        warn!("Could not record the run in the history: {}", e.report());
+    }
+    // whatever the exit status, e.g. to open a downloaded test report
+    if let Some(hook) = &conf.hooks.post_run {
+        if !finished.interrupted && !finished.timed_out {
+            let status = ("CSERUN_EXIT_STATUS", finished.exit_status.to_string());
+            hooks::run_local("post_run", hook, &conf.local_dir, &[status])?;
+        }
+    }
+    // whoever pressed ctrl-c is already looking
+    if !finished.interrupted {
+        notify_finished(conf, &finished, started.elapsed());
+    }
+    Ok(finished.code())
+}
+
+// --notify and --bell
+pub fn notify_finished(conf: &Config, finished: &Finished, elapsed: Duration) {
+    if conf.bell {
+        notify::bell();
+    }
+    if !conf.notify {
+        return;
+    }
+    let mut command = match &conf.script {
+        Some(script) => script.display().to_string(),
+        None => conf.command.lines().next().unwrap_or_default().to_string(),
+    };
+    if command.chars().count() > 60 {
+        command = command.chars().take(57).collect::<String>() + "...";
+    }
+    let body = match (finished.timed_out, finished.exit_status) {
+        (true, _) => format!("Timed out after {}", HumanDuration(elapsed)),
+        (false, 0) => format!("Succeeded after {}", HumanDuration(elapsed)),
+        (false, status) => format!(
+            "Failed with exit status {} after {}",
+            status,
+            HumanDuration(elapsed)
+        ),
+    };
+    notify::send(&format!("cserun: {}", command), &body);
+}
+
+// a run for the history, still without its exit status
+fn run_record(conf: &Config, run_dir: &RunDir, provenance: &Provenance) -> RunRecord {
+    RunRecord {
+        id: 0,
+        started: run_dir.name.clone(),
+        server_addr: conf.server_addr.clone(),
+        username: conf.username.clone(),
+        local_dir: std::env::current_dir()
+            .map(|dir| dir.join(&conf.local_dir))
+            .and_then(|dir| dir.canonicalize())
+            .unwrap_or_else(|_| conf.local_dir.clone()),
+        command: conf.command.clone(),
+        script: conf
+            .script
+            .as_ref()
+            .map(|script| script.canonicalize().unwrap_or_else(|_| script.clone())),
+        workdir: conf.workdir.clone(),
+        remote_dir: run_dir.path.clone(),
+        exit_status: 0,
+        seconds: 0.0,
+        files_uploaded: provenance.files,
+        bytes_uploaded: provenance.bytes,
+        detached: false,
+        running: false,
+        provenance: Some(provenance.clone()),
+    }
+}
+
+// --detach: the command was started in the background on the server. `launcher` is tmux or
+// nohup. the run dir stays for attach and status, and is cleaned like one kept with --keep
+fn start_detached(
+    conn: &Connection,
+    conf: &Config,
+    command: &str,
+    run_dir: &RunDir,
+) -> error::Result<String> {
+    // the server may accept setenv, but the detached command does not run in this channel
+    let line = command_line(
+        conf,
+        &slurm::prepare(conn, conf, command, run_dir)?,
+        &run_dir.pid_file(),
+        &run_dir.workdir,
+        &remote_envs(conf),
+    );
+    conn.write_file(
+        &Path::new(&run_dir.path).join("detached.sh"),
+        detach::script(&run_dir.path, &line).as_bytes(),
+    )?;
+    let session = detach::session_name(&run_dir.name);
+    let output = run_remote(&conn.sess, &detach::launch_command(&run_dir.path, &session))
+        .context(CserunError::Exec)?;
+    Ok(String::from_utf8_lossy(&output).trim().to_string())
+}
+
+fn detached(
+    conf: &Config,
+    run_dir: &RunDir,
+    launcher: &str,
+    provenance: &Provenance,
+    elapsed: Duration,
+) -> error::Result<i32> {
+    history::record_kept(KeptRun {
+        server_addr: conf.server_addr.clone(),
+        username: conf.username.clone(),
+        remote_dir: run_dir.path.clone(),
+        created: run_dir.name.clone(),
+    })?;
+    let id = history::record_run(RunRecord {
+        seconds: elapsed.as_secs_f64(),
+        detached: true,
+        running: true,
+        ..run_record(conf, run_dir, provenance)
+    })?;
+    let session = detach::session_name(&run_dir.name);
+    events::emit(
+        "detached",
+        &[
+            ("id", id.into()),
+            ("remote_dir", run_dir.path.as_str().into()),
+            ("launcher", launcher.into()),
+        ],
+    );
+    if !events::enabled() {
+        match launcher {
+            "tmux" => println!(
+                "Detached as run {} in tmux session {} on the server",
+                id, session
+            ),
+            _ => println!("Detached as run {} under nohup on the server", id),
+        }
+        println!(
+            "Follow it with `cserun attach {}`, check on it with `cserun status {}`",
+            id, id
+        );
+    }
+    Ok(0)
+}
+
+// with --timings, and always in the json output
+fn report_timings(conf: &Config, timings: &Timings, uploaded: (usize, u64), total: Duration) {
+    events::emit(
+        "timings",
+        &[
+            ("connect", timings.connect.as_secs_f64().into()),
+            ("auth", timings.auth.as_secs_f64().into()),
+            ("sync", timings.sync.as_secs_f64().into()),
+            ("files", uploaded.0.into()),
+            ("bytes", uploaded.1.into()),
+            ("exec", timings.exec.as_secs_f64().into()),
+            ("total", total.as_secs_f64().into()),
+        ],
+    );
+    if !conf.timings {
+        return;
+    }
+    eprintln!("{}", style("Timings:").bold());
+    eprintln!("  connect {:>9.3}s", timings.connect.as_secs_f64());
+    eprintln!("  auth    {:>9.3}s", timings.auth.as_secs_f64());
+    eprintln!(
+        "  sync    {:>9.3}s  ({} file(s), {})",
+        timings.sync.as_secs_f64(),
+        uploaded.0,
+        HumanBytes(uploaded.1)
+    );
+    eprintln!("  exec    {:>9.3}s", timings.exec.as_secs_f64());
+    eprintln!("  total   {:>9.3}s", total.as_secs_f64());
+}
+
+// the outcome of the run in a few lines, so it is not lost above the command's output. -q leaves
+// it out, the json output has it as an event
+fn report_summary(
+    server_addr: &str,
+    finished: &Finished,
+    timings: &Timings,
+    ((files, bytes), skipped): ((usize, u64), usize),
+    remote_dir: &str,
+    kept: bool,
+) {
+    let status = match (finished.timed_out, finished.interrupted) {
+        (true, _) => "timed out".to_string(),
+        (false, true) => "interrupted".to_string(),
+        (false, false) => finished.exit_status.to_string(),
+    };
+    events::emit(
+        "summary",
+        &[
+            ("exit_status", finished.code().into()),
+            ("files", files.into()),
+            ("skipped", skipped.into()),
+            ("bytes", bytes.into()),
+            ("sync", timings.sync.as_secs_f64().into()),
+            ("exec", timings.exec.as_secs_f64().into()),
+            ("remote_dir", remote_dir.into()),
+            ("kept", kept.into()),
+        ],
+    );
+    if events::enabled() || log::max_level() < log::LevelFilter::Warn {
+        return;
+    }
+    let status = match finished.code() {
+        0 => style(status).green(),
+        _ => style(status).red(),
+    };
+    // written at once, runs on several hosts finish side by side
+    eprint!(
+        "{}\n  exit status {}\n  uploaded    {} file(s), {} ({} skipped)\n  sync        {:.3}s\n  exec        {:.3}s\n  run dir     {}{}\n",
+        style(format!("Summary for {}:", server_addr)).bold(),
+        status.bold(),
+        files,
+        HumanBytes(bytes),
+        skipped,
+        timings.sync.as_secs_f64(),
+        timings.exec.as_secs_f64(),
+        display_path(remote_dir),
+        if kept { "" } else { " (removed)" }
+    );
+}
+
+// download the files the command created or changed, the pid file is written as it starts.
+// fetched and ignored files are left out, build output usually is ignored
+fn sync_back(conn: &Connection, conf: &Config, run_dir: &RunDir) -> error::Result<()> {
+    let fetched = sync::build_globset(&conf.fetch)?;
+    let ignore = sync::ignore_matcher(&conf.local_dir, &conf.walk)?;
+    let changed: Vec<_> =
+        sync::changed_since(&conn.sess, &run_dir.container_path, &run_dir.pid_file())?
+            .into_iter()
+            .filter(|(_, strip_path, _)| {
+                !fetched.is_match(strip_path)
+                    && !ignore
+                        .matched_path_or_any_parents(strip_path, false)
+                        .is_ignore()
+            })
+            .collect();
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    info!("{} {} file(s) changed on the server:", INBOX, changed.len());
+    for (_, strip_path, size) in &changed {
+        info!("  {} ({})", strip_path.display(), HumanBytes(*size));
+    }
+    let interactive = !events::enabled() && console::Term::stderr().is_term();
+    match conf.sync_back {
+        SyncBack::Always => {}
+        SyncBack::Ask if interactive => {
+            eprint!("Download them into the local directory? [y/N]: ");
+            io::stderr().flush().context(CserunError::Local)?;
+            let mut answer = String::new();
+            io::stdin()
+                .read_line(&mut answer)
+                .context(CserunError::Local)?;
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                return Ok(());
+            }
+        }
+        _ => {
+            info!("Use --sync-back to download them");
+            return Ok(());
+        }
+    }
+    let count = sync::download_files(
+        &conn.sess,
+        conn.sftp.as_ref(),
+        changed,
+        &conf.local_dir,
+        conf.progress,
+    )?;
+    info!("{} Synced {} changed file(s) back", INBOX, count);
+    events::emit("sync_back_finished", &[("files", count.into())]);
+    Ok(())
+}
+
+// run the command in the workspace, then again whenever a local file changes until ctrl-c.
+// the workspace manifest keeps each re-sync down to the changed files. a run that fails, e.g.
+// on a dropped connection, is reported and the next change tries again
+pub fn watch(conf: &Config, interval: Duration) -> error::Result<i32> {
+    terminal::catch_interrupt();
+    let mut watcher = Watcher::new(&conf.local_dir, &conf.walk)?;
+    loop {
+        let mut before = local_state(conf)?;
+        let result = exec(conf);
+        if terminal::interrupted() {
+            return result;
+        }
+        let exit_code = match result {
+            Ok(exit_code) => {
+                eprintln!(
+                    "{}",
+                    style(format!(
+                        "Exited with {}, waiting for changes (ctrl-c to stop)",
+                        exit_code
+                    ))
+                    .dim()
+                );
+                exit_code
+            }
+            Err(e) => {
+                error!("{}", e.report());
+                eprintln!(
+                    "{}",
+                    style("Waiting for changes to try again (ctrl-c to stop)").dim()
+                );
+                1
+            }
+        };
+        // wait for a change, then until the files settle, e.g. while an editor saves several
+        let mut changed = false;
+        loop {
+            let woke = watcher.wait(interval)?;
+            if terminal::interrupted() {
+                return Ok(exit_code);
+            }
+            if !woke && !changed {
+                continue;
+            }
+            let now = local_state(conf)?;
+            if now == before && changed {
+                break;
+            }
+            changed |= now != before;
+            before = now;
+        }
+    }
+}
+
+// every file an upload would consider, with its size and modification time
+fn local_state(conf: &Config) -> error::Result<Vec<(PathBuf, u64, Option<SystemTime>)>> {
+    let files = sync::list_files(&conf.local_dir, &conf.walk)?;
+    Ok(files
+        .into_iter()
+        .map(|(path, size)| {
+            let mtime = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, size, mtime)
+        })
+        .collect())
+}
+
+// the variables the command runs with, the ones asking for colors included
+fn remote_envs(conf: &Config) -> Vec<(String, String)> {
+    let colored = match conf.color {
+        Color::Never => false,
+        Color::Auto => !events::enabled() && console::Term::stdout().is_term(),
+        Color::Always => true,
+    };
+    let mut envs = conf.envs.clone();
+    if colored {
+        // variables from the config or --env win over the color defaults
+        for (key, value) in terminal::color_envs() {
+            if !envs.iter().any(|(existing, _)| *existing == key) {
+                envs.push((key, value));
+            }
+        }
+    }
+    envs
+}
+
+// set up the environment and start the command in the container dir, which must exist
+pub fn start_command(
+    conn: &Connection,
+    conf: &Config,
+    command: &str,
+    run_dir: &RunDir,
+) -> error::Result<(ssh2::Channel, Option<X11>, Option<Agent>)> {
+    let mut channel = conn.sess.channel_session().context(CserunError::Exec)?;
+    let exports = set_envs(&mut channel, conf);
+    info!(
+        "{} {} Environment variables set",
+        style("[5/5]").bold().dim(),
+        SPARKLE
+    );
+    let x11 = match conf.x11 {
+        true => Some(X11::listen(&conn.sess)?),
+        false => None,
+    };
+    let mut prepared = slurm::prepare(conn, conf, command, run_dir)?;
+    if let Some(x11) = &x11 {
+        prepared = x11.wrap(&prepared);
+    }
+    let command_line = command_line(
+        conf,
+        &prepared,
+        &run_dir.pid_file(),
+        &run_dir.workdir,
+        &exports,
+    );
+    if conf.pty {
+        let (cols, rows) = terminal::size();
+        channel
+            .request_pty(&terminal::term_name(), None, Some((cols, rows, 0, 0)))
+            .context(CserunError::Exec)?;
+    }
+    let agent = match conf.forward_agent {
+        true => Some(Agent::request(&conn.sess, &mut channel)?),
+        false => None,
+    };
+    channel.exec(&command_line).context(CserunError::Exec)?;
+    events::emit("command_started", &[("command", command.into())]);
+    info!(
+        "{} {} Command sented: {}",
+        style("[5/5]").bold().dim(),
+        SPACESHIP,
+        style(command).yellow(),
+    );
+    Ok((channel, x11, agent))
+}
+
+// set the environment variables on the channel, and return the ones to export in the command
+// line instead
+fn set_envs(channel: &mut ssh2::Channel, conf: &Config) -> Vec<(String, String)> {
+    let mut exports = Vec::new();
+    for (key, value) in remote_envs(conf) {
+        // what the server sets does not reach the --target-host
+        if conf.target_host.is_some() {
+            exports.push((key, value));
+            continue;
+        }
+        // libssh2's setenv may not work with cse server https://github.com/libssh2/libssh2/issues/546
+        if channel.setenv(&key, &value).is_err() {
+            exports.push((key, value));
+        }
+    }
+    exports
+}
+
+// one running command of a matrix
+struct MatrixJob {
+    index: usize,
+    channel: ssh2::Channel,
+    pid_file: String,
+    started: Instant,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    kill_deadline: Option<Instant>,
+    killed: bool,
+    timed_out: bool,
+}
+
+// run the commands side by side in the container dir over the one session, at most
+// conf.parallel at a time. each command's output is printed in one piece once it is done,
+// then a table of how they all went. the timeout applies to every command on its own
+pub fn run_matrix(
+    conn: &Connection,
+    conf: &Config,
+    run_dir: &RunDir,
+    commands: &[String],
+) -> error::Result<(Finished, Vec<Outcome>)> {
+    let sess = &conn.sess;
+    let parallel = conf.parallel.max(1);
+    info!(
+        "{} {} Running {} command(s), {} at a time",
+        style("[5/5]").bold().dim(),
+        SPACESHIP,
+        commands.len(),
+        parallel
+    );
+    let mut outcomes: Vec<Outcome> = commands
+        .iter()
+        .map(|command| Outcome {
+            command: command.clone(),
+            exit_status: None,
+            seconds: 0.0,
+            timed_out: false,
+        })
+        .collect();
+    let mut pending = commands.iter().enumerate();
+    let mut running: Vec<MatrixJob> = Vec::new();
+    let mut tunnels = Tunnels::bind(&conf.forwards).context(CserunError::Local)?;
+    let mut buffer = [0; 4096];
+    let mut interrupted = false;
+    terminal::catch_interrupt();
+    sess.set_blocking(false);
+    loop {
+        if stop_requested(conf) && !interrupted {
+            warn!("Interrupted, stopping the remote commands...");
+            interrupted = true;
+            for job in &mut running {
+                signal_remote(sess, conf, &job.pid_file, "INT")?;
+                job.kill_deadline = Some(Instant::now() + Duration::from_secs(3));
+            }
+        }
+        while !interrupted && running.len() < parallel {
+            let Some((index, command)) = pending.next() else {
+                break;
+            };
+            sess.set_blocking(true);
+            let job = start_matrix_job(sess, conf, run_dir, index, command);
+            sess.set_blocking(false);
+            running.push(job?);
+        }
+        if running.is_empty() {
+            break;
+        }
+
+        let mut is_data_available = tunnels.poll(sess);
+        for job in &mut running {
+            let timeout_at = conf
+                .timeout
+                .and_then(|timeout| job.started.checked_add(timeout));
+            if timeout_at.is_some_and(|at| Instant::now() > at) && job.kill_deadline.is_none() {
+                job.timed_out = true;
+                signal_remote(sess, conf, &job.pid_file, "INT")?;
+                job.kill_deadline = Some(Instant::now() + Duration::from_secs(3));
+            }
+            if let Some(deadline) = job.kill_deadline {
+                if !job.killed && Instant::now() > deadline {
+                    signal_remote(sess, conf, &job.pid_file, "KILL")?;
+                    job.killed = true;
+                }
+            }
+            is_data_available |= read_available(&mut job.channel, &mut buffer, &mut job.stdout)
+                .context(CserunError::Exec)?;
+            is_data_available |=
+                read_available(&mut job.channel.stderr(), &mut buffer, &mut job.stderr)
+                    .context(CserunError::Exec)?;
+        }
+
+        let mut index = 0;
+        while index < running.len() {
+            if !running[index].channel.eof() {
+                index += 1;
+                continue;
+            }
+            let mut job = running.swap_remove(index);
+            sess.set_blocking(true);
+            let exit_status = finish_matrix_job(&mut job);
+            sess.set_blocking(false);
+            let outcome = &mut outcomes[job.index];
+            outcome.exit_status = Some(exit_status?);
+            outcome.seconds = job.started.elapsed().as_secs_f64();
+            outcome.timed_out = job.timed_out;
+            print_matrix_output(conf, job.index, commands.len(), outcome, &job)?;
+            is_data_available = true;
+        }
+
+        if !is_data_available {
+            send_keepalive(sess).context(CserunError::Exec)?;
+            let deadlines: Vec<Option<Instant>> = running
+                .iter()
+                .flat_map(|job| {
+                    let timeout_at = conf
+                        .timeout
+                        .and_then(|timeout| job.started.checked_add(timeout));
+                    [timeout_at, job.kill_deadline]
+                })
+                .collect();
+            wait_for_socket(sess, &tunnels, idle_wait(&deadlines));
+        }
+    }
+    drop(tunnels);
+    sess.set_blocking(true);
+
+    if !events::enabled() {
+        for line in matrix::summary(&outcomes) {
+            info!("{}", line);
+        }
+    }
+    let finished = Finished {
+        exit_status: matrix::exit_status(&outcomes),
+        interrupted,
+        timed_out: false,
+    };
+    events::emit(
+        "command_finished",
+        &[
+            ("exit_status", finished.exit_status.into()),
+            ("interrupted", interrupted.into()),
+        ],
+    );
+    Ok((finished, outcomes))
+}
+
+// a matrix command with its own pid file, so it can be stopped on its own. it gets no stdin
+fn start_matrix_job(
+    sess: &Session,
+    conf: &Config,
+    run_dir: &RunDir,
+    index: usize,
+    command: &str,
+) -> error::Result<MatrixJob> {
+    let pid_file = format!("{}.{}", run_dir.pid_file(), index + 1);
+    let mut channel = sess.channel_session().context(CserunError::Exec)?;
+    let exports = set_envs(&mut channel, conf);
+    let command_line = command_line(
+        conf,
+        &hooks::with_pre_run(&conf.hooks, command.to_string(), run_dir),
+        &pid_file,
+        &run_dir.workdir,
+        &exports,
+    );
+    channel.exec(&command_line).context(CserunError::Exec)?;
+    channel.send_eof().context(CserunError::Exec)?;
+    events::emit(
+        "command_started",
+        &[("command", command.into()), ("index", (index + 1).into())],
+    );
+    debug!("Started [{}] {}", index + 1, command);
+    Ok(MatrixJob {
+        index,
+        channel,
+        pid_file,
+        started: Instant::now(),
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        kill_deadline: None,
+        killed: false,
+        timed_out: false,
+    })
+}
+
+// read whatever the stream has without blocking, whether there was anything
+fn read_available(
+    stream: &mut impl Read,
+    buffer: &mut [u8],
+    output: &mut impl Write,
+) -> io::Result<bool> {
+    let mut read_any = false;
+    loop {
+        match stream.read(buffer) {
+            Ok(0) => return Ok(read_any),
+            Ok(size) => {
+                output.write_all(&buffer[..size])?;
+                read_any = true;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(read_any),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// the rest of the output once the command is done, and its exit status
+fn finish_matrix_job(job: &mut MatrixJob) -> error::Result<i32> {
+    job.channel
+        .read_to_end(&mut job.stdout)
+        .context(CserunError::Exec)?;
+    job.channel
+        .stderr()
+        .read_to_end(&mut job.stderr)
+        .context(CserunError::Exec)?;
+    job.channel.wait_close().context(CserunError::Exec)?;
+    job.channel.exit_status().context(CserunError::Exec)
+}
+
+fn print_matrix_output(
+    conf: &Config,
+    index: usize,
+    count: usize,
+    outcome: &Outcome,
+    job: &MatrixJob,
+) -> error::Result<()> {
+    let stdout = conf.encoding.decode(&job.stdout);
+    let stderr = conf.encoding.decode(&job.stderr);
+    if events::enabled() {
+        events::emit(
+            "matrix_command_finished",
+            &[
+                ("index", (index + 1).into()),
+                ("command", outcome.command.as_str().into()),
+                ("exit_status", outcome.exit_status.unwrap_or(-1).into()),
+                ("seconds", outcome.seconds.into()),
+                ("timed_out", outcome.timed_out.into()),
+                ("stdout", String::from_utf8_lossy(&stdout).as_ref().into()),
+                ("stderr", String::from_utf8_lossy(&stderr).as_ref().into()),
+            ],
+        );
+        return Ok(());
+    }
+    let status = match (outcome.exit_status, outcome.timed_out) {
+        (_, true) => style("timed out".to_string()).red(),
+        (Some(0), false) => style("exit 0".to_string()).green(),
+        (status, false) => style(format!("exit {}", status.unwrap_or(-1))).red(),
+    };
+    info!(
+        "{} [{}/{}] {} ({}, {:.1}s)",
+        style("===").bold().magenta(),
+        index + 1,
+        count,
+        style(&outcome.command).yellow(),
+        status,
+        outcome.seconds
+    );
+    if conf.filter.is_empty() {
+        write_output(&mut io::stdout(), &stdout)?;
+        return write_output(&mut io::stderr(), &stderr);
+    }
+    let mut filter = LineFilter::new(&conf.filter);
+    let (shown_stdout, shown_stderr) = (filter.stdout(&stdout), filter.stderr(&stderr));
+    let [rest_stdout, rest_stderr] = filter.finish();
+    write_output(&mut io::stdout(), &[shown_stdout, rest_stdout].concat())?;
+    write_output(&mut io::stderr(), &[shown_stderr, rest_stderr].concat())
+}
+
+// forward stdin and the command's output until it exits, stopping it on ctrl-c or the timeout
+pub fn wait_command(
+    sess: &Session,
+    (mut channel, x11, agent): (ssh2::Channel, Option<X11>, Option<Agent>),
+    conf: &Config,
+    run_dir: &RunDir,
+    command: &str,
+) -> error::Result<Finished> {
+    let pid_file = run_dir.pid_file();
+    let mut transcript = Transcript::open(conf, run_dir, command);
+    let mut artifacts = match conf.save_output {
+        true => Artifacts::open(&run_dir.name),
+        false => None,
+    };
+    // set to unblocking mode
+    sess.set_blocking(false);
+
+    info!(
+        "{} {} {}",
+        style("===============").bold().magenta(),
+        style("Output").italic().bold().magenta(),
+        style("===============").bold().magenta()
+    );
+    // each stream has a buffer of its own, the size of an ssh packet
+    let mut stdout_buffer = vec![0; 32 * 1024];
+    let mut stderr_buffer = vec![0; 32 * 1024];
+    let mut stdout_events = events::OutputStream::new("stdout");
+    let mut stderr_events = events::OutputStream::new("stderr");
+    // a character cut off by the end of the output is dropped
+    let mut stdout_decoder = conf.encoding.decoder();
+    let mut stderr_decoder = conf.encoding.decoder();
+    let mut prefixer = conf.prefix.as_ref().map(Prefixer::new);
+    let mut filter = (!conf.filter.is_empty()).then(|| LineFilter::new(&conf.filter));
+    let command_started = Instant::now();
+    let stdin = match conf.stdin {
+        true => terminal::spawn_stdin_reader(),
+        false => terminal::closed_stdin(),
+    };
+    let timeout_at = conf
+        .timeout
+        .and_then(|timeout| Instant::now().checked_add(timeout));
+    let mut tunnels = Tunnels::bind(&conf.forwards).context(CserunError::Local)?;
+    tunnels.add_x11(x11);
+    tunnels.add_agent(agent);
+    if conf.pty {
+        // ctrl-c is just a keystroke for the remote pty
+        pty_loop(
+            sess,
+            &mut channel,
+            &stdin,
+            &mut tunnels,
+            transcript.as_mut(),
+            timeout_at,
+            conf.encoding,
+        )?;
+    } else {
+        terminal::catch_interrupt();
+    }
+    let mut stdin_open = true;
+    let mut kill_deadline = None;
+    let mut killed = false;
+    let mut timed_out = false;
+    loop {
+        if channel.eof() {
+            // if channel closed, break the loop
+            break;
+        }
+
+        // forward ctrl-c, and kill the command if it does not stop in time
+        if stop_requested(conf) && kill_deadline.is_none() {
+            warn!("Interrupted, stopping the remote command...");
+            signal_remote(sess, conf, &pid_file, "INT")?;
+            kill_deadline = Some(Instant::now() + Duration::from_secs(3));
+        }
+        if timeout_at.is_some_and(|at| Instant::now() > at) && kill_deadline.is_none() {
+            timed_out = true;
+            signal_remote(sess, conf, &pid_file, "INT")?;
+            kill_deadline = Some(Instant::now() + Duration::from_secs(3));
+        }
+        if let Some(deadline) = kill_deadline {
+            if !killed && Instant::now() > deadline {
+                signal_remote(sess, conf, &pid_file, "KILL")?;
+                killed = true;
+            }
+        }
+
+        let mut is_data_available = tunnels.poll(sess);
+
+        // forward local stdin, and close the remote stdin once ours is exhausted
+        while stdin_open {
+            match stdin.try_recv() {
+                Ok(data) if data.is_empty() => {
+                    send_eof_nonblocking(&mut channel).context(CserunError::Exec)?;
+                    stdin_open = false;
+                }
+                Ok(data) => {
+                    write_all_nonblocking(&mut channel, &data).context(CserunError::Exec)?;
+                    is_data_available = true;
+                }
+                Err(_) => break,
+            }
+        }
+
+        // drain stdout and then stderr, each up to a budget so a command printing without a
+        // pause on one of them neither starves the other nor keeps ctrl-c and stdin waiting
+        let mut budget = DRAIN_BUDGET;
+        while budget > 0 {
+            let size = match channel.read(&mut stdout_buffer) {
+                Ok(size) if size > 0 => size,
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(CserunError::Exec(e.into())),
+            };
+            budget = budget.saturating_sub(size);
+            let data = stdout_decoder.decode(&stdout_buffer[..size]);
+            if !events::enabled() {
+                write_output(
+                    &mut io::stdout(),
+                    &displayed(&mut filter, &mut prefixer, 0, &data),
+                )?;
+            }
+            stdout_events.push(&data);
+            if let Some(transcript) = &mut transcript {
+                transcript.stdout(&data);
+            }
+            if let Some(artifacts) = &mut artifacts {
+                artifacts.stdout(&data);
+            }
+            is_data_available = true;
+        }
+        let mut budget = DRAIN_BUDGET;
+        while budget > 0 {
+            let size = match channel.stderr().read(&mut stderr_buffer) {
+                Ok(size) if size > 0 => size,
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(CserunError::Exec(e.into())),
+            };
+            budget = budget.saturating_sub(size);
+            let data = stderr_decoder.decode(&stderr_buffer[..size]);
+            if !events::enabled() {
+                write_output(
+                    &mut io::stderr(),
+                    &displayed(&mut filter, &mut prefixer, 1, &data),
+                )?;
+            }
+            stderr_events.push(&data);
+            if let Some(transcript) = &mut transcript {
+                transcript.stderr(&data);
+            }
+            if let Some(artifacts) = &mut artifacts {
+                artifacts.stderr(&data);
+            }
+            is_data_available = true;
+        }
+
+        if !is_data_available {
+            send_keepalive(sess).context(CserunError::Exec)?;
+            // stdin and ctrl-c wake the wait up, the deadlines have to be checked in time
+            wait_for_socket(sess, &tunnels, idle_wait(&[timeout_at, kill_deadline]));
+        }
+    }
+
+    // the tunnels go away with the command
+    drop(tunnels);
+    channel.wait_close().context(CserunError::Exec)?;
+    stdout_events.finish();
+    stderr_events.finish();
+    if let Some(filter) = &mut filter {
+        let [stdout, stderr] = filter.finish();
+        write_output(
+            &mut io::stdout(),
+            &displayed(&mut None, &mut prefixer, 0, &stdout),
+        )?;
+        write_output(
+            &mut io::stderr(),
+            &displayed(&mut None, &mut prefixer, 1, &stderr),
+        )?;
+    }
+    if let Some(prefixer) = &mut prefixer {
+        let [stdout, stderr] = prefixer.finish();
+        write_output(&mut io::stdout(), &stdout)?;
+        write_output(&mut io::stderr(), &stderr)?;
+    }
+    info!(
+        "{}",
+        style("======================================")
+            .bold()
+            .magenta()
+    );
+
+    let exit_status = channel.exit_status().context(CserunError::Exec)?;
+    // sftp and the cleanup channel need the session back in blocking mode
+    sess.set_blocking(true);
+    check_setup(sess, run_dir, exit_status)?;
+    hooks::check_pre_run(sess, &conf.hooks, run_dir, exit_status)?;
+    match exit_status {
+        0 => info!("Exit status: {}", style("Success").green()),
+        _status => info!("Exit status: {}", style(format!("Error {}", _status)).red()),
+    }
+    let interrupted = kill_deadline.is_some() && !timed_out;
+    if let Some(transcript) = transcript {
+        transcript.finish(exit_status);
+    }
+    if let Some(artifacts) = artifacts {
+        artifacts.finish();
+    }
+    events::emit(
+        "command_finished",
+        &[
+            ("exit_status", exit_status.into()),
+            ("seconds", command_started.elapsed().as_secs_f64().into()),
+            ("timed_out", timed_out.into()),
+            ("interrupted", interrupted.into()),
+        ],
+    );
+
+    Ok(Finished {
+        exit_status,
+        interrupted,
+        timed_out,
+    })
+}
+
+// what is shown of a chunk of stdout (0) or stderr (1): the lines --grep lets through, tagged
+// by --prefix
+fn displayed<'a>(
+    filter: &mut Option<LineFilter>,
+    prefixer: &mut Option<Prefixer>,
+    stream: usize,
+    data: &'a [u8],
+) -> Cow<'a, [u8]> {
+    let data = match (filter, stream) {
+        (Some(filter), 0) => Cow::Owned(filter.stdout(data)),
+        (Some(filter), _) => Cow::Owned(filter.stderr(data)),
+        (None, _) => Cow::Borrowed(data),
+    };
+    match (prefixer, stream) {
+        (Some(prefixer), 0) => Cow::Owned(prefixer.stdout(&data)),
+        (Some(prefixer), _) => Cow::Owned(prefixer.stderr(&data)),
+        (None, _) => data,
+    }
+}
+
+// upload the local directory into its persistent workspace without running anything
+pub fn sync_workspace(conf: Config) -> error::Result<()> {
+    let (workspace_path, manifest_id) = workspace(&conf);
+    if let Some(hook) = &conf.hooks.pre_sync {
+        hooks::run_local("pre_sync", hook, &conf.local_dir, &[])?;
+    }
+    with_retries(&conf, |resume| {
+        let conn = connect(&conf)?;
+        sync_files(&conn, &conf, &workspace_path, &manifest_id, resume)
+    })?;
+    println!(
+        "{} Workspace is at {}",
+        FOLDER,
+        style(display_path(&workspace_path)).italic()
+    );
+    Ok(())
+}
+
+// `cserun sync --watch`: keep the connection and mirror the local directory into the workspace
+// until ctrl-c, so a `cserun shell` there always has the latest files. with `sync_back` the files
+// changed on the server come back as well, a file changed on both sides keeps the local version
+pub fn watch_workspace(conf: Config, interval: Duration, sync_back: bool) -> error::Result<()> {
+    let (workspace_path, manifest_id) = workspace(&conf);
+    // files in the workspace newer than this changed on the server since the last upload
+    let marker = format!("{}.synced", remote_str(&workspace_path));
+    let mut conn = connect(&conf)?;
+    let upload = |conn: &Connection| -> error::Result<()> {
+        if let Some(hook) = &conf.hooks.pre_sync {
+            hooks::run_local("pre_sync", hook, &conf.local_dir, &[])?;
+        }
+        sync_files(conn, &conf, &workspace_path, &manifest_id, false)?;
+        run_remote(&conn.sess, &format!("touch {}", shell_path(&marker)))
+            .context(CserunError::Transfer)?;
+        Ok(())
+    };
+    terminal::catch_interrupt();
+    let mut watcher = Watcher::new(&conf.local_dir, &conf.walk)?;
+    let mut state = local_state(&conf)?;
+    upload(&conn)?;
+    println!(
+        "{} Workspace is at {}, watching for changes (ctrl-c to stop)",
+        FOLDER,
+        style(display_path(&workspace_path)).italic()
+    );
+    let ignore = sync::ignore_matcher(&conf.local_dir, &conf.walk)?;
+    let mut changed = false;
+    while !terminal::interrupted() {
+        let woke = watcher.wait(interval)?;
+        if terminal::interrupted() {
+            break;
+        }
+        if let Err(e) = send_keepalive(&conn.sess) {
+            warn!(
+                "Lost the connection to the server ({}), connecting again",
+                e
+            );
+            conn = with_retries(&conf, |_| connect(&conf))?;
+        }
+        // upload once the files settle, e.g. while an editor saves several
+        if woke || changed {
+            let now = local_state(&conf)?;
+            if now != state {
+                state = now;
+                changed = true;
+                continue;
+            }
+        }
+        if changed {
+            // a failed upload is tried again with the next change
+            if let Err(e) = upload(&conn) {
+                error!("{}", e.report());
+            }
+            changed = false;
+        }
+        if !sync_back {
+            continue;
+        }
+        let remote: Vec<_> = sync::changed_since(&conn.sess, &workspace_path, &marker)?
+            .into_iter()
+            .filter(|(_, strip_path, _)| {
+                !ignore
+                    .matched_path_or_any_parents(strip_path, false)
+                    .is_ignore()
+            })
+            .collect();
+        if remote.is_empty() {
+            continue;
+        }
+        run_remote(&conn.sess, &format!("touch {}", shell_path(&marker)))
+            .context(CserunError::Transfer)?;
+        let count = sync::download_files(
+            &conn.sess,
+            conn.sftp.as_ref(),
+            remote,
+            &conf.local_dir,
+            conf.progress,
+        )?;
+        info!("{} Synced {} file(s) changed on the server", INBOX, count);
+        // not a local change to upload again
+        state = local_state(&conf)?;
+    }
+    // the marker is only of use while watching
+    let _ = run_remote(&conn.sess, &format!("rm -f {}", shell_path(&marker)));
+    Ok(())
+}
+
+// download files matching the fetch patterns from the workspace into the local directory
+pub fn fetch_workspace(conf: Config) -> error::Result<()> {
+    let conn = connect(&conf)?;
+    let (workspace_path, _) = workspace(&conf);
+    if !conn.exists(&workspace_path) {
+        return Err(CserunError::Transfer(
+            format!(
+                "Workspace {} does not exist, run `cserun sync` first",
+                display_path(&workspace_path)
+            )
+            .into(),
+        ));
+    }
+    let patterns = sync::build_globset(&conf.fetch)?;
+    let count = conn.download_dir(&workspace_path, &conf.local_dir, &patterns, conf.progress)?;
+    println!("{} Fetched {} file(s) from remote", INBOX, count);
+    Ok(())
+}
+
+// what `cserun clean` removes, the kept runs of the account unless older_than or all is set
+pub struct CleanOptions {
+    pub older_than: Option<Duration>,
+    pub all: bool,
+    pub workspace: bool,
+    pub dry_run: bool, // only list what would be removed
+}
+
+// remove run directories from the server, and forget the kept ones among them
+pub fn clean(conf: Config, options: CleanOptions) -> error::Result<()> {
+    let conn = connect(&conf)?;
+    let mine: Vec<KeptRun> = history::load_kept()?
+        .into_iter()
+        .filter(|run| run.server_addr == conf.server_addr && run.username == conf.username)
+        .collect();
+
+    let mut remove: Vec<(String, Option<Duration>)> = match (options.all, options.older_than) {
+        (false, None) => mine
+            .iter()
+            .map(|run| (run.remote_dir.clone(), None))
+            .collect(),
+        (all, older_than) => {
+            let mut selected = Vec::new();
+            for (dir, age, running) in run_dirs(&conn.sess, &conf)? {
+                if !all && older_than.is_some_and(|older_than| age < older_than) {
+                    continue;
+                }
+                // removing it would pull the files from under the command
+                if running {
+                    warn!(
+                        "Skipped {}, its command is still running",
+                        display_path(dir)
+                    );
+                    continue;
+                }
+                selected.push((dir, Some(age)));
+            }
+            selected
+        }
+    };
+    remove.sort();
+
+    // the kept runs that are gone are forgotten even if a later one fails to go
+    let mut removed = Vec::new();
+    let forget = |removed: &[&String]| {
+        history::update_kept(|runs| {
+            runs.retain(|run| {
+                run.server_addr != conf.server_addr
+                    || run.username != conf.username
+                    || !removed.contains(&&run.remote_dir)
+            })
+        })
+    };
+    for (dir, age) in &remove {
+        let age = match age {
+            Some(age) => format!(" ({} old)", HumanDuration(*age)),
+            None => String::new(),
+        };
+        if options.dry_run {
+            println!(
+                "{} Would remove {}{}",
+                FOLDER,
+                style(display_path(dir)).italic(),
+                age
+            );
+            continue;
+        }
+        if let Err(e) = remove_remote_dir(&conn.sess, dir) {
+            forget(&removed)?;
+            return Err(e);
+        }
+        removed.push(dir);
+        println!(
+            "{} Removed {}{}",
+            FOLDER,
+            style(display_path(dir)).italic(),
+            age
+        );
+    }
+    if !options.dry_run {
+        forget(&removed)?;
+    }
+
+    if options.workspace {
+        let (workspace_path, manifest_id) = workspace(&conf);
+        let workspace_path = remote_str(&workspace_path);
+        match options.dry_run {
+            true => println!(
+                "{} Would remove workspace {}",
+                FOLDER,
+                style(display_path(&workspace_path)).italic()
+            ),
+            false => {
+                remove_remote_dir(&conn.sess, &workspace_path)?;
+                Manifest::remove(&manifest_id)?;
+                println!(
+                    "{} Removed workspace {}",
+                    FOLDER,
+                    style(display_path(&workspace_path)).italic()
+                );
+            }
+        }
+    }
+    match options.dry_run {
+        true => println!(
+            "Would clean {} run(s), run again without --dry-run to remove them",
+            remove.len()
+        ),
+        false => println!("Cleaned {} run(s)", remove.len()),
+    }
+    Ok(())
+}
+
+// every run directory on the server with its age, and whether its command is still running.
+// ages are by the server's clock, which wrote the modification times
+fn run_dirs(sess: &Session, conf: &Config) -> error::Result<Vec<(String, Duration, bool)>> {
+    let temp = format!("{}/temp", conf.remote_dir());
+    let script = format!("cd {} 2>/dev/null || exit 0\n", shell_quote(&temp))
+        + r#"now=$(date +%s)
+for dir in */; do
+    dir=${dir%/}
+    [ -d "$dir" ] || continue
+    running=0
+    if [ -f "$dir/pid" ] && kill -0 "$(cat "$dir/pid")" 2>/dev/null; then running=1; fi
+    echo "$(( now - $(stat -c %Y "$dir" 2>/dev/null || stat -f %m "$dir") )) $running $dir"
+done
+"#;
+    let output = run_remote_script(sess, &script).context(CserunError::Cleanup)?;
+    Ok(String::from_utf8_lossy(&output)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let age = fields.next()?.parse::<u64>().ok()?;
+            let running = fields.next()? == "1";
+            let dir = format!("{}/{}", temp, fields.next()?);
+            Some((dir, Duration::from_secs(age), running))
+        })
+        .collect())
+}
+
+// the last runs on this account as a table, newest last
+pub fn list_history(conf: &Config, limit: usize) -> error::Result<()> {
+    let runs: Vec<RunRecord> = history::load_runs()?
+        .into_iter()
+        .filter(|run| run.server_addr == conf.server_addr && run.username == conf.username)
+        .collect();
+    if runs.is_empty() {
+        println!("No runs yet");
+        return Ok(());
+    }
+    println!(
+        "{}",
+        style(format!(
+            "{:>4}  {:<19}  {:>8}  {:>6}  {:<20}  {}",
+            "ID", "STARTED", "TIME", "STATUS", "UPLOADED", "COMMAND"
+        ))
+        .bold()
+    );
+    for run in &runs[runs.len().saturating_sub(limit)..] {
+        // the run directory is named after the local time it started at, then pid and random part
+        let timestamp = run.started.get(..23).unwrap_or(&run.started);
+        let started = chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d-%H-%M-%S-%3f")
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|_| run.started.clone());
+        let status = format!("{:>6}", run.exit_status);
+        // a detached run's status is only known once attach or status saw it done
+        let status = match (run.running, run.exit_status) {
+            (true, _) => style(format!("{:>6}", "detach")).yellow(),
+            (false, 0) => style(status).green(),
+            (false, _) => style(status).red(),
+        };
+        let uploaded = format!(
+            "{} files, {}",
+            run.files_uploaded,
+            HumanBytes(run.bytes_uploaded)
+        );
+        // one line per run, long commands are cut short
+        let mut command = run.command.replace('\n', " ");
+        if command.chars().count() > 60 {
+            command = command.chars().take(57).collect::<String>() + "...";
+        }
+        println!(
+            "{:>4}  {:<19}  {:>7.1}s  {}  {:<20}  {}",
+            run.id, started, run.seconds, status, uploaded, command
+        );
+    }
+    Ok(())
+}
+
+// show what cserun has left on the remote side
+pub fn list(conf: Config) -> error::Result<()> {
+    let conn = connect(&conf)?;
+    let (current_workspace, _) = workspace(&conf);
+    for (title, dir) in [("Run directories", "temp"), ("Workspaces", "workspaces")] {
+        let dir = format!("{}/{}", conf.remote_dir(), dir);
+        println!("{}", style(title).bold());
+        // a missing dir just means nothing was ever left there
+        let mut entries = conn.list_dirs(Path::new(&dir))?;
+        entries.sort();
+        if entries.is_empty() {
+            println!("  (none)");
+        }
+        for path in entries {
+            let marker = if path == current_workspace {
+                " (this directory)"
+            } else {
+                ""
+            };
+            println!("  {}{}", display_path(&path), style(marker).dim());
+        }
+    }
+    Ok(())
+}
+
+// open an interactive login shell inside the workspace, after bringing it up to date with the
+// local directory over the same connection
+pub fn shell(conf: Config, sync: bool) -> error::Result<i32> {
+    let (workspace_path, manifest_id) = workspace(&conf);
+    let conn = match sync {
+        true => {
+            if let Some(hook) = &conf.hooks.pre_sync {
+                hooks::run_local("pre_sync", hook, &conf.local_dir, &[])?;
+            }
+            with_retries(&conf, |resume| {
+                let conn = connect(&conf)?;
+                sync_files(&conn, &conf, &workspace_path, &manifest_id, resume)?;
+                Ok(conn)
+            })?
+        }
+        false => {
+            let conn = connect(&conf)?;
+            conn.mkdir(&workspace_path)?;
+            conn
+        }
+    };
+
+    let mut channel = conn.sess.channel_session().context(CserunError::Exec)?;
+    let (cols, rows) = terminal::size();
+    channel
+        .request_pty(&terminal::term_name(), None, Some((cols, rows, 0, 0)))
+        .context(CserunError::Exec)?;
+    channel
+        .exec(&format!(
+            "cd {} && exec \"${{SHELL:-/bin/sh}}\" -l",
+            shell_quote(&remote_str(&workspace_path))
+        ))
+        .context(CserunError::Exec)?;
+
+    conn.sess.set_blocking(false);
+    let stdin = terminal::spawn_stdin_reader();
+    pty_loop(
+        &conn.sess,
+        &mut channel,
+        &stdin,
+        &mut Tunnels::default(),
+        None,
+        None,
+        conf.encoding,
+    )?;
+    conn.sess.set_blocking(true);
+    channel.wait_close().context(CserunError::Exec)?;
+    channel.exit_status().context(CserunError::Exec)
+}
+
+// the keyring entry for the configured authentication, and what it holds
+fn keyring_account(conf: &Config) -> error::Result<(String, &'static str)> {
+    let (host, _) = split_addr(&conf.server_addr)?;
+    // the first method with a secret
+    conf.auth
+        .iter()
+        .find_map(|auth| match auth {
+            Auth::Password(_) => {
+                Some((keyring::password_account(&conf.username, host), "password"))
+            }
+            Auth::AuthKey(key) => Some((keyring::passphrase_account(&key.privekey), "passphrase")),
+            Auth::Agent(_) | Auth::KeyboardInteractive => None,
+        })
+        .ok_or_else(|| {
+            CserunError::Config(
+                "Only password and key authentication have a secret to keep in the keyring".into(),
+            )
+        })
+}
+
+// save the password or key passphrase in the system keyring, it is then used instead of prompting
+pub fn store_secret(conf: &Config) -> error::Result<()> {
+    let (account, what) = keyring_account(conf)?;
+    keyring::store(&account, || {
+        rpassword::prompt_password(format!("{} to store in the keyring: ", what))
+    })?;
+    println!("Stored the {} for {}", what, account);
+    Ok(())
+}
+
+pub fn forget_secret(conf: &Config) -> error::Result<()> {
+    let (account, what) = keyring_account(conf)?;
+    match keyring::forget(&account)? {
+        true => println!("Removed the {} from the keyring", what),
+        false => println!("No {} stored in the keyring", what),
+    }
+    Ok(())
+}
+
+// ctrl-c, or a cancel from the program driving the run
+fn stop_requested(conf: &Config) -> bool {
+    terminal::interrupted() || conf.cancel.is_cancelled()
+}
+
+// send a signal to the process group of the remote command over a second channel
+fn signal_remote(sess: &Session, conf: &Config, pid_file: &str, signal: &str) -> error::Result<()> {
+    let kill = format!(
+        "kill -{} -- -$(cat {}) 2>/dev/null",
+        signal,
+        shell_quote(pid_file)
+    );
+    sess.set_blocking(true);
+    let result = (|| -> Result<(), error::Source> {
+        let mut channel = sess.channel_session()?;
+        channel.exec(&on_target(conf, &kill))?;
+        channel.wait_close()?;
+        Ok(())
+    })();
+    sess.set_blocking(false);
+    result.context(CserunError::Exec)
+}
+
+// run `attempt` again on a fresh connection after network errors, with exponential backoff.
+// `attempt` is told whether it is resuming after a failed try
+pub fn with_retries<T>(
+    conf: &Config,
+    mut attempt: impl FnMut(bool) -> error::Result<T>,
+) -> error::Result<T> {
+    let mut retries = 0;
+    loop {
+        match attempt(retries > 0) {
+            Ok(value) => return Ok(value),
+            Err(e) if retries < conf.retries && is_transient(&e) => {
+                conf.cancel.check("before retrying after a network error")?;
+                retries += 1;
+                let delay = Duration::from_secs(1 << retries);
+                warn!(
+                    "{}, retrying in {}s ({}/{})",
+                    e.report(),
+                    delay.as_secs(),
+                    retries,
+                    conf.retries
+                );
+                std::thread::sleep(delay);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// network failures are worth a retry, wrong passwords or sftp permission errors are not
+fn is_transient(e: &CserunError) -> bool {
+    match e {
+        CserunError::Connect(source)
+        | CserunError::Handshake(source)
+        | CserunError::Sftp(source)
+        | CserunError::Transfer(source)
+        | CserunError::Exec(source) => is_network_error(source.as_ref()),
+        _ => false,
+    }
+}
+
+fn is_network_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(e) = e.downcast_ref::<io::Error>() {
+        return match e.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof => true,
+            _ => e.get_ref().is_some_and(|inner| is_network_error(inner)),
+        };
+    }
+    if let Some(e) = e.downcast_ref::<ssh2::Error>() {
+        // libssh2's socket level errors: SOCKET_NONE, BANNER_RECV, BANNER_SEND, SOCKET_SEND,
+        // TIMEOUT, SOCKET_DISCONNECT, SOCKET_TIMEOUT and SOCKET_RECV
+        return matches!(
+            e.code(),
+            ssh2::ErrorCode::Session(-1 | -2 | -3 | -7 | -9 | -13 | -30 | -43)
+        );
+    }
+    false
+}
+
+// a run dir whose upload failed, before its command ever started
+fn discard_run_dir(sess: &Session, run_dir: &RunDir) {
+    match remove_remote_dir(sess, &run_dir.path) {
+        Ok(()) => info!(
+            "{} Removed the partial upload {}",
+            FOLDER,
+            style(display_path(&run_dir.path)).italic()
+        ),
+        Err(e) => warn!("{}", e.report()),
+    }
 }
 
-pub fn exec(conf: Config) -> Result<i32, Box<dyn std::error::Error>> {
-    let tcp = TcpStream::connect(&conf.server_addr)?;
-    println!(
-        "{} {} Connecting to {}",
-        style("[1/5]").bold().dim(),
-        NETWORK,
-        style(conf.server_addr).italic().cyan()
-    );
+// delete a remote directory tree, one `rm -rf` is much faster than walking it over sftp
+pub fn remove_remote_dir(sess: &Session, path: &str) -> error::Result<()> {
+    run_remote(sess, &format!("rm -rf -- {}", shell_quote(path)))
+        .map_err(|e| format!("Failed to remove remote directory {}: {}", path, e))
+        .context(CserunError::Cleanup)?;
+    Ok(())
+}
 
-    let mut sess = Session::new()?;
-    sess.set_tcp_stream(tcp);
-    sess.handshake()?;
-    println!(
-        "{} {} Handshake successful",
-        style("[2/5]").bold().dim(),
-        CLIP
-    );
+// run a command on its own channel and return its stdout, failing with its stderr.
+// callers tag the error with what the command was for
+pub fn run_remote(sess: &Session, command: &str) -> Result<Vec<u8>, error::Source> {
+    let mut channel = sess.channel_session()?;
+    channel.exec(command)?;
+    remote_output(sess, channel)
+}
+
+// run a longer shell script fed through stdin, which is not limited like a command line
+pub fn run_remote_script(sess: &Session, script: &str) -> Result<Vec<u8>, error::Source> {
+    let mut channel = sess.channel_session()?;
+    channel.exec("sh -s")?;
+    channel.write_all(script.as_bytes())?;
+    channel.send_eof()?;
+    remote_output(sess, channel)
+}
+
+// the stdout of a finished command, or its stderr as the error
+fn remote_output(sess: &Session, mut channel: ssh2::Channel) -> Result<Vec<u8>, error::Source> {
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    copy_output(sess, &mut channel, &mut stdout, &mut stderr)?;
+    channel.wait_close()?;
+    if channel.exit_status()? != 0 {
+        return Err(String::from_utf8_lossy(&stderr).trim().to_string().into());
+    }
+    Ok(stdout)
+}
 
-    match conf.auth {
-        Auth::Password(p) => {
-            sess.userauth_password(conf.username.as_str(), p.as_str())?;
+// copy a channel's stdout and stderr as they come until the command is done. reading one to
+// the end first would hang once the other fills the channel's window
+pub fn copy_output(
+    sess: &Session,
+    channel: &mut ssh2::Channel,
+    stdout: &mut impl Write,
+    stderr: &mut impl Write,
+) -> io::Result<()> {
+    let blocking = sess.is_blocking();
+    sess.set_blocking(false);
+    let mut buffer = [0; 16384];
+    let result = (|| loop {
+        let mut read_any = read_available(channel, &mut buffer, stdout)?;
+        read_any |= read_available(&mut channel.stderr(), &mut buffer, stderr)?;
+        stdout.flush()?;
+        stderr.flush()?;
+        // libssh2 only reports the end once everything before it has been read
+        if channel.eof() {
+            return Ok(());
         }
-        Auth::AuthKey(auth_key) => {
-            sess.userauth_pubkey_file(
-                conf.username.as_str(),
-                auth_key.pubkey.as_ref().map(|p| p.as_path()),
-                auth_key.privekey.as_path(),
-                auth_key.passphrase.as_ref().map(|p| p.as_str()),
-            )?;
+        if !read_any {
+            wait_for_session(sess, IDLE_WAIT);
+        }
+    })();
+    sess.set_blocking(blocking);
+    result
+}
+
+// a remote path as the server's shell and tar see it, windows separators become forward slashes.
+// sftp paths need no help, ssh2 converts them itself
+pub fn remote_str(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    match cfg!(windows) {
+        true => path.replace('\\', "/"),
+        false => path.into_owned(),
+    }
+}
+
+// how a remote path is shown, relative ones are in the home directory
+pub fn display_path(path: impl AsRef<Path>) -> String {
+    let path = remote_str(path.as_ref());
+    match path.starts_with('/') {
+        true => path,
+        false => format!("~/{}", path),
+    }
+}
+
+// a remote path for the shell, relative ones are in the home directory wherever it runs
+pub fn shell_path(path: impl AsRef<Path>) -> String {
+    let path = remote_str(path.as_ref());
+    match path.starts_with('/') {
+        true => shell_quote(&path),
+        false => format!("\"$HOME\"/{}", shell_quote(&path)),
+    }
+}
+
+// a script saved with windows line endings fails on the server with `$'\r': command not found`
+fn unix_line_endings(script: Vec<u8>) -> Vec<u8> {
+    if !script.windows(2).any(|pair| pair == b"\r\n") {
+        return script;
+    }
+    debug!("Converting the script's CRLF line endings to LF");
+    let mut converted = Vec::with_capacity(script.len());
+    for (index, &byte) in script.iter().enumerate() {
+        if byte != b'\r' || script.get(index + 1) != Some(&b'\n') {
+            converted.push(byte);
+        }
+    }
+    converted
+}
+
+// quote a string for a POSIX shell
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// words as one command line, quoted where the shell would otherwise split or expand them.
+// `=` is quoted too, a leading `NAME=value` would be an assignment instead of the command
+pub fn join_args(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-+%@:,./".contains(c));
+            match plain {
+                true => arg.clone(),
+                false => shell_quote(arg),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// open an extra authenticated sftp session, e.g. for a parallel upload worker.
+// the host key must match the one already verified for the main session
+fn open_sftp(
+    conf: &Config,
+    addr: &str,
+    expected_host_key: Option<&[u8]>,
+) -> error::Result<ssh2::Sftp> {
+    let tcp = open_tcp(conf, addr)?;
+    let sess = start_session(tcp, addr, conf)?;
+    if sess.host_key().map(|(key, _)| key) != expected_host_key {
+        return Err(CserunError::Handshake(
+            "Host key changed while opening another connection".into(),
+        ));
+    }
+    let (account_host, _) = split_addr(&conf.server_addr)?;
+    authenticate_timed(&sess, &conf.username, account_host, conf)?;
+    sess.sftp().context(CserunError::Sftp)
+}
+
+// bridge the local terminal and the remote pty until the command exits
+fn pty_loop(
+    sess: &Session,
+    channel: &mut ssh2::Channel,
+    stdin: &Receiver<Vec<u8>>,
+    tunnels: &mut Tunnels,
+    mut transcript: Option<&mut Transcript>,
+    timeout_at: Option<Instant>,
+    encoding: Encoding,
+) -> error::Result<()> {
+    let _raw_mode = terminal::RawMode::enable().context(CserunError::Local)?;
+    terminal::catch_resize();
+    let mut stdout = io::stdout();
+    let mut size = terminal::size();
+    let mut buffer = [0; 4096];
+    let mut decoder = encoding.decoder();
+    loop {
+        // the caller stops the command once the timeout has passed
+        if channel.eof() || timeout_at.is_some_and(|at| Instant::now() > at) {
+            break;
+        }
+
+        let mut is_data_available = tunnels.poll(sess);
+
+        // the pty merges stderr into stdout, pass the raw bytes through untouched
+        match channel.read(&mut buffer) {
+            Ok(size) if size > 0 => {
+                let data = decoder.decode(&buffer[..size]);
+                write_output(&mut stdout, &data)?;
+                if let Some(transcript) = transcript.as_deref_mut() {
+                    transcript.stdout(&data);
+                }
+                is_data_available = true;
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(CserunError::Exec(e.into())),
         }
-        Auth::Agent => {
-            let mut agent = sess.agent()?;
-            agent.connect()?;
-            agent.list_identities()?;
-            let identities = agent.identities()?;
-            if identities.len() == 0 {
-                return Err("No identities found in the ssh-agent".into());
+
+        // keystrokes go to the remote side, ctrl-d is just another byte in raw mode
+        while let Ok(data) = stdin.try_recv() {
+            if !data.is_empty() {
+                write_all_nonblocking(channel, &data).context(CserunError::Exec)?;
+                is_data_available = true;
             }
-            sess.userauth_agent(conf.username.as_str())?;
+        }
+
+        // forward window resizes
+        let new_size = terminal::size();
+        if new_size != size {
+            size = new_size;
+            let _ = channel.request_pty_size(size.0, size.1, None, None);
+        }
+
+        if !is_data_available {
+            send_keepalive(sess).context(CserunError::Exec)?;
+            wait_for_socket(sess, tunnels, idle_wait(&[timeout_at]));
         }
     }
-    println!(
-        "{} {} Authenticated as {}",
-        style("[3/5]").bold().dim(),
-        LOCK,
-        style(conf.username).italic().green()
-    );
+    Ok(())
+}
 
-    let sftp = sess.sftp()?;
+fn handshake(tcp: TcpStream, addr: &str, conf: &Config) -> error::Result<Session> {
+    let (host, port) = split_addr(addr)?;
+    let sess = start_session(tcp, addr, conf)?;
+    known_hosts::check(&sess, host, port, conf.strict_host_key_checking)?;
+    if conf.keepalive_interval > 0 {
+        sess.set_keepalive(false, conf.keepalive_interval);
+    }
+    Ok(sess)
+}
 
-    let local_dir = "./";
-    // get current timestep as file name. e.g. ~/.cserun/temp/2024-02-14-01-10-40-224/
-    let temp_dir_name = chrono::Local::now()
-        .format("%Y-%m-%d-%H-%M-%S-%3f")
-        .to_string();
-    let remote_dir = format!(".cserun/temp/{}", temp_dir_name); // ssh2's sftp use ~/ as root, no need to add ~/
-    let remote_dir_path = Path::new(&remote_dir);
+// a session that has done the ssh handshake, under handshake_timeout. the timeout stays on
+// for logging in, authenticate_timed lifts it
+fn start_session(tcp: TcpStream, addr: &str, conf: &Config) -> error::Result<Session> {
+    let mut sess = Session::new().context(CserunError::Handshake)?;
+    // compression is negotiated during the handshake
+    sess.set_compress(conf.compress);
+    sess.set_tcp_stream(tcp);
+    sess.set_timeout(timeout_millis(conf.handshake_timeout));
+    sess.handshake().map_err(|e| match is_timeout(&e) {
+        true => CserunError::Handshake(
+            timed_out(format!(
+                "{} accepted the connection but did not finish the handshake within {:?}",
+                addr, conf.handshake_timeout
+            ))
+            .into(),
+        ),
+        false => CserunError::Handshake(e.into()),
+    })?;
+    Ok(sess)
+}
 
-    // create the remote dir
-    sftp_mkdir_recursive(&sftp, remote_dir_path)?;
+// log in under handshake_timeout, then let the session block for as long as it needs
+fn authenticate_timed(
+    sess: &Session,
+    username: &str,
+    host: &str,
+    conf: &Config,
+) -> error::Result<()> {
+    let result = authenticate(sess, username, host, &conf.auth);
+    sess.set_timeout(0);
+    result.map_err(|e| match is_timeout_error(&e) {
+        true => CserunError::Auth(
+            timed_out(format!(
+                "The server did not answer the login within {:?}",
+                conf.handshake_timeout
+            ))
+            .into(),
+        ),
+        false => e,
+    })
+}
 
-    // log the command to command.txt
-    let mut remote_command_file = sftp.create(remote_dir_path.join("command.txt").as_path())?;
-    remote_command_file.write_all(conf.command.as_bytes())?;
+// a blocking call that ran past set_timeout
+fn is_timeout(e: &ssh2::Error) -> bool {
+    error_code(e) == Some(LIBSSH2_ERROR_TIMEOUT)
+}
 
-    // setup the container dir
-    let container_path = remote_dir_path.join("container");
-    if !conf.no_sync {
-        upload_dir(&sftp, Path::new(local_dir), container_path.as_path())?;
-        println!(
-            "{} {} Synced local files to remote",
-            style("[4/5]").bold().dim(),
-            TRUCK
-        );
-    } else {
-        // only create the container dir
-        sftp.mkdir(container_path.as_path(), 0o755)?;
-        println!(
-            "{} {} Skipped syncing local files",
-            style("[4/5]").bold().dim(),
-            PROHIBITED
+fn is_timeout_error(e: &CserunError) -> bool {
+    e.source_error()
+        .downcast_ref::<ssh2::Error>()
+        .is_some_and(is_timeout)
+}
+
+// still an io timeout underneath, so the retries treat it as a network error
+fn timed_out(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, message)
+}
+
+// 0 would mean no timeout at all
+fn timeout_millis(timeout: Duration) -> u32 {
+    (timeout.as_millis() as u32).max(1)
+}
+
+// let the kernel probe idle connections too, so NAT boxes and firewalls keep them open. the
+// first probe goes out after keepalive_interval idle seconds instead of the usual two hours
+#[cfg(unix)]
+fn set_tcp_keepalive(tcp: &TcpStream, interval: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if interval == 0 {
+        return Ok(());
+    }
+    let fd = tcp.as_raw_fd();
+    let set = |level: libc::c_int, name: libc::c_int, value: u32| -> io::Result<()> {
+        let value = value.min(libc::c_int::MAX as u32) as libc::c_int;
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+    set(libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, interval)?;
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, interval)?;
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    set(libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, interval)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_tcp_keepalive(_tcp: &TcpStream, _interval: u32) -> io::Result<()> {
+    Ok(())
+}
+
+// how much of a stream is read in one round of the output loop before the rest gets a turn
+const DRAIN_BUDGET: usize = 256 * 1024;
+
+// the longest an idle loop sleeps, only keepalives need it to wake up without a reason
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+// how long an idle loop may sleep and still notice the earliest deadline in time
+fn idle_wait(deadlines: &[Option<Instant>]) -> Duration {
+    let now = Instant::now();
+    deadlines
+        .iter()
+        .flatten()
+        .map(|at| at.saturating_duration_since(now))
+        .fold(IDLE_WAIT, Duration::min)
+}
+
+// block until the session's socket has something for libssh2, there is stdin, ctrl-c or a
+// forwarded connection to handle, or the timeout passes
+#[cfg(unix)]
+fn wait_for_socket(sess: &Session, tunnels: &Tunnels, timeout: Duration) {
+    let mut fds = tunnels.raw_fds();
+    fds.push(terminal::wake_fd());
+    poll_session(sess, &fds, timeout);
+    terminal::drain_wake();
+}
+
+#[cfg(not(unix))]
+fn wait_for_socket(_sess: &Session, _tunnels: &Tunnels, timeout: Duration) {
+    // without poll(2) stdin and ctrl-c are only seen after the sleep
+    std::thread::sleep(timeout.min(Duration::from_millis(100)));
+}
+
+#[cfg(unix)]
+fn wait_for_session(sess: &Session, timeout: Duration) {
+    poll_session(sess, &[], timeout);
+}
+
+#[cfg(not(unix))]
+fn wait_for_session(_sess: &Session, timeout: Duration) {
+    std::thread::sleep(timeout.min(Duration::from_millis(10)));
+}
+
+// block until the session's socket or one of `fds` is ready, or the timeout passes
+#[cfg(unix)]
+fn poll_session(sess: &Session, fds: &[std::os::unix::io::RawFd], timeout: Duration) {
+    use std::os::unix::io::AsRawFd;
+    let mut events = libc::POLLIN;
+    // libssh2 may be stuck on a full send buffer rather than waiting for data
+    if matches!(
+        sess.block_directions(),
+        ssh2::BlockDirections::Outbound | ssh2::BlockDirections::Both
+    ) {
+        events |= libc::POLLOUT;
+    }
+    let mut fds: Vec<libc::pollfd> = std::iter::once((sess.as_raw_fd(), events))
+        .chain(fds.iter().map(|&fd| (fd, libc::POLLIN)))
+        .map(|(fd, events)| libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        })
+        .collect();
+    // errors and interrupts just end the wait early, the caller polls the channel anyway.
+    // rounded up, a wait cut down to 0ms would spin until the deadline
+    unsafe {
+        libc::poll(
+            fds.as_mut_ptr(),
+            fds.len() as libc::nfds_t,
+            timeout.as_micros().div_ceil(1000) as libc::c_int,
         );
     }
+}
 
-    let mut channel = sess.channel_session()?;
-    let mut pre_exec_command = String::new();
-    // set environment variables
-    for env in conf.envs {
-        let env: Vec<&str> = env.split(':').collect();
-        // libssh2's setenv may not work with cse server https://github.com/libssh2/libssh2/issues/546
-        channel.setenv(env[0], env[1]).unwrap_or_else(|_| {
-            pre_exec_command.push_str(&format!("export {}={} && ", env[0], env[1]));
-        });
+// send an ssh keepalive if one is due, a full send buffer just means the link is busy anyway
+fn send_keepalive(sess: &Session) -> io::Result<()> {
+    match sess.keepalive_send() {
+        Ok(_) => Ok(()),
+        Err(e) => match io::Error::from(e) {
+            e if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            e => Err(e),
+        },
     }
-    println!(
-        "{} {} Environment variables set",
-        style("[5/5]").bold().dim(),
-        SPARKLE
-    );
-    // before exec, cd to the remote dir
-    pre_exec_command.push_str(&format!("cd {}/container && ", remote_dir));
-    let command = format!("{}{}", pre_exec_command, conf.command);
-    channel.exec(&command)?;
-    println!(
-        "{} {} Command sented: {}",
-        style("[5/5]").bold().dim(),
-        SPACESHIP,
-        style(conf.command).yellow(),
-    );
+}
 
-    // set to unblocking mode
-    sess.set_blocking(false);
+impl Auth {
+    // the name used in messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Auth::Password(_) => "password",
+            Auth::AuthKey(_) => "key",
+            Auth::Agent(_) => "agent",
+            Auth::KeyboardInteractive => "keyboard-interactive",
+        }
+    }
 
-    println!(
-        "{} {} {}",
-        style("===============").bold().magenta(),
-        style("Output").italic().bold().magenta(),
-        style("===============").bold().magenta()
+    // the ssh userauth method it needs the server to offer
+    fn method(&self) -> &'static str {
+        match self {
+            Auth::Password(_) => "password",
+            Auth::AuthKey(_) | Auth::Agent(_) => "publickey",
+            Auth::KeyboardInteractive => "keyboard-interactive",
+        }
+    }
+}
+
+// try the methods in order, skipping the ones the server does not offer
+fn authenticate(sess: &Session, username: &str, host: &str, auths: &[Auth]) -> error::Result<()> {
+    // asking for the methods tries "none" auth first, which some servers accept
+    let offered = sess
+        .auth_methods(username)
+        .map(String::from)
+        .unwrap_or_default();
+    if sess.authenticated() {
+        return Ok(());
+    }
+    let mut failures = Vec::new();
+    for auth in auths {
+        if !offered.is_empty() && !offered.split(',').any(|method| method == auth.method()) {
+            debug!(
+                "Skipping {} authentication, the server does not offer it",
+                auth.name()
+            );
+            continue;
+        }
+        match try_auth(sess, username, host, auth) {
+            Ok(()) if sess.authenticated() => return Ok(()),
+            Ok(()) => failures.push(format!("{} (not accepted)", auth.name())),
+            // a server that stopped answering will not answer the next method either
+            Err(e) if is_timeout_error(&e) => return Err(e),
+            Err(e) => {
+                debug!("{} authentication failed: {}", auth.name(), e.report());
+                failures.push(format!(
+                    "{} ({})",
+                    auth.name(),
+                    error::Report(e.source_error())
+                ));
+            }
+        }
+    }
+    let message = match failures.is_empty() {
+        true => format!(
+            "None of the configured methods are offered by the server, it accepts {}",
+            offered
+        ),
+        false => format!("Tried {}", failures.join(", ")),
+    };
+    Err(CserunError::Auth(message.into()))
+}
+
+fn try_auth(sess: &Session, username: &str, host: &str, auth: &Auth) -> error::Result<()> {
+    (|| -> Result<(), error::Source> {
+        match auth {
+            Auth::Password(Some(p)) => {
+                sess.userauth_password(username, p.as_str())?;
+            }
+            Auth::Password(None) => {
+                let key = keyring::password_account(username, host);
+                let prompt = format!("{}'s password: ", username);
+                for attempt in 1..=PROMPT_ATTEMPTS {
+                    let password = prompt_secret(&key, &prompt, attempt > 1)?;
+                    match sess.userauth_password(username, &password) {
+                        Ok(()) => break,
+                        Err(e)
+                            if attempt < PROMPT_ATTEMPTS
+                                && error_code(&e) == Some(LIBSSH2_ERROR_AUTHENTICATION_FAILED) =>
+                        {
+                            eprintln!("Permission denied, please try again.");
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            Auth::AuthKey(auth_key) => {
+                // a missing or wrong passphrase is asked for, an unencrypted key needs none
+                let key = keyring::passphrase_account(&auth_key.privekey);
+                let prompt = format!(
+                    "Enter passphrase for key '{}': ",
+                    auth_key.privekey.display()
+                );
+                let mut passphrase = auth_key.passphrase.clone();
+                let mut attempt = 0;
+                loop {
+                    match sess.userauth_pubkey_file(
+                        username,
+                        auth_key.pubkey.as_deref(),
+                        auth_key.privekey.as_path(),
+                        passphrase.as_deref(),
+                    ) {
+                        Ok(()) => break,
+                        Err(e)
+                            if attempt < PROMPT_ATTEMPTS
+                                && error_code(&e) == Some(LIBSSH2_ERROR_FILE) =>
+                        {
+                            if passphrase.is_some() {
+                                eprintln!("Bad passphrase, try again.");
+                            }
+                            attempt += 1;
+                            passphrase = Some(prompt_secret(&key, &prompt, passphrase.is_some())?);
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            Auth::Agent(identity) => {
+                let mut agent = sess.agent()?;
+                agent.connect()?;
+                agent.list_identities()?;
+                let identities = agent.identities()?;
+                if identities.is_empty() {
+                    return Err("No identities found in the ssh-agent".into());
+                }
+                match identity {
+                    None => sess.userauth_agent(username)?,
+                    Some(identity) => {
+                        let key = identities
+                            .iter()
+                            .find(|key| {
+                                key.comment() == identity
+                                    || hash::ssh_fingerprint(key.blob()) == *identity
+                            })
+                            .ok_or_else(|| {
+                                let available: Vec<String> = identities
+                                    .iter()
+                                    .map(|key| {
+                                        format!(
+                                            "{} ({})",
+                                            key.comment(),
+                                            hash::ssh_fingerprint(key.blob())
+                                        )
+                                    })
+                                    .collect();
+                                format!(
+                                    "No identity {:?} in the ssh-agent, it has {}",
+                                    identity,
+                                    available.join(", ")
+                                )
+                            })?;
+                        agent.userauth(username, key)?;
+                    }
+                }
+            }
+            Auth::KeyboardInteractive => {
+                // libssh2 times the whole exchange, typing in a 2FA code included
+                sess.set_timeout(0);
+                sess.userauth_keyboard_interactive(username, &mut TerminalPrompt)?;
+            }
+        }
+        Ok(())
+    })()
+    .context(CserunError::Auth)
+}
+
+// the load average of the last minute per cpu of one address, e.g. 0.5 for a half busy node.
+// an unknown host key fails the probe instead of asking, the real connection asks
+pub fn probe_load(conf: &Config, addr: &str) -> error::Result<f64> {
+    let (host, port) = split_addr(addr)?;
+    let tcp = open_tcp(conf, addr)?;
+    let mut sess = Session::new().context(CserunError::Handshake)?;
+    sess.set_tcp_stream(tcp);
+    sess.set_timeout(timeout_millis(conf.connect_timeout));
+    sess.handshake().context(CserunError::Handshake)?;
+    known_hosts::check(&sess, host, port, true)?;
+    let (account_host, _) = split_addr(&conf.server_addr)?;
+    authenticate(&sess, &conf.username, account_host, &conf.auth)?;
+    let output = run_remote(&sess, "cat /proc/loadavg; getconf _NPROCESSORS_ONLN")
+        .context(CserunError::Exec)?;
+    let output = String::from_utf8_lossy(&output);
+    let mut lines = output.lines();
+    let load = lines
+        .next()
+        .and_then(|line| line.split_whitespace().next()?.parse::<f64>().ok());
+    let cpus = lines
+        .next()
+        .and_then(|line| line.trim().parse::<f64>().ok());
+    match (load, cpus) {
+        (Some(load), Some(cpus)) if cpus > 0.0 => Ok(load / cpus),
+        _ => Err(CserunError::Exec(
+            format!("Unexpected load probe output {:?}", output.trim()).into(),
+        )),
+    }
+}
+
+// a tcp connection to one address, directly or through the jump host
+fn open_tcp(conf: &Config, addr: &str) -> error::Result<TcpStream> {
+    let (host, port) = split_addr(addr)?;
+    if let Some(jump) = &conf.jump_host {
+        return connect_via_jump(jump, conf, host, port);
+    }
+    dial(addr, conf)
+}
+
+// a tcp connection to addr, through the proxy if there is one
+fn dial(addr: &str, conf: &Config) -> error::Result<TcpStream> {
+    let Some(proxy) = &conf.proxy else {
+        return connect_tcp(addr, conf);
+    };
+    let (host, port) = split_addr(addr)?;
+    let mut tcp = connect_tcp(&proxy.addr, conf)?;
+    proxy
+        .open(&mut tcp, host, port, conf.handshake_timeout)
+        .map_err(|e| {
+            let message = match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => format!(
+                    "Proxy {} did not answer within {:?}",
+                    proxy.addr, conf.handshake_timeout
+                ),
+                _ => format!("Proxy {}: {}", proxy.addr, e),
+            };
+            // WouldBlock is how a read timeout shows up on unix
+            let kind = match e.kind() {
+                io::ErrorKind::WouldBlock => io::ErrorKind::TimedOut,
+                kind => kind,
+            };
+            CserunError::Connect(io::Error::new(kind, message).into())
+        })?;
+    Ok(tcp)
+}
+
+// every resolved address of the host, the next one started alongside whenever the last
+// has not answered within CONNECTION_ATTEMPT_DELAY, and the first to connect wins. a broken
+// IPv6 route then costs a quarter of a second instead of the whole connect_timeout.
+// without a timeout a connect to a network that is down (e.g. the VPN) hangs for minutes
+fn connect_tcp(addr: &str, conf: &Config) -> error::Result<TcpStream> {
+    let timeout = conf.connect_timeout;
+    let resolved: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .context(CserunError::Connect)?
+        .collect();
+    if resolved.is_empty() {
+        return Err(CserunError::Connect(
+            format!("{} did not resolve to any address", addr).into(),
+        ));
+    }
+    let candidates = interleave_families(
+        resolved
+            .into_iter()
+            .filter(|socket_addr| conf.address_family.allows(socket_addr))
+            .collect(),
     );
-    let mut buffer = [0; 4096];
+    if candidates.is_empty() {
+        let family = match conf.address_family {
+            AddressFamily::Ipv6 => "IPv6",
+            _ => "IPv4",
+        };
+        return Err(CserunError::Connect(
+            format!("{} has no {} address", addr, family).into(),
+        ));
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut started = 0;
+    let mut pending = 0;
+    let mut failures: Vec<(SocketAddr, io::Error)> = Vec::new();
     loop {
-        if channel.eof() {
-            // if channel closed, break the loop
+        if let Some(&socket_addr) = candidates.get(started) {
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                // the losers' streams are dropped once nobody is listening
+                let _ = sender.send((
+                    socket_addr,
+                    TcpStream::connect_timeout(&socket_addr, timeout),
+                ));
+            });
+            started += 1;
+            pending += 1;
+        }
+        if pending == 0 {
             break;
         }
+        let result = match started < candidates.len() {
+            true => match receiver.recv_timeout(CONNECTION_ATTEMPT_DELAY) {
+                Ok(result) => result,
+                Err(_) => continue,
+            },
+            // every attempt gives up by itself after connect_timeout
+            false => match receiver.recv() {
+                Ok(result) => result,
+                Err(_) => break,
+            },
+        };
+        pending -= 1;
+        match result {
+            (socket_addr, Ok(stream)) => {
+                if candidates.len() > 1 {
+                    debug!("Connected to {} over {}", addr, socket_addr);
+                }
+                set_tcp_keepalive(&stream, conf.keepalive_interval)
+                    .context(CserunError::Connect)?;
+                return Ok(stream);
+            }
+            (socket_addr, Err(e)) => {
+                debug!("Could not connect to {}: {}", socket_addr, e);
+                failures.push((socket_addr, e));
+            }
+        }
+    }
+
+    let describe = |e: &io::Error| match e.kind() {
+        io::ErrorKind::TimedOut => format!("did not answer within {:?}", timeout),
+        _ => e.to_string(),
+    };
+    let all_timed_out = failures
+        .iter()
+        .all(|(_, e)| e.kind() == io::ErrorKind::TimedOut);
+    let kind = failures
+        .last()
+        .map_or(io::ErrorKind::TimedOut, |(_, e)| e.kind());
+    let message = match (failures.as_slice(), all_timed_out) {
+        ([(_, e)], false) => describe(e),
+        ([_], true) => format!(
+            "{} did not answer within {:?}, is the network or VPN up?",
+            addr, timeout
+        ),
+        (failures, _) => format!(
+            "No address of {} could be reached ({})",
+            addr,
+            failures
+                .iter()
+                .map(|(socket_addr, e)| format!("{}: {}", socket_addr, describe(e)))
+                .collect::<Vec<_>>()
+                .join("; ")
+        ),
+    };
+    // the kind is kept, so the retries still tell network errors apart
+    Err(CserunError::Connect(io::Error::new(kind, message).into()))
+}
+
+// alternate between IPv6 and IPv4, starting with the family the resolver listed first
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_is_ipv6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .into_iter()
+        .partition(|socket_addr| socket_addr.is_ipv6() == first_is_ipv6);
+    preferred.reverse();
+    other.reverse();
+    let mut interleaved = Vec::new();
+    while let Some(socket_addr) = preferred.pop() {
+        interleaved.push(socket_addr);
+        interleaved.extend(other.pop());
+    }
+    interleaved.extend(other.into_iter().rev());
+    interleaved
+}
+
+// like OpenSSH's -J: log into the jump host, open a direct-tcpip channel to the target
+// and hand back a local socket that is bridged to that channel
+fn connect_via_jump(
+    jump: &str,
+    conf: &Config,
+    target_host: &str,
+    target_port: u16,
+) -> error::Result<TcpStream> {
+    // jump host is in the format of [user@]host[:port]
+    let (jump_user, jump_addr) = match jump.split_once('@') {
+        Some((user, addr)) => (user, addr),
+        None => (conf.username.as_str(), jump),
+    };
+    let jump_addr = normalize_addr(jump_addr, 22)
+        .map_err(|e| format!("Invalid jump host: {}", e))
+        .context(CserunError::Config)?;
+    let (jump_host, _) = split_addr(&jump_addr)?;
+    let tcp = dial(&jump_addr, conf)?;
+    let jump_sess = handshake(tcp, &jump_addr, conf)?;
+    authenticate_timed(&jump_sess, jump_user, jump_host, conf)?;
+    let channel = jump_sess
+        .channel_direct_tcpip(target_host, target_port, None)
+        .context(CserunError::Connect)?;
+
+    // libssh2 needs a real socket, so bridge the channel through a loopback connection
+    let (stream, bridge) = (|| -> io::Result<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (bridge, _) = listener.accept()?;
+        Ok((stream, bridge))
+    })()
+    .context(CserunError::Local)?;
+    std::thread::spawn(move || {
+        let _ = pump_channel(jump_sess, channel, bridge);
+    });
+    Ok(stream)
+}
 
+// copy bytes both ways between the loopback socket and the jump channel until either side closes
+fn pump_channel(
+    sess: Session,
+    mut channel: ssh2::Channel,
+    mut socket: TcpStream,
+) -> io::Result<()> {
+    sess.set_blocking(false);
+    socket.set_nonblocking(true)?;
+    let mut buffer = [0; 16384];
+    loop {
         let mut is_data_available = false;
 
-        // try to read the standard output
-        match channel.read(&mut buffer) {
-            Ok(size) if size > 0 => {
-                print!("{}", String::from_utf8_lossy(&buffer[..size]));
+        match socket.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => {
+                write_all_nonblocking(&mut channel, &buffer[..size])?;
                 is_data_available = true;
             }
-            Ok(_) => {}
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(e.into()),
+            Err(e) => return Err(e),
         }
 
-        // try to read the standard error
-        match channel.stderr().read(&mut buffer) {
+        match channel.read(&mut buffer) {
             Ok(size) if size > 0 => {
-                eprint!("{}", String::from_utf8_lossy(&buffer[..size]));
+                write_all_nonblocking(&mut socket, &buffer[..size])?;
                 is_data_available = true;
             }
             Ok(_) => {}
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(e.into()),
+            Err(e) => return Err(e),
         }
 
+        if channel.eof() {
+            break;
+        }
         if !is_data_available {
-            // wait for 100ms to reduce CPU usage
-            std::thread::sleep(Duration::from_millis(100));
+            send_keepalive(&sess)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::io::AsRawFd;
+                poll_session(&sess, &[socket.as_raw_fd()], IDLE_WAIT);
+            }
+            #[cfg(not(unix))]
+            std::thread::sleep(Duration::from_millis(5));
         }
     }
+    Ok(())
+}
 
-    channel.wait_close()?;
-    println!(
-        "{}",
-        style("======================================")
-            .bold()
-            .magenta()
-    );
+// the command's output goes out as the bytes it wrote, e.g. binary data piped through cserun
+fn write_output<W: Write>(out: &mut W, data: &[u8]) -> error::Result<()> {
+    out.write_all(data)
+        .and_then(|_| out.flush())
+        .context(CserunError::Local)
+}
 
-    let exit_status = channel.exit_status()?;
-    match exit_status {
-        0 => println!("Exit status: {}", style("Success").green()),
-        _status => println!("Exit status: {}", style(format!("Error {}", _status)).red()),
+fn write_all_nonblocking<W: Write>(writer: &mut W, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(1))
+            }
+            Err(e) => return Err(e),
+        }
     }
-
-    Ok(exit_status)
+    Ok(())
 }
 
-fn sftp_mkdir_recursive(sftp: &ssh2::Sftp, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    let mut current_path = PathBuf::new();
-    for component in path.components() {
-        current_path.push(component);
-        if let Ok(metadata) = sftp.stat(current_path.as_path()) {
-            if metadata.is_dir() {
-                continue;
+fn send_eof_nonblocking(channel: &mut ssh2::Channel) -> io::Result<()> {
+    loop {
+        match channel.send_eof().map_err(io::Error::from) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(1))
             }
-            return Err(format!("{:?} is not a directory", current_path).into());
+            result => return result,
         }
-        sftp.mkdir(current_path.as_path(), 0o755)?;
     }
-    Ok(())
 }
 
-// upload every file and directory in the local directory to remote directory
-fn upload_dir(
-    sftp: &ssh2::Sftp,
-    local_path: &Path,
-    remote_base_path: &Path,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let walker = WalkBuilder::new(local_path)
-        .ignore(true) // https://docs.rs/ignore/latest/ignore/struct.WalkBuilder.html#method.ignore
-        .git_ignore(true) // https://docs.rs/ignore/latest/ignore/struct.WalkBuilder.html#method.git_ignore
-        .build();
-
-    let spinner_style = ProgressStyle::default_spinner()
-        .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏")
-        .template("{prefix:.bold.dim} {spinner} {wide_msg}")?;
-    let pb = ProgressBar::new_spinner();
-    pb.set_style(spinner_style);
-    pb.set_prefix("Syncing");
-    pb.enable_steady_tick(Duration::from_millis(100));
-
-    for result in walker {
-        if let Ok(entry) = result {
-            let path = entry.path();
-            // Calculate the relative path
-            if let Ok(strip_path) = path.strip_prefix(local_path) {
-                let remote_path = remote_base_path.join(strip_path);
-                if path.is_dir() {
-                    // Make sure the remote directory exists
-                    match sftp.mkdir(&remote_path, 0o755) {
-                        Ok(_) => pb.set_message(
-                            format!("{} Created remote dir: {:?}", FOLDER, remote_path).to_string(),
-                        ),
-                        Err(err) => {
-                            println!("Directory creation error (might already exist): {:?}", err)
-                        }
-                    }
-                } else {
-                    upload_file(sftp, path, &remote_path)?;
-                    pb.set_message(
-                        format!("{} Uploaded file: {:?}", FILE, remote_path).to_string(),
-                    );
-                }
+// split "host:port" or "[ipv6]:port" into its parts, the host without brackets
+fn split_addr(addr: &str) -> error::Result<(&str, u16)> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Invalid server address: {}", addr))
+        .context(CserunError::Config)?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok((host, port.parse().context(CserunError::Config)?))
+}
+
+// "host", "host:port", "ipv6", "[ipv6]" or "[ipv6]:port" as the "host:port" everything else
+// works with, `port` when the address has none
+pub fn normalize_addr(addr: &str, port: u16) -> Result<String, String> {
+    let addr = addr.trim();
+    let (host, own_port) = match addr.strip_prefix('[') {
+        Some(rest) => {
+            let (ip, after) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("{:?} is missing the closing ]", addr))?;
+            if ip.parse::<std::net::Ipv6Addr>().is_err() {
+                return Err(format!("{:?} is not an IPv6 address", ip));
+            }
+            match after {
+                "" => (format!("[{}]", ip), None),
+                _ => match after.strip_prefix(':') {
+                    Some(port) => (format!("[{}]", ip), Some(port)),
+                    None => return Err(format!("unexpected {:?} after the ] in {:?}", after, addr)),
+                },
             }
         }
+        None if addr.matches(':').count() > 1 => match addr.parse::<std::net::Ipv6Addr>() {
+            Ok(_) => (format!("[{}]", addr), None),
+            Err(_) => {
+                return Err(format!(
+                    "{:?} is not a valid address, put IPv6 addresses with a port in brackets, e.g. [2001:db8::1]:22",
+                    addr
+                ))
+            }
+        },
+        None => match addr.split_once(':') {
+            Some((host, port)) => (host.to_string(), Some(port)),
+            None => (addr.to_string(), None),
+        },
+    };
+    if host.is_empty() {
+        return Err(format!("{:?} has no host name", addr));
     }
+    if host.contains(|c: char| c.is_whitespace() || c == '/' || c == '@') {
+        return Err(format!(
+            "{:?} is not a host name, give the user and port separately",
+            host
+        ));
+    }
+    let port = match own_port {
+        Some(own_port) => match own_port.parse::<u16>() {
+            Ok(port) if port > 0 => port,
+            _ => return Err(format!("invalid port {:?} in {:?}", own_port, addr)),
+        },
+        None => port,
+    };
+    Ok(format!("{}:{}", host, port))
+}
 
-    Ok(())
+// the same address on another port
+pub fn replace_port(addr: &str, port: u16) -> String {
+    match addr.rsplit_once(':') {
+        Some((host, _)) => format!("{}:{}", host, port),
+        None => format!("{}:{}", addr, port),
+    }
 }
 
-fn upload_file(
-    sftp: &ssh2::Sftp,
-    local_path: &Path,
-    remote_path: &Path,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut file = fs::File::open(local_path)?;
-    let mut contents = Vec::new();
-    file.read_to_end(&mut contents)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_addr_strips_ipv6_brackets() {
+        assert_eq!(
+            split_addr("cse.unsw.edu.au:22").unwrap(),
+            ("cse.unsw.edu.au", 22)
+        );
+        assert_eq!(
+            split_addr("[2001:db8::1]:2222").unwrap(),
+            ("2001:db8::1", 2222)
+        );
+        assert!(split_addr("cse.unsw.edu.au").is_err());
+        assert!(split_addr("host:ssh").is_err());
+    }
+
+    #[test]
+    fn normalize_addr_adds_the_port_and_brackets() {
+        assert_eq!(
+            normalize_addr("cse.unsw.edu.au", 22).unwrap(),
+            "cse.unsw.edu.au:22"
+        );
+        assert_eq!(normalize_addr(" login0:2222 ", 22).unwrap(), "login0:2222");
+        assert_eq!(
+            normalize_addr("2001:db8::1", 22).unwrap(),
+            "[2001:db8::1]:22"
+        );
+        assert_eq!(
+            normalize_addr("[2001:db8::1]", 22).unwrap(),
+            "[2001:db8::1]:22"
+        );
+        assert_eq!(normalize_addr("[::1]:2222", 22).unwrap(), "[::1]:2222");
+    }
+
+    #[test]
+    fn normalize_addr_rejects_what_is_not_an_address() {
+        for addr in [
+            "",
+            ":22",
+            "host:0",
+            "host:99999",
+            "z5555555@cse.unsw.edu.au",
+            "cse.unsw.edu.au/22",
+            "[2001:db8::1",
+            "[not-ipv6]:22",
+            "[::1]22",
+            "2001:db8::zz",
+        ] {
+            assert!(normalize_addr(addr, 22).is_err(), "{:?}", addr);
+        }
+    }
 
-    let mut remote_file = sftp.create(remote_path)?;
-    remote_file.write_all(&contents)?;
+    #[test]
+    fn chain_commands_stops_at_the_first_failure() {
+        let commands = ["make".to_string(), "./test # all".to_string()];
+        assert_eq!(chain_commands(&commands[..1], false), "make");
+        assert_eq!(
+            chain_commands(&commands, false),
+            "{ make\n} && { ./test # all\n}"
+        );
+        assert_eq!(
+            chain_commands(&commands, true),
+            "{ status=0; { make\n} || status=$?; { ./test # all\n} || status=$?; exit $status; }"
+        );
+    }
 
-    Ok(())
+    #[test]
+    fn without_login_drops_only_the_login_flags() {
+        assert_eq!(without_login("bash -l -c"), "bash -c");
+        assert_eq!(without_login("bash -lc"), "bash -c");
+        assert_eq!(without_login("zsh --login -c"), "zsh -c");
+        assert_eq!(without_login("bash -c"), "bash -c");
+        assert_eq!(
+            without_login("/usr/local/bin/fish -l -c"),
+            "/usr/local/bin/fish -c"
+        );
+    }
 }
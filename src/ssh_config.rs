@@ -0,0 +1,123 @@
+use std::fs;
+use std::path::PathBuf;
+
+// options cserun cares about from a `Host` block in ~/.ssh/config
+#[derive(Debug, Default)]
+pub struct HostConfig {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_file: Option<PathBuf>,
+    pub proxy_jump: Option<String>,
+}
+
+fn ssh_config_path() -> Option<PathBuf> {
+    let mut path = dirs::home_dir()?;
+    path.push(".ssh");
+    path.push("config");
+    Some(path)
+}
+
+// resolve a host alias through ~/.ssh/config, a missing file just yields an empty config
+pub fn lookup(alias: &str) -> Result<HostConfig, Box<dyn std::error::Error>> {
+    match ssh_config_path() {
+        Some(path) if path.exists() => parse(&fs::read_to_string(path)?, alias),
+        _ => Ok(HostConfig::default()),
+    }
+}
+
+fn parse(contents: &str, alias: &str) -> Result<HostConfig, Box<dyn std::error::Error>> {
+    let mut config = HostConfig::default();
+    // options before the first Host line apply to every host
+    let mut active = true;
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // both "Key value" and "Key=value" are valid
+        let (key, value) = match line.find(|c: char| c.is_whitespace() || c == '=') {
+            Some(i) => (
+                &line[..i],
+                line[i..].trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+            ),
+            None => continue,
+        };
+        let value = value.trim().trim_matches('"');
+
+        match key.to_lowercase().as_str() {
+            "host" => active = host_matches(value, alias),
+            // Match blocks need runtime evaluation, skip them entirely
+            "match" => active = false,
+            // like OpenSSH, the first obtained value for each option wins
+            _ if !active => {}
+            "hostname" if config.hostname.is_none() => {
+                config.hostname = Some(value.replace("%h", alias))
+            }
+            "user" if config.user.is_none() => config.user = Some(value.to_string()),
+            "port" if config.port.is_none() => config.port = Some(value.parse()?),
+            "identityfile" if config.identity_file.is_none() => {
+                config.identity_file = Some(expand_tilde(value))
+            }
+            "proxyjump" if config.proxy_jump.is_none() => {
+                if value.contains(',') {
+                    return Err("ProxyJump with multiple hops is not supported".into());
+                }
+                config.proxy_jump = Some(value.to_string());
+            }
+            _ => {}
+        }
+    }
+    // "ProxyJump none" still wins over later blocks, but means no jump host
+    config.proxy_jump = config
+        .proxy_jump
+        .filter(|jump| !jump.eq_ignore_ascii_case("none"));
+    Ok(config)
+}
+
+// a Host line holds several patterns, any negated match excludes the host
+fn host_matches(patterns: &str, alias: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split_whitespace() {
+        if let Some(negated) = pattern.strip_prefix('!') {
+            if wildcard_match(negated, alias) {
+                return false;
+            }
+        } else if wildcard_match(pattern, alias) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+// `*` matches any run of characters and `?` a single one
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn expand_tilde(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
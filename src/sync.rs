@@ -0,0 +1,1718 @@
+use crate::cache::{file_mode, FileEntry, Manifest};
+use crate::delta::{self, Op, Signature};
+use crate::error::{self, Context, CserunError};
+use crate::events;
+use crate::hash;
+use crate::partial;
+use crate::ssh::{self, GitFiles, Symlinks, TarCompression, WalkOptions};
+use crate::tar::TarWriter;
+use crate::throttle::{RateLimit, Throttled};
+use console::Emoji;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use log::{debug, warn};
+use ssh2::{FileStat, OpenFlags, OpenType, Session, Sftp};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
+
+static FOLDER: Emoji<'_, '_> = Emoji("📁 ", "");
+static FILE: Emoji<'_, '_> = Emoji("📄 ", "");
+
+// like mkdir -p -m, the mode only applies to the last directory, the parents are shared ones
+pub fn sftp_mkdir_recursive(sftp: &Sftp, path: &Path, mode: u32) -> error::Result<()> {
+    let mut current_path = PathBuf::new();
+    let last = path.components().count();
+    for (index, component) in path.components().enumerate() {
+        current_path.push(component);
+        if let Ok(metadata) = sftp.stat(current_path.as_path()) {
+            if metadata.is_dir() {
+                continue;
+            }
+            return Err(CserunError::Sftp(
+                format!("{:?} is not a directory", current_path).into(),
+            ));
+        }
+        let mode = match index + 1 == last {
+            true => mode,
+            false => ssh::DIR_MODE,
+        };
+        sftp_mkdir(sftp, &current_path, mode)?;
+    }
+    Ok(())
+}
+
+// create a remote directory, false if there already is one. OpenSSH reports an existing path
+// as a generic failure, so only a directory found there afterwards makes a failure harmless.
+// with nothing there it is tried once more, then it is a real error like a permission or quota
+fn sftp_mkdir(sftp: &Sftp, path: &Path, mode: u32) -> error::Result<bool> {
+    let mut retried = false;
+    loop {
+        let err = match sftp.mkdir(path, mode as i32) {
+            Ok(()) => return Ok(true),
+            Err(err) => err,
+        };
+        match sftp.stat(path) {
+            Ok(stat) if stat.is_dir() => return Ok(false),
+            Ok(_) => {
+                return Err(CserunError::Transfer(
+                    format!("{:?} is not a directory", path).into(),
+                ))
+            }
+            Err(_) if !retried => retried = true,
+            Err(_) => {
+                return Err(CserunError::Transfer(
+                    format!("Failed to create the directory {:?}: {}", path, err).into(),
+                ))
+            }
+        }
+    }
+}
+
+// without progress, nothing is drawn and callers print plain lines instead
+pub fn sync_spinner(progress: bool) -> error::Result<ProgressBar> {
+    if !progress {
+        return Ok(ProgressBar::hidden());
+    }
+    let spinner_style = ProgressStyle::default_spinner()
+        .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏")
+        .template("{prefix:.bold.dim} {spinner} {wide_msg}")
+        .context(CserunError::Local)?;
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(spinner_style);
+    pb.set_prefix("Syncing");
+    pb.enable_steady_tick(Duration::from_millis(100));
+    Ok(pb)
+}
+
+fn transfer_bar(
+    prefix: &'static str,
+    total_bytes: u64,
+    progress: bool,
+) -> error::Result<ProgressBar> {
+    if !progress {
+        return Ok(ProgressBar::hidden());
+    }
+    let bar_style = ProgressStyle::default_bar()
+        .template(
+            "{prefix:.bold.dim} [{bar:30.cyan/blue}] {bytes}/{total_bytes} ({eta}) {wide_msg}",
+        )
+        .context(CserunError::Local)?
+        .progress_chars("=> ");
+    let pb = ProgressBar::new(total_bytes);
+    pb.set_style(bar_style);
+    pb.set_prefix(prefix);
+    Ok(pb)
+}
+
+// a file that needs to be sent, with the manifest entry to record once it's there
+pub struct PendingUpload {
+    local_path: PathBuf,
+    pub size: u64,
+    remote_path: PathBuf,
+    manifest_entry: Option<(String, FileEntry)>,
+    previous_hash: Option<String>, // what the workspace copy holds, for sending a delta against
+}
+
+// walk the local directory, create the remote directories and work out which files need
+// uploading. with a manifest only new or changed files are returned. returns (pending, unchanged)
+pub fn plan_upload(
+    sftp: &Sftp,
+    local_path: &Path,
+    remote_base_path: &Path,
+    mut manifest: Option<&mut Manifest>,
+    resume: bool,
+    conf: &ssh::Config,
+    pb: &ProgressBar,
+) -> error::Result<(Vec<PendingUpload>, usize)> {
+    let (walk, dir_mode) = (&conf.walk, conf.remote_dir_mode.unwrap_or(ssh::DIR_MODE));
+    let walker = build_walker(local_path, walk).context(CserunError::Config)?;
+
+    // what is already on the remote side, one readdir per directory instead of a stat per file
+    let remote_files = match manifest.is_some() || resume {
+        true => remote_tree(sftp, remote_base_path)?,
+        false => HashMap::new(),
+    };
+
+    // the walk starts below these with --path
+    for path in &walk.paths {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            sftp_mkdir_recursive(sftp, &remote_base_path.join(parent), dir_mode)?;
+        }
+    }
+
+    let mut pending = Vec::new();
+    let mut unchanged = 0;
+    for entry in walker.flatten() {
+        let path = entry.path();
+        // Calculate the relative path
+        if let Ok(strip_path) = path.strip_prefix(local_path) {
+            // the base directory itself is created by the caller
+            if strip_path.as_os_str().is_empty() {
+                continue;
+            }
+            let remote_path = remote_base_path.join(strip_path);
+            if entry.path_is_symlink() {
+                // only reached when links are not followed
+                match walk.symlinks {
+                    Symlinks::Preserve => {
+                        let target = fs::read_link(path).context(CserunError::Local)?;
+                        // replace whatever an earlier run left there
+                        let _ = sftp.unlink(&remote_path);
+                        // OpenSSH swaps the sftp symlink arguments, ssh2 documents it that way round
+                        sftp.symlink(&target, &remote_path)
+                            .context(CserunError::Sftp)?;
+                        pb.set_message(format!(
+                            "{} Linked {:?} -> {:?}",
+                            FILE, remote_path, target
+                        ));
+                    }
+                    _ => warn!("Skipped symlink: {:?}", strip_path),
+                }
+            } else if path.is_dir() {
+                if remote_files
+                    .get(&remote_path)
+                    .is_some_and(|stat| stat.is_dir())
+                {
+                    continue;
+                }
+                // Make sure the remote directory exists
+                if sftp_mkdir(sftp, &remote_path, dir_mode)? {
+                    debug!("{} Created remote dir: {:?}", FOLDER, remote_path);
+                    pb.set_message(format!("{} Created remote dir: {:?}", FOLDER, remote_path))
+                }
+            } else if let Some(manifest) = manifest.as_deref_mut() {
+                let key = ssh::remote_str(strip_path);
+                let local_entry = manifest.entry_for(&key, path).context(CserunError::Local)?;
+                let remote_size = remote_files.get(&remote_path).and_then(|stat| stat.size);
+                let old = manifest.files.get(&key);
+                let is_unchanged = old.is_some_and(|old| {
+                    old.hash == local_entry.hash && old.mode == local_entry.mode
+                }) && remote_size == Some(local_entry.size);
+                // the remote copy is only trusted to be the recorded one if the size still matches
+                let previous_hash = old
+                    .filter(|old| remote_size == Some(old.size))
+                    .map(|old| old.hash.clone());
+                if is_unchanged {
+                    // refresh the entry anyway, e.g. for a new mtime
+                    manifest.files.insert(key, local_entry);
+                    unchanged += 1;
+                } else {
+                    pending.push(PendingUpload {
+                        local_path: path.to_path_buf(),
+                        size: local_entry.size,
+                        remote_path,
+                        manifest_entry: Some((key, local_entry)),
+                        previous_hash,
+                    });
+                }
+            } else {
+                let size = entry.metadata().context(CserunError::Local)?.len();
+                // when resuming into the same run dir, a full size file was written by the last attempt
+                let remote_size = remote_files.get(&remote_path).and_then(|stat| stat.size);
+                if resume && remote_size == Some(size) {
+                    unchanged += 1;
+                    continue;
+                }
+                pending.push(PendingUpload {
+                    local_path: path.to_path_buf(),
+                    size,
+                    remote_path,
+                    manifest_entry: None,
+                    previous_hash: None,
+                });
+            }
+        }
+    }
+
+    Ok((pending, unchanged))
+}
+
+// the files of the last sync that are gone from the local directory, deleted or renamed.
+// a file that is only left out by the ignore rules or --exclude this time is still there
+pub fn removed_files(local_path: &Path, manifest: &Manifest) -> Vec<String> {
+    manifest
+        .files
+        .keys()
+        .filter(|key| {
+            fs::symlink_metadata(local_path.join(key))
+                .is_err_and(|e| e.kind() == io::ErrorKind::NotFound)
+        })
+        .cloned()
+        .collect()
+}
+
+// delete the remote copies of files removed locally, and the directories that leaves empty.
+// returns how many files were deleted
+pub fn delete_removed(
+    sftp: &Sftp,
+    local_path: &Path,
+    remote_base_path: &Path,
+    manifest: &mut Manifest,
+) -> error::Result<usize> {
+    let removed = removed_files(local_path, manifest);
+    for key in &removed {
+        let remote_path = remote_base_path.join(key);
+        match sftp.unlink(&remote_path) {
+            Ok(()) => debug!("Deleted remote file: {:?}", remote_path),
+            // already gone, e.g. the command deleted it
+            Err(e) if e.code() == ssh2::ErrorCode::SFTP(2) => {}
+            Err(e) => return Err(CserunError::Sftp(e.into())),
+        }
+        manifest.files.remove(key);
+        // rmdir fails on a directory that still has something in it, which ends the climb
+        for dir in remote_path.ancestors().skip(1) {
+            if dir == remote_base_path || !dir.starts_with(remote_base_path) {
+                break;
+            }
+            if sftp.rmdir(dir).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(removed.len())
+}
+
+// the files an upload of the local directory would send, with their sizes
+pub fn list_files(local_path: &Path, walk: &WalkOptions) -> error::Result<Vec<(PathBuf, u64)>> {
+    let walker = build_walker(local_path, walk).context(CserunError::Config)?;
+    let mut files = Vec::new();
+    for entry in walker.flatten() {
+        if entry.path_is_symlink() || !entry.path().is_file() {
+            continue;
+        }
+        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        files.push((entry.path().to_path_buf(), size));
+    }
+    Ok(files)
+}
+
+// count the files and bytes an upload of the local directory would send
+pub fn scan(local_path: &Path, walk: &WalkOptions) -> error::Result<(usize, u64)> {
+    let files = list_files(local_path, walk)?;
+    Ok((files.len(), files.iter().map(|(_, size)| size).sum()))
+}
+
+// a relative path that does not lead above the directory it is relative to
+pub fn is_inside(path: &Path) -> bool {
+    let mut depth = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+// build output and environments nobody means to upload, left out unless default_excludes = false
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    "target/",
+    "node_modules/",
+    ".venv/",
+    "venv/",
+    "__pycache__/",
+    ".pytest_cache/",
+    ".mypy_cache/",
+    "*.o",
+    "*.pyc",
+    "*.class",
+];
+
+fn build_walker(local_path: &Path, walk: &WalkOptions) -> io::Result<ignore::Walk> {
+    // --path walks just the listed files and directories, otherwise the whole local dir
+    let mut roots = Vec::new();
+    for path in &walk.paths {
+        let problem = match (is_inside(path), local_path.join(path).exists()) {
+            (false, _) => "is not inside",
+            (true, false) => "does not exist in",
+            (true, true) => "",
+        };
+        if !problem.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "--path {} {} {}",
+                    path.display(),
+                    problem,
+                    local_path.display()
+                ),
+            ));
+        }
+        roots.push(local_path.join(path));
+    }
+    if roots.is_empty() {
+        roots.push(local_path.to_path_buf());
+    }
+
+    // patterns from the config apply on top of the ignore files, relative to the local dir
+    let mut config_ignore = GitignoreBuilder::new(local_path);
+    for pattern in &walk.ignore {
+        config_ignore
+            .add_line(None, pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    let config_ignore = config_ignore
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut default_ignore = GitignoreBuilder::new(local_path);
+    if walk.default_excludes {
+        for pattern in DEFAULT_EXCLUDES {
+            default_ignore
+                .add_line(None, pattern)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+    }
+    let default_ignore = default_ignore
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // with --include the walk goes into the default excluded directories, for the files it names
+    let has_includes = !walk.include.is_empty();
+
+    // --include/--exclude only ever narrow down what the ignore files let through
+    let mut overrides = OverrideBuilder::new(local_path);
+    let excludes = walk.exclude.iter().map(|glob| format!("!{}", glob));
+    for glob in walk.include.iter().cloned().chain(excludes) {
+        overrides
+            .add(&glob)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    let overrides = overrides
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut builder = WalkBuilder::new(&roots[0]);
+    for root in &roots[1..] {
+        builder.add(root);
+    }
+    let git_files = match walk.git_only {
+        Some(files) => Some(git_files(local_path, files)?),
+        None => None,
+    };
+    let (max_file_size, skip_binaries) = (walk.max_file_size, walk.skip_binaries);
+    let hidden = walk.hidden;
+    let base = local_path.to_path_buf();
+    let sources = walk.ignore_files;
+    if sources.cserunignore {
+        builder.add_custom_ignore_filename(".cserunignore"); // same syntax as .gitignore, for files that are committed but not needed remotely
+    }
+    Ok(builder
+        .ignore(sources.ignore) // https://docs.rs/ignore/latest/ignore/struct.WalkBuilder.html#method.ignore
+        .git_ignore(sources.gitignore) // https://docs.rs/ignore/latest/ignore/struct.WalkBuilder.html#method.git_ignore
+        .git_global(sources.git_global) // editor swap files, .DS_Store and the like
+        .git_exclude(sources.git_exclude)
+        // like git, the git ignore files only count inside a repository unless asked for
+        .require_git(!sources.outside_git)
+        // dotfiles are left out below instead, to say which
+        .hidden(false)
+        .follow_links(matches!(walk.symlinks, Symlinks::Follow))
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            let configured = config_ignore.matched_path_or_any_parents(entry.path(), is_dir);
+            let overridden = overrides.matched(entry.path(), is_dir);
+            // a `!pattern` in the config or an --include brings a default excluded file back
+            let brought_back =
+                configured.is_whitelist() || overridden.is_whitelist() || (is_dir && has_includes);
+            let default_excluded = default_ignore
+                .matched_path_or_any_parents(entry.path(), is_dir)
+                .is_ignore()
+                && !brought_back;
+            // the repository itself is never uploaded, --hidden or not
+            if entry.depth() > 0 && entry.file_name() == ".git" {
+                return false;
+            }
+            !configured.is_ignore()
+                && !default_excluded
+                && !overridden.is_ignore()
+                && git_files.as_ref().is_none_or(|(files, dirs)| {
+                    files.contains(entry.path()) || (is_dir && dirs.contains(entry.path()))
+                })
+                && (hidden == Some(true)
+                    || brought_back
+                    || !hidden_skipped(&base, entry, hidden.is_none()))
+                && (is_dir || !skipped(&base, entry, max_file_size, skip_binaries))
+        })
+        .build())
+}
+
+// why a file was left out by --max-file-size, --skip-binaries or for being hidden
+#[derive(Clone, Copy)]
+enum Skip {
+    TooLarge(u64),
+    Binary,
+    Hidden,
+}
+
+// what the walks left out since the last report, by path relative to the local dir. a run walks
+// the tree more than once, each file is reported once. kept per thread, which is per run: runs on
+// several --host go side by side on threads of their own, and each reports its own
+thread_local! {
+    static SKIPPED: RefCell<BTreeMap<PathBuf, Skip>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn skipped(
+    base: &Path,
+    entry: &ignore::DirEntry,
+    max_file_size: Option<u64>,
+    skip_binaries: bool,
+) -> bool {
+    if max_file_size.is_none() && !skip_binaries {
+        return false;
+    }
+    let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+    let skip = match max_file_size {
+        Some(max) if size > max => Skip::TooLarge(size),
+        _ if skip_binaries && is_binary(entry.path()) => Skip::Binary,
+        _ => return false,
+    };
+    let path = entry.path().strip_prefix(base).unwrap_or(entry.path());
+    SKIPPED.with_borrow_mut(|skipped| skipped.insert(path.to_path_buf(), skip));
+    true
+}
+
+// a dotfile or dot directory below the local dir, remembered for the report unless `hidden = false`
+// was set on purpose
+fn hidden_skipped(base: &Path, entry: &ignore::DirEntry, report: bool) -> bool {
+    let is_hidden = entry.depth() > 0 && entry.file_name().as_encoded_bytes().starts_with(b".");
+    if is_hidden && report {
+        let path = entry.path().strip_prefix(base).unwrap_or(entry.path());
+        SKIPPED.with_borrow_mut(|skipped| skipped.insert(path.to_path_buf(), Skip::Hidden));
+    }
+    is_hidden
+}
+
+// git's test: a NUL byte in the first 8000 bytes
+fn is_binary(path: &Path) -> bool {
+    let mut start = Vec::with_capacity(8000);
+    match fs::File::open(path) {
+        Ok(file) => file.take(8000).read_to_end(&mut start).is_ok() && start.contains(&0),
+        Err(_) => false,
+    }
+}
+
+// say what --max-file-size, --skip-binaries and the hidden files rule left out, so a core dump
+// that is not uploaded does not go unnoticed, nor a .env the build needs. returns how many were
+// left out
+pub fn report_skipped(max_file_size: Option<u64>) -> usize {
+    let skipped = SKIPPED.take();
+    let count = skipped.len();
+    let (hidden, skipped): (Vec<_>, Vec<_>) = skipped
+        .into_iter()
+        .partition(|(_, skip)| matches!(skip, Skip::Hidden));
+    let (large, binary): (Vec<_>, Vec<_>) = skipped
+        .into_iter()
+        .partition(|(_, skip)| matches!(skip, Skip::TooLarge(_)));
+    let list = |files: &[(PathBuf, Skip)]| {
+        let mut names: Vec<String> = files
+            .iter()
+            .take(5)
+            .map(|(path, skip)| match skip {
+                Skip::TooLarge(size) => format!("{} ({})", path.display(), HumanBytes(*size)),
+                Skip::Binary | Skip::Hidden => path.display().to_string(),
+            })
+            .collect();
+        if files.len() > 5 {
+            names.push(format!("and {} more", files.len() - 5));
+        }
+        names.join(", ")
+    };
+    if !large.is_empty() {
+        warn!(
+            "Left out {} file(s) over {}: {}",
+            large.len(),
+            HumanBytes(max_file_size.unwrap_or_default()),
+            list(&large)
+        );
+    }
+    if !binary.is_empty() {
+        warn!(
+            "Left out {} binary file(s): {}",
+            binary.len(),
+            list(&binary)
+        );
+    }
+    if !hidden.is_empty() {
+        warn!(
+            "Left out {} hidden file(s) and directories: {}. Upload them with --hidden or \
+             `hidden = true` in [sync], `hidden = false` leaves them out without this message",
+            hidden.len(),
+            list(&hidden)
+        );
+    }
+    count
+}
+
+// the files git lists for --git-only, and the directories leading to them
+fn git_files(
+    local_path: &Path,
+    files: GitFiles,
+) -> io::Result<(HashSet<PathBuf>, HashSet<PathBuf>)> {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(local_path).args(["ls-files", "-z"]);
+    if files == GitFiles::Untracked {
+        command.args(["--cached", "--others", "--exclude-standard"]);
+    }
+    let output = command.stderr(Stdio::piped()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--git-only needs a git repository: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    let mut dirs = HashSet::from([local_path.to_path_buf()]);
+    let files: HashSet<PathBuf> = String::from_utf8_lossy(&output.stdout)
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .map(|path| local_path.join(path))
+        .collect();
+    for file in &files {
+        for dir in file.ancestors().skip(1) {
+            if !dir.starts_with(local_path) || !dirs.insert(dir.to_path_buf()) {
+                break;
+            }
+        }
+    }
+    Ok((files, dirs))
+}
+
+// tracked files with changes that are not committed, --git-only uploads them as they are now
+pub fn uncommitted_changes(local_path: &Path) -> Vec<String> {
+    Command::new("git")
+        .arg("-C")
+        .arg(local_path)
+        .args(["status", "--porcelain", "--untracked-files=no", "."])
+        .stderr(Stdio::null())
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| line.get(3..).map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// stream the local directory as a single tar archive into `tar -x` on the remote side,
+// which saves a round trip per file compared to sftp. returns the number of files and bytes sent
+pub fn upload_tar(
+    sess: &Session,
+    local_path: &Path,
+    remote_base_path: &Path,
+    compression: &TarCompression,
+    walk: &WalkOptions,
+    progress: bool,
+    limit: Option<&RateLimit>,
+) -> error::Result<(usize, u64)> {
+    let (compressor, decompressor) = match compression {
+        TarCompression::None => (None, ""),
+        TarCompression::Gzip => (Some(["gzip", "-c"]), "gzip -dc | "),
+        TarCompression::Zstd => (Some(["zstd", "-cq"]), "zstd -dcq | "),
+    };
+
+    let pb = sync_spinner(progress)?;
+    pb.set_message(format!("{} Uploading archive", FILE));
+
+    let mut channel = sess.channel_session().context(CserunError::Transfer)?;
+    let remote_dir = ssh::shell_quote(&ssh::remote_str(remote_base_path));
+    channel
+        .exec(&format!(
+            "mkdir -p {} && {}tar -xf - -C {}",
+            remote_dir, decompressor, remote_dir
+        ))
+        .context(CserunError::Transfer)?;
+
+    let sent = match compressor {
+        None => write_tar(local_path, walk, Throttled::new(&mut channel, limit))
+            .context(CserunError::Transfer),
+        Some([program, flag]) => (|| {
+            let mut child = Command::new(program)
+                .arg(flag)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(|e| format!("Failed to run {}: {}", program, e))
+                .context(CserunError::Local)?;
+            let stdin = child
+                .stdin
+                .take()
+                .ok_or("Failed to open compressor stdin")
+                .context(CserunError::Local)?;
+            let local_path = local_path.to_path_buf();
+            let walk = walk.clone();
+            let writer = std::thread::spawn(move || write_tar(&local_path, &walk, stdin));
+            let mut stdout = child
+                .stdout
+                .take()
+                .ok_or("Failed to open compressor stdout")
+                .context(CserunError::Local)?;
+            if let Err(e) = io::copy(&mut stdout, &mut Throttled::new(&mut channel, limit)) {
+                // the writer stops once the compressor is gone and its stdin with it
+                let _ = child.kill();
+                let _ = writer.join();
+                let _ = child.wait();
+                return Err(CserunError::Transfer(e.into()));
+            }
+            let sent = writer
+                .join()
+                .map_err(|_| "Archive writer panicked")
+                .context(CserunError::Local)?
+                .context(CserunError::Transfer)?;
+            if !child.wait().context(CserunError::Local)?.success() {
+                return Err(CserunError::Local(
+                    format!("{} failed to compress the archive", program).into(),
+                ));
+            }
+            Ok(sent)
+        })(),
+    };
+    let sent = match sent {
+        Ok(sent) => sent,
+        Err(e) => return Err(remote_tar_failure(&mut channel, e)),
+    };
+
+    channel.send_eof().context(CserunError::Transfer)?;
+    let mut stderr = String::new();
+    channel
+        .stderr()
+        .read_to_string(&mut stderr)
+        .context(CserunError::Transfer)?;
+    channel.wait_close().context(CserunError::Transfer)?;
+    if channel.exit_status().context(CserunError::Transfer)? != 0 {
+        return Err(CserunError::Transfer(
+            format!("Remote tar extraction failed: {}", stderr.trim()).into(),
+        ));
+    }
+    pb.finish_and_clear();
+    Ok(sent)
+}
+
+// an upload that failed half way, e.g. because the remote tar gave up on a full disk. what tar
+// said is usually the better explanation, so it is added to the error when there is any
+fn remote_tar_failure(channel: &mut ssh2::Channel, e: CserunError) -> CserunError {
+    let _ = channel.send_eof();
+    let mut stderr = String::new();
+    let _ = channel.stderr().read_to_string(&mut stderr);
+    let _ = channel.wait_close();
+    let status = channel.exit_status().unwrap_or(0);
+    if stderr.trim().is_empty() && status == 0 {
+        return e;
+    }
+    CserunError::Transfer(
+        format!(
+            "{} (remote tar exited with {}{})",
+            error::Report(e.source_error()),
+            status,
+            match stderr.trim() {
+                "" => String::new(),
+                stderr => format!(": {}", stderr),
+            }
+        )
+        .into(),
+    )
+}
+
+// write every file and directory under local_path into a tar stream, returns the number of files and bytes
+fn write_tar<W: Write>(
+    local_path: &Path,
+    walk: &WalkOptions,
+    writer: W,
+) -> io::Result<(usize, u64)> {
+    let mut tar = TarWriter::new(writer);
+    let mut count = 0;
+    let mut bytes = 0;
+    for entry in build_walker(local_path, walk)?.flatten() {
+        let path = entry.path();
+        let strip_path = match path.strip_prefix(local_path) {
+            Ok(p) if !p.as_os_str().is_empty() => ssh::remote_str(p),
+            _ => continue,
+        };
+        if entry.path_is_symlink() {
+            match walk.symlinks {
+                Symlinks::Preserve => {
+                    let target = fs::read_link(path)?;
+                    tar.append_symlink(&strip_path, &ssh::remote_str(&target))?;
+                }
+                _ => warn!("Skipped symlink: {:?}", strip_path),
+            }
+            continue;
+        }
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if metadata.is_dir() {
+            tar.append_dir(&strip_path, file_mode(&metadata), mtime)?;
+        } else {
+            let mut file = fs::File::open(path)?;
+            tar.append_file(
+                &strip_path,
+                file_mode(&metadata),
+                mtime,
+                metadata.len(),
+                &mut file,
+            )?;
+            count += 1;
+            bytes += metadata.len();
+        }
+    }
+    tar.finish()?;
+    Ok((count, bytes))
+}
+
+// stat everything under a remote directory, a missing directory is just empty
+fn remote_tree(
+    sftp: &Sftp,
+    remote_base_path: &Path,
+) -> error::Result<HashMap<PathBuf, ssh2::FileStat>> {
+    let mut files = HashMap::new();
+    let mut pending = vec![remote_base_path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match sftp.readdir(&dir) {
+            Ok(entries) => entries,
+            Err(_) if dir == remote_base_path => return Ok(files),
+            Err(e) => return Err(CserunError::Sftp(e.into())),
+        };
+        for (remote_path, stat) in entries {
+            if stat.is_dir() {
+                pending.push(remote_path.clone());
+            }
+            files.insert(remote_path, stat);
+        }
+    }
+    Ok(files)
+}
+
+// upload the pending files over `jobs` workers, recording each finished file in the manifest.
+// the first worker uses the given sftp handle, the others open their own through `connect`
+pub fn upload_files(
+    sftp: &Sftp,
+    files: Vec<PendingUpload>,
+    manifest: Option<&mut Manifest>,
+    jobs: usize,
+    connect: &(dyn Fn() -> Result<Sftp, String> + Sync),
+    conf: &ssh::Config,
+    limit: Option<&RateLimit>,
+) -> error::Result<(usize, u64)> {
+    let count = files.len();
+    let bytes = files.iter().map(|f| f.size).sum();
+    let pb = transfer_bar("Uploading", bytes, conf.progress)?;
+    // partial uploads to different servers are told apart
+    let account = format!("{}@{}", conf.username, conf.server_addr);
+    // no file gets more than its directory allows with remote_dir_mode
+    let mode_mask = conf.remote_dir_mode.map_or(0o7777, |mode| mode | 0o7000);
+    let jobs = jobs.clamp(1, count.max(1));
+    let queue = Mutex::new(files.into_iter());
+    let done = Mutex::new((0usize, 0u64)); // files and bytes, for the progress events
+    let manifest = Mutex::new(manifest);
+    let error: Mutex<Option<io::Error>> = Mutex::new(None);
+
+    let worker = |sftp: &Sftp| {
+        // stop picking up files as soon as any worker failed or the run was cancelled
+        while error.lock().unwrap().is_none() && !conf.cancel.is_cancelled() {
+            let file = match queue.lock().unwrap().next() {
+                Some(file) => file,
+                None => break,
+            };
+            pb.set_message(format!("{} {:?}", FILE, file.remote_path));
+            let (files_done, bytes_done) = *done.lock().unwrap();
+            events::emit(
+                "upload_progress",
+                &[
+                    ("file", file.remote_path.to_string_lossy().as_ref().into()),
+                    ("files_done", files_done.into()),
+                    ("bytes_done", bytes_done.into()),
+                    (
+                        "percent",
+                        (bytes_done as f64 * 100.0 / bytes.max(1) as f64).into(),
+                    ),
+                ],
+            );
+            match upload_file(
+                sftp,
+                &file.local_path,
+                &file.remote_path,
+                &pb,
+                limit,
+                &account,
+                mode_mask,
+            ) {
+                Ok(()) => {
+                    debug!("{} Uploaded file: {:?}", FILE, file.remote_path);
+                    let mut done = done.lock().unwrap();
+                    *done = (done.0 + 1, done.1 + file.size);
+                    if let (Some(manifest), Some((key, entry))) =
+                        (manifest.lock().unwrap().as_deref_mut(), file.manifest_entry)
+                    {
+                        manifest.files.insert(key, entry);
+                    }
+                }
+                Err(e) => {
+                    error.lock().unwrap().get_or_insert(e);
+                }
+            }
+        }
+    };
+
+    std::thread::scope(|scope| {
+        for _ in 1..jobs {
+            scope.spawn(|| match connect() {
+                Ok(sftp) => worker(&sftp),
+                Err(e) => {
+                    error.lock().unwrap().get_or_insert(io::Error::other(e));
+                }
+            });
+        }
+        worker(sftp);
+    });
+    pb.finish_and_clear();
+
+    let (files_done, _) = *done.lock().unwrap();
+    match error.into_inner().unwrap() {
+        Some(e) => Err(CserunError::Transfer(e.into())),
+        // only a cancel stops the workers early without an error
+        None if files_done < count => Err(CserunError::Cancelled(
+            format!("while uploading, after {} of {} file(s)", files_done, count).into(),
+        )),
+        None => Ok((count, bytes)),
+    }
+}
+
+// the server side store of uploaded files, named by their sha256, shared by all runs. under
+// remote_dir, so it is on the same fast filesystem as the run directories it is copied into
+fn objects_dir(conf: &ssh::Config) -> String {
+    format!("{}/cache/objects", conf.remote_dir())
+}
+
+// how long a cached file may go unused before it is removed, unless cache_max_age says otherwise
+pub const CACHE_MAX_AGE: Duration = Duration::from_secs(30 * 86400);
+
+// an uploaded file to keep in the server cache, its remote path and sha256
+pub type CacheEntry = (PathBuf, String);
+
+// copy the files the server cache already holds into place instead of uploading them again.
+// returns the files left to upload with the hash to cache each under, and how many were copied
+pub fn reuse_cached(
+    sess: &Session,
+    sftp: &Sftp,
+    files: Vec<PendingUpload>,
+    mut manifest: Option<&mut Manifest>,
+    conf: &ssh::Config,
+) -> error::Result<(Vec<PendingUpload>, Vec<CacheEntry>, usize)> {
+    let objects = objects_dir(conf);
+    // no cache dir yet is just an empty cache
+    let cached: HashSet<String> = sftp
+        .readdir(Path::new(&objects))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(path, _)| Some(path.file_name()?.to_string_lossy().to_string()))
+        .collect();
+
+    let mut hits = Vec::new();
+    let mut missing = Vec::new();
+    for file in files {
+        let hash = match &file.manifest_entry {
+            Some((_, entry)) => entry.hash.clone(),
+            None => hash::sha256_file(&file.local_path).context(CserunError::Local)?,
+        };
+        match cached.contains(&hash) {
+            true => hits.push((file, hash)),
+            false => missing.push((file, hash)),
+        }
+    }
+
+    // one script for all copies, a failed copy prints its index and is uploaded after all.
+    // the cached file is copied rather than linked, so the command can not change it
+    let mut script = String::new();
+    for (index, (file, hash)) in hits.iter().enumerate() {
+        let mode = file_mode(&fs::metadata(&file.local_path).context(CserunError::Local)?);
+        let target = ssh::shell_quote(&ssh::remote_str(&file.remote_path));
+        let blob = ssh::shell_quote(&format!("{}/{}", objects, hash));
+        script.push_str(&format!(
+            "cp -- {} {} && chmod {:o} {} && touch -c -- {} || echo {}\n",
+            blob, target, mode, target, blob, index
+        ));
+    }
+    let failed: HashSet<usize> = match hits.is_empty() {
+        true => HashSet::new(),
+        false => match ssh::run_remote_script(sess, &script) {
+            Ok(output) => String::from_utf8_lossy(&output)
+                .lines()
+                .filter_map(|line| line.trim().parse().ok())
+                .collect(),
+            Err(e) => {
+                warn!("Could not copy from the server cache: {}", e);
+                (0..hits.len()).collect()
+            }
+        },
+    };
+
+    let mut reused = 0;
+    for (index, (file, hash)) in hits.into_iter().enumerate() {
+        if failed.contains(&index) {
+            missing.push((file, hash));
+            continue;
+        }
+        debug!(
+            "{} Copied from the server cache: {:?}",
+            FILE, file.remote_path
+        );
+        reused += 1;
+        if let (Some(manifest), Some((key, entry))) = (manifest.as_deref_mut(), file.manifest_entry)
+        {
+            manifest.files.insert(key, entry);
+        }
+    }
+    let to_cache = missing
+        .iter()
+        .map(|(file, hash)| (file.remote_path.clone(), hash.clone()))
+        .collect();
+    let pending = missing.into_iter().map(|(file, _)| file).collect();
+    Ok((pending, to_cache, reused))
+}
+
+// keep a copy of the freshly uploaded files in the server cache for the next runs.
+// if that fails the next run simply uploads them again
+pub fn store_cached(sess: &Session, files: &[CacheEntry], conf: &ssh::Config) {
+    if files.is_empty() {
+        return;
+    }
+    let objects = objects_dir(conf);
+    let mut script = format!("mkdir -p -- {} || exit 1\n", ssh::shell_quote(&objects));
+    for (remote_path, hash) in files {
+        // written under a temporary name, so a cut off copy is never taken for the file
+        let blob = ssh::shell_quote(&format!("{}/{}", objects, hash));
+        script.push_str(&format!(
+            "test -e {blob} || {{ cp -- {} {blob}.$$ && mv -f -- {blob}.$$ {blob}; }}\n",
+            ssh::shell_quote(&ssh::remote_str(remote_path)),
+        ));
+    }
+    match ssh::run_remote_script(sess, &script) {
+        Ok(_) => debug!("Cached {} uploaded file(s) on the server", files.len()),
+        Err(e) => warn!(
+            "Could not add the uploaded files to the server cache: {}",
+            e
+        ),
+    }
+}
+
+// remove cached files no run copied or added within cache_max_age, so the cache does not grow
+// without bound. copies and additions touch a file, so its mtime is when it was last used
+pub fn prune_cache(sess: &Session, conf: &ssh::Config) {
+    let days = conf.cache_max_age.as_secs() / 86400;
+    let command = format!(
+        "test ! -d {dir} || find {dir} -type f -mtime +{days} -exec rm -f -- {{}} +",
+        dir = ssh::shell_quote(&objects_dir(conf)),
+        days = days
+    );
+    if let Err(e) = ssh::run_remote(sess, &command) {
+        warn!("Could not remove old files from the server cache: {}", e);
+    }
+}
+
+// send the large files the workspace holds an older version of as a delta against it: the new
+// parts go up in one file, and dd on the server puts them together with the blocks still in the
+// old copy, in order. the result is checked against the local hash before it replaces the old
+// copy, and anything that goes wrong leaves the file to be uploaded whole. returns the files left
+// to upload, how many were patched and the bytes sent for them
+pub fn upload_deltas(
+    sess: &Session,
+    sftp: &Sftp,
+    files: Vec<PendingUpload>,
+    mut manifest: Option<&mut Manifest>,
+    limit: Option<&RateLimit>,
+) -> error::Result<(Vec<PendingUpload>, usize, u64)> {
+    let mut whole = Vec::new();
+    let mut patches = Vec::new();
+    for file in files {
+        let signature = match (&file.previous_hash, &file.manifest_entry) {
+            (Some(hash), Some(_)) if file.size >= delta::MIN_SIZE => Signature::load(hash),
+            _ => None,
+        };
+        let Some(signature) = signature else {
+            whole.push(file);
+            continue;
+        };
+        let local_file = fs::File::open(&file.local_path).context(CserunError::Local)?;
+        let ops = signature
+            .diff(BufReader::new(local_file))
+            .context(CserunError::Local)?;
+        // nothing in common, the delta would only add round trips
+        if !ops.iter().any(|op| matches!(op, Op::Copy { .. })) {
+            whole.push(file);
+            continue;
+        }
+        patches.push((file, signature.block_size(), ops));
+    }
+    if patches.is_empty() {
+        return Ok((whole, 0, 0));
+    }
+
+    // the new parts go into a file next to the old copy over sftp
+    let mut sent = 0;
+    let mut failed = HashSet::new();
+    for (index, (file, _, ops)) in patches.iter().enumerate() {
+        let literals = delta_path(&file.remote_path, "literals");
+        match write_literals(sftp, &file.local_path, &literals, ops, limit) {
+            Ok(bytes) => sent += bytes,
+            Err(e) => {
+                warn!("Could not send the delta of {:?}: {}", file.remote_path, e);
+                failed.insert(index);
+            }
+        }
+    }
+
+    // then one script puts each file together from its old copy and the new parts, a failed
+    // file prints its index. only the dd operands of POSIX, the new parts are read in order
+    // from stdin, and a read from a regular file is never short before its end
+    let mut script = String::new();
+    for (index, (file, block_size, ops)) in patches.iter().enumerate() {
+        let Some((_, entry)) = file
+            .manifest_entry
+            .as_ref()
+            .filter(|_| !failed.contains(&index))
+        else {
+            continue;
+        };
+        let target = ssh::shell_quote(&ssh::remote_str(&file.remote_path));
+        let temp = ssh::shell_quote(&ssh::remote_str(&delta_path(&file.remote_path, "delta")));
+        let literals =
+            ssh::shell_quote(&ssh::remote_str(&delta_path(&file.remote_path, "literals")));
+        let mut parts = Vec::new();
+        for op in ops {
+            match *op {
+                // copies are whole blocks of the old copy
+                Op::Copy { from, len, .. } => parts.push(format!(
+                    "dd if={} bs={} skip={} count={}",
+                    target,
+                    block_size,
+                    from / *block_size as u64,
+                    len / *block_size as u64
+                )),
+                Op::Literal { len, .. } => {
+                    if len >= LITERAL_BLOCK {
+                        parts.push(format!(
+                            "dd bs={} count={}",
+                            LITERAL_BLOCK,
+                            len / LITERAL_BLOCK
+                        ));
+                    }
+                    if len % LITERAL_BLOCK > 0 {
+                        parts.push(format!("dd bs={} count=1", len % LITERAL_BLOCK));
+                    }
+                }
+            }
+        }
+        let steps = [
+            format!(
+                "{{ {}; }} < {} > {} 2>/dev/null",
+                parts.join(" && "),
+                literals,
+                temp
+            ),
+            format!(
+                "test \"$(sha256sum < {} | cut -c1-64)\" = {}",
+                temp, entry.hash
+            ),
+            format!("chmod {:o} {}", entry.mode, temp),
+            format!("mv -f -- {} {}", temp, target),
+        ];
+        script.push_str(&format!(
+            "{{ {}; }} || {{ rm -f -- {}; echo {}; }}; rm -f -- {}\n",
+            steps.join(" && "),
+            temp,
+            index,
+            literals
+        ));
+    }
+    if !script.is_empty() {
+        match ssh::run_remote_script(sess, &script) {
+            Ok(output) => failed.extend(
+                String::from_utf8_lossy(&output)
+                    .lines()
+                    .filter_map(|line| line.trim().parse::<usize>().ok()),
+            ),
+            Err(e) => {
+                warn!("Could not apply the deltas on the server: {}", e);
+                failed.extend(0..patches.len());
+            }
+        }
+    }
+
+    let mut patched = 0;
+    for (index, (file, _, _)) in patches.into_iter().enumerate() {
+        if failed.contains(&index) {
+            debug!("Uploading {:?} whole, its delta failed", file.remote_path);
+            whole.push(file);
+            continue;
+        }
+        debug!("{} Patched file: {:?}", FILE, file.remote_path);
+        patched += 1;
+        if let Some((key, entry)) = file.manifest_entry {
+            store_signature(&file.local_path, &entry.hash, file.previous_hash.as_deref());
+            if let Some(manifest) = manifest.as_deref_mut() {
+                manifest.files.insert(key, entry);
+            }
+        }
+    }
+    Ok((whole, patched, sent))
+}
+
+// where the new parts go and where a delta is put together, next to the file it replaces
+fn delta_path(remote_path: &Path, kind: &str) -> PathBuf {
+    let mut name = remote_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".cserun-{}", kind));
+    remote_path.with_file_name(name)
+}
+
+// dd copies the new parts in blocks of this size, and the rest of each in one block
+const LITERAL_BLOCK: u64 = 65536;
+
+// stream the literal parts of the local file up one after another, in the order the script
+// reads them back
+fn write_literals(
+    sftp: &Sftp,
+    local_path: &Path,
+    remote_path: &Path,
+    ops: &[Op],
+    limit: Option<&RateLimit>,
+) -> io::Result<u64> {
+    let mut local_file = fs::File::open(local_path)?;
+    let remote_file = sftp.open_mode(
+        remote_path,
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        0o600,
+        OpenType::File,
+    )?;
+    let mut remote_file = Throttled::new(remote_file, limit);
+    let mut sent = 0;
+    for op in ops {
+        if let Op::Literal { to, len } = *op {
+            local_file.seek(SeekFrom::Start(to))?;
+            let copied = io::copy(&mut (&mut local_file).take(len), &mut remote_file)?;
+            if copied != len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the file got shorter while it was uploaded",
+                ));
+            }
+            sent += len;
+        }
+    }
+    Ok(sent)
+}
+
+// a large file uploaded whole, to keep a signature of for the next delta: its local path, its
+// sha256 and the hash of the version it replaced
+pub type SignatureEntry = (PathBuf, String, Option<String>);
+
+pub fn signature_entries(files: &[PendingUpload]) -> Vec<SignatureEntry> {
+    files
+        .iter()
+        .filter(|file| file.size >= delta::MIN_SIZE)
+        .filter_map(|file| {
+            let (_, entry) = file.manifest_entry.as_ref()?;
+            Some((
+                file.local_path.clone(),
+                entry.hash.clone(),
+                file.previous_hash.clone(),
+            ))
+        })
+        .collect()
+}
+
+pub fn store_signatures(files: &[SignatureEntry]) {
+    for (local_path, hash, previous_hash) in files {
+        store_signature(local_path, hash, previous_hash.as_deref());
+    }
+}
+
+// without a signature the next change to the file is simply uploaded whole
+fn store_signature(local_path: &Path, hash: &str, previous_hash: Option<&str>) {
+    let signature = fs::File::open(local_path)
+        .and_then(|file| {
+            let len = file.metadata()?.len();
+            Signature::new(BufReader::new(file), len)
+        })
+        .map_err(|e| format!("{:?}: {}", local_path, e))
+        .context(CserunError::Local);
+    if let Err(e) = signature.and_then(|signature| signature.save(hash)) {
+        warn!(
+            "Could not keep the block signature of an upload: {}",
+            e.report()
+        );
+    }
+    if let Some(previous_hash) = previous_hash.filter(|previous| *previous != hash) {
+        Signature::remove(previous_hash);
+    }
+}
+
+pub fn build_globset(patterns: &[String]) -> error::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).context(CserunError::Config)?);
+    }
+    builder.build().context(CserunError::Config)
+}
+
+// download every file under the remote directory whose relative path matches the patterns,
+// returns the number of files downloaded
+pub fn download_dir(
+    sftp: &Sftp,
+    remote_base_path: &Path,
+    local_base_path: &Path,
+    patterns: &GlobSet,
+    progress: bool,
+) -> error::Result<usize> {
+    // find everything first so the progress bar knows the total size
+    let mut matched = Vec::new();
+    let mut pending = vec![remote_base_path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for (remote_path, stat) in sftp.readdir(&dir).context(CserunError::Sftp)? {
+            if stat.is_dir() {
+                pending.push(remote_path);
+                continue;
+            }
+            let strip_path = remote_path
+                .strip_prefix(remote_base_path)
+                .context(CserunError::Sftp)?
+                .to_path_buf();
+            if patterns.is_match(&strip_path) {
+                matched.push((remote_path, strip_path, stat.size.unwrap_or(0)));
+            }
+        }
+    }
+    download_matched(
+        matched,
+        local_base_path,
+        progress,
+        |remote_path, local_path, pb| {
+            let remote_file = sftp.open(remote_path)?;
+            let mut file = fs::File::create(local_path)?;
+            io::copy(&mut pb.wrap_read(chunked(remote_file)), &mut file)?;
+            Ok(())
+        },
+    )
+}
+
+// same as download_dir for servers without the sftp subsystem, listing with `find` and copying over scp
+pub fn download_dir_scp(
+    sess: &Session,
+    remote_base_path: &Path,
+    local_base_path: &Path,
+    patterns: &GlobSet,
+    progress: bool,
+) -> error::Result<usize> {
+    let listing = ssh::run_remote(sess, &list_files_command(remote_base_path, ""))
+        .context(CserunError::Transfer)?;
+    let matched = parse_listing(&listing, remote_base_path)?
+        .into_iter()
+        .filter(|(_, strip_path, _)| patterns.is_match(strip_path))
+        .collect();
+    download_files(sess, None, matched, local_base_path, progress)
+}
+
+// a command printing "size ./path\0" for every file under the remote dir that `tests` selects.
+// `find -printf` is GNU only, so the size comes from `wc -c`, which BSD and busybox have too
+fn list_files_command(remote_base_path: &Path, tests: &str) -> String {
+    format!(
+        r#"cd {} && find . -type f {} -exec sh -c 'for f do printf "%s %s\000" "$(wc -c < "$f")" "$f"; done' sh {{}} +"#,
+        ssh::shell_quote(&ssh::remote_str(remote_base_path)),
+        tests
+    )
+}
+
+// the output of list_files_command as (remote path, relative path, size) entries
+fn parse_listing(
+    listing: &[u8],
+    remote_base_path: &Path,
+) -> error::Result<Vec<(PathBuf, PathBuf, u64)>> {
+    let mut files = Vec::new();
+    for line in listing.split(|&b| b == 0).filter(|line| !line.is_empty()) {
+        let line = String::from_utf8_lossy(line);
+        // BSD wc pads the size with spaces
+        let (size, path) = line
+            .trim_start()
+            .split_once(' ')
+            .ok_or("Unexpected output from find")
+            .context(CserunError::Transfer)?;
+        let strip_path = PathBuf::from(path.strip_prefix("./").unwrap_or(path));
+        files.push((
+            remote_base_path.join(&strip_path),
+            strip_path,
+            size.parse().unwrap_or(0),
+        ));
+    }
+    Ok(files)
+}
+
+// files under the remote dir modified after the marker file. `find -newer` compares with the
+// server's clock and works without sftp too
+pub fn changed_since(
+    sess: &Session,
+    remote_base_path: &Path,
+    marker: &str,
+) -> error::Result<Vec<(PathBuf, PathBuf, u64)>> {
+    let tests = format!("-newer {}", ssh::shell_path(marker));
+    let listing = ssh::run_remote(sess, &list_files_command(remote_base_path, &tests))
+        .context(CserunError::Transfer)?;
+    parse_listing(&listing, remote_base_path)
+}
+
+// bytes that can still be written in the remote home, the smaller of the free disk space and
+// what is left of the user's quota. None when `df` fails, the upload then goes ahead unchecked
+pub fn remote_space(sess: &Session) -> Option<u64> {
+    let df = ssh::run_remote(sess, "df -Pk .")
+        .map_err(|e| debug!("df failed on the server: {}", e))
+        .ok()?;
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    let df = String::from_utf8_lossy(&df);
+    let fields: Vec<&str> = df.lines().nth(1)?.split_whitespace().collect();
+    let filesystem = *fields.first()?;
+    let mut available = fields.get(3)?.parse::<u64>().ok()? * 1024;
+
+    // quota exits non-zero when over quota, and may not be installed at all
+    let quota = ssh::run_remote(sess, "quota -w 2>/dev/null; true").unwrap_or_default();
+    let quota = String::from_utf8_lossy(&quota);
+    // Filesystem blocks quota limit grace files quota limit grace, in 1K blocks
+    let entries: Vec<(&str, u64)> = quota
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("Filesystem"))
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let used = fields.get(1)?.trim_end_matches('*').parse::<u64>().ok()?;
+            let soft = fields.get(2)?.parse::<u64>().ok()?;
+            let hard = fields.get(3)?.parse::<u64>().ok()?;
+            // 0 means no limit
+            let limit = [soft, hard].into_iter().filter(|&l| l > 0).min()?;
+            Some((fields[0], limit.saturating_sub(used) * 1024))
+        })
+        .collect();
+    let left = match entries.iter().find(|(fs, _)| *fs == filesystem) {
+        Some((_, left)) => Some(*left),
+        // the names do not always match, e.g. an nfs home, take a single entry as the home's
+        None if entries.len() == 1 => Some(entries[0].1),
+        None => None,
+    };
+    if let Some(left) = left {
+        available = available.min(left);
+    }
+    Some(available)
+}
+
+// the root ignore files and config patterns of the local dir, for paths that come back from the server
+pub fn ignore_matcher(local_path: &Path, walk: &WalkOptions) -> error::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(local_path);
+    let sources = walk.ignore_files;
+    let git = sources.outside_git || local_path.ancestors().any(|dir| dir.join(".git").exists());
+    let files = [
+        (".gitignore", sources.gitignore && git),
+        (".ignore", sources.ignore),
+        (".cserunignore", sources.cserunignore),
+        (".git/info/exclude", sources.git_exclude && git),
+    ];
+    for (name, enabled) in files {
+        let path = local_path.join(name);
+        if enabled && path.is_file() {
+            if let Some(e) = builder.add(path) {
+                warn!("{}", e);
+            }
+        }
+    }
+    for pattern in walk.ignore.iter() {
+        builder
+            .add_line(None, pattern)
+            .context(CserunError::Config)?;
+    }
+    for glob in walk.exclude.iter() {
+        builder.add_line(None, glob).context(CserunError::Config)?;
+    }
+    builder.build().context(CserunError::Config)
+}
+
+// download (remote path, relative path, size) entries over sftp, or scp without it
+pub fn download_files(
+    sess: &Session,
+    sftp: Option<&Sftp>,
+    files: Vec<(PathBuf, PathBuf, u64)>,
+    local_base_path: &Path,
+    progress: bool,
+) -> error::Result<usize> {
+    download_matched(
+        files,
+        local_base_path,
+        progress,
+        |remote_path, local_path, pb| match sftp {
+            Some(sftp) => {
+                let remote_file = sftp.open(remote_path)?;
+                let mut file = fs::File::create(local_path)?;
+                io::copy(&mut pb.wrap_read(chunked(remote_file)), &mut file)?;
+                Ok(())
+            }
+            None => {
+                let (channel, stat) = sess.scp_recv(remote_path)?;
+                let mut file = fs::File::create(local_path)?;
+                io::copy(&mut pb.wrap_read(channel.take(stat.size())), &mut file)?;
+                Ok(())
+            }
+        },
+    )
+}
+
+// download (remote path, relative path, size) entries into the local dir, returns the count
+fn download_matched(
+    matched: Vec<(PathBuf, PathBuf, u64)>,
+    local_base_path: &Path,
+    progress: bool,
+    mut download: impl FnMut(&Path, &Path, &ProgressBar) -> io::Result<()>,
+) -> error::Result<usize> {
+    let pb = transfer_bar("Fetching", matched.iter().map(|m| m.2).sum(), progress)?;
+    for (remote_path, strip_path, _) in &matched {
+        let local_path = local_base_path.join(strip_path);
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).context(CserunError::Local)?;
+        }
+        pb.set_message(format!("{} {:?}", FILE, strip_path));
+        download(remote_path, &local_path, &pb).context(CserunError::Transfer)?;
+        debug!("{} Downloaded file: {:?}", FILE, strip_path);
+    }
+    pb.finish_and_clear();
+    Ok(matched.len())
+}
+
+fn upload_file(
+    sftp: &Sftp,
+    local_path: &Path,
+    remote_path: &Path,
+    pb: &ProgressBar,
+    limit: Option<&RateLimit>,
+    account: &str,
+    mode_mask: u32,
+) -> io::Result<()> {
+    let file = fs::File::open(local_path)?;
+    let metadata = file.metadata()?;
+    let (mode, size) = (file_mode(&metadata) & mode_mask, metadata.len());
+    if size >= partial::MIN_SIZE {
+        return upload_resumable(sftp, file, mode, remote_path, pb, limit, account);
+    }
+
+    // keep the local permissions, so scripts stay executable
+    let mut remote_file = sftp.open_mode(
+        remote_path,
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        mode as i32,
+        OpenType::File,
+    )?;
+    io::copy(
+        &mut pb.wrap_read(chunked(file.take(size))),
+        &mut Throttled::new(&mut remote_file, limit),
+    )?;
+    check_size(&mut remote_file, remote_path, size)?;
+    // the mode passed on open only applies to new files
+    remote_file.setstat(FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: Some(mode),
+        atime: None,
+        mtime: None,
+    })?;
+
+    Ok(())
+}
+
+// a large file goes into a .cserun.part file next to it first, which is renamed into place once
+// complete. if the connection drops, the next attempt at the same contents appends to the part
+// instead of starting from byte zero
+fn upload_resumable(
+    sftp: &Sftp,
+    mut file: fs::File,
+    mode: u32,
+    remote_path: &Path,
+    pb: &ProgressBar,
+    limit: Option<&RateLimit>,
+    account: &str,
+) -> io::Result<()> {
+    let part_path = part_path(remote_path);
+    let key = format!("{}:{}", account, ssh::remote_str(remote_path));
+    let size = file.metadata()?.len();
+    let hash = hash::sha256_reader(&mut file)?;
+    // the part holds what was written before the drop, sftp writes arrive in order
+    let offset = match partial::started(&key) == Some(hash.clone()) {
+        true => sftp
+            .stat(&part_path)
+            .ok()
+            .and_then(|stat| stat.size)
+            .filter(|part_size| *part_size <= size)
+            .unwrap_or(0),
+        false => 0,
+    };
+    let flags = match offset {
+        0 => {
+            partial::start(&key, &hash);
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE
+        }
+        _ => {
+            debug!(
+                "{} Resuming {:?} at {}",
+                FILE,
+                remote_path,
+                HumanBytes(offset)
+            );
+            OpenFlags::WRITE
+        }
+    };
+
+    // writable until it is complete, a read-only file could not be resumed
+    let mut remote_file = sftp.open_mode(&part_path, flags, 0o600, OpenType::File)?;
+    remote_file.seek(SeekFrom::Start(offset))?;
+    file.seek(SeekFrom::Start(offset))?;
+    pb.inc(offset);
+    io::copy(
+        &mut pb.wrap_read(chunked(file.take(size - offset))),
+        &mut Throttled::new(&mut remote_file, limit),
+    )?;
+    check_size(&mut remote_file, remote_path, size)?;
+    remote_file.setstat(FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: Some(mode),
+        atime: None,
+        mtime: None,
+    })?;
+    drop(remote_file);
+
+    // sftp v3 servers refuse to rename over an existing file
+    let _ = sftp.unlink(remote_path);
+    sftp.rename(&part_path, remote_path, None)?;
+    partial::finish(&key);
+    Ok(())
+}
+
+// files are streamed through a buffer of this size rather than read into memory whole. large
+// enough that sftp gets to pipeline its writes
+const CHUNK_SIZE: usize = 256 * 1024;
+
+fn chunked<R: Read>(reader: R) -> io::BufReader<R> {
+    io::BufReader::with_capacity(CHUNK_SIZE, reader)
+}
+
+fn part_path(remote_path: &Path) -> PathBuf {
+    let mut name = remote_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".cserun.part");
+    remote_path.with_file_name(name)
+}
+
+// a server out of quota can cut a file short without the write failing
+fn check_size(remote_file: &mut ssh2::File, remote_path: &Path, size: u64) -> io::Result<()> {
+    match remote_file.stat()?.size {
+        Some(remote_size) if remote_size != size => Err(io::Error::other(format!(
+            "{:?} is {} on the server instead of {}, is the disk or quota full?",
+            remote_path,
+            HumanBytes(remote_size),
+            HumanBytes(size)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// how many files one sha256sum is given at a time, well below the argument limit
+const VERIFY_BATCH: usize = 200;
+
+// compare the sha256 of every file the upload covers with what the server holds, so a corrupted
+// tree is caught before the command runs on it. returns how many files were checked
+pub fn verify_upload(
+    sess: &Session,
+    local_path: &Path,
+    remote_base_path: &Path,
+    walk: &WalkOptions,
+) -> error::Result<usize> {
+    let mut local = Vec::new();
+    for (path, _) in list_files(local_path, walk)? {
+        let hash = hash::sha256_file(&path).context(CserunError::Local)?;
+        let relative = path.strip_prefix(local_path).unwrap_or(&path);
+        local.push((ssh::remote_str(relative), hash));
+    }
+
+    // a missing file only leaves its line out, the comparison below reports it
+    let mut script = format!(
+        "cd {} || exit 1\n",
+        ssh::shell_quote(&ssh::remote_str(remote_base_path))
+    );
+    for batch in local.chunks(VERIFY_BATCH) {
+        let paths: Vec<String> = batch
+            .iter()
+            .map(|(path, _)| ssh::shell_quote(path))
+            .collect();
+        script.push_str(&format!("sha256sum -- {} 2>/dev/null\n", paths.join(" ")));
+    }
+    script.push_str("exit 0\n");
+    let output = ssh::run_remote_script(sess, &script).context(CserunError::Transfer)?;
+    let remote: HashMap<String, String> = String::from_utf8_lossy(&output)
+        .lines()
+        .filter_map(parse_checksum)
+        .collect();
+
+    let differing: Vec<&str> = local
+        .iter()
+        .filter(|(path, hash)| remote.get(path) != Some(hash))
+        .map(|(path, _)| path.as_str())
+        .collect();
+    if !differing.is_empty() {
+        let mut listed = differing[..differing.len().min(5)].join(", ");
+        if differing.len() > 5 {
+            listed.push_str(&format!(" and {} more", differing.len() - 5));
+        }
+        return Err(CserunError::Transfer(
+            format!(
+                "{} file(s) differ on the server after the upload: {}",
+                differing.len(),
+                listed
+            )
+            .into(),
+        ));
+    }
+    Ok(local.len())
+}
+
+// a line of sha256sum output as (path, hash). names with a backslash or newline are escaped,
+// which the line marks with a leading backslash
+fn parse_checksum(line: &str) -> Option<(String, String)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (hash, path) = line.split_once("  ")?;
+    if !escaped {
+        return Some((path.to_string(), hash.to_string()));
+    }
+    let mut unescaped = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => unescaped.push('\n'),
+                c => unescaped.push(c),
+            },
+            c => unescaped.push(c),
+        }
+    }
+    Some((unescaped, hash.to_string()))
+}
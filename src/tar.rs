@@ -0,0 +1,291 @@
+use std::io::{self, Read, Write};
+
+// minimal ustar writer, just enough to stream a directory tree into `tar -x` on the remote side
+
+const BLOCK_SIZE: usize = 512;
+
+pub struct TarWriter<W: Write> {
+    inner: W,
+}
+
+// the header fields besides the path
+struct Entry<'a> {
+    typeflag: u8,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    link_name: &'a str,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(inner: W) -> TarWriter<W> {
+        TarWriter { inner }
+    }
+
+    pub fn append_dir(&mut self, path: &str, mode: u32, mtime: u64) -> io::Result<()> {
+        let path = format!("{}/", path.trim_end_matches('/'));
+        let entry = Entry {
+            typeflag: b'5',
+            mode,
+            size: 0,
+            mtime,
+            link_name: "",
+        };
+        self.write_header(&path, &entry)
+    }
+
+    pub fn append_file<R: Read>(
+        &mut self,
+        path: &str,
+        mode: u32,
+        mtime: u64,
+        size: u64,
+        data: &mut R,
+    ) -> io::Result<()> {
+        let entry = Entry {
+            typeflag: b'0',
+            mode,
+            size,
+            mtime,
+            link_name: "",
+        };
+        self.write_header(path, &entry)?;
+        let copied = io::copy(&mut data.take(size), &mut self.inner)?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} changed size while archiving", path),
+            ));
+        }
+        self.pad(size)
+    }
+
+    pub fn append_symlink(&mut self, path: &str, target: &str) -> io::Result<()> {
+        if target.len() >= 100 {
+            // GNU long link name extension, the same trick as for long paths
+            self.write_long_record(b'K', target)?;
+        }
+        let mut end = target.len().min(99);
+        while !target.is_char_boundary(end) {
+            end -= 1;
+        }
+        let entry = Entry {
+            typeflag: b'2',
+            mode: 0o777,
+            size: 0,
+            mtime: 0,
+            link_name: &target[..end],
+        };
+        self.write_header(path, &entry)
+    }
+
+    // two empty blocks mark the end of the archive
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&[0; BLOCK_SIZE * 2])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_header(&mut self, path: &str, entry: &Entry) -> io::Result<()> {
+        let (prefix, name) = match split_path(path) {
+            Some(split) => split,
+            None => {
+                // GNU long name extension, understood by both GNU tar and bsdtar
+                self.write_long_record(b'L', path)?;
+                let mut start = path.len().saturating_sub(99);
+                while !path.is_char_boundary(start) {
+                    start += 1;
+                }
+                ("", &path[start..])
+            }
+        };
+        self.write_raw_header(prefix, name, entry)
+    }
+
+    // a GNU ././@LongLink record carrying a name that does not fit in the header
+    fn write_long_record(&mut self, typeflag: u8, value: &str) -> io::Result<()> {
+        let value = format!("{}\0", value);
+        let entry = Entry {
+            typeflag,
+            mode: 0,
+            size: value.len() as u64,
+            mtime: 0,
+            link_name: "",
+        };
+        self.write_raw_header("", "././@LongLink", &entry)?;
+        self.inner.write_all(value.as_bytes())?;
+        self.pad(value.len() as u64)
+    }
+
+    fn write_raw_header(&mut self, prefix: &str, name: &str, entry: &Entry) -> io::Result<()> {
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut header[100..108], entry.mode as u64);
+        write_octal(&mut header[108..116], 0); // uid
+        write_octal(&mut header[116..124], 0); // gid
+        write_number(&mut header[124..136], entry.size);
+        write_number(&mut header[136..148], entry.mtime);
+        header[156] = entry.typeflag;
+        header[157..157 + entry.link_name.len()].copy_from_slice(entry.link_name.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        // the checksum is computed with its own field filled with spaces
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+        write_octal(&mut header[148..155], checksum as u64);
+        header[155] = b' ';
+
+        self.inner.write_all(&header)
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let remainder = (size % BLOCK_SIZE as u64) as usize;
+        if remainder != 0 {
+            self.inner.write_all(&vec![0; BLOCK_SIZE - remainder])?;
+        }
+        Ok(())
+    }
+}
+
+// ustar stores long paths as prefix (155 bytes) + name (100 bytes), split on a '/'
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() < 100 {
+        return Some(("", path));
+    }
+    path.char_indices()
+        .filter(|&(_, c)| c == '/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && !name.is_empty() && name.len() < 100)
+}
+
+// octal while it fits, e.g. sizes below 8 GiB, otherwise the GNU base-256 encoding: the high
+// bit of the first byte set and the value big endian in the rest, understood by GNU tar and bsdtar
+fn write_number(field: &mut [u8], value: u64) {
+    if value < 1 << (3 * (field.len() - 1)) {
+        return write_octal(field, value);
+    }
+    field.fill(0);
+    let bytes = value.to_be_bytes();
+    let len = field.len();
+    field[len - bytes.len()..].copy_from_slice(&bytes);
+    field[0] |= 0x80;
+}
+
+// NUL terminated, zero padded octal
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[field.len() - 1] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(header: &[u8], range: std::ops::Range<usize>) -> &str {
+        let field = &header[range];
+        let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+        std::str::from_utf8(&field[..end]).unwrap()
+    }
+
+    fn checksum_ok(header: &[u8]) -> bool {
+        let stored = u32::from_str_radix(field(header, 148..155), 8).unwrap();
+        let sum: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u32
+                } else {
+                    b as u32
+                }
+            })
+            .sum();
+        stored == sum
+    }
+
+    #[test]
+    fn file_header_and_padding() {
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append_file("src/main.c", 0o644, 1700000000, 5, &mut &b"hello"[..])
+            .unwrap();
+        let archive = tar.finish().unwrap();
+        assert_eq!(archive.len(), BLOCK_SIZE * 4);
+        let header = &archive[..BLOCK_SIZE];
+        assert_eq!(field(header, 0..100), "src/main.c");
+        assert_eq!(field(header, 100..108), "0000644");
+        assert_eq!(field(header, 124..136), "00000000005");
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[257..263], b"ustar\0");
+        assert!(checksum_ok(header));
+        assert_eq!(&archive[BLOCK_SIZE..BLOCK_SIZE + 5], b"hello");
+        assert!(archive[BLOCK_SIZE + 5..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn a_file_that_shrank_is_an_error() {
+        let mut tar = TarWriter::new(Vec::new());
+        let result = tar.append_file("log.txt", 0o644, 0, 10, &mut &b"short"[..]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn long_paths_use_the_prefix_field() {
+        let dir = "d".repeat(120);
+        let path = format!("{}/file.txt", dir);
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append_dir(&path, 0o755, 0).unwrap();
+        let archive = tar.finish().unwrap();
+        let header = &archive[..BLOCK_SIZE];
+        assert_eq!(field(header, 345..500), dir);
+        assert_eq!(field(header, 0..100), "file.txt/");
+        assert_eq!(header[156], b'5');
+        assert!(checksum_ok(header));
+    }
+
+    #[test]
+    fn longer_paths_get_a_gnu_long_name_record() {
+        let path = format!("{}/{}", "a".repeat(200), "b".repeat(120));
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append_file(&path, 0o600, 0, 0, &mut io::empty())
+            .unwrap();
+        let archive = tar.finish().unwrap();
+        let long_link = &archive[..BLOCK_SIZE];
+        assert_eq!(field(long_link, 0..100), "././@LongLink");
+        assert_eq!(long_link[156], b'L');
+        assert!(checksum_ok(long_link));
+        let size = u64::from_str_radix(field(long_link, 124..136), 8).unwrap();
+        assert_eq!(size as usize, path.len() + 1);
+        let name = &archive[BLOCK_SIZE..BLOCK_SIZE + path.len() + 1];
+        assert_eq!(name, format!("{}\0", path).as_bytes());
+        // the name record takes whole blocks, the real header follows with the path cut short
+        let header = &archive[BLOCK_SIZE * 2..BLOCK_SIZE * 3];
+        assert_eq!(field(header, 0..100), &path[path.len() - 99..]);
+        assert!(checksum_ok(header));
+    }
+
+    #[test]
+    fn long_symlink_targets_get_a_long_link_record() {
+        let target = "t".repeat(150);
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append_symlink("link", &target).unwrap();
+        let archive = tar.finish().unwrap();
+        assert_eq!(archive[156], b'K');
+        assert_eq!(&archive[BLOCK_SIZE..BLOCK_SIZE + 150], target.as_bytes());
+        let header = &archive[BLOCK_SIZE * 2..BLOCK_SIZE * 3];
+        assert_eq!(header[156], b'2');
+        assert_eq!(field(header, 157..257), &target[..99]);
+    }
+
+    #[test]
+    fn big_sizes_switch_to_base_256() {
+        let mut field = [0u8; 12];
+        write_number(&mut field, 0o77777777777);
+        assert_eq!(&field, b"77777777777\0");
+        write_number(&mut field, 10 << 30);
+        assert_eq!(field[0], 0x80);
+        assert_eq!(&field[4..], &(10u64 << 30).to_be_bytes());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::error;
+use crate::runner::Runner;
+use crate::ssh::Finished;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+// futures for async programs, e.g. tools on tokio. this is not an async ssh backend: ssh2 only
+// blocks, so the work runs on a thread of its own and wakes the waiting task when it is done.
+// nothing here depends on a runtime, any executor can poll them, and several can be awaited at
+// once with join
+pub struct Task<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+struct Shared<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+// run blocking work on a new thread, the result is what the future gives
+pub fn spawn<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> Task<T> {
+    let shared = Arc::new(Mutex::new(Shared {
+        result: None,
+        waker: None,
+    }));
+    let done = shared.clone();
+    thread::spawn(move || {
+        // a panic is passed on to the task awaiting it, instead of leaving it pending forever
+        let result = panic::catch_unwind(AssertUnwindSafe(work));
+        let mut done = done.lock().unwrap();
+        done.result = Some(result);
+        if let Some(waker) = done.waker.take() {
+            waker.wake();
+        }
+    });
+    Task { shared }
+}
+
+impl<T> Future for Task<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// a whole run like `cserun run`: connect, upload, run the command, fetch the files matching
+// `fetch` and remove the run directory, also when one of the steps failed. the runner's cancel
+// token stops it like in the blocking calls
+pub async fn exec(runner: Arc<Runner>, command: &str, fetch: &[String]) -> error::Result<Finished> {
+    let (command, fetch) = (command.to_string(), fetch.to_vec());
+    spawn(move || -> error::Result<Finished> {
+        let session = runner.connect()?;
+        let result = (|| {
+            session.sync()?;
+            let finished = session.run(&command)?;
+            if !fetch.is_empty() && !finished.interrupted && !finished.timed_out {
+                session.fetch(&fetch)?;
+            }
+            Ok(finished)
+        })();
+        // the first error is the one worth reporting, a failed cleanup only if all else went well
+        let cleaned = session.cleanup();
+        let finished = result?;
+        cleaned?;
+        Ok(finished)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wakes_with_the_result() {
+        let mut task = spawn(|| 6 * 7);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(value) = Pin::new(&mut task).poll(&mut cx) {
+                assert_eq!(value, 42);
+                break;
+            }
+            thread::yield_now();
+        }
+    }
+}
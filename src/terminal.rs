@@ -0,0 +1,197 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+#[cfg(unix)]
+use std::sync::OnceLock;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// a pipe written to when there is stdin, a ctrl-c or a window resize to handle,
+// so the output loops can sleep on the ssh socket and still wake up for them
+#[cfg(unix)]
+static WAKE_PIPE: OnceLock<[libc::c_int; 2]> = OnceLock::new();
+
+// puts the local terminal into raw mode and restores it when dropped
+pub struct RawMode {
+    #[cfg(unix)]
+    original: libc::termios,
+}
+
+impl RawMode {
+    #[cfg(unix)]
+    pub fn enable() -> io::Result<RawMode> {
+        unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original })
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn enable() -> io::Result<RawMode> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "raw terminal mode is only supported on unix",
+        ))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+// (columns, rows) of the local terminal, falls back to 80x24 when not a tty
+pub fn size() -> (u32, u32) {
+    match console::Term::stdout().size_checked() {
+        Some((rows, cols)) => (cols as u32, rows as u32),
+        None => (80, 24),
+    }
+}
+
+pub fn term_name() -> String {
+    std::env::var("TERM").unwrap_or_else(|_| "xterm".to_string())
+}
+
+// what makes common tools print colors without a terminal, e.g. cargo, BSD ls, node and pytest.
+// gcc has no such variable and needs -fdiagnostics-color=always
+pub fn color_envs() -> Vec<(String, String)> {
+    let term = match term_name() {
+        term if term == "dumb" => "xterm-256color".to_string(),
+        term => term,
+    };
+    [
+        ("TERM", term.as_str()),
+        ("CLICOLOR_FORCE", "1"),
+        ("FORCE_COLOR", "1"),
+        ("CARGO_TERM_COLOR", "always"),
+        ("PY_COLORS", "1"),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect()
+}
+
+// read stdin on a background thread so the channel loop never blocks on it,
+// an empty chunk is sent once stdin reaches EOF
+pub fn spawn_stdin_reader() -> Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    // the pipe has to exist before the first wake up
+    #[cfg(unix)]
+    wake_fd();
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buffer = [0; 4096];
+        loop {
+            match stdin.read(&mut buffer) {
+                Ok(0) | Err(_) => {
+                    let _ = tx.send(Vec::new());
+                    break;
+                }
+                Ok(size) => {
+                    if tx.send(buffer[..size].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+            wake();
+        }
+        wake();
+    });
+    rx
+}
+
+// what spawn_stdin_reader gives a command that gets no stdin: EOF right away
+pub fn closed_stdin() -> Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    let _ = tx.send(Vec::new());
+    rx
+}
+
+// the read end of the wake pipe, for poll(2)
+#[cfg(unix)]
+pub fn wake_fd() -> libc::c_int {
+    WAKE_PIPE.get_or_init(|| unsafe {
+        let mut fds = [-1; 2];
+        if libc::pipe(fds.as_mut_ptr()) == 0 {
+            for fd in fds {
+                // never block in the signal handler, nor when draining
+                libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+        fds
+    })[0]
+}
+
+// only a write(2), so it is safe to call from a signal handler
+pub fn wake() {
+    #[cfg(unix)]
+    if let Some(fds) = WAKE_PIPE.get() {
+        unsafe {
+            libc::write(fds[1], [0u8].as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+// empty the wake pipe once the loop is awake
+#[cfg(unix)]
+pub fn drain_wake() {
+    let mut buffer = [0u8; 64];
+    while unsafe { libc::read(wake_fd(), buffer.as_mut_ptr() as *mut libc::c_void, 64) } > 0 {}
+}
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    wake();
+    // a second ctrl-c kills us right away, in case the remote side never answers
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+// catch ctrl-c instead of dying, so the remote command can be stopped first
+pub fn catch_interrupt() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as *const () as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_resize(_: libc::c_int) {
+    wake();
+}
+
+// wake the pty loop up when the local terminal is resized
+pub fn catch_resize() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(libc::SIGWINCH, on_resize as *const () as libc::sighandler_t);
+    }
+}
+
+// for ctrl-c pressed somewhere else, e.g. in the client of the control daemon
+pub fn set_interrupted(interrupted: bool) {
+    INTERRUPTED.store(interrupted, Ordering::SeqCst);
+    wake();
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
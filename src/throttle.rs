@@ -0,0 +1,65 @@
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// a token bucket shared by every upload connection, so together they stay under the rate
+pub struct RateLimit {
+    rate: f64,                     // bytes per second
+    chunk: usize,                  // the most written at once, small enough to keep the rate even
+    bucket: Mutex<(Instant, f64)>, // when it was last filled, and the bytes that may go right away
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64) -> RateLimit {
+        let rate = bytes_per_second.max(1);
+        RateLimit {
+            rate: rate as f64,
+            chunk: (rate / 10).clamp(1024, 16384) as usize,
+            bucket: Mutex::new((Instant::now(), 0.0)),
+        }
+    }
+
+    // wait until `bytes` more fit under the rate, a writer that went over pays it back here
+    fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (filled, tokens) = &mut *bucket;
+            let now = Instant::now();
+            // an idle second at most builds up, so a pause is not followed by a long burst
+            *tokens = (*tokens + now.duration_since(*filled).as_secs_f64() * self.rate)
+                .min(self.rate)
+                - bytes as f64;
+            *filled = now;
+            Duration::from_secs_f64((-*tokens).max(0.0) / self.rate)
+        };
+        thread::sleep(wait);
+    }
+}
+
+// a writer capped at the rate, or passed straight through without a limit
+pub struct Throttled<'a, W> {
+    inner: W,
+    limit: Option<&'a RateLimit>,
+}
+
+impl<'a, W: Write> Throttled<'a, W> {
+    pub fn new(inner: W, limit: Option<&'a RateLimit>) -> Throttled<'a, W> {
+        Throttled { inner, limit }
+    }
+}
+
+impl<W: Write> Write for Throttled<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(limit) = self.limit else {
+            return self.inner.write(buf);
+        };
+        let written = self.inner.write(&buf[..buf.len().min(limit.chunk)])?;
+        limit.take(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,104 @@
+use crate::ssh::{Config, RunDir};
+use log::{info, warn};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+// a local copy of the remote command's output, every line prefixed with the time it arrived
+pub struct Transcript {
+    path: PathBuf,
+    file: Option<BufWriter<File>>, // dropped after a write error, the run goes on without it
+    partial: [Vec<u8>; 2],         // unfinished last line of stdout and stderr
+}
+
+fn logs_dir() -> Option<PathBuf> {
+    let mut path = dirs::home_dir()?;
+    path.push(".cserun");
+    path.push("logs");
+    Some(path)
+}
+
+fn now() -> String {
+    chrono::Local::now()
+        .format("%Y-%m-%d %H:%M:%S%.3f")
+        .to_string()
+}
+
+impl Transcript {
+    // --log-file, or one file per run under ~/.cserun/logs with `log = true`.
+    // the command is already running, so a file that can not be created is only a warning
+    pub fn open(conf: &Config, run_dir: &RunDir, command: &str) -> Option<Transcript> {
+        let path = match (&conf.log_file, conf.log_runs) {
+            (Some(path), _) => path.clone(),
+            (None, true) => logs_dir()?.join(format!("{}.log", run_dir.name)),
+            (None, false) => return None,
+        };
+        let open = || -> io::Result<BufWriter<File>> {
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = BufWriter::new(File::create(&path)?);
+            writeln!(file, "# {}@{}", conf.username, conf.server_addr)?;
+            writeln!(file, "# {} started: {}", now(), command)?;
+            Ok(file)
+        };
+        match open() {
+            Ok(file) => Some(Transcript {
+                path,
+                file: Some(file),
+                partial: [Vec::new(), Vec::new()],
+            }),
+            Err(e) => {
+                warn!("Can not write the log {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn stdout(&mut self, data: &[u8]) {
+        self.push(0, data);
+    }
+
+    pub fn stderr(&mut self, data: &[u8]) {
+        self.push(1, data);
+    }
+
+    fn push(&mut self, stream: usize, data: &[u8]) {
+        self.partial[stream].extend_from_slice(data);
+        while let Some(end) = self.partial[stream].iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial[stream].drain(..=end).collect();
+            self.write_line(stream, &line[..line.len() - 1]);
+        }
+    }
+
+    fn write_line(&mut self, stream: usize, line: &[u8]) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let tag = ["out", "err"][stream];
+        let line = String::from_utf8_lossy(line);
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        if let Err(e) = writeln!(file, "{} {} {}", now(), tag, line) {
+            warn!("Stopped writing {}: {}", self.path.display(), e);
+            self.file = None;
+        }
+    }
+
+    // write out unfinished lines and how the command ended
+    pub fn finish(mut self, exit_status: i32) {
+        for stream in 0..2 {
+            if !self.partial[stream].is_empty() {
+                let line = std::mem::take(&mut self.partial[stream]);
+                self.write_line(stream, &line);
+            }
+        }
+        if let Some(mut file) = self.file.take() {
+            let result = writeln!(file, "# {} exit status: {}", now(), exit_status)
+                .and_then(|_| file.flush());
+            match result {
+                Ok(()) => info!("Output saved to {}", self.path.display()),
+                Err(e) => warn!("Stopped writing {}: {}", self.path.display(), e),
+            }
+        }
+    }
+}
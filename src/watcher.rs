@@ -0,0 +1,155 @@
+use crate::error::{self, Context, CserunError};
+use crate::ssh::WalkOptions;
+use crate::sync;
+use log::{debug, warn};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// wakes watch mode when local files change. on linux the kernel tells with inotify, elsewhere,
+// or when inotify runs out of watches, the files are simply checked again after each interval
+pub struct Watcher {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    inotify: Option<Inotify>,
+}
+
+impl Watcher {
+    // watch the directories an upload would walk into, the local directory itself included
+    pub fn new(local_dir: &Path, walk: &WalkOptions) -> error::Result<Watcher> {
+        let mut dirs = BTreeSet::new();
+        dirs.insert(local_dir.to_path_buf());
+        for (path, _) in sync::list_files(local_dir, walk)? {
+            let parents = path.ancestors().skip(1);
+            dirs.extend(
+                parents
+                    .take_while(|dir| dir.starts_with(local_dir) && *dir != local_dir)
+                    .map(Path::to_path_buf),
+            );
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let inotify = Inotify::new(&dirs)
+                .inspect_err(|e| {
+                    warn!(
+                        "Could not watch the local files ({}), checking them every interval instead",
+                        e
+                    )
+                })
+                .ok();
+            Ok(Watcher { inotify })
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            let _ = dirs;
+            Ok(Watcher {})
+        }
+    }
+
+    // wait up to `timeout` for a change. false if nothing happened, true if something may
+    // have, which is always the case without notifications. ctrl-c ends the wait early
+    pub fn wait(&mut self, timeout: Duration) -> error::Result<bool> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(inotify) = &mut self.inotify {
+            return inotify.wait(timeout).context(CserunError::Local);
+        }
+        std::thread::sleep(timeout);
+        Ok(true)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+struct Inotify {
+    fd: std::os::fd::OwnedFd,
+    dirs: Vec<(libc::c_int, PathBuf)>, // watch descriptor and directory
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Inotify {
+    const EVENTS: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ATTRIB;
+
+    fn new(dirs: &BTreeSet<PathBuf>) -> std::io::Result<Inotify> {
+        use std::os::fd::FromRawFd;
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut inotify = Inotify {
+            fd: unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) },
+            dirs: Vec::new(),
+        };
+        for dir in dirs {
+            inotify.add(dir)?;
+        }
+        debug!("Watching {} local directories", inotify.dirs.len());
+        Ok(inotify)
+    }
+
+    fn add(&mut self, dir: &Path) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+        let wd =
+            unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), Self::EVENTS) };
+        if wd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.dirs.push((wd, dir.to_path_buf()));
+        Ok(())
+    }
+
+    fn wait(&mut self, timeout: Duration) -> std::io::Result<bool> {
+        use std::os::fd::AsRawFd;
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        if unsafe { libc::poll(&mut pollfd, 1, millis) } <= 0 {
+            // a timeout, or ctrl-c interrupting the poll
+            return Ok(false);
+        }
+        let mut buffer = [0u8; 16384];
+        let mut changed = false;
+        loop {
+            let size = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len(),
+                )
+            };
+            if size <= 0 {
+                return Ok(changed);
+            }
+            changed = true;
+            let mut offset = 0;
+            while offset < size as usize {
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+                let name_start = offset + std::mem::size_of::<libc::inotify_event>();
+                offset = name_start + event.len as usize;
+                // a new directory gets a watch of its own, files may appear in it next
+                if event.mask & libc::IN_ISDIR != 0
+                    && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0
+                {
+                    let name = &buffer[name_start..offset];
+                    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                    if let Some((_, dir)) = self.dirs.iter().find(|(wd, _)| *wd == event.wd) {
+                        use std::os::unix::ffi::OsStrExt;
+                        let dir = dir.join(std::ffi::OsStr::from_bytes(name));
+                        if let Err(e) = self.add(&dir) {
+                            debug!("Could not watch {}: {}", dir.display(), e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}